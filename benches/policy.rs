@@ -0,0 +1,75 @@
+//! Benchmarks per-request evaluation of compiled audience policies.
+//!
+//! Evaluating the policy for a request should stay well under a microsecond, even with a large
+//! number of configured audiences, since it happens on every validation request.
+
+use cloudflare_access_forwardauth::{
+    config::{AudienceConfig, Config, TokenType},
+    expression::Expression,
+    policy::AudiencePolicies,
+};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde_json::json;
+
+const AUDIENCE_COUNT: usize = 1000;
+
+fn audience_tag(i: usize) -> String {
+    format!("{:064x}", i)
+}
+
+fn build_config() -> Config {
+    let mut config = Config::default();
+    for i in 0..AUDIENCE_COUNT {
+        let audience_config = AudienceConfig {
+            allowed_token_types: vec![TokenType::App],
+            ..Default::default()
+        };
+        config.audiences.insert(audience_tag(i), audience_config);
+    }
+    config
+}
+
+fn evaluate_policy(c: &mut Criterion) {
+    let policies = AudiencePolicies::compile(&build_config()).expect("policies should compile");
+    let known_audience = audience_tag(AUDIENCE_COUNT / 2);
+    let unknown_audience = audience_tag(AUDIENCE_COUNT * 2);
+
+    c.bench_function("evaluate policy (configured audience)", |b| {
+        b.iter(|| {
+            let policy = policies.for_audience(black_box(&known_audience));
+            black_box(policy.map(|policy| policy.allows_token_type(black_box(Some("app")))))
+        })
+    });
+
+    c.bench_function("evaluate policy (default audience)", |b| {
+        b.iter(|| {
+            let policy = policies.for_audience(black_box(&unknown_audience));
+            black_box(policy.map(|policy| policy.allows_token_type(black_box(Some("org")))))
+        })
+    });
+}
+
+fn evaluate_expression(c: &mut Criterion) {
+    let expression = Expression::parse(
+        r#"claims.email.endsWith("@corp.com") && "sre" in claims.groups || request.path.startsWith("/public")"#,
+    )
+    .expect("expression should be valid");
+    let variables = json!({
+        "claims": {
+            "email": "user@corp.com",
+            "groups": ["dev", "sre"],
+        },
+        "request": {
+            "audience": audience_tag(0),
+            "path": "/admin/users",
+        },
+    });
+    let variables = variables.as_object().expect("variables should be a map");
+
+    c.bench_function("evaluate expression", |b| {
+        b.iter(|| black_box(expression.evaluate(black_box(variables))))
+    });
+}
+
+criterion_group!(benches, evaluate_policy, evaluate_expression);
+criterion_main!(benches);
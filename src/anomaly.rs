@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{header, Body, Method, Request, Uri};
+use serde_json::json;
+use tokio::time::interval;
+use tracing::{error, warn};
+
+use crate::{
+    config::AnomalyDetectionConfig,
+    gc::{SweepLimits, SweepOutcome, SweepableCache},
+    validation::HttpClient,
+};
+
+/// How many windows in a row an audience can go without requests before its stats, including its
+/// baseline, are forgotten.
+const MAX_IDLE_WINDOWS: u32 = 60;
+
+/// Detects spikes in the rate of failed validations, per audience.
+///
+/// Validations are counted over fixed windows. At the end of each window, the failure rate for each
+/// audience is compared against its baseline, an exponentially weighted moving average of the
+/// failure rates of previous windows. A failure rate well above the baseline usually means
+/// something changed: keys were rotated, a policy was misconfigured, or a client started sending
+/// bad tokens.
+pub struct AnomalyDetector {
+    window: Duration,
+    ewma_alpha: f64,
+    min_requests: u64,
+    threshold: f64,
+    webhook_url: Option<Uri>,
+    http_client: HttpClient,
+    audiences: Mutex<HashMap<String, AudienceStats>>,
+}
+
+#[derive(Default)]
+struct AudienceStats {
+    requests: u64,
+    failures: u64,
+    baseline: Option<f64>,
+    idle_windows: u32,
+}
+
+impl AudienceStats {
+    /// Roughly how much memory the stats for the given audience use, in bytes.
+    fn memory_bytes(audience: &str) -> usize {
+        std::mem::size_of::<String>() + audience.len() + std::mem::size_of::<Self>()
+    }
+}
+
+/// A window in which an audience's failure rate was well above its baseline.
+struct Anomaly {
+    audience: String,
+    requests: u64,
+    failures: u64,
+    failure_rate: f64,
+    baseline: f64,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: &AnomalyDetectionConfig, http_client: HttpClient) -> Result<Self, String> {
+        let webhook_url = config
+            .webhook_url
+            .as_deref()
+            .map(|url| {
+                Uri::from_str(url).map_err(|e| format!("Invalid anomaly webhook URL: {}", e))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            window: config.window(),
+            ewma_alpha: config.ewma_alpha,
+            min_requests: config.min_requests,
+            threshold: config.threshold,
+            webhook_url,
+            http_client,
+            audiences: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Records the outcome of a validation request for the given audience.
+    ///
+    /// Requests without a token are routine, so only outcomes other than `success` and
+    /// `missing_token` count as failures.
+    pub fn record(&self, audience_label: &str, outcome: &str) {
+        let failed = !matches!(outcome, "success" | "missing_token");
+
+        let mut audiences = self.audiences.lock().unwrap_or_else(|e| e.into_inner());
+        // Only allocate a key for the first request of each audience.
+        if !audiences.contains_key(audience_label) {
+            audiences.insert(audience_label.to_string(), AudienceStats::default());
+        }
+
+        let stats = audiences
+            .get_mut(audience_label)
+            .expect("stats were just inserted");
+        stats.requests += 1;
+        if failed {
+            stats.failures += 1;
+        }
+    }
+
+    /// Evaluates every window as it ends, for as long as the service runs.
+    pub async fn run(self: Arc<Self>) {
+        let mut windows = interval(self.window);
+        windows.tick().await;
+
+        loop {
+            windows.tick().await;
+
+            for anomaly in self.end_window() {
+                warn!(
+                    audience = anomaly.audience.as_str(),
+                    requests = anomaly.requests,
+                    failures = anomaly.failures,
+                    failure_rate = anomaly.failure_rate,
+                    baseline = anomaly.baseline,
+                    "Validation failure rate is well above its baseline."
+                );
+
+                if let Some(webhook_url) = &self.webhook_url {
+                    tokio::spawn(send_webhook(
+                        self.http_client.clone(),
+                        webhook_url.clone(),
+                        anomaly,
+                        self.window,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Ends the current window, returning every audience whose failure rate was anomalous in it.
+    fn end_window(&self) -> Vec<Anomaly> {
+        let mut audiences = self.audiences.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut anomalies = Vec::new();
+        for (audience, stats) in audiences.iter_mut() {
+            let requests = std::mem::take(&mut stats.requests);
+            let failures = std::mem::take(&mut stats.failures);
+            if requests == 0 {
+                stats.idle_windows = stats.idle_windows.saturating_add(1);
+            } else {
+                stats.idle_windows = 0;
+            }
+
+            // Rates from a handful of requests are mostly noise, so they neither trigger an alert
+            // nor move the baseline.
+            if requests < self.min_requests {
+                continue;
+            }
+
+            let failure_rate = failures as f64 / requests as f64;
+            match stats.baseline {
+                None => stats.baseline = Some(failure_rate),
+                Some(baseline) => {
+                    if failure_rate - baseline >= self.threshold {
+                        anomalies.push(Anomaly {
+                            audience: audience.clone(),
+                            requests,
+                            failures,
+                            failure_rate,
+                            baseline,
+                        });
+                    }
+
+                    stats.baseline =
+                        Some(self.ewma_alpha * failure_rate + (1.0 - self.ewma_alpha) * baseline);
+                }
+            }
+        }
+
+        anomalies
+    }
+}
+
+impl SweepableCache for AnomalyDetector {
+    fn name(&self) -> &'static str {
+        "anomaly"
+    }
+
+    /// Forgets audiences that have been idle for too long, and then the audiences that have been
+    /// idle the longest until the stats fit within `limits`.
+    fn sweep(&self, limits: SweepLimits) -> SweepOutcome {
+        let mut audiences = self.audiences.lock().unwrap_or_else(|e| e.into_inner());
+
+        let entries_before = audiences.len();
+        audiences.retain(|_, stats| stats.idle_windows < MAX_IDLE_WINDOWS);
+        let expired = (entries_before - audiences.len()) as u64;
+
+        let mut memory_bytes = audiences
+            .keys()
+            .map(|audience| AudienceStats::memory_bytes(audience))
+            .sum::<usize>();
+
+        let excess_entries = limits.excess_entries(audiences.len());
+        let mut evicted = 0;
+        if limits.exceeded(audiences.len(), memory_bytes) {
+            let mut by_idleness = audiences
+                .iter()
+                .map(|(audience, stats)| (stats.idle_windows, audience.clone()))
+                .collect::<Vec<_>>();
+            by_idleness.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+            for (_, audience) in by_idleness {
+                if !limits.exceeded(audiences.len(), memory_bytes) {
+                    break;
+                }
+
+                audiences.remove(&audience);
+                memory_bytes -= AudienceStats::memory_bytes(&audience);
+                evicted += 1;
+            }
+        }
+
+        SweepOutcome::new(
+            audiences.len(),
+            memory_bytes,
+            expired,
+            evicted,
+            excess_entries,
+        )
+    }
+}
+
+async fn send_webhook(
+    http_client: HttpClient,
+    webhook_url: Uri,
+    anomaly: Anomaly,
+    window: Duration,
+) {
+    let body = json!({
+        "audience": anomaly.audience,
+        "requests": anomaly.requests,
+        "failures": anomaly.failures,
+        "failure_rate": anomaly.failure_rate,
+        "baseline": anomaly.baseline,
+        "window_secs": window.as_secs(),
+    });
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook_url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("should not fail to build request");
+
+    match http_client.request(request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => error!(
+            status = response.status().as_u16(),
+            "Anomaly webhook responded with an error."
+        ),
+        Err(e) => error!(error = %e, "Failed to send anomaly webhook."),
+    }
+}
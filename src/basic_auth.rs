@@ -0,0 +1,111 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use hyper::header::HeaderValue;
+use serde_json::Value;
+use zeroize::Zeroizing;
+
+use crate::config::BasicAuthConfig;
+
+/// Builds `Authorization: Basic` headers for upstreams that only understand Basic auth.
+///
+/// The username is taken from a claim of the validated token, and the password is a secret shared
+/// with the upstream, either for everyone or for a specific service token.
+pub struct BasicAuth {
+    username_claim: String,
+    password: Option<Zeroizing<String>>,
+    service_token_passwords: HashMap<String, Zeroizing<String>>,
+}
+
+impl BasicAuth {
+    /// Loads the passwords for the given settings.
+    pub fn from_config(config: &BasicAuthConfig) -> Result<Self, String> {
+        let password = config
+            .password_file
+            .as_deref()
+            .map(read_password)
+            .transpose()?;
+        let service_token_passwords = config
+            .service_token_password_files
+            .iter()
+            .map(|(client_id, path)| Ok((client_id.clone(), read_password(path)?)))
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self {
+            username_claim: config.username_claim.clone(),
+            password,
+            service_token_passwords,
+        })
+    }
+
+    /// Builds the header value for the given claims, and service token if the token was issued to
+    /// one.
+    ///
+    /// If the username claim is missing, or can't be used as a username, or there's no password
+    /// for the principal, the reason is returned instead.
+    pub fn header_value(
+        &self,
+        claims_json: &Value,
+        service_token_id: Option<&str>,
+    ) -> Result<HeaderValue, String> {
+        let username = match claims_json.pointer(&self.username_claim) {
+            Some(Value::String(username)) => username.clone(),
+            Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+            Some(_) => return Err("username claim is not a scalar".to_string()),
+            None => return Err("username claim is missing".to_string()),
+        };
+
+        // The username can't contain a colon, since it separates the username from the password.
+        if username.is_empty() || username.contains(':') || username.chars().any(char::is_control) {
+            return Err("username claim is not a valid username".to_string());
+        }
+
+        let password = service_token_id
+            .and_then(|client_id| self.service_token_passwords.get(client_id))
+            .or(self.password.as_ref())
+            .ok_or_else(|| "no password is configured for the principal".to_string())?;
+
+        let credentials = Zeroizing::new(format!("{}:{}", username, password.as_str()));
+        let encoded = Zeroizing::new(format!("Basic {}", base64::encode(credentials.as_bytes())));
+        let mut header_value = HeaderValue::from_str(&encoded)
+            .map_err(|_| "credentials are not a valid header value".to_string())?;
+        header_value.set_sensitive(true);
+        Ok(header_value)
+    }
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The passwords themselves are never shown.
+        f.debug_struct("BasicAuth")
+            .field("username_claim", &self.username_claim)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field(
+                "service_tokens",
+                &self.service_token_passwords.keys().collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// Reads a password from the given file, ignoring leading and trailing whitespace.
+fn read_password(path: &Path) -> Result<Zeroizing<String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            format!(
+                "Failed to read Basic auth password file '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+
+    let password = contents.trim();
+    if password.is_empty() {
+        return Err(format!(
+            "Basic auth password file '{}' is empty.",
+            path.display()
+        ));
+    }
+
+    Ok(Zeroizing::new(password.to_string()))
+}
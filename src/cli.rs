@@ -0,0 +1,358 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use cloudflare_access_forwardauth::{
+    config::{
+        BearerMode, ClaimHeaderCase, ClaimsHeaderMode, Config, CredentialMode, HeaderPreset,
+        MetricsMode,
+    },
+    web::MissingTokenPolicy,
+};
+use openidconnect::IssuerUrl;
+
+/// A ForwardAuth implementation based on Cloudflare Access.
+///
+/// Running without a subcommand is equivalent to running `serve`.
+#[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub serve: ConfigArgs,
+
+    /// Prints every environment variable that overrides a setting, and exits.
+    #[arg(long)]
+    pub print_env_mapping: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the ForwardAuth server.
+    Serve(ConfigArgs),
+
+    /// Loads and validates the configuration, and then exits.
+    CheckConfig(ConfigArgs),
+
+    /// Replays recorded validation requests offline, against a JWKS snapshot, and prints the
+    /// decision made for each one.
+    Replay(ReplayArgs),
+
+    /// Prints the version and exits.
+    Version,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// Directory holding a JWKS snapshot for each issuer, named like the files in a JWKS cache
+    /// directory.
+    #[arg(long, value_name = "DIR")]
+    pub jwks_dir: PathBuf,
+
+    /// File of recorded requests, one JSON object per line, such as
+    /// `{"audience": "<aud>", "headers": {"cf-access-jwt-assertion": "<token>"}}`.
+    #[arg(value_name = "PATH")]
+    pub requests: PathBuf,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+/// Arguments for locating the configuration file and overriding individual settings.
+///
+/// Settings given on the command line take precedence over both environment variables and the
+/// configuration file.
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    /// Path to the YAML configuration file.
+    #[arg(long, env = "CONFIG_FILE", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on for HTTP requests.
+    #[arg(long, value_name = "ADDR")]
+    pub listen_address: Option<SocketAddr>,
+
+    /// Cloudflare Access team domain, such as `https://your-team-name.cloudflareaccess.com`.
+    #[arg(long, value_name = "URL")]
+    pub auth_domain: Option<String>,
+
+    /// The header to take the access token from, such as `Authorization` for Bearer tokens.
+    #[arg(long, value_name = "NAME")]
+    pub token_header: Option<String>,
+
+    /// Which credentials to use when both a token header and a cookie are present: `header-first`,
+    /// `cookie-first`, or `try-all`.
+    #[arg(long, value_name = "MODE")]
+    pub credential_mode: Option<CredentialMode>,
+
+    /// Whether to also take the token from an `Authorization: Bearer` header: `disabled`,
+    /// `after-header`, or `before-header`.
+    #[arg(long, value_name = "MODE")]
+    pub bearer_mode: Option<BearerMode>,
+
+    /// How often to refresh the JWKS data, in seconds.
+    #[arg(long, value_name = "SECS")]
+    pub jwks_refresh_interval_secs: Option<u64>,
+
+    /// The maximum random delay added to each JWKS refresh, in seconds.
+    #[arg(long, value_name = "SECS")]
+    pub jwks_refresh_jitter_secs: Option<u64>,
+
+    /// Whether or not to retry validating a token signed with an unknown key after refreshing the
+    /// JWKS data.
+    #[arg(long, value_name = "BOOL")]
+    pub jwks_retry_on_unknown_key: Option<bool>,
+
+    /// How long ago the JWKS data may have last been refreshed before reporting as not ready, in
+    /// seconds, or 0 for no limit.
+    #[arg(long, value_name = "SECS")]
+    pub jwks_max_staleness_secs: Option<u64>,
+
+    /// How long clients should wait before retrying a request made before the JWKS data was loaded,
+    /// in seconds.
+    #[arg(long, value_name = "SECS")]
+    pub not_ready_retry_after_secs: Option<u64>,
+
+    /// How long to wait for in-flight requests to finish when shutting down, in seconds.
+    #[arg(long, value_name = "SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+
+    /// How far the clocks of this host and Cloudflare Access may drift apart, in seconds, up to
+    /// 300.
+    #[arg(long, value_name = "SECS")]
+    pub clock_skew_leeway_secs: Option<u64>,
+
+    /// Header the proxy sends how long it'll wait for a response in.
+    #[arg(long, value_name = "HEADER")]
+    pub deadline_header: Option<String>,
+
+    /// What to do with requests that carry no access token, such as `unauthorized,<aud>=allow`.
+    #[arg(long, value_name = "SPEC")]
+    pub missing_token_behavior: Option<String>,
+
+    /// Claim names, or patterns, whose values must only ever be logged as hashes.
+    #[arg(long, value_name = "PATTERN", value_delimiter = ',')]
+    pub redacted_claims: Option<Vec<String>>,
+
+    /// How metrics are exported: `standard`, or `private`.
+    #[arg(long, value_name = "MODE")]
+    pub metrics_mode: Option<MetricsMode>,
+
+    /// The lowest count that's exported in private metrics mode.
+    #[arg(long, value_name = "COUNT")]
+    pub metrics_min_count: Option<u64>,
+
+    /// How many levels of nested objects in the custom claims to flatten into claims of their own.
+    #[arg(long, value_name = "DEPTH")]
+    pub claim_flatten_depth: Option<usize>,
+
+    /// JSON pointer to the group claim in the custom claims.
+    #[arg(long, value_name = "POINTER")]
+    pub groups_claim: Option<String>,
+
+    /// Which set of identity headers to add to successful responses: `native`, `oauth2-proxy`,
+    /// `oauth2-proxy-only`, or `authelia`.
+    #[arg(long, value_name = "PRESET")]
+    pub header_preset: Option<HeaderPreset>,
+
+    /// Whether or not to add the standard claims of the token as headers.
+    #[arg(long, value_name = "BOOL")]
+    pub standard_claim_headers: Option<bool>,
+
+    /// What to prefix the names of headers that custom claims are added as with.
+    #[arg(long, value_name = "PREFIX")]
+    pub claim_header_prefix: Option<String>,
+
+    /// How the names of custom claims are cased in header names: `train`, `kebab`, `lowercase`, or
+    /// `passthrough`.
+    #[arg(long, value_name = "CASE")]
+    pub claim_header_case: Option<ClaimHeaderCase>,
+
+    /// Whether to add all of the validated claims as a single header: `disabled`, `additional`, or
+    /// `exclusive`.
+    #[arg(long, value_name = "MODE")]
+    pub claims_header: Option<ClaimsHeaderMode>,
+
+    /// The largest claims header to add, in bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub claims_header_max_bytes: Option<usize>,
+
+    /// Path to the service token to header mapping file.
+    #[arg(long, value_name = "PATH")]
+    pub service_token_auth_mapping_file: Option<PathBuf>,
+
+    /// Path to the file containing the token required to use the admin endpoints.
+    #[arg(long, value_name = "PATH")]
+    pub admin_token_file: Option<PathBuf>,
+
+    /// Path to the file containing the secret that proxies must present on validation requests.
+    #[arg(long, value_name = "PATH")]
+    pub proxy_secret_file: Option<PathBuf>,
+
+    /// URL to fetch service token to header mappings from.
+    #[arg(long, value_name = "URL")]
+    pub service_auth_map_url: Option<String>,
+
+    /// Path to a file containing the Bearer token to fetch the service auth map URL with.
+    #[arg(long, value_name = "PATH")]
+    pub service_auth_map_token_file: Option<PathBuf>,
+
+    /// How often to re-fetch the service auth map URL, in seconds.
+    #[arg(long, value_name = "SECS")]
+    pub service_auth_map_refresh_interval_secs: Option<u64>,
+
+    /// Whether or not to reject service tokens that have no header mappings.
+    #[arg(long, value_name = "BOOL")]
+    pub strict_service_tokens: Option<bool>,
+
+    /// Whether or not to reject validation requests for audiences that aren't configured.
+    #[arg(long, value_name = "BOOL")]
+    pub restrict_audiences: Option<bool>,
+}
+
+impl ConfigArgs {
+    /// Loads the configuration, applying any overrides given on the command line.
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut config = Config::load(self.config.as_deref())?;
+
+        if let Some(listen_address) = self.listen_address {
+            config.listen_address = Some(listen_address);
+        }
+
+        if let Some(auth_domain) = &self.auth_domain {
+            let auth_domain = IssuerUrl::new(auth_domain.clone())
+                .map_err(|e| format!("Invalid value for `--auth-domain`: {}", e))?;
+            config.auth_domain = Some(auth_domain);
+        }
+
+        if let Some(token_header) = &self.token_header {
+            config.token_header = token_header.clone();
+        }
+
+        if let Some(credential_mode) = self.credential_mode {
+            config.credential_mode = credential_mode;
+        }
+
+        if let Some(bearer_mode) = self.bearer_mode {
+            config.bearer_mode = bearer_mode;
+        }
+
+        if let Some(secs) = self.jwks_refresh_interval_secs {
+            config.jwks_refresh_interval_secs = secs;
+        }
+
+        if let Some(secs) = self.jwks_refresh_jitter_secs {
+            config.jwks_refresh_jitter_secs = secs;
+        }
+
+        if let Some(retry) = self.jwks_retry_on_unknown_key {
+            config.jwks_retry_on_unknown_key = retry;
+        }
+
+        if let Some(secs) = self.jwks_max_staleness_secs {
+            config.jwks_max_staleness_secs = secs;
+        }
+
+        if let Some(secs) = self.not_ready_retry_after_secs {
+            config.not_ready_retry_after_secs = secs;
+        }
+
+        if let Some(secs) = self.shutdown_timeout_secs {
+            config.shutdown_timeout_secs = secs;
+        }
+
+        if let Some(secs) = self.clock_skew_leeway_secs {
+            config.clock_skew_leeway_secs = secs;
+        }
+
+        if let Some(header_name) = &self.deadline_header {
+            config.deadline_header = Some(header_name.clone());
+        }
+
+        if let Some(spec) = &self.missing_token_behavior {
+            config.missing_token = MissingTokenPolicy::from_spec(spec)
+                .map_err(|e| format!("Invalid value for `--missing-token-behavior`: {}", e))?;
+        }
+
+        if let Some(patterns) = &self.redacted_claims {
+            config.redacted_claims = patterns.clone();
+        }
+
+        if let Some(metrics_mode) = self.metrics_mode {
+            config.metrics_mode = metrics_mode;
+        }
+
+        if let Some(min_count) = self.metrics_min_count {
+            config.metrics_min_count = min_count;
+        }
+
+        if let Some(depth) = self.claim_flatten_depth {
+            config.claim_flatten_depth = depth;
+        }
+
+        if let Some(groups_claim) = &self.groups_claim {
+            config.groups_claim = groups_claim.clone();
+        }
+
+        if let Some(header_preset) = self.header_preset {
+            config.header_preset = header_preset;
+        }
+
+        if let Some(standard_claim_headers) = self.standard_claim_headers {
+            config.standard_claim_headers = standard_claim_headers;
+        }
+
+        if let Some(prefix) = &self.claim_header_prefix {
+            config.claim_header_prefix = prefix.clone();
+        }
+
+        if let Some(case) = self.claim_header_case {
+            config.claim_header_case = case;
+        }
+
+        if let Some(claims_header) = self.claims_header {
+            config.claims_header = claims_header;
+        }
+
+        if let Some(max_bytes) = self.claims_header_max_bytes {
+            config.claims_header_max_bytes = max_bytes;
+        }
+
+        if let Some(path) = &self.service_token_auth_mapping_file {
+            config.service_token_auth_mapping_file = Some(path.clone());
+        }
+
+        if let Some(path) = &self.admin_token_file {
+            config.admin_token_file = Some(path.clone());
+        }
+
+        if let Some(path) = &self.proxy_secret_file {
+            config.proxy_secret_file = Some(path.clone());
+        }
+
+        if let Some(url) = &self.service_auth_map_url {
+            config.service_auth_map_url = Some(url.clone());
+        }
+
+        if let Some(path) = &self.service_auth_map_token_file {
+            config.service_auth_map_token_file = Some(path.clone());
+        }
+
+        if let Some(secs) = self.service_auth_map_refresh_interval_secs {
+            config.service_auth_map_refresh_interval_secs = secs;
+        }
+
+        if let Some(strict) = self.strict_service_tokens {
+            config.strict_service_tokens = strict;
+        }
+
+        if let Some(restrict_audiences) = self.restrict_audiences {
+            config.restrict_audiences = restrict_audiences;
+        }
+
+        // Overrides can affect settings that refer to each other, so validate again.
+        config.validate()?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use hyper::{body::to_bytes, header, Body, Request};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::timeout;
+use zeroize::Zeroizing;
+
+use crate::{config::CloudflareApiConfig, validation::HttpClient};
+
+/// The base URL of the Cloudflare API.
+const API_BASE_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// How many results to ask for in each page of a list.
+const LIST_PAGE_SIZE: usize = 100;
+
+/// The most pages of a list to fetch, which is well beyond the size of any real list, so that a
+/// misbehaving API can't keep us paging forever.
+const MAX_LIST_PAGES: usize = 1000;
+
+/// The envelope every Cloudflare API response is wrapped in.
+#[derive(Deserialize)]
+struct ApiResponse {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    #[serde(default)]
+    result: Value,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: i64,
+    message: String,
+}
+
+/// A client for the Cloudflare API, scoped to a single account.
+pub struct CloudflareApi {
+    http_client: HttpClient,
+    account_id: String,
+    api_token: Zeroizing<String>,
+    timeout: Duration,
+}
+
+impl CloudflareApi {
+    /// Creates the client from the given configuration, loading the API token from its file.
+    pub fn from_config(
+        config: &CloudflareApiConfig,
+        http_client: HttpClient,
+    ) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(&config.api_token_file)
+            .map(Zeroizing::new)
+            .map_err(|e| {
+                format!(
+                    "Failed to read Cloudflare API token file '{}': {}",
+                    config.api_token_file.display(),
+                    e
+                )
+            })?;
+
+        let api_token = contents.trim();
+        if api_token.is_empty() {
+            return Err(format!(
+                "Cloudflare API token file '{}' is empty.",
+                config.api_token_file.display()
+            ));
+        }
+
+        Ok(Self {
+            http_client,
+            account_id: config.account_id.clone(),
+            api_token: Zeroizing::new(api_token.to_string()),
+            timeout: config.timeout(),
+        })
+    }
+
+    /// Makes a `GET` request for the given path, relative to the account, such as
+    /// `/access/users?email=...`, returning the `result` of the response.
+    pub async fn get(&self, path: &str) -> Result<Value, String> {
+        let url = format!("{}/accounts/{}{}", API_BASE_URL, self.account_id, path);
+        let request = Request::get(url)
+            .header(header::ACCEPT, "application/json")
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", self.api_token.as_str()),
+            )
+            .body(Body::empty())
+            .map_err(|e| format!("Failed to build Cloudflare API request: {}", e))?;
+
+        let response = timeout(self.timeout, self.http_client.request(request))
+            .await
+            .map_err(|_| "Cloudflare API request timed out".to_string())?
+            .map_err(|e| format!("Cloudflare API request failed: {}", e))?;
+        let status = response.status();
+        let body = to_bytes(response.into_body())
+            .await
+            .map_err(|e| format!("Failed to read Cloudflare API response: {}", e))?;
+
+        // Errors come back in the same envelope, so parse it regardless of the status, falling back
+        // to the status if the body isn't an envelope at all.
+        let response = serde_json::from_slice::<ApiResponse>(&body).map_err(|_| {
+            format!(
+                "Cloudflare API responded with {} and an unexpected body",
+                status
+            )
+        })?;
+        if !response.success {
+            let errors = response
+                .errors
+                .iter()
+                .map(|e| format!("{} ({})", e.message, e.code))
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "Cloudflare API responded with {}: {}",
+                status,
+                errors.join(", ")
+            ));
+        }
+
+        Ok(response.result)
+    }
+
+    /// Fetches every page of the list at the given path, relative to the account, such as
+    /// `/access/groups`, returning all of the results.
+    pub async fn list(&self, path: &str) -> Result<Vec<Value>, String> {
+        let mut results = Vec::new();
+        for page in 1..=MAX_LIST_PAGES {
+            let page_path = format!("{}?page={}&per_page={}", path, page, LIST_PAGE_SIZE);
+            let page_results = match self.get(&page_path).await? {
+                Value::Array(page_results) => page_results,
+                _ => return Err(format!("Cloudflare API returned a non-list for '{}'", path)),
+            };
+
+            let is_last_page = page_results.len() < LIST_PAGE_SIZE;
+            results.extend(page_results);
+            if is_last_page {
+                break;
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+/// Percent-encodes the given value for use in a query string.
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(b));
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,2636 @@
+use std::{
+    borrow::Cow, collections::HashMap, net::SocketAddr, path::Path, path::PathBuf, str::FromStr,
+    time::Duration,
+};
+
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Method, Uri,
+};
+use openidconnect::IssuerUrl;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    cloudflare::CloudflareApi,
+    expression::Expression,
+    gc::{SweepLimits, SweepSettings},
+    internal_token::InternalTokenIssuer,
+    opa::OpaClient,
+    path_rules,
+    policy::normalize_audience,
+    redaction::wildcard_match,
+    signing::MessageSigner,
+    template::Template,
+    validation::{
+        service_auth::{RawTokenMapping, RemoteTokenMap, ServiceAuthTokenHeaderMap},
+        token::{DEFAULT_TOKEN_HEADER, MAX_CLAIM_FLATTEN_DEPTH},
+        HttpClient,
+    },
+    web::{AdminToken, ErrorPages, MissingTokenPolicy, ProxySecret},
+};
+
+#[cfg(feature = "redis")]
+use crate::shared_cache::SharedCache;
+
+/// The most leeway allowed for clock skew, in seconds, since tokens are still accepted for this
+/// long after they expire.
+const MAX_CLOCK_SKEW_LEEWAY_SECS: u64 = 300;
+
+/// Application configuration.
+///
+/// Configuration is loaded from an optional YAML file, after which any of the environment variables
+/// noted on each field are applied on top, overriding the value from the file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen on for HTTP requests. (`LISTEN_ADDRESS`)
+    pub listen_address: Option<SocketAddr>,
+
+    /// Cloudflare Access team domain, such as `https://your-team-name.cloudflareaccess.com`.
+    /// (`AUTH_DOMAIN`)
+    pub auth_domain: Option<IssuerUrl>,
+
+    /// Additional Cloudflare Access team domains, keyed by a name that audiences can refer to.
+    ///
+    /// Audiences use the team domain from `auth_domain` unless they're explicitly mapped to one of
+    /// these.
+    pub issuers: HashMap<String, IssuerUrl>,
+
+    /// The header to take the access token from. (`TOKEN_HEADER`)
+    ///
+    /// The value may use the `Bearer` scheme, so this can be set to `Authorization` for clients
+    /// that forward the token as `Authorization: Bearer <token>`.
+    pub token_header: String,
+
+    /// Which credentials to use when a request carries both a token header and a cookie.
+    /// (`CREDENTIAL_MODE`)
+    pub credential_mode: CredentialMode,
+
+    /// Whether to also take the access token from a standard `Authorization: Bearer` header, and
+    /// if so, with what precedence over the token header. (`BEARER_MODE`)
+    pub bearer_mode: BearerMode,
+
+    /// Where, and how, to fetch JWKS data from.
+    pub jwks_fetch: JwksFetchConfig,
+
+    /// How often to refresh the JWKS data, in seconds. (`JWKS_REFRESH_INTERVAL_SECS`)
+    pub jwks_refresh_interval_secs: u64,
+
+    /// The maximum random delay added to each JWKS refresh, in seconds, so that replicas started at
+    /// the same time don't all refresh at the same time. (`JWKS_REFRESH_JITTER_SECS`)
+    pub jwks_refresh_jitter_secs: u64,
+
+    /// Whether or not to retry validating a token signed with an unknown key, once the JWKS
+    /// refresh that it triggers has finished. (`JWKS_RETRY_ON_UNKNOWN_KEY`)
+    ///
+    /// Tokens signed with an unknown key always trigger a refresh, subject to a cooldown, but
+    /// are otherwise rejected right away.
+    pub jwks_retry_on_unknown_key: bool,
+
+    /// How long ago the JWKS data may have last been refreshed successfully before this replica
+    /// reports itself as not ready, in seconds. If zero, stale JWKS data never affects readiness.
+    /// (`JWKS_MAX_STALENESS_SECS`)
+    pub jwks_max_staleness_secs: u64,
+
+    /// How long clients should wait before retrying a validation request made before the JWKS data
+    /// was loaded, in seconds. (`NOT_READY_RETRY_AFTER_SECS`)
+    pub not_ready_retry_after_secs: u64,
+
+    /// How long to wait for in-flight requests to finish when shutting down, in seconds.
+    /// (`SHUTDOWN_TIMEOUT_SECS`)
+    pub shutdown_timeout_secs: u64,
+
+    /// How far the clocks of this host and Cloudflare Access may drift apart, in seconds, for the
+    /// checks of the time-based claims of a token, up to 300. (`CLOCK_SKEW_LEEWAY_SECS`)
+    ///
+    /// Tokens are still accepted for this long after they expire, and this long before they're
+    /// issued, or valid from.
+    pub clock_skew_leeway_secs: u64,
+
+    /// The header the proxy sends how long it'll wait for a response in, such as
+    /// `X-Request-Timeout-Ms`, either as a number of milliseconds or in the `grpc-timeout` format.
+    /// (`DEADLINE_HEADER`)
+    ///
+    /// Validation doesn't wait on anything, such as a JWKS refresh or the Cloudflare API, for any
+    /// longer than that. If not set, or the proxy doesn't send the header, there's no deadline.
+    pub deadline_header: Option<String>,
+
+    /// What to do with validation requests that carry no access token. (`MISSING_TOKEN_BEHAVIOR`)
+    pub missing_token: MissingTokenPolicy,
+
+    /// Claim names, or patterns, whose values must only ever be logged as hashes.
+    /// (`REDACTED_CLAIMS`, comma-separated)
+    pub redacted_claims: Vec<String>,
+
+    /// How metrics are exported. (`METRICS_MODE`)
+    pub metrics_mode: MetricsMode,
+
+    /// The lowest count that's exported in private metrics mode. Counters, histograms and
+    /// summaries counting anything less are left out entirely. (`METRICS_MIN_COUNT`)
+    pub metrics_min_count: u64,
+
+    /// Custom claims to extract from nested values, keyed by the claim name to report them under.
+    ///
+    /// Each value is a JSON pointer into the custom claims, such as `/github/email`, for identity
+    /// providers that deliver claims nested under provider-specific keys rather than as flat
+    /// strings.
+    pub custom_claim_paths: HashMap<String, String>,
+
+    /// How many levels of nested objects in the custom claims to flatten into claims of their own.
+    /// (`CLAIM_FLATTEN_DEPTH`)
+    ///
+    /// Each flattened claim is named by the keys leading to it, separated by dots, such as `dept.id`
+    /// for `{"dept": {"id": "42"}}`, which is added as the `X-Dept-Id` header. Objects nested any
+    /// deeper are skipped, as are all objects if this is zero.
+    pub claim_flatten_depth: usize,
+
+    /// JSON pointer to the group claim in the custom claims, holding either a list of groups or a
+    /// single one. (`GROUPS_CLAIM`)
+    ///
+    /// This is what `required_groups` is matched against, and what `group_names` resolves.
+    pub groups_claim: String,
+
+    /// Headers to set from the verified claims, keyed by header name.
+    ///
+    /// Each value is a JSON pointer into the full set of claims, such as `/custom/groups/0` or
+    /// `/iat`, as an escape hatch for claims that aren't otherwise mapped to headers. Scalars, and
+    /// arrays of them, are rendered according to `claim_rendering`, and any other value is rendered
+    /// as JSON.
+    pub claim_headers: HashMap<String, String>,
+
+    /// How boolean, number, and array claim values are rendered as header values.
+    pub claim_rendering: ClaimRenderingConfig,
+
+    /// Which set of identity headers, matching another authentication proxy, to add to successful
+    /// responses. (`HEADER_PRESET`)
+    pub header_preset: HeaderPreset,
+
+    /// Whether or not to add the standard claims of the token, such as the email and subject, as
+    /// headers, in addition to the custom claims. (`STANDARD_CLAIM_HEADERS`)
+    ///
+    /// Otherwise, they're only added if they're also configured as custom "OIDC Claims" in Cloudflare
+    /// Access.
+    pub standard_claim_headers: bool,
+
+    /// What to prefix the names of the headers that custom claims are added as with, which may be
+    /// empty. (`CLAIM_HEADER_PREFIX`)
+    pub claim_header_prefix: String,
+
+    /// How the names of custom claims are cased in the names of the headers they're added as.
+    /// (`CLAIM_HEADER_CASE`)
+    pub claim_header_case: ClaimHeaderCase,
+
+    /// Whether to also add all of the validated claims as a single `X-Auth-Claims` header, holding
+    /// their JSON, base64url-encoded, or to add them only as that header. (`CLAIMS_HEADER`)
+    pub claims_header: ClaimsHeaderMode,
+
+    /// The largest `X-Auth-Claims` header to add, in bytes. Larger ones are left out entirely.
+    /// (`CLAIMS_HEADER_MAX_BYTES`)
+    pub claims_header_max_bytes: usize,
+
+    /// Path to the service token to header mapping file. (`SERVICE_TOKEN_AUTH_MAPPING_FILE`)
+    pub service_token_auth_mapping_file: Option<PathBuf>,
+
+    /// Service token to header mappings given inline, in the same shape as the mapping file.
+    /// (`SERVICE_TOKEN_AUTH_MAPPINGS`, as a JSON or YAML object)
+    ///
+    /// These are merged with the mappings from the mapping file, which take precedence for any
+    /// header mapped in both.
+    pub service_token_auth_mappings: HashMap<String, RawTokenMapping>,
+
+    /// URL to fetch service token to header mappings from, in the same shape as the mapping file,
+    /// as JSON or YAML. (`SERVICE_AUTH_MAP_URL`)
+    ///
+    /// The local mappings take precedence for any header mapped in both.
+    pub service_auth_map_url: Option<String>,
+
+    /// Path to a file containing the Bearer token to fetch `service_auth_map_url` with, if it
+    /// requires one. (`SERVICE_AUTH_MAP_TOKEN_FILE`)
+    pub service_auth_map_token_file: Option<PathBuf>,
+
+    /// How often to re-fetch `service_auth_map_url`, in seconds.
+    /// (`SERVICE_AUTH_MAP_REFRESH_INTERVAL_SECS`)
+    pub service_auth_map_refresh_interval_secs: u64,
+
+    /// Whether or not to reject service tokens that have no header mappings.
+    /// (`STRICT_SERVICE_TOKENS`)
+    ///
+    /// Otherwise, a valid token for an unmapped service token is let through, just without any
+    /// mapped headers.
+    pub strict_service_tokens: bool,
+
+    /// Path to the file containing the token required to use the admin endpoints, which are
+    /// disabled if not set. (`ADMIN_TOKEN_FILE`)
+    pub admin_token_file: Option<PathBuf>,
+
+    /// Path to the file containing the secret that proxies must present, in the
+    /// `X-Forwardauth-Secret` header, on every validation request. Not required if not set.
+    /// (`PROXY_SECRET_FILE`)
+    pub proxy_secret_file: Option<PathBuf>,
+
+    /// Settings for detecting spikes in the rate of failed validations, which is disabled if not
+    /// set.
+    pub anomaly_detection: Option<AnomalyDetectionConfig>,
+
+    /// Settings for periodically sweeping the in-memory caches.
+    pub cache_gc: CacheGcConfig,
+
+    /// Settings for caching validations, so repeated requests with the same token skip verifying it
+    /// again, which is disabled if not set.
+    pub validation_cache: Option<ValidationCacheConfig>,
+
+    /// Settings for sharing cached validations and enrichments between replicas through Redis, and
+    /// for revoking tokens centrally, which is disabled if not set.
+    ///
+    /// Only available when built with the `redis` feature.
+    #[cfg(feature = "redis")]
+    pub redis: Option<RedisConfig>,
+
+    /// Credentials for the Cloudflare API, which the features that query it require.
+    pub cloudflare_api: Option<CloudflareApiConfig>,
+
+    /// Settings for adding user details from the Cloudflare Access users API to successful
+    /// validation responses, which is disabled if not set.
+    pub user_enrichment: Option<UserEnrichmentConfig>,
+
+    /// Settings for adding details about the identity behind each token, from Cloudflare Access'
+    /// get-identity endpoint, to successful validation responses, which is disabled if not set.
+    pub identity_enrichment: Option<IdentityEnrichmentConfig>,
+
+    /// Settings for resolving the Access group IDs in tokens into group names, which is disabled
+    /// if not set.
+    pub group_names: Option<GroupNamesConfig>,
+
+    /// Settings for signing the headers of successful validation responses with HTTP Message
+    /// Signatures, which is disabled if not set.
+    pub message_signatures: Option<MessageSignatureConfig>,
+
+    /// Settings for minting internal tokens carrying the validated identity, for upstreams to
+    /// verify, which is disabled if not set.
+    pub internal_token: Option<InternalTokenConfig>,
+
+    /// Settings for asking Open Policy Agent to authorize requests once their token is validated,
+    /// which is disabled if not set.
+    pub opa: Option<OpaConfig>,
+
+    /// Settings for specific audiences, keyed by the application AUD tag.
+    pub audiences: HashMap<String, AudienceConfig>,
+
+    /// Audiences to use for validation requests made to `/validate`, keyed by the host in the
+    /// `X-Forwarded-Host` header.
+    ///
+    /// This allows the proxy to use a single validation URL for every application, rather than
+    /// templating the audience into the URL.
+    pub hosts: HashMap<String, String>,
+
+    /// Templates for the bodies of rejected validation requests, keyed by status code.
+    ///
+    /// Rejections with any other status code keep their JSON body.
+    pub error_pages: HashMap<u16, ErrorPageConfig>,
+
+    /// Settings for the `/envoy` endpoint, for Envoy's HTTP `ext_authz` filter, which is disabled
+    /// if not set.
+    pub envoy: Option<EnvoyConfig>,
+
+    /// Settings for adapting validation responses to nginx's `auth_request` module, which is
+    /// disabled if not set.
+    pub nginx: Option<NginxConfig>,
+
+    /// Settings for the experimental HTTP/3 listener, which is disabled if not set.
+    ///
+    /// Only available when built with the `http3` feature.
+    #[cfg(feature = "http3")]
+    pub http3: Option<Http3Config>,
+
+    /// Settings for the gRPC listener for Envoy's `ext_authz` filter, which is disabled if not set.
+    ///
+    /// Only available when built with the `ext-authz` feature.
+    #[cfg(feature = "ext-authz")]
+    pub ext_authz: Option<ExtAuthzConfig>,
+
+    /// Whether or not to reject validation requests for audiences not listed in `audiences`.
+    /// (`RESTRICT_AUDIENCES`)
+    ///
+    /// This guards against typos in proxy configuration silently validating tokens against an
+    /// audience that doesn't exist.
+    pub restrict_audiences: bool,
+}
+
+/// Settings for detecting spikes in the rate of failed validations.
+///
+/// Failure rates are tracked per audience over fixed windows, and compared against a baseline that
+/// is an exponentially weighted moving average of previous windows.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyDetectionConfig {
+    /// How long each window is, in seconds.
+    pub window_secs: u64,
+
+    /// How much weight the latest window has in the baseline, between 0 and 1.
+    pub ewma_alpha: f64,
+
+    /// The fewest requests an audience must have in a window for its failure rate to be considered.
+    pub min_requests: u64,
+
+    /// How far above the baseline the failure rate must be to be considered anomalous, as a
+    /// fraction of all requests between 0 and 1.
+    pub threshold: f64,
+
+    /// URL to send a JSON `POST` request to for every anomaly, in addition to logging a warning.
+    pub webhook_url: Option<String>,
+}
+
+impl AnomalyDetectionConfig {
+    /// How long each window is.
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+impl Default for AnomalyDetectionConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            ewma_alpha: 0.2,
+            min_requests: 20,
+            threshold: 0.25,
+            webhook_url: None,
+        }
+    }
+}
+
+/// Settings for periodically sweeping the in-memory caches.
+///
+/// Each sweep removes the entries that are no longer worth keeping, and then evicts the least
+/// recently used entries until the cache fits within its memory and entry limits.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheGcConfig {
+    /// How often to sweep each cache, in seconds.
+    pub sweep_interval_secs: u64,
+
+    /// Roughly how much memory each cache may use, in bytes. If 0, there's no limit.
+    pub max_memory_bytes: usize,
+
+    /// How many entries each cache may hold. If 0, there's no limit.
+    pub max_entries: usize,
+
+    /// Settings for specific caches, keyed by cache name, overriding the ones above.
+    pub caches: HashMap<String, CacheGcRule>,
+}
+
+/// Settings for sweeping a specific cache.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheGcRule {
+    pub sweep_interval_secs: Option<u64>,
+    pub max_memory_bytes: Option<usize>,
+    pub max_entries: Option<usize>,
+}
+
+impl CacheGcConfig {
+    /// Gets how the given cache is swept.
+    pub fn for_cache(&self, name: &str) -> SweepSettings {
+        let rule = self.caches.get(name);
+        let sweep_interval_secs = rule
+            .and_then(|rule| rule.sweep_interval_secs)
+            .unwrap_or(self.sweep_interval_secs);
+        let max_memory_bytes = rule
+            .and_then(|rule| rule.max_memory_bytes)
+            .unwrap_or(self.max_memory_bytes);
+        let max_entries = rule
+            .and_then(|rule| rule.max_entries)
+            .unwrap_or(self.max_entries);
+
+        SweepSettings {
+            interval: Duration::from_secs(sweep_interval_secs),
+            limits: SweepLimits {
+                max_memory_bytes: (max_memory_bytes > 0).then_some(max_memory_bytes),
+                max_entries: (max_entries > 0).then_some(max_entries),
+            },
+        }
+    }
+}
+
+impl Default for CacheGcConfig {
+    fn default() -> Self {
+        Self {
+            sweep_interval_secs: 60,
+            max_memory_bytes: 16 * 1024 * 1024,
+            max_entries: 0,
+            caches: HashMap::new(),
+        }
+    }
+}
+
+/// Credentials for the Cloudflare API.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloudflareApiConfig {
+    /// The ID of the Cloudflare account that the Access applications belong to.
+    pub account_id: String,
+
+    /// Path to the file containing the API token.
+    pub api_token_file: PathBuf,
+
+    /// How long to wait for each API request, in milliseconds.
+    #[serde(default = "default_cloudflare_api_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl CloudflareApiConfig {
+    /// How long to wait for each API request.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+fn default_cloudflare_api_timeout_ms() -> u64 {
+    2000
+}
+
+/// Settings for asking Open Policy Agent to authorize requests.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpaConfig {
+    /// The URL of the decision to query, such as
+    /// `http://localhost:8181/v1/data/forwardauth/decision`.
+    pub url: String,
+
+    /// How long to wait for each decision, in milliseconds.
+    #[serde(default = "default_opa_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl OpaConfig {
+    /// How long to wait for each decision.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+fn default_opa_timeout_ms() -> u64 {
+    500
+}
+
+/// Settings for adding user details from the Cloudflare Access users API to successful validation
+/// responses.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserEnrichmentConfig {
+    /// The user fields to add, such as `access_seat` or `last_successful_login`, mapped to the
+    /// header to add each one as.
+    pub fields: HashMap<String, String>,
+
+    /// How long to cache the details of each user, in seconds.
+    #[serde(default = "default_user_enrichment_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+}
+
+impl UserEnrichmentConfig {
+    /// How long to cache the details of each user.
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+}
+
+/// Settings for adding details about the identity behind each token, from Cloudflare Access'
+/// get-identity endpoint, to successful validation responses.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityEnrichmentConfig {
+    /// Headers to set from the identity, keyed by header name.
+    ///
+    /// Each value is a JSON pointer into the identity, such as `/groups`, `/idp/type` or
+    /// `/devicePosture`. Lists are rendered as their items separated by commas, using the name of
+    /// items that have one, and other objects are rendered as JSON.
+    pub headers: HashMap<String, String>,
+
+    /// How long to cache the identity of each subject, in seconds.
+    #[serde(default = "default_identity_enrichment_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+
+    /// How long to wait for each lookup, in milliseconds.
+    #[serde(default = "default_identity_enrichment_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl IdentityEnrichmentConfig {
+    /// How long to cache the identity of each subject.
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+
+    /// How long to wait for each lookup.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+fn default_identity_enrichment_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_identity_enrichment_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_user_enrichment_cache_ttl_secs() -> u64 {
+    300
+}
+
+/// Settings for caching validations.
+///
+/// Successful validations are only cached for audiences without path rules or an expression, since
+/// those depend on the original request, rather than only the token.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationCacheConfig {
+    /// How long to cache each validation for at most, in seconds. Validations are never cached
+    /// past the expiry of their token.
+    #[serde(default = "default_validation_cache_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+
+    /// How long to cache validations that failed because of the token itself, such as an expired
+    /// or forged one, in seconds. If 0, failures aren't cached.
+    #[serde(default = "default_validation_cache_failure_ttl_secs")]
+    pub failure_ttl_secs: u64,
+}
+
+impl ValidationCacheConfig {
+    /// How long to cache each successful validation for at most.
+    pub fn max_ttl(&self) -> Duration {
+        Duration::from_secs(self.max_ttl_secs)
+    }
+
+    /// How long to cache each failed validation for.
+    pub fn failure_ttl(&self) -> Duration {
+        Duration::from_secs(self.failure_ttl_secs)
+    }
+}
+
+fn default_validation_cache_max_ttl_secs() -> u64 {
+    60
+}
+
+fn default_validation_cache_failure_ttl_secs() -> u64 {
+    10
+}
+
+/// Settings for the Redis server shared between replicas.
+#[cfg(feature = "redis")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisConfig {
+    /// The URL of the Redis server, such as `redis://redis:6379/0`.
+    pub url: String,
+
+    /// Path to the file containing the password for the Redis server, if it requires one.
+    pub password_file: Option<PathBuf>,
+
+    /// Prefixed to every key, so several deployments can share a Redis server.
+    #[serde(default = "default_redis_key_prefix")]
+    pub key_prefix: String,
+
+    /// How long to wait for each Redis command, in milliseconds.
+    #[serde(default = "default_redis_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// How long to share each entry for at most, in seconds. If not set, entries are shared for as
+    /// long as the cache they come from keeps them.
+    pub max_ttl_secs: Option<u64>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisConfig {
+    /// How long to wait for each Redis command.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// How long to share each entry for at most, if limited.
+    pub fn max_ttl(&self) -> Option<Duration> {
+        self.max_ttl_secs.map(Duration::from_secs)
+    }
+}
+
+#[cfg(feature = "redis")]
+fn default_redis_key_prefix() -> String {
+    "forwardauth:".to_string()
+}
+
+#[cfg(feature = "redis")]
+fn default_redis_timeout_ms() -> u64 {
+    100
+}
+
+/// Settings for resolving Access group IDs into group names.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupNamesConfig {
+    /// The header to emit the group names in, separated by commas.
+    pub header: String,
+
+    /// How often to refresh the group names, in seconds.
+    pub refresh_interval_secs: u64,
+}
+
+impl GroupNamesConfig {
+    /// How often to refresh the group names.
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval_secs)
+    }
+}
+
+impl Default for GroupNamesConfig {
+    fn default() -> Self {
+        Self {
+            header: "X-Auth-Group-Names".to_string(),
+            refresh_interval_secs: 3600,
+        }
+    }
+}
+
+/// Settings for signing response headers with HMAC-SHA256.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageSignatureConfig {
+    /// The key ID to send in the `keyid` signature parameter, so upstreams know which key to verify
+    /// the signature with.
+    pub key_id: String,
+
+    /// Path to the file containing the base64-encoded HMAC-SHA256 key, at least 32 bytes long.
+    pub key_file: PathBuf,
+
+    /// Keys that were current before this one, which responses are signed with as well, so
+    /// upstreams can move over to the current key at their own pace while it's rotated.
+    #[serde(default)]
+    pub previous_keys: Vec<SigningKeyConfig>,
+
+    /// Names of the response headers to cover with the signature.
+    pub headers: Vec<String>,
+
+    /// Which headers the signatures are added in.
+    #[serde(default)]
+    pub format: MessageSignatureFormat,
+}
+
+/// A key to sign response headers with.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKeyConfig {
+    pub key_id: String,
+    pub key_file: PathBuf,
+}
+
+/// Which headers response header signatures are added in.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageSignatureFormat {
+    /// `Signature-Input` and `Signature`, as HTTP Message Signatures (RFC 9421).
+    #[default]
+    Rfc9421,
+
+    /// A single `X-Auth-Signature` header, for upstreams without an RFC 9421 implementation.
+    XAuthSignature,
+}
+
+/// Settings for the template of the body of rejections with a given status code.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPageConfig {
+    /// The file to read the template from.
+    pub path: PathBuf,
+
+    /// The content type of the rendered template, which is inferred from the extension of its file
+    /// if not set.
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+/// Settings for the endpoint for Envoy's HTTP `ext_authz` filter.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvoyConfig {
+    /// Names of the headers for Envoy to remove from allowed requests before sending them upstream,
+    /// such as the header carrying the access token.
+    pub headers_to_remove: Vec<String>,
+}
+
+/// Settings for adapting validation responses to nginx's `auth_request` module.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NginxConfig {
+    /// Whether or not to also add the identity as `X-Auth-User`, `X-Auth-Email` and
+    /// `X-Auth-Groups`, so every nginx configuration can read it from the same variables.
+    pub identity_headers: bool,
+}
+
+/// Settings for minting internal tokens.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InternalTokenConfig {
+    /// The issuer of internal tokens, such as the URL upstreams reach this service at.
+    pub issuer: String,
+
+    /// The key ID to send in the `kid` header of internal tokens, and in the JWKS data.
+    pub key_id: String,
+
+    /// Path to the file containing the RSA private key to sign internal tokens with, PEM-encoded
+    /// in PKCS#1 format.
+    pub key_file: PathBuf,
+
+    /// How long internal tokens are valid for, in seconds.
+    #[serde(default = "default_internal_token_ttl_secs")]
+    pub ttl_secs: u64,
+
+    /// The header to add internal tokens as.
+    #[serde(default = "default_internal_token_header")]
+    pub header: String,
+
+    /// Names of the custom claims to carry over into internal tokens.
+    #[serde(default)]
+    pub claims: Vec<String>,
+}
+
+impl InternalTokenConfig {
+    /// How long internal tokens are valid for.
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+fn default_internal_token_ttl_secs() -> u64 {
+    300
+}
+
+fn default_internal_token_header() -> String {
+    "X-Auth-Internal-Token".to_string()
+}
+
+/// Settings for the HTTP/3 listener.
+#[cfg(feature = "http3")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// Address to listen on for HTTP/3 requests, over UDP.
+    pub listen_address: SocketAddr,
+
+    /// Where to load the TLS certificate chain and private key from.
+    pub tls: TlsSource,
+
+    /// How often to reload the TLS certificate chain and private key, in seconds.
+    ///
+    /// This picks up rotated certificates without a restart. If zero, they're only loaded once.
+    /// SVIDs from the SPIFFE Workload API are always picked up as they're rotated, instead.
+    #[serde(default)]
+    pub tls_reload_interval_secs: u64,
+
+    /// How long clients may cache the `Alt-Svc` advertisement sent on the TCP listener, in seconds.
+    ///
+    /// If zero, HTTP/3 is not advertised.
+    #[serde(default = "default_alt_svc_max_age_secs")]
+    pub alt_svc_max_age_secs: u64,
+}
+
+#[cfg(feature = "http3")]
+impl Http3Config {
+    /// Gets the `Alt-Svc` header value advertising the HTTP/3 listener, if it should be advertised.
+    pub fn alt_svc(&self) -> Option<String> {
+        (self.alt_svc_max_age_secs > 0).then(|| {
+            format!(
+                "h3=\":{}\"; ma={}",
+                self.listen_address.port(),
+                self.alt_svc_max_age_secs
+            )
+        })
+    }
+}
+
+#[cfg(feature = "http3")]
+fn default_alt_svc_max_age_secs() -> u64 {
+    86400
+}
+
+/// Settings for the gRPC listener for Envoy's `ext_authz` filter.
+#[cfg(feature = "ext-authz")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtAuthzConfig {
+    /// Address to listen on for `envoy.service.auth.v3.Authorization` gRPC calls.
+    pub listen_address: SocketAddr,
+}
+
+/// Where to load a TLS certificate chain and private key from.
+///
+/// Apart from SVIDs from the SPIFFE Workload API, both are expected to be PEM-encoded.
+#[cfg(feature = "http3")]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum TlsSource {
+    /// Files on disk.
+    File {
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    },
+
+    /// Environment variables holding the PEM blobs themselves.
+    Env { cert_var: String, key_var: String },
+
+    /// A `kubernetes.io/tls` secret, read from the Kubernetes API with the pod's service account.
+    ///
+    /// If no namespace is given, the pod's own namespace is used.
+    KubernetesSecret {
+        namespace: Option<String>,
+        name: String,
+    },
+
+    /// The X.509-SVID of this workload, from the SPIFFE Workload API, such as a SPIRE agent's.
+    ///
+    /// The Workload API sends a new SVID whenever it's rotated, which is swapped in right away, so
+    /// this isn't reloaded on an interval. If no socket is given, it's taken from
+    /// `SPIFFE_ENDPOINT_SOCKET`, and if no SPIFFE ID is given, the workload's default SVID is used.
+    #[cfg(feature = "spiffe")]
+    Spiffe {
+        /// The `unix:` address of the Workload API, like `unix:///run/spire/sockets/agent.sock`.
+        socket: Option<String>,
+        spiffe_id: Option<String>,
+
+        /// A response header to send the SPIFFE ID of the SVID in, so that the proxy can tell
+        /// which workload made the decision.
+        id_header: Option<String>,
+
+        /// The SPIFFE ID that clients must present an X.509-SVID for, verified against the trust
+        /// bundle from the Workload API.
+        ///
+        /// If not set, clients aren't asked for a certificate at all.
+        peer_spiffe_id: Option<String>,
+    },
+}
+
+/// Settings for fetching JWKS data.
+///
+/// JWKS data can be fetched both directly from the team domain and through regional proxy
+/// endpoints, so that refreshes stay reliable even when one egress path is degraded. The fetch is
+/// raced, or hedged, across all of them, and the first success is used.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwksFetchConfig {
+    /// Whether or not to fetch JWKS data directly from the team domain.
+    pub direct: bool,
+
+    /// Proxy endpoints to fetch JWKS data through.
+    ///
+    /// Each endpoint must forward requests for `<endpoint>/<team domain host>/<path>` to
+    /// `https://<team domain host>/<path>`.
+    pub proxies: Vec<String>,
+
+    /// How long to wait for a response before also trying the next source, in milliseconds.
+    ///
+    /// If zero, every source is tried at once.
+    pub hedge_delay_ms: u64,
+
+    /// Directory holding a static JWKS file for each issuer, used instead of fetching JWKS data
+    /// over HTTPS, for environments that can't reach the team domain.
+    ///
+    /// Each issuer's JWKS data is read from `<team domain host>.jwks.json`, and is re-read on every
+    /// refresh to pick up changes. If set, `direct` and `proxies` are ignored.
+    pub static_dir: Option<PathBuf>,
+
+    /// Directory to cache JWKS data in, which is disabled if not set.
+    ///
+    /// JWKS data is written to the cache after every successful refresh, and loaded from it at
+    /// startup, so that restarts don't depend on the team domain being reachable.
+    pub cache_dir: Option<PathBuf>,
+
+    /// The oldest cached JWKS data that will be loaded at startup, in seconds.
+    pub cache_max_age_secs: u64,
+}
+
+impl JwksFetchConfig {
+    /// How long to wait for a response before also trying the next source.
+    pub fn hedge_delay(&self) -> Duration {
+        Duration::from_millis(self.hedge_delay_ms)
+    }
+
+    /// The oldest cached JWKS data that will be loaded at startup.
+    pub fn cache_max_age(&self) -> Duration {
+        Duration::from_secs(self.cache_max_age_secs)
+    }
+}
+
+impl Default for JwksFetchConfig {
+    fn default() -> Self {
+        Self {
+            direct: true,
+            proxies: Vec::new(),
+            hedge_delay_ms: 0,
+            static_dir: None,
+            cache_dir: None,
+            cache_max_age_secs: 86400,
+        }
+    }
+}
+
+/// Settings for a specific audience.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudienceConfig {
+    /// The name of the issuer, from `issuers`, whose tokens are accepted for this audience.
+    ///
+    /// If not set, tokens from the team domain in `auth_domain` are accepted.
+    pub issuer: Option<String>,
+
+    /// The token types accepted for this audience.
+    ///
+    /// If empty, tokens of any type are accepted.
+    pub allowed_token_types: Vec<TokenType>,
+
+    /// The kinds of principal accepted for this audience: `user`, for tokens issued to a user
+    /// identity, and `service`, for tokens issued to a service token.
+    ///
+    /// If empty, both are accepted.
+    pub allowed_principals: Vec<PrincipalType>,
+
+    /// Groups, from the group claim, that the principal must be a member of at least one of.
+    ///
+    /// If empty, no group membership is required.
+    pub required_groups: Vec<String>,
+
+    /// Emails, from the email claim, that are accepted for this audience.
+    ///
+    /// Tokens without an email, such as those issued to service tokens, are never on the
+    /// allowlist. If both this and `allowed_email_domains` are empty, any email is accepted.
+    pub allowed_emails: Vec<String>,
+
+    /// Email domains, such as `example.com`, whose emails are accepted for this audience.
+    ///
+    /// Only exact domains match, so subdomains must be listed separately.
+    pub allowed_email_domains: Vec<String>,
+
+    /// Rules for requests to specific paths, and methods, of this audience.
+    ///
+    /// The first rule matching the original request applies, on top of the settings above. If no
+    /// rule matches, the request is let through.
+    pub path_rules: Vec<PathRuleConfig>,
+
+    /// An expression, in a subset of CEL, that must evaluate to `true` for requests to this
+    /// audience, such as `claims.email.endsWith("@corp.com") || request.path.startsWith("/public")`.
+    ///
+    /// This is checked after every other rule for the audience.
+    pub expression: Option<String>,
+
+    /// Settings for adding an `Authorization: Basic` header to successful validation responses for
+    /// this audience, for upstreams that only understand Basic auth. Not added if not set.
+    pub basic_auth: Option<BasicAuthConfig>,
+
+    /// Static headers to add to successful validation responses for this audience, such as
+    /// `X-Env: staging`, to pass deployment context to the application alongside identity.
+    ///
+    /// These take precedence over headers derived from the token's claims.
+    pub static_headers: HashMap<String, String>,
+
+    /// Exact header names to add claims as for this audience, such as `Remote-User`, keyed by claim
+    /// name, instead of the `X-<Claim>` header they'd be added as otherwise.
+    ///
+    /// Custom claims are named as they are for the automatic headers, including flattened and
+    /// extracted claims, and the standard `email`, `sub`, `iat`, `exp` and `country` claims can be
+    /// mapped as well, with custom claims of the same name taking precedence.
+    pub claim_header_names: HashMap<String, String>,
+
+    /// Which claims are added as headers for this audience, whether automatically or as mapped in
+    /// `claim_header_names`. All of them are by default.
+    pub forwarded_claims: ClaimFilterConfig,
+
+    /// Headers to add to successful validation responses for this audience, keyed by header name,
+    /// with values templated from the token's claims, such as `{{email}} ({{sub}})`.
+    ///
+    /// If any claim a template refers to is missing, or isn't a scalar or an array of them, that
+    /// header is left out. These take precedence over other headers derived from the token's
+    /// claims, but not over `static_headers`.
+    pub header_templates: HashMap<String, String>,
+
+    /// Whether, and where, to record the `jti` of every token accepted for this audience until it
+    /// expires, so that a token presented again is rejected as a replay.
+    ///
+    /// Tokens without a `jti` are rejected outright when enabled.
+    pub jti_replay: JtiReplayMode,
+}
+
+/// A rule for requests to matching paths, and methods, of an audience.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathRuleConfig {
+    /// The path pattern to match, such as `/admin/**`.
+    ///
+    /// `*` matches any single path segment, and `**` matches any number of them, including none.
+    pub path: String,
+
+    /// The methods to match, such as `POST`.
+    ///
+    /// If empty, requests with any method match.
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    /// The kinds of principal accepted for matching requests.
+    ///
+    /// If empty, both are accepted.
+    #[serde(default)]
+    pub allowed_principals: Vec<PrincipalType>,
+
+    /// Groups, from the group claim, that the principal must be a member of at least one of for
+    /// matching requests.
+    ///
+    /// If empty, no group membership is required.
+    #[serde(default)]
+    pub required_groups: Vec<String>,
+}
+
+/// Which claims are added as headers for an audience.
+///
+/// Claims are named as they are for the automatic headers, and patterns may use `*` as a wildcard
+/// for any number of characters (e.g. `email`, `github.*`). A claim matching any `deny` pattern is
+/// never added, while a claim matching any `allow` pattern otherwise is, and any other claim is
+/// handled according to `default`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClaimFilterConfig {
+    pub default: ClaimFilterDefault,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl ClaimFilterConfig {
+    /// Whether or not the given claim is added as a header.
+    pub fn allows(&self, claim_name: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern, claim_name))
+        };
+
+        if matches(&self.deny) {
+            return false;
+        }
+
+        matches(&self.allow) || self.default == ClaimFilterDefault::Allow
+    }
+}
+
+/// What happens to claims that a claim filter doesn't list.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClaimFilterDefault {
+    /// They're added as headers.
+    #[default]
+    Allow,
+
+    /// They're left out.
+    Deny,
+}
+
+/// Settings for deriving Basic auth credentials from validated tokens.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    /// JSON pointer to the claim to use as the username, into the full set of claims, such as
+    /// `/email` or `/custom/username`.
+    pub username_claim: String,
+
+    /// Path to the file containing the password to use, unless the token was issued to a service
+    /// token with a password of its own.
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+
+    /// Paths to the files containing the passwords to use for specific service tokens, keyed by
+    /// client ID.
+    #[serde(default)]
+    pub service_token_password_files: HashMap<String, PathBuf>,
+}
+
+/// How boolean, number, and array claim values are rendered as header values.
+///
+/// Strings are always used as-is, while objects, nulls, and arrays holding anything but scalars are
+/// left to whatever is mapping the claim to a header.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClaimRenderingConfig {
+    /// How booleans are rendered, unless overridden for the claim.
+    pub booleans: BooleanFormat,
+
+    /// How many decimal places to render non-integer numbers with, unless overridden for the claim.
+    ///
+    /// If not set, numbers are rendered exactly as they appear in the token.
+    pub number_precision: Option<usize>,
+
+    /// What to separate the items of arrays with, unless overridden for the claim.
+    ///
+    /// Items aren't escaped, so items containing the delimiter can't be told apart from two items.
+    pub array_delimiter: String,
+
+    /// Rendering rules for specific claims, keyed by claim name.
+    pub claims: HashMap<String, ClaimRenderingRule>,
+}
+
+impl Default for ClaimRenderingConfig {
+    fn default() -> Self {
+        Self {
+            booleans: BooleanFormat::default(),
+            number_precision: None,
+            array_delimiter: ",".to_string(),
+            claims: HashMap::new(),
+        }
+    }
+}
+
+impl ClaimRenderingConfig {
+    /// Renders the given value of the given claim.
+    ///
+    /// Arrays of scalars are rendered as their items, separated by the array delimiter, and empty
+    /// arrays aren't rendered at all.
+    ///
+    /// Returns `None` if the value isn't a string, boolean, number, or array of them.
+    pub fn render<'a>(&self, claim_name: &str, value: &'a Value) -> Option<Cow<'a, str>> {
+        let rule = self.claims.get(claim_name);
+        match value {
+            Value::Array(items) if !items.is_empty() => {
+                let items = items
+                    .iter()
+                    .map(|item| self.render_scalar(rule, item))
+                    .collect::<Option<Vec<_>>>()?;
+                let delimiter = rule
+                    .and_then(|rule| rule.array_delimiter.as_deref())
+                    .unwrap_or(&self.array_delimiter);
+                Some(Cow::Owned(items.join(delimiter)))
+            }
+            _ => self.render_scalar(rule, value),
+        }
+    }
+
+    fn render_scalar<'a>(
+        &self,
+        rule: Option<&ClaimRenderingRule>,
+        value: &'a Value,
+    ) -> Option<Cow<'a, str>> {
+        match value {
+            Value::String(s) => Some(Cow::Borrowed(s.as_str())),
+            Value::Bool(b) => {
+                let booleans = rule.and_then(|rule| rule.booleans).unwrap_or(self.booleans);
+                Some(Cow::Borrowed(booleans.render(*b)))
+            }
+            Value::Number(n) => {
+                let precision = rule
+                    .and_then(|rule| rule.number_precision)
+                    .or(self.number_precision);
+                let rendered = match (precision, n.as_f64()) {
+                    // Integers are rendered as-is, as decimal places would only add noise.
+                    (Some(precision), Some(f)) if !n.is_i64() && !n.is_u64() => {
+                        format!("{:.*}", precision, f)
+                    }
+                    _ => n.to_string(),
+                };
+                Some(Cow::Owned(rendered))
+            }
+            Value::Null | Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+}
+
+/// How a single claim's boolean and number values are rendered, overriding the defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClaimRenderingRule {
+    pub booleans: Option<BooleanFormat>,
+    pub number_precision: Option<usize>,
+    pub array_delimiter: Option<String>,
+}
+
+/// How a boolean claim value is rendered.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BooleanFormat {
+    /// `true` or `false`.
+    #[default]
+    TrueFalse,
+
+    /// `1` or `0`.
+    OneZero,
+}
+
+impl BooleanFormat {
+    fn render(self, value: bool) -> &'static str {
+        match (self, value) {
+            (Self::TrueFalse, true) => "true",
+            (Self::TrueFalse, false) => "false",
+            (Self::OneZero, true) => "1",
+            (Self::OneZero, false) => "0",
+        }
+    }
+}
+
+/// Which of the credentials presented with a request are used.
+///
+/// A request can carry a token in both the token header and the `CF_Authorization` cookie, and they
+/// don't necessarily agree: a stale cookie for one application may accompany a fresh header for
+/// another.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialMode {
+    /// Use the token header if present, and otherwise the cookie.
+    HeaderFirst,
+
+    /// Use the cookie if present, and otherwise the token header.
+    CookieFirst,
+
+    /// Try every presented credential, header first, and succeed if any of them validates.
+    ///
+    /// Malformed credentials are skipped, rather than rejecting the request outright.
+    TryAll,
+}
+
+impl FromStr for CredentialMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header-first" => Ok(Self::HeaderFirst),
+            "cookie-first" => Ok(Self::CookieFirst),
+            "try-all" => Ok(Self::TryAll),
+            other => Err(format!(
+                "unknown credential mode '{}' (expected one of: header-first, cookie-first, try-all)",
+                other
+            )),
+        }
+    }
+}
+
+/// How metrics are exported.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetricsMode {
+    /// Export every metric with all of its labels.
+    Standard,
+
+    /// Export metrics without anything that could identify, or single out, the people using the
+    /// protected applications, for deployments where even aggregate per-user telemetry is
+    /// restricted.
+    ///
+    /// Only labels with a fixed set of values are kept, audiences and issuers are only exported as
+    /// hashes, and counts below the minimum count are left out.
+    Private,
+}
+
+impl FromStr for MetricsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Self::Standard),
+            "private" => Ok(Self::Private),
+            other => Err(format!(
+                "unknown metrics mode '{}' (expected one of: standard, private)",
+                other
+            )),
+        }
+    }
+}
+
+/// A set of identity headers to add to successful responses, matching those of another
+/// authentication proxy, so this can replace it without changing what applications expect.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeaderPreset {
+    /// Only add our own headers.
+    Native,
+
+    /// Also add the headers that oauth2-proxy sets: `X-Auth-Request-User`,
+    /// `X-Auth-Request-Email`, `X-Auth-Request-Groups`, and the validated token as
+    /// `Authorization: Bearer <token>`.
+    Oauth2Proxy,
+
+    /// Add the headers that oauth2-proxy sets instead of our own claim headers, so applications
+    /// see the exact same set of headers as they did behind it.
+    Oauth2ProxyOnly,
+
+    /// Also add the headers that Authelia sets: `Remote-User`, `Remote-Email`, `Remote-Name` (from
+    /// the `name` custom claim), and `Remote-Groups`.
+    Authelia,
+}
+
+impl HeaderPreset {
+    /// Whether or not our own claim headers are added alongside the preset's.
+    pub fn includes_native(self) -> bool {
+        self != Self::Oauth2ProxyOnly
+    }
+
+    /// Whether or not the headers that oauth2-proxy sets are added.
+    pub fn includes_oauth2_proxy(self) -> bool {
+        matches!(self, Self::Oauth2Proxy | Self::Oauth2ProxyOnly)
+    }
+}
+
+impl FromStr for HeaderPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Self::Native),
+            "oauth2-proxy" => Ok(Self::Oauth2Proxy),
+            "oauth2-proxy-only" => Ok(Self::Oauth2ProxyOnly),
+            "authelia" => Ok(Self::Authelia),
+            other => Err(format!(
+                "unknown header preset '{}' (expected one of: native, oauth2-proxy, oauth2-proxy-only, authelia)",
+                other
+            )),
+        }
+    }
+}
+
+/// How the names of custom claims are cased in the names of the headers they're added as.
+///
+/// Dots in the names of flattened claims separate words, as do underscores, dashes, and changes in
+/// case, for every mode that splits names into words.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClaimHeaderCase {
+    /// Words capitalized, and separated by dashes, such as `Dept-Id` for `dept_id`.
+    Train,
+
+    /// Words in lowercase, and separated by dashes, such as `dept-id` for `deptId`.
+    Kebab,
+
+    /// The name in lowercase, as it is otherwise, such as `dept_id` for `Dept_Id`.
+    Lowercase,
+
+    /// The name exactly as it is.
+    Passthrough,
+}
+
+impl FromStr for ClaimHeaderCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "train" => Ok(Self::Train),
+            "kebab" => Ok(Self::Kebab),
+            "lowercase" => Ok(Self::Lowercase),
+            "passthrough" => Ok(Self::Passthrough),
+            other => Err(format!(
+                "unknown claim header case '{}' (expected one of: train, kebab, lowercase, passthrough)",
+                other
+            )),
+        }
+    }
+}
+
+/// Whether the validated claims are added as a single `X-Auth-Claims` header.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClaimsHeaderMode {
+    /// Only add a header per claim.
+    Disabled,
+
+    /// Add the claims header as well as a header per claim.
+    Additional,
+
+    /// Only add the claims header, instead of a header per standard or custom claim.
+    Exclusive,
+}
+
+impl FromStr for ClaimsHeaderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "additional" => Ok(Self::Additional),
+            "exclusive" => Ok(Self::Exclusive),
+            other => Err(format!(
+                "unknown claims header mode '{}' (expected one of: disabled, additional, exclusive)",
+                other
+            )),
+        }
+    }
+}
+
+/// Whether, and with what precedence, the access token is also taken from a standard
+/// `Authorization: Bearer` header (RFC 6750).
+///
+/// This lets tooling and internal services that already speak Bearer use the validator without
+/// setting Cloudflare-specific headers. Either way, the two headers count as a single "header"
+/// credential for the purposes of the credential mode.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BearerMode {
+    /// Only use the token header.
+    Disabled,
+
+    /// Use the token header if present, and otherwise the `Authorization` header.
+    AfterHeader,
+
+    /// Use the `Authorization` header if present, and otherwise the token header.
+    BeforeHeader,
+}
+
+impl FromStr for BearerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "after-header" => Ok(Self::AfterHeader),
+            "before-header" => Ok(Self::BeforeHeader),
+            other => Err(format!(
+                "unknown bearer mode '{}' (expected one of: disabled, after-header, before-header)",
+                other
+            )),
+        }
+    }
+}
+
+/// Where the `jti` of tokens accepted for an audience are recorded, to reject replays.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum JtiReplayMode {
+    /// Replays aren't checked for.
+    #[default]
+    Disabled,
+
+    /// In memory, so only replays to the same replica are caught.
+    Memory,
+
+    /// In Redis, so replays to any replica sharing it are caught.
+    ///
+    /// If Redis is unavailable, this falls back to recording them in memory.
+    Redis,
+}
+
+/// The type of a Cloudflare Access token, as given by its `type` claim.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    /// A token issued for a specific application.
+    App,
+
+    /// A token issued for the team domain itself.
+    Org,
+}
+
+impl TokenType {
+    /// Gets the token type for the given value of the `type` claim, if it's a known type.
+    pub fn from_claim(token_type: &str) -> Option<Self> {
+        match token_type {
+            "app" => Some(Self::App),
+            "org" => Some(Self::Org),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of principal a Cloudflare Access token was issued to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrincipalType {
+    /// A user, who logged in with an identity provider.
+    User,
+
+    /// A service token, identified by its client ID in the `common_name` claim.
+    Service,
+}
+
+impl PrincipalType {
+    /// Gets the kind of principal a token with the given `common_name` claim was issued to.
+    pub fn from_service_token_id(service_token_id: Option<&str>) -> Self {
+        match service_token_id {
+            Some(_) => Self::Service,
+            None => Self::User,
+        }
+    }
+
+    /// Gets a short, stable name for the kind of principal.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Service => "service",
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the given file, if any, and applies any environment variable
+    /// overrides.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that settings which refer to other settings are consistent with each other.
+    pub fn validate(&self) -> Result<(), String> {
+        if HeaderName::from_bytes(self.token_header.as_bytes()).is_err() {
+            return Err(format!(
+                "Token header '{}' is not a valid header name.",
+                self.token_header
+            ));
+        }
+
+        // The token header would be read as a Bearer header too, so every token would count twice.
+        if self.bearer_mode != BearerMode::Disabled
+            && self
+                .token_header
+                .eq_ignore_ascii_case(header::AUTHORIZATION.as_str())
+        {
+            return Err(
+                "The bearer mode must be `disabled` when the token header is `Authorization`."
+                    .to_string(),
+            );
+        }
+
+        if self.jwks_refresh_interval_secs == 0 {
+            return Err("JWKS refresh interval must be at least one second.".to_string());
+        }
+
+        if self.clock_skew_leeway_secs > MAX_CLOCK_SKEW_LEEWAY_SECS {
+            return Err(format!(
+                "Clock skew leeway must be at most {} seconds, got {}.",
+                MAX_CLOCK_SKEW_LEEWAY_SECS, self.clock_skew_leeway_secs
+            ));
+        }
+
+        if self.service_auth_map_refresh_interval_secs == 0 {
+            return Err(
+                "Service token auth mapping refresh interval must be at least one second."
+                    .to_string(),
+            );
+        }
+
+        // Audiences are normalized before being looked up, so they must be configured in their
+        // normalized form to ever match.
+        let audience_names = self
+            .audiences
+            .keys()
+            .map(String::as_str)
+            .chain(self.missing_token.audiences())
+            .chain(self.hosts.values().map(String::as_str));
+        for audience in audience_names {
+            match normalize_audience(audience) {
+                Ok(normalized) if normalized == audience => {}
+                Ok(normalized) => {
+                    return Err(format!(
+                        "Audience '{}' must be written in its normalized form: '{}'.",
+                        audience, normalized
+                    ))
+                }
+                Err(reason) => {
+                    return Err(format!(
+                        "Audience '{}' is not a valid AUD tag ({}).",
+                        audience,
+                        reason.as_str()
+                    ))
+                }
+            }
+        }
+
+        for (name, path) in &self.custom_claim_paths {
+            if !path.starts_with('/') {
+                return Err(format!(
+                    "Custom claim path for '{}' must be a JSON pointer starting with '/', got '{}'.",
+                    name, path
+                ));
+            }
+        }
+
+        for (header_name, pointer) in &self.claim_headers {
+            if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                return Err(format!(
+                    "Claim header '{}' is not a valid header name.",
+                    header_name
+                ));
+            }
+
+            if !pointer.starts_with('/') {
+                return Err(format!(
+                    "Claim header '{}' must use a JSON pointer starting with '/', got '{}'.",
+                    header_name, pointer
+                ));
+            }
+        }
+
+        if let Some(anomaly_detection) = &self.anomaly_detection {
+            if anomaly_detection.window_secs == 0 {
+                return Err("Anomaly detection window must be at least one second.".to_string());
+            }
+
+            if !(anomaly_detection.ewma_alpha > 0.0 && anomaly_detection.ewma_alpha <= 1.0) {
+                return Err(
+                    "Anomaly detection `ewma_alpha` must be greater than 0, and at most 1."
+                        .to_string(),
+                );
+            }
+
+            if !(anomaly_detection.threshold > 0.0 && anomaly_detection.threshold <= 1.0) {
+                return Err(
+                    "Anomaly detection `threshold` must be greater than 0, and at most 1."
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.cache_gc.sweep_interval_secs == 0 {
+            return Err("Cache sweep interval must be at least one second.".to_string());
+        }
+
+        for (name, rule) in &self.cache_gc.caches {
+            if rule.sweep_interval_secs == Some(0) {
+                return Err(format!(
+                    "Sweep interval for cache '{}' must be at least one second.",
+                    name
+                ));
+            }
+        }
+
+        if let Some(validation_cache) = &self.validation_cache {
+            if validation_cache.max_ttl_secs == 0 {
+                return Err("Validation cache max TTL must be at least one second.".to_string());
+            }
+        }
+
+        for (audience, audience_config) in &self.audiences {
+            #[cfg(feature = "redis")]
+            let has_redis = self.redis.is_some();
+            #[cfg(not(feature = "redis"))]
+            let has_redis = false;
+            if audience_config.jti_replay == JtiReplayMode::Redis && !has_redis {
+                return Err(format!(
+                    "JTI replay protection in Redis for audience '{}' requires `redis` to be \
+                     configured, which requires the `redis` feature.",
+                    audience
+                ));
+            }
+        }
+
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            if redis.timeout_ms == 0 {
+                return Err("Redis timeout must be at least one millisecond.".to_string());
+            }
+
+            if redis.max_ttl_secs == Some(0) {
+                return Err("Redis max TTL must be at least one second.".to_string());
+            }
+        }
+
+        if let Some(user_enrichment) = &self.user_enrichment {
+            if self.cloudflare_api.is_none() {
+                return Err(
+                    "User enrichment requires `cloudflare_api` to be configured.".to_string(),
+                );
+            }
+
+            for (field, header_name) in &user_enrichment.fields {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Header '{}' for user field '{}' is not a valid header name.",
+                        header_name, field
+                    ));
+                }
+            }
+        }
+
+        if let Some(identity_enrichment) = &self.identity_enrichment {
+            for (header_name, pointer) in &identity_enrichment.headers {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Identity header '{}' is not a valid header name.",
+                        header_name
+                    ));
+                }
+
+                if !pointer.starts_with('/') {
+                    return Err(format!(
+                        "Identity header '{}' must map to a JSON pointer starting with '/', got '{}'.",
+                        header_name, pointer
+                    ));
+                }
+            }
+
+            if identity_enrichment.timeout_ms == 0 {
+                return Err(
+                    "Identity enrichment timeout must be at least one millisecond.".to_string(),
+                );
+            }
+        }
+
+        // Prefixing a valid header name with another one always results in a valid header name.
+        if !self.claim_header_prefix.is_empty()
+            && HeaderName::from_bytes(self.claim_header_prefix.as_bytes()).is_err()
+        {
+            return Err(format!(
+                "Claim header prefix '{}' is not valid in a header name.",
+                self.claim_header_prefix
+            ));
+        }
+
+        if self.claim_flatten_depth > MAX_CLAIM_FLATTEN_DEPTH {
+            return Err(format!(
+                "Claim flatten depth must be at most {}, got {}.",
+                MAX_CLAIM_FLATTEN_DEPTH, self.claim_flatten_depth
+            ));
+        }
+
+        if !self.groups_claim.starts_with('/') {
+            return Err(format!(
+                "Groups claim must be a JSON pointer starting with '/', got '{}'.",
+                self.groups_claim
+            ));
+        }
+
+        if let Some(header_name) = &self.deadline_header {
+            if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                return Err(format!(
+                    "Deadline header '{}' is not a valid header name.",
+                    header_name
+                ));
+            }
+        }
+
+        if let Some(group_names) = &self.group_names {
+            if self.cloudflare_api.is_none() {
+                return Err(
+                    "Group name resolution requires `cloudflare_api` to be configured.".to_string(),
+                );
+            }
+
+            if HeaderName::from_bytes(group_names.header.as_bytes()).is_err() {
+                return Err(format!(
+                    "Group names header '{}' is not a valid header name.",
+                    group_names.header
+                ));
+            }
+
+            if group_names.refresh_interval_secs == 0 {
+                return Err("Group names refresh interval must be at least one second.".to_string());
+            }
+        }
+
+        if let Some(opa) = &self.opa {
+            if Uri::from_str(&opa.url).is_err() {
+                return Err(format!("OPA URL '{}' is not a valid URL.", opa.url));
+            }
+
+            if opa.timeout_ms == 0 {
+                return Err("OPA timeout must be at least one millisecond.".to_string());
+            }
+        }
+
+        if let Some(message_signatures) = &self.message_signatures {
+            if message_signatures.headers.is_empty() {
+                return Err(
+                    "At least one header must be signed when message signatures are enabled."
+                        .to_string(),
+                );
+            }
+
+            for header_name in &message_signatures.headers {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Signed header '{}' is not a valid header name.",
+                        header_name
+                    ));
+                }
+            }
+
+            // Key IDs are sent in quoted strings, and have to tell the keys apart.
+            let key_ids = std::iter::once(&message_signatures.key_id).chain(
+                message_signatures
+                    .previous_keys
+                    .iter()
+                    .map(|previous_key| &previous_key.key_id),
+            );
+            let mut seen_key_ids = Vec::new();
+            for key_id in key_ids {
+                if key_id.is_empty()
+                    || !key_id
+                        .chars()
+                        .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\')
+                {
+                    return Err(format!("Signing key ID '{}' is not valid.", key_id));
+                }
+
+                if seen_key_ids.contains(&key_id) {
+                    return Err(format!(
+                        "Signing key ID '{}' is used more than once.",
+                        key_id
+                    ));
+                }
+                seen_key_ids.push(key_id);
+            }
+        }
+
+        for status in self.error_pages.keys() {
+            if !(400..600).contains(status) {
+                return Err(format!(
+                    "Error page status code '{}' is not a client or server error.",
+                    status
+                ));
+            }
+        }
+
+        if let Some(envoy) = &self.envoy {
+            for header_name in &envoy.headers_to_remove {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Envoy header to remove '{}' is not a valid header name.",
+                        header_name
+                    ));
+                }
+            }
+        }
+
+        if let Some(internal_token) = &self.internal_token {
+            if IssuerUrl::new(internal_token.issuer.clone()).is_err() {
+                return Err(format!(
+                    "Internal token issuer '{}' is not a valid URL.",
+                    internal_token.issuer
+                ));
+            }
+
+            if internal_token.key_id.is_empty() {
+                return Err("Internal token key ID must not be empty.".to_string());
+            }
+
+            if internal_token.ttl_secs == 0 {
+                return Err("Internal token TTL must be at least one second.".to_string());
+            }
+
+            if HeaderName::from_bytes(internal_token.header.as_bytes()).is_err() {
+                return Err(format!(
+                    "Internal token header '{}' is not a valid header name.",
+                    internal_token.header
+                ));
+            }
+        }
+
+        for (audience, audience_config) in &self.audiences {
+            if let Some(issuer) = &audience_config.issuer {
+                if !self.issuers.contains_key(issuer) {
+                    return Err(format!(
+                        "Audience '{}' refers to unknown issuer '{}'.",
+                        audience, issuer
+                    ));
+                }
+            }
+
+            for email in &audience_config.allowed_emails {
+                if !email.contains('@') {
+                    return Err(format!(
+                        "Allowed email '{}' for audience '{}' is not an email.",
+                        email, audience
+                    ));
+                }
+            }
+
+            for domain in &audience_config.allowed_email_domains {
+                if domain.is_empty() || domain.contains('@') {
+                    return Err(format!(
+                        "Allowed email domain '{}' for audience '{}' is not a domain.",
+                        domain, audience
+                    ));
+                }
+            }
+
+            for path_rule in &audience_config.path_rules {
+                path_rules::validate_pattern(&path_rule.path).map_err(|e| {
+                    format!(
+                        "Path rule '{}' for audience '{}' is invalid: {}.",
+                        path_rule.path, audience, e
+                    )
+                })?;
+
+                for method in &path_rule.methods {
+                    if Method::from_bytes(method.as_bytes()).is_err() {
+                        return Err(format!(
+                            "Path rule '{}' for audience '{}' has invalid method '{}'.",
+                            path_rule.path, audience, method
+                        ));
+                    }
+                }
+            }
+
+            if let Some(expression) = &audience_config.expression {
+                Expression::parse(expression).map_err(|e| {
+                    format!("Expression for audience '{}' is invalid: {}.", audience, e)
+                })?;
+            }
+
+            if let Some(basic_auth) = &audience_config.basic_auth {
+                if !basic_auth.username_claim.starts_with('/') {
+                    return Err(format!(
+                        "Basic auth username claim for audience '{}' must be a JSON pointer starting with '/', got '{}'.",
+                        audience, basic_auth.username_claim
+                    ));
+                }
+
+                if basic_auth.password_file.is_none()
+                    && basic_auth.service_token_password_files.is_empty()
+                {
+                    return Err(format!(
+                        "Basic auth for audience '{}' requires a password file.",
+                        audience
+                    ));
+                }
+            }
+
+            for (header_name, header_value) in &audience_config.static_headers {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Static header '{}' for audience '{}' is not a valid header name.",
+                        header_name, audience
+                    ));
+                }
+
+                if HeaderValue::from_str(header_value).is_err() {
+                    return Err(format!(
+                        "Static header '{}' for audience '{}' has an invalid value.",
+                        header_name, audience
+                    ));
+                }
+            }
+
+            for (header_name, template) in &audience_config.header_templates {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Header template '{}' for audience '{}' is not a valid header name.",
+                        header_name, audience
+                    ));
+                }
+
+                Template::parse(template).map_err(|e| {
+                    format!(
+                        "Header template '{}' for audience '{}' is invalid: {}.",
+                        header_name, audience, e
+                    )
+                })?;
+            }
+
+            for (claim_name, header_name) in &audience_config.claim_header_names {
+                if HeaderName::from_bytes(header_name.as_bytes()).is_err() {
+                    return Err(format!(
+                        "Header '{}' for claim '{}' of audience '{}' is not a valid header name.",
+                        header_name, claim_name, audience
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let file = std::fs::File::open(path).map_err(|e| {
+            format!(
+                "Failed to open configuration file '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+        serde_yaml::from_reader(file).map_err(|e| {
+            format!(
+                "Failed to parse configuration file '{}': {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    fn apply_env_overrides(&mut self) -> Result<(), String> {
+        if let Some(listen_address) = env_override("LISTEN_ADDRESS")? {
+            self.listen_address = Some(listen_address);
+        }
+
+        if let Some(auth_domain) = env_var("AUTH_DOMAIN") {
+            let auth_domain = IssuerUrl::new(auth_domain)
+                .map_err(|e| format!("Invalid value for `AUTH_DOMAIN`: {}", e))?;
+            self.auth_domain = Some(auth_domain);
+        }
+
+        if let Some(token_header) = env_var("TOKEN_HEADER") {
+            self.token_header = token_header;
+        }
+
+        if let Some(credential_mode) = env_override("CREDENTIAL_MODE")? {
+            self.credential_mode = credential_mode;
+        }
+
+        if let Some(bearer_mode) = env_override("BEARER_MODE")? {
+            self.bearer_mode = bearer_mode;
+        }
+
+        if let Some(secs) = env_override("JWKS_REFRESH_INTERVAL_SECS")? {
+            self.jwks_refresh_interval_secs = secs;
+        }
+
+        if let Some(secs) = env_override("JWKS_REFRESH_JITTER_SECS")? {
+            self.jwks_refresh_jitter_secs = secs;
+        }
+
+        if let Some(retry) = env_override("JWKS_RETRY_ON_UNKNOWN_KEY")? {
+            self.jwks_retry_on_unknown_key = retry;
+        }
+
+        if let Some(secs) = env_override("JWKS_MAX_STALENESS_SECS")? {
+            self.jwks_max_staleness_secs = secs;
+        }
+
+        if let Some(secs) = env_override("NOT_READY_RETRY_AFTER_SECS")? {
+            self.not_ready_retry_after_secs = secs;
+        }
+
+        if let Some(secs) = env_override("SHUTDOWN_TIMEOUT_SECS")? {
+            self.shutdown_timeout_secs = secs;
+        }
+
+        if let Some(secs) = env_override("CLOCK_SKEW_LEEWAY_SECS")? {
+            self.clock_skew_leeway_secs = secs;
+        }
+
+        if let Some(header_name) = env_var("DEADLINE_HEADER") {
+            self.deadline_header = Some(header_name);
+        }
+
+        if let Some(spec) = env_var("MISSING_TOKEN_BEHAVIOR") {
+            self.missing_token = MissingTokenPolicy::from_spec(&spec)
+                .map_err(|e| format!("Invalid value for `MISSING_TOKEN_BEHAVIOR`: {}", e))?;
+        }
+
+        if let Some(patterns) = env_var("REDACTED_CLAIMS") {
+            self.redacted_claims = patterns.split(',').map(|s| s.trim().to_string()).collect();
+        }
+
+        if let Some(metrics_mode) = env_override("METRICS_MODE")? {
+            self.metrics_mode = metrics_mode;
+        }
+
+        if let Some(min_count) = env_override("METRICS_MIN_COUNT")? {
+            self.metrics_min_count = min_count;
+        }
+
+        if let Some(depth) = env_override("CLAIM_FLATTEN_DEPTH")? {
+            self.claim_flatten_depth = depth;
+        }
+
+        if let Some(groups_claim) = env_var("GROUPS_CLAIM") {
+            self.groups_claim = groups_claim;
+        }
+
+        if let Some(header_preset) = env_override("HEADER_PRESET")? {
+            self.header_preset = header_preset;
+        }
+
+        if let Some(standard_claim_headers) = env_override("STANDARD_CLAIM_HEADERS")? {
+            self.standard_claim_headers = standard_claim_headers;
+        }
+
+        if let Some(prefix) = env_var("CLAIM_HEADER_PREFIX") {
+            self.claim_header_prefix = prefix;
+        }
+
+        if let Some(case) = env_override("CLAIM_HEADER_CASE")? {
+            self.claim_header_case = case;
+        }
+
+        if let Some(claims_header) = env_override("CLAIMS_HEADER")? {
+            self.claims_header = claims_header;
+        }
+
+        if let Some(max_bytes) = env_override("CLAIMS_HEADER_MAX_BYTES")? {
+            self.claims_header_max_bytes = max_bytes;
+        }
+
+        if let Some(path) = env_var("SERVICE_TOKEN_AUTH_MAPPING_FILE") {
+            self.service_token_auth_mapping_file = Some(PathBuf::from(path));
+        }
+
+        if let Some(mappings) = env_var("SERVICE_TOKEN_AUTH_MAPPINGS") {
+            self.service_token_auth_mappings = serde_yaml::from_str(&mappings)
+                .map_err(|e| format!("Invalid value for `SERVICE_TOKEN_AUTH_MAPPINGS`: {}", e))?;
+        }
+
+        if let Some(url) = env_var("SERVICE_AUTH_MAP_URL") {
+            self.service_auth_map_url = Some(url);
+        }
+
+        if let Some(path) = env_var("SERVICE_AUTH_MAP_TOKEN_FILE") {
+            self.service_auth_map_token_file = Some(PathBuf::from(path));
+        }
+
+        if let Some(secs) = env_override("SERVICE_AUTH_MAP_REFRESH_INTERVAL_SECS")? {
+            self.service_auth_map_refresh_interval_secs = secs;
+        }
+
+        if let Some(strict) = env_override("STRICT_SERVICE_TOKENS")? {
+            self.strict_service_tokens = strict;
+        }
+
+        if let Some(path) = env_var("ADMIN_TOKEN_FILE") {
+            self.admin_token_file = Some(PathBuf::from(path));
+        }
+
+        if let Some(path) = env_var("PROXY_SECRET_FILE") {
+            self.proxy_secret_file = Some(PathBuf::from(path));
+        }
+
+        if let Some(restrict_audiences) = env_override("RESTRICT_AUDIENCES")? {
+            self.restrict_audiences = restrict_audiences;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the listen address, which must be configured.
+    pub fn require_listen_address(&self) -> Result<SocketAddr, String> {
+        self.listen_address.ok_or_else(|| {
+            "Listen address must be specified via `listen_address` or `LISTEN_ADDRESS` (example: 127.0.0.1:9000)"
+                .to_string()
+        })
+    }
+
+    /// Gets the Cloudflare Access team domain, which must be configured.
+    pub fn require_auth_domain(&self) -> Result<IssuerUrl, String> {
+        self.auth_domain.clone().ok_or_else(|| {
+            "Cloudflare Access team domain must be specified via `auth_domain` or `AUTH_DOMAIN` (example: https://your-team-name.cloudflareaccess.com)"
+                .to_string()
+        })
+    }
+
+    /// Loads the service token to header mappings, from the inline mappings and the mapping file,
+    /// if one is configured.
+    pub fn load_service_token_map(&self) -> Result<ServiceAuthTokenHeaderMap, String> {
+        let mut token_map =
+            ServiceAuthTokenHeaderMap::from_raw(self.service_token_auth_mappings.clone())
+                .map_err(|e| format!("Invalid inline service token auth mappings: {}", e))?;
+
+        if let Some(path) = &self.service_token_auth_mapping_file {
+            let file_token_map = ServiceAuthTokenHeaderMap::from_mapping_file(path)
+                .map_err(|e| format!("Failed to load service token auth mapping file: {}", e))?;
+            token_map.merge(file_token_map);
+        }
+
+        Ok(token_map)
+    }
+
+    /// Creates the fetcher for the remote service token to header mappings, if a URL is
+    /// configured.
+    pub fn load_remote_token_map(
+        &self,
+        http_client: HttpClient,
+    ) -> Result<Option<RemoteTokenMap>, String> {
+        self.service_auth_map_url
+            .as_deref()
+            .map(|url| {
+                RemoteTokenMap::new(
+                    http_client,
+                    url,
+                    self.service_auth_map_token_file.as_deref(),
+                )
+            })
+            .transpose()
+    }
+
+    /// How often to re-fetch the remote service token to header mappings.
+    pub fn service_auth_map_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.service_auth_map_refresh_interval_secs)
+    }
+
+    /// Loads the admin token, if the admin endpoints are enabled.
+    pub fn load_admin_token(&self) -> Result<Option<AdminToken>, String> {
+        self.admin_token_file
+            .as_deref()
+            .map(AdminToken::from_file)
+            .transpose()
+    }
+
+    /// Loads the proxy secret, if proxies are required to present one.
+    pub fn load_proxy_secret(&self) -> Result<Option<ProxySecret>, String> {
+        self.proxy_secret_file
+            .as_deref()
+            .map(ProxySecret::from_file)
+            .transpose()
+    }
+
+    /// Creates the Cloudflare API client, if the API is configured.
+    pub fn load_cloudflare_api(
+        &self,
+        http_client: HttpClient,
+    ) -> Result<Option<CloudflareApi>, String> {
+        self.cloudflare_api
+            .as_ref()
+            .map(|cloudflare_api| CloudflareApi::from_config(cloudflare_api, http_client))
+            .transpose()
+    }
+
+    /// Creates the OPA client, if OPA is asked to authorize requests.
+    pub fn load_opa(&self, http_client: HttpClient) -> Result<Option<OpaClient>, String> {
+        self.opa
+            .as_ref()
+            .map(|opa| OpaClient::from_config(opa, http_client))
+            .transpose()
+    }
+
+    /// Loads the message signing key, if message signatures are enabled.
+    pub fn load_message_signer(&self) -> Result<Option<MessageSigner>, String> {
+        self.message_signatures
+            .as_ref()
+            .map(MessageSigner::from_config)
+            .transpose()
+    }
+
+    /// Loads the internal token signing key, if internal tokens are enabled.
+    pub fn load_internal_token(&self) -> Result<Option<InternalTokenIssuer>, String> {
+        self.internal_token
+            .as_ref()
+            .map(InternalTokenIssuer::from_config)
+            .transpose()
+    }
+
+    /// Creates the Redis client, if caches are shared between replicas.
+    #[cfg(feature = "redis")]
+    pub fn load_shared_cache(&self) -> Result<Option<SharedCache>, String> {
+        self.redis
+            .as_ref()
+            .map(SharedCache::from_config)
+            .transpose()
+    }
+
+    /// Loads the error page templates, if any are configured.
+    pub fn load_error_pages(&self) -> Result<Option<ErrorPages>, String> {
+        if self.error_pages.is_empty() {
+            return Ok(None);
+        }
+
+        ErrorPages::from_config(&self.error_pages).map(Some)
+    }
+
+    /// Gets the settings for the given audience, if any.
+    pub fn audience(&self, audience: &str) -> Option<&AudienceConfig> {
+        self.audiences.get(audience)
+    }
+
+    /// How often to refresh the JWKS data.
+    pub fn jwks_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.jwks_refresh_interval_secs)
+    }
+
+    /// The maximum random delay added to each JWKS refresh.
+    pub fn jwks_refresh_jitter(&self) -> Duration {
+        Duration::from_secs(self.jwks_refresh_jitter_secs)
+    }
+
+    /// How long ago the JWKS data may have last been refreshed before this replica isn't ready, if
+    /// there's a limit.
+    pub fn jwks_max_staleness(&self) -> Option<Duration> {
+        (self.jwks_max_staleness_secs > 0)
+            .then(|| Duration::from_secs(self.jwks_max_staleness_secs))
+    }
+
+    /// How long clients should wait before retrying a validation request made before the JWKS data
+    /// was loaded.
+    pub fn not_ready_retry_after(&self) -> Duration {
+        Duration::from_secs(self.not_ready_retry_after_secs)
+    }
+
+    /// How long to wait for in-flight requests to finish when shutting down.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    /// How far the clocks of this host and Cloudflare Access may drift apart.
+    pub fn clock_skew_leeway(&self) -> chrono::Duration {
+        // The leeway is validated when loading the configuration, so it always fits.
+        chrono::Duration::seconds(self.clock_skew_leeway_secs as i64)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_address: None,
+            auth_domain: None,
+            issuers: HashMap::new(),
+            token_header: DEFAULT_TOKEN_HEADER.to_string(),
+            credential_mode: CredentialMode::HeaderFirst,
+            bearer_mode: BearerMode::Disabled,
+            jwks_fetch: JwksFetchConfig::default(),
+            jwks_refresh_interval_secs: 3600,
+            jwks_refresh_jitter_secs: 300,
+            jwks_retry_on_unknown_key: false,
+            jwks_max_staleness_secs: 0,
+            not_ready_retry_after_secs: 5,
+            shutdown_timeout_secs: 30,
+            clock_skew_leeway_secs: 5,
+            deadline_header: None,
+            missing_token: MissingTokenPolicy::default(),
+            redacted_claims: Vec::new(),
+            metrics_mode: MetricsMode::Standard,
+            metrics_min_count: 10,
+            custom_claim_paths: HashMap::new(),
+            claim_flatten_depth: 0,
+            groups_claim: "/groups".to_string(),
+            claim_headers: HashMap::new(),
+            claim_rendering: ClaimRenderingConfig::default(),
+            header_preset: HeaderPreset::Native,
+            standard_claim_headers: false,
+            claim_header_prefix: "X-".to_string(),
+            claim_header_case: ClaimHeaderCase::Train,
+            claims_header: ClaimsHeaderMode::Disabled,
+            claims_header_max_bytes: 4096,
+            service_token_auth_mapping_file: None,
+            service_token_auth_mappings: HashMap::new(),
+            service_auth_map_url: None,
+            service_auth_map_token_file: None,
+            service_auth_map_refresh_interval_secs: 300,
+            strict_service_tokens: false,
+            admin_token_file: None,
+            proxy_secret_file: None,
+            anomaly_detection: None,
+            cache_gc: CacheGcConfig::default(),
+            validation_cache: None,
+            #[cfg(feature = "redis")]
+            redis: None,
+            cloudflare_api: None,
+            user_enrichment: None,
+            identity_enrichment: None,
+            group_names: None,
+            message_signatures: None,
+            internal_token: None,
+            opa: None,
+            audiences: HashMap::new(),
+            hosts: HashMap::new(),
+            error_pages: HashMap::new(),
+            envoy: None,
+            nginx: None,
+            #[cfg(feature = "http3")]
+            http3: None,
+            #[cfg(feature = "ext-authz")]
+            ext_authz: None,
+            restrict_audiences: false,
+        }
+    }
+}
+
+/// An environment variable that overrides a configuration setting.
+pub struct EnvVar {
+    /// The name of the variable.
+    pub name: &'static str,
+
+    /// Names the variable used to have, which still work, but log a deprecation warning.
+    pub deprecated_names: &'static [&'static str],
+
+    /// The configuration setting that the variable overrides.
+    pub setting: &'static str,
+}
+
+/// Every environment variable that overrides a configuration setting.
+///
+/// Renaming a variable means adding its old name to `deprecated_names`, so that existing
+/// deployments keep working while they're migrated to the new name.
+pub const ENV_VARS: &[EnvVar] = &[
+    EnvVar {
+        name: "LISTEN_ADDRESS",
+        deprecated_names: &["LISTEN_ADDR"],
+        setting: "listen_address",
+    },
+    EnvVar {
+        name: "AUTH_DOMAIN",
+        deprecated_names: &["CF_AUTH_DOMAIN"],
+        setting: "auth_domain",
+    },
+    EnvVar {
+        name: "TOKEN_HEADER",
+        deprecated_names: &[],
+        setting: "token_header",
+    },
+    EnvVar {
+        name: "CREDENTIAL_MODE",
+        deprecated_names: &[],
+        setting: "credential_mode",
+    },
+    EnvVar {
+        name: "BEARER_MODE",
+        deprecated_names: &[],
+        setting: "bearer_mode",
+    },
+    EnvVar {
+        name: "JWKS_REFRESH_INTERVAL_SECS",
+        deprecated_names: &[],
+        setting: "jwks_refresh_interval_secs",
+    },
+    EnvVar {
+        name: "JWKS_REFRESH_JITTER_SECS",
+        deprecated_names: &[],
+        setting: "jwks_refresh_jitter_secs",
+    },
+    EnvVar {
+        name: "JWKS_RETRY_ON_UNKNOWN_KEY",
+        deprecated_names: &[],
+        setting: "jwks_retry_on_unknown_key",
+    },
+    EnvVar {
+        name: "JWKS_MAX_STALENESS_SECS",
+        deprecated_names: &[],
+        setting: "jwks_max_staleness_secs",
+    },
+    EnvVar {
+        name: "NOT_READY_RETRY_AFTER_SECS",
+        deprecated_names: &[],
+        setting: "not_ready_retry_after_secs",
+    },
+    EnvVar {
+        name: "SHUTDOWN_TIMEOUT_SECS",
+        deprecated_names: &[],
+        setting: "shutdown_timeout_secs",
+    },
+    EnvVar {
+        name: "CLOCK_SKEW_LEEWAY_SECS",
+        deprecated_names: &[],
+        setting: "clock_skew_leeway_secs",
+    },
+    EnvVar {
+        name: "DEADLINE_HEADER",
+        deprecated_names: &[],
+        setting: "deadline_header",
+    },
+    EnvVar {
+        name: "MISSING_TOKEN_BEHAVIOR",
+        deprecated_names: &[],
+        setting: "missing_token",
+    },
+    EnvVar {
+        name: "REDACTED_CLAIMS",
+        deprecated_names: &["LOG_REDACTED_CLAIMS"],
+        setting: "redacted_claims",
+    },
+    EnvVar {
+        name: "METRICS_MODE",
+        deprecated_names: &[],
+        setting: "metrics_mode",
+    },
+    EnvVar {
+        name: "METRICS_MIN_COUNT",
+        deprecated_names: &[],
+        setting: "metrics_min_count",
+    },
+    EnvVar {
+        name: "CLAIM_FLATTEN_DEPTH",
+        deprecated_names: &[],
+        setting: "claim_flatten_depth",
+    },
+    EnvVar {
+        name: "GROUPS_CLAIM",
+        deprecated_names: &[],
+        setting: "groups_claim",
+    },
+    EnvVar {
+        name: "HEADER_PRESET",
+        deprecated_names: &[],
+        setting: "header_preset",
+    },
+    EnvVar {
+        name: "STANDARD_CLAIM_HEADERS",
+        deprecated_names: &[],
+        setting: "standard_claim_headers",
+    },
+    EnvVar {
+        name: "CLAIM_HEADER_PREFIX",
+        deprecated_names: &[],
+        setting: "claim_header_prefix",
+    },
+    EnvVar {
+        name: "CLAIM_HEADER_CASE",
+        deprecated_names: &[],
+        setting: "claim_header_case",
+    },
+    EnvVar {
+        name: "CLAIMS_HEADER",
+        deprecated_names: &[],
+        setting: "claims_header",
+    },
+    EnvVar {
+        name: "CLAIMS_HEADER_MAX_BYTES",
+        deprecated_names: &[],
+        setting: "claims_header_max_bytes",
+    },
+    EnvVar {
+        name: "SERVICE_TOKEN_AUTH_MAPPING_FILE",
+        deprecated_names: &[],
+        setting: "service_token_auth_mapping_file",
+    },
+    EnvVar {
+        name: "SERVICE_TOKEN_AUTH_MAPPINGS",
+        deprecated_names: &[],
+        setting: "service_token_auth_mappings",
+    },
+    EnvVar {
+        name: "SERVICE_AUTH_MAP_URL",
+        deprecated_names: &[],
+        setting: "service_auth_map_url",
+    },
+    EnvVar {
+        name: "SERVICE_AUTH_MAP_TOKEN_FILE",
+        deprecated_names: &[],
+        setting: "service_auth_map_token_file",
+    },
+    EnvVar {
+        name: "SERVICE_AUTH_MAP_REFRESH_INTERVAL_SECS",
+        deprecated_names: &[],
+        setting: "service_auth_map_refresh_interval_secs",
+    },
+    EnvVar {
+        name: "STRICT_SERVICE_TOKENS",
+        deprecated_names: &[],
+        setting: "strict_service_tokens",
+    },
+    EnvVar {
+        name: "ADMIN_TOKEN_FILE",
+        deprecated_names: &[],
+        setting: "admin_token_file",
+    },
+    EnvVar {
+        name: "PROXY_SECRET_FILE",
+        deprecated_names: &[],
+        setting: "proxy_secret_file",
+    },
+    EnvVar {
+        name: "RESTRICT_AUDIENCES",
+        deprecated_names: &[],
+        setting: "restrict_audiences",
+    },
+];
+
+/// Gets the value of the given environment variable, falling back to any of its deprecated names.
+fn env_var(name: &str) -> Option<String> {
+    if let Ok(value) = std::env::var(name) {
+        return Some(value);
+    }
+
+    let deprecated_names = ENV_VARS
+        .iter()
+        .find(|env_var| env_var.name == name)
+        .map_or(&[][..], |env_var| env_var.deprecated_names);
+    deprecated_names.iter().find_map(|deprecated_name| {
+        let value = std::env::var(deprecated_name).ok()?;
+        warn!(
+            "Environment variable `{}` is deprecated and will be removed in a future release. Use `{}` instead.",
+            deprecated_name, name
+        );
+        Some(value)
+    })
+}
+
+fn env_override<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    env_var(name)
+        .map(|s| {
+            s.parse()
+                .map_err(|e| format!("Invalid value for `{}`: {}", name, e))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_skew_leeway_limit() {
+        let cases = [
+            (0, true),
+            (5, true),
+            (300, true),
+            (301, false),
+            (u64::MAX, false),
+        ];
+        for (clock_skew_leeway_secs, valid) in cases {
+            let config = Config {
+                clock_skew_leeway_secs,
+                ..Config::default()
+            };
+            assert_eq!(
+                config.validate().is_ok(),
+                valid,
+                "{}",
+                clock_skew_leeway_secs
+            );
+        }
+    }
+}
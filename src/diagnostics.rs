@@ -0,0 +1,267 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    validation::{service_auth::ServiceAuthTokenHeaderMap, SignatureStates},
+    web::MissingTokenBehavior,
+};
+
+/// The maximum number of recent errors kept for diagnostic snapshots.
+const RECENT_ERRORS_CAPACITY: usize = 32;
+
+/// The most recent errors, oldest first.
+static RECENT_ERRORS: Mutex<Vec<RecentError>> = Mutex::new(Vec::new());
+
+struct RecentError {
+    at: Instant,
+    kind: &'static str,
+    message: String,
+}
+
+/// Records an error so that it shows up in the next diagnostic snapshot.
+///
+/// Only the most recent errors are kept. The message must not contain anything sensitive, such as
+/// raw tokens or unredacted claim values.
+pub fn record_error(kind: &'static str, message: impl Into<String>) {
+    let mut recent_errors = RECENT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    if recent_errors.len() >= RECENT_ERRORS_CAPACITY {
+        recent_errors.remove(0);
+    }
+
+    recent_errors.push(RecentError {
+        at: Instant::now(),
+        kind,
+        message: message.into(),
+    });
+}
+
+/// Logs a summary of the effective configuration at startup, along with warnings for settings that
+/// are likely to be mistakes.
+///
+/// Warnings are heuristics: none of them stop the service from starting, as each of them can be
+/// intentional.
+pub fn log_startup_summary(
+    config: &Config,
+    listen_address: &SocketAddr,
+    token_map: &ServiceAuthTokenHeaderMap,
+) {
+    #[cfg(feature = "http3")]
+    let http3_listen_address = config.http3.as_ref().map(|http3| http3.listen_address);
+    #[cfg(not(feature = "http3"))]
+    let http3_listen_address: Option<SocketAddr> = None;
+    #[cfg(feature = "ext-authz")]
+    let ext_authz_listen_address = config
+        .ext_authz
+        .as_ref()
+        .map(|ext_authz| ext_authz.listen_address);
+    #[cfg(not(feature = "ext-authz"))]
+    let ext_authz_listen_address: Option<SocketAddr> = None;
+    #[cfg(feature = "redis")]
+    let redis = config.redis.is_some();
+    #[cfg(not(feature = "redis"))]
+    let redis = false;
+
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        %listen_address,
+        http3_listen_address = ?http3_listen_address,
+        ext_authz_listen_address = ?ext_authz_listen_address,
+        auth_domain = config.auth_domain.as_ref().map(|url| url.as_str()),
+        issuers = ?config.issuers.keys().collect::<Vec<_>>(),
+        audiences = config.audiences.len(),
+        hosts = config.hosts.len(),
+        error_pages = config.error_pages.len(),
+        restrict_audiences = config.restrict_audiences,
+        credential_mode = ?config.credential_mode,
+        bearer_mode = ?config.bearer_mode,
+        header_preset = ?config.header_preset,
+        standard_claim_headers = config.standard_claim_headers,
+        claim_header_case = ?config.claim_header_case,
+        claims_header = ?config.claims_header,
+        metrics_mode = ?config.metrics_mode,
+        missing_token_behavior = ?config.missing_token.default_behavior(),
+        service_tokens = token_map.len(),
+        jwks_sources = usize::from(config.jwks_fetch.direct) + config.jwks_fetch.proxies.len(),
+        jwks_static_dir = ?config.jwks_fetch.static_dir,
+        jwks_cache = config.jwks_fetch.cache_dir.is_some(),
+        validation_cache = config.validation_cache.is_some(),
+        redis,
+        message_signatures = config.message_signatures.is_some(),
+        internal_token = config.internal_token.is_some(),
+        envoy = config.envoy.is_some(),
+        nginx = config.nginx.is_some(),
+        otel = cfg!(feature = "otel"),
+        "Starting up."
+    );
+
+    for warning in startup_warnings(config, listen_address, token_map) {
+        warn!("Possible misconfiguration: {}", warning);
+    }
+}
+
+fn startup_warnings(
+    config: &Config,
+    listen_address: &SocketAddr,
+    token_map: &ServiceAuthTokenHeaderMap,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if config.service_token_auth_mapping_file.is_some() && token_map.is_empty() {
+        warnings.push(
+            "the service token auth mapping file is configured, but doesn't map any tokens"
+                .to_string(),
+        );
+    }
+
+    if is_public(listen_address.ip()) {
+        warnings.push(format!(
+            "listening on {} exposes `/metrics` on an interface that may be publicly reachable",
+            listen_address
+        ));
+    }
+
+    if config.restrict_audiences && config.audiences.is_empty() {
+        warnings.push(
+            "audiences are restricted, but none are configured, so every request will be rejected"
+                .to_string(),
+        );
+    }
+
+    if config.restrict_audiences {
+        for (host, audience) in &config.hosts {
+            if !config.audiences.contains_key(audience) {
+                warnings.push(format!(
+                    "host '{}' maps to audience '{}', which is rejected as audiences are restricted",
+                    host, audience
+                ));
+            }
+        }
+    }
+
+    if config.missing_token.default_behavior() == MissingTokenBehavior::Allow {
+        warnings
+            .push("requests without an access token are allowed through by default".to_string());
+    }
+
+    if config.jwks_refresh_jitter_secs > config.jwks_refresh_interval_secs {
+        warnings.push(format!(
+            "the JWKS refresh jitter ({}s) is longer than the refresh interval ({}s)",
+            config.jwks_refresh_jitter_secs, config.jwks_refresh_interval_secs
+        ));
+    }
+
+    #[cfg(feature = "redis")]
+    if config.redis.is_some() && config.validation_cache.is_none() {
+        warnings.push(
+            "Redis is configured, but validations aren't cached, so revoked tokens aren't rejected"
+                .to_string(),
+        );
+    }
+
+    #[cfg(feature = "http3")]
+    if let Some(http3) = &config.http3 {
+        use crate::config::TlsSource;
+
+        if matches!(http3.tls, TlsSource::KubernetesSecret { .. })
+            && http3.tls_reload_interval_secs == 0
+        {
+            warnings.push(
+                "the HTTP/3 certificate comes from a Kubernetes secret, but is never reloaded, so \
+                 rotations won't be picked up"
+                    .to_string(),
+            );
+        }
+
+        #[cfg(feature = "spiffe")]
+        if matches!(http3.tls, TlsSource::Spiffe { socket: None, .. })
+            && std::env::var_os("SPIFFE_ENDPOINT_SOCKET").is_none()
+        {
+            warnings.push(
+                "the HTTP/3 certificate comes from the SPIFFE Workload API, but no socket is \
+                 configured and `SPIFFE_ENDPOINT_SOCKET` is not set"
+                    .to_string(),
+            );
+        }
+
+        let (has_cert, has_key) = match &http3.tls {
+            TlsSource::File {
+                cert_file,
+                key_file,
+            } => (cert_file.exists(), key_file.exists()),
+            TlsSource::Env { cert_var, key_var } => (
+                std::env::var_os(cert_var).is_some(),
+                std::env::var_os(key_var).is_some(),
+            ),
+            TlsSource::KubernetesSecret { .. } => (true, true),
+            #[cfg(feature = "spiffe")]
+            TlsSource::Spiffe { .. } => (true, true),
+        };
+        if has_key && !has_cert {
+            warnings.push(
+                "the HTTP/3 TLS private key is present, but its certificate chain is missing"
+                    .to_string(),
+            );
+        }
+    }
+
+    warnings
+}
+
+/// Whether or not the given address could be reachable from outside of a private network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local()),
+        IpAddr::V6(ip) => {
+            // Unique local addresses (fc00::/7) are the IPv6 equivalent of private addresses.
+            !(ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00)
+        }
+    }
+}
+
+/// Logs a snapshot of the current state of the service, as a one-shot debugging aid.
+///
+/// This covers a summary of the configuration, the JWKS data loaded for each issuer, and the most
+/// recent errors. Runtime task statistics aren't included, as Tokio only exposes them when built
+/// with `--cfg tokio_unstable`.
+pub fn dump(config: &Config, states: &SignatureStates) {
+    info!(
+        listen_address = ?config.listen_address,
+        auth_domain = config.auth_domain.as_ref().map(|url| url.as_str()),
+        issuers = ?config.issuers.keys().collect::<Vec<_>>(),
+        audiences = config.audiences.len(),
+        restrict_audiences = config.restrict_audiences,
+        jwks_refresh_interval_secs = config.jwks_refresh_interval_secs,
+        jwks_refresh_jitter_secs = config.jwks_refresh_jitter_secs,
+        jwks_max_staleness_secs = config.jwks_max_staleness_secs,
+        missing_token_behavior = ?config.missing_token.default_behavior(),
+        service_token_auth_mapping_file = ?config.service_token_auth_mapping_file,
+        "Diagnostics: configuration."
+    );
+
+    for state in states.iter() {
+        info!(
+            issuer_url = state.issuer_url().as_str(),
+            loaded = state.has_jwks_loaded(),
+            key_ids = ?state.key_ids(),
+            age_secs = state.jwks_age().as_ref().map(Duration::as_secs),
+            "Diagnostics: JWKS data."
+        );
+    }
+
+    let recent_errors = RECENT_ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    info!(count = recent_errors.len(), "Diagnostics: recent errors.");
+    for recent_error in recent_errors.iter() {
+        info!(
+            kind = recent_error.kind,
+            message = recent_error.message.as_str(),
+            age_secs = recent_error.at.elapsed().as_secs(),
+            "Diagnostics: recent error."
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{
+    cloudflare::{encode_query_value, CloudflareApi},
+    config::UserEnrichmentConfig,
+    gc::{SweepLimits, SweepOutcome, SweepableCache},
+    telemetry,
+};
+
+#[cfg(feature = "redis")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "redis")]
+use crate::shared_cache::SharedCache;
+
+/// Adds details about the user, from the Cloudflare Access users API, to successful validation
+/// responses.
+///
+/// Details are looked up by email, and cached for a while, so only the first request from each user
+/// in that time waits on the API. If the lookup fails, the request is still let through, just
+/// without the extra headers.
+pub struct UserEnricher {
+    api: Arc<CloudflareApi>,
+    fields: Vec<(String, HeaderName)>,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, CachedUser>>,
+    #[cfg(feature = "redis")]
+    shared_cache: Option<Arc<SharedCache>>,
+}
+
+struct CachedUser {
+    headers: HeaderMap,
+    fetched_at: Instant,
+    last_used: Instant,
+}
+
+impl CachedUser {
+    /// Roughly how much memory the cached details for the given email use, in bytes.
+    fn memory_bytes(&self, email: &str) -> usize {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+        std::mem::size_of::<String>() + email.len() + std::mem::size_of::<Self>() + headers
+    }
+}
+
+impl UserEnricher {
+    pub fn new(config: &UserEnrichmentConfig, api: Arc<CloudflareApi>) -> Result<Self, String> {
+        let fields = config
+            .fields
+            .iter()
+            .map(|(field, header_name)| {
+                HeaderName::from_bytes(header_name.as_bytes())
+                    .map(|header_name| (field.clone(), header_name))
+                    .map_err(|_| {
+                        format!(
+                            "Header '{}' for user field '{}' is not a valid header name.",
+                            header_name, field
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            api,
+            fields,
+            cache_ttl: config.cache_ttl(),
+            cache: Mutex::new(HashMap::new()),
+            #[cfg(feature = "redis")]
+            shared_cache: None,
+        })
+    }
+
+    /// Shares user details with other replicas through the given Redis cache.
+    #[cfg(feature = "redis")]
+    pub fn with_shared_cache(mut self, shared_cache: Arc<SharedCache>) -> Self {
+        self.shared_cache = Some(shared_cache);
+        self
+    }
+
+    /// Adds the configured details for the user with the given email to `headers`.
+    ///
+    /// Headers that are already set are left alone, so nothing derived from the token, or
+    /// configured for the audience, is ever overridden.
+    pub async fn enrich(&self, email: &str, headers: &mut HeaderMap) {
+        let cached = self.cached(email);
+        telemetry::record_cache_lookup(self.name(), cached.is_some());
+        let user_headers = match cached {
+            Some(user_headers) => user_headers,
+            None => match self.look_up(email).await {
+                Ok(user_headers) => {
+                    let now = Instant::now();
+                    let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+                    cache.insert(
+                        email.to_string(),
+                        CachedUser {
+                            headers: user_headers.clone(),
+                            fetched_at: now,
+                            last_used: now,
+                        },
+                    );
+                    user_headers
+                }
+                Err(e) => {
+                    warn!(error = e, "Failed to look up user details.");
+                    return;
+                }
+            },
+        };
+
+        for (header_name, header_value) in user_headers.iter() {
+            if !headers.contains_key(header_name) {
+                headers.insert(header_name.clone(), header_value.clone());
+            }
+        }
+    }
+
+    fn cached(&self, email: &str) -> Option<HeaderMap> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let cached = cache.get_mut(email)?;
+        if cached.fetched_at.elapsed() >= self.cache_ttl {
+            return None;
+        }
+
+        cached.last_used = Instant::now();
+        Some(cached.headers.clone())
+    }
+
+    /// Looks up the details for the user with the given email, from Redis if another replica has
+    /// already fetched them.
+    #[cfg(feature = "redis")]
+    async fn look_up(&self, email: &str) -> Result<HeaderMap, String> {
+        let shared_cache = match &self.shared_cache {
+            Some(shared_cache) => shared_cache,
+            None => return self.fetch(email).await,
+        };
+
+        let key = shared_cache.key("user", &Sha256::digest(email.as_bytes()));
+        let user_headers = shared_cache.get_headers(key.clone()).await;
+        telemetry::record_cache_lookup("redis_user_enrichment", user_headers.is_some());
+        if let Some(user_headers) = user_headers {
+            return Ok(user_headers);
+        }
+
+        let user_headers = self.fetch(email).await?;
+        shared_cache.set_headers(key, &user_headers, self.cache_ttl);
+        Ok(user_headers)
+    }
+
+    #[cfg(not(feature = "redis"))]
+    async fn look_up(&self, email: &str) -> Result<HeaderMap, String> {
+        self.fetch(email).await
+    }
+
+    async fn fetch(&self, email: &str) -> Result<HeaderMap, String> {
+        let path = format!("/access/users?email={}", encode_query_value(email));
+        let users = self.api.get(&path).await?;
+
+        // Users that Cloudflare doesn't know about are cached too, just without any details.
+        let user = users.as_array().and_then(|users| {
+            users.iter().find(|user| {
+                user.get("email")
+                    .and_then(Value::as_str)
+                    .map_or(false, |user_email| user_email.eq_ignore_ascii_case(email))
+            })
+        });
+        let user = match user {
+            Some(user) => user,
+            None => {
+                debug!("No Cloudflare Access user found for email.");
+                return Ok(HeaderMap::new());
+            }
+        };
+
+        let mut headers = HeaderMap::new();
+        for (field, header_name) in &self.fields {
+            let value = match user.get(field) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Bool(value)) => value.to_string(),
+                Some(Value::Number(value)) => value.to_string(),
+                _ => continue,
+            };
+
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(header_name.clone(), value);
+            }
+        }
+
+        Ok(headers)
+    }
+}
+
+impl SweepableCache for UserEnricher {
+    fn name(&self) -> &'static str {
+        "user_enrichment"
+    }
+
+    /// Removes user details older than the cache TTL, and then the least recently used details
+    /// until the cache fits within `limits`.
+    fn sweep(&self, limits: SweepLimits) -> SweepOutcome {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+
+        let entries_before = cache.len();
+        cache.retain(|_, cached| cached.fetched_at.elapsed() < self.cache_ttl);
+        let expired = (entries_before - cache.len()) as u64;
+
+        let mut memory_bytes = cache
+            .iter()
+            .map(|(email, cached)| cached.memory_bytes(email))
+            .sum::<usize>();
+
+        let excess_entries = limits.excess_entries(cache.len());
+        let mut evicted = 0;
+        if limits.exceeded(cache.len(), memory_bytes) {
+            let mut by_last_use = cache
+                .iter()
+                .map(|(email, cached)| (cached.last_used, email.clone()))
+                .collect::<Vec<_>>();
+            by_last_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+            for (_, email) in by_last_use {
+                if !limits.exceeded(cache.len(), memory_bytes) {
+                    break;
+                }
+
+                if let Some(cached) = cache.remove(&email) {
+                    memory_bytes -= cached.memory_bytes(&email);
+                    evicted += 1;
+                }
+            }
+        }
+
+        SweepOutcome::new(cache.len(), memory_bytes, expired, evicted, excess_entries)
+    }
+}
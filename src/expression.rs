@@ -0,0 +1,743 @@
+use std::{borrow::Cow, cmp::Ordering};
+
+use serde_json::{Map, Number, Value};
+
+/// The variables an expression can refer to.
+pub const VARIABLES: &[&str] = &["claims", "request"];
+
+/// The functions an expression can call, either as methods, such as `claims.email.endsWith(...)`,
+/// or, for `size`, as a function, such as `size(claims.groups)`.
+const FUNCTIONS: &[&str] = &["contains", "endsWith", "lowerAscii", "size", "startsWith"];
+
+/// How deeply parentheses, lists, arguments and negations may nest in an expression, so that
+/// parsing a pathological expression can't overflow the stack.
+const MAX_DEPTH: usize = 32;
+
+/// A policy expression, written in a small subset of CEL, such as
+/// `claims.email.endsWith("@corp.com") && "sre" in claims.groups`.
+///
+/// Expressions support string, number, boolean, `null` and list literals, member access and
+/// indexing, the `!`, `&&`, `||`, `==`, `!=`, `<`, `<=`, `>`, `>=` and `in` operators, and the
+/// functions in `FUNCTIONS`. As in CEL, `&&` and `||` ignore an error on one side if the other side
+/// decides the result on its own, so `request.path.startsWith("/public") || ...` still works for
+/// requests without a known path.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    root: Expr,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Literal(Value),
+    List(Vec<Expr>),
+    Variable(String),
+    Member(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Expression {
+    /// Parses the given expression.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+        };
+        let root = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!(
+                "unexpected {} after the end of the expression",
+                token
+            ));
+        }
+
+        Ok(Self { root })
+    }
+
+    /// Evaluates the expression against the given variables.
+    ///
+    /// Only an expression that evaluates to `true` passes. Anything else, including an expression
+    /// that refers to a claim the token doesn't have, is an error describing why it didn't.
+    pub fn evaluate(&self, variables: &Map<String, Value>) -> Result<(), String> {
+        match evaluate(&self.root, variables)?.as_ref() {
+            Value::Bool(true) => Ok(()),
+            Value::Bool(false) => Err("evaluated to false".to_string()),
+            value => Err(format!(
+                "evaluated to {}, rather than a boolean",
+                type_name(value)
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(Number),
+    True,
+    False,
+    Null,
+    In,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Dot,
+    Comma,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Identifier(name) => write!(f, "'{}'", name),
+            Self::String(_) => write!(f, "string"),
+            Self::Number(_) => write!(f, "number"),
+            Self::True => write!(f, "'true'"),
+            Self::False => write!(f, "'false'"),
+            Self::Null => write!(f, "'null'"),
+            Self::In => write!(f, "'in'"),
+            Self::LeftParen => write!(f, "'('"),
+            Self::RightParen => write!(f, "')'"),
+            Self::LeftBracket => write!(f, "'['"),
+            Self::RightBracket => write!(f, "']'"),
+            Self::Dot => write!(f, "'.'"),
+            Self::Comma => write!(f, "','"),
+            Self::Not => write!(f, "'!'"),
+            Self::And => write!(f, "'&&'"),
+            Self::Or => write!(f, "'||'"),
+            Self::Eq => write!(f, "'=='"),
+            Self::Ne => write!(f, "'!='"),
+            Self::Lt => write!(f, "'<'"),
+            Self::Le => write!(f, "'<='"),
+            Self::Gt => write!(f, "'>'"),
+            Self::Ge => write!(f, "'>='"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut next_is = |expected: char| {
+            if chars.peek().map(|(_, c)| *c) == Some(expected) {
+                chars.next();
+                true
+            } else {
+                false
+            }
+        };
+
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            '!' if next_is('=') => Token::Ne,
+            '!' => Token::Not,
+            '&' if next_is('&') => Token::And,
+            '|' if next_is('|') => Token::Or,
+            '=' if next_is('=') => Token::Eq,
+            '<' if next_is('=') => Token::Le,
+            '<' => Token::Lt,
+            '>' if next_is('=') => Token::Ge,
+            '>' => Token::Gt,
+            quote @ ('"' | '\'') => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, c)) if c == quote => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => value.push('\n'),
+                            Some((_, 't')) => value.push('\t'),
+                            Some((_, c @ ('\\' | '"' | '\''))) => value.push(c),
+                            _ => return Err(format!("invalid escape in string at {}", start)),
+                        },
+                        Some((_, c)) => value.push(c),
+                        None => return Err(format!("unterminated string at {}", start)),
+                    }
+                }
+                Token::String(value)
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = start + 1;
+                while let Some((i, c)) = chars.peek() {
+                    if !c.is_ascii_digit() && *c != '.' {
+                        break;
+                    }
+                    end = i + 1;
+                    chars.next();
+                }
+                let literal = &source[start..end];
+                let number = match literal.parse::<u64>() {
+                    Ok(number) => Number::from(number),
+                    Err(_) => literal
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .ok_or_else(|| format!("invalid number '{}' at {}", literal, start))?,
+                };
+                Token::Number(number)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = start + 1;
+                while let Some((i, c)) = chars.peek() {
+                    if !c.is_ascii_alphanumeric() && *c != '_' {
+                        break;
+                    }
+                    end = i + 1;
+                    chars.next();
+                }
+                match &source[start..end] {
+                    "true" => Token::True,
+                    "false" => Token::False,
+                    "null" => Token::Null,
+                    "in" => Token::In,
+                    identifier => Token::Identifier(identifier.to_string()),
+                }
+            }
+            c => return Err(format!("unexpected character '{}' at {}", c, start)),
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == *expected => Ok(()),
+            Some(token) => Err(format!("expected {}, found {}", expected, token)),
+            None => Err(format!(
+                "expected {}, found the end of the expression",
+                expected
+            )),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("expression is nested too deeply".to_string());
+        }
+
+        let mut expr = self.parse_and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_relation()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_relation()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_relation(&mut self) -> Result<Expr, String> {
+        let left = self.parse_unary()?;
+        let op = match self.peek() {
+            Some(Token::Eq) => CompareOp::Eq,
+            Some(Token::Ne) => CompareOp::Ne,
+            Some(Token::Lt) => CompareOp::Lt,
+            Some(Token::Le) => CompareOp::Le,
+            Some(Token::Gt) => CompareOp::Gt,
+            Some(Token::Ge) => CompareOp::Ge,
+            Some(Token::In) => {
+                self.position += 1;
+                let right = self.parse_unary()?;
+                return Ok(Expr::In(Box::new(left), Box::new(right)));
+            }
+            _ => return Ok(left),
+        };
+        self.position += 1;
+
+        let right = self.parse_unary()?;
+        Ok(Expr::Compare(op, Box::new(left), Box::new(right)))
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            self.depth += 1;
+            if self.depth > MAX_DEPTH {
+                return Err("expression is nested too deeply".to_string());
+            }
+            let expr = self.parse_unary()?;
+            self.depth -= 1;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            if self.eat(&Token::Dot) {
+                let name = match self.next() {
+                    Some(Token::Identifier(name)) => name,
+                    Some(token) => {
+                        return Err(format!("expected a name after '.', found {}", token))
+                    }
+                    None => return Err("expected a name after '.'".to_string()),
+                };
+
+                if self.eat(&Token::LeftParen) {
+                    check_function(&name)?;
+                    let mut args = vec![expr];
+                    args.extend(self.parse_list(&Token::RightParen)?);
+                    expr = Expr::Call(name, args);
+                } else {
+                    expr = Expr::Member(Box::new(expr), name);
+                }
+            } else if self.eat(&Token::LeftBracket) {
+                let index = self.parse_or()?;
+                self.expect(&Token::RightBracket)?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::String(value)) => Ok(Expr::Literal(Value::String(value))),
+            Some(Token::Number(value)) => Ok(Expr::Literal(Value::Number(value))),
+            Some(Token::True) => Ok(Expr::Literal(Value::Bool(true))),
+            Some(Token::False) => Ok(Expr::Literal(Value::Bool(false))),
+            Some(Token::Null) => Ok(Expr::Literal(Value::Null)),
+            Some(Token::LeftParen) => {
+                let expr = self.parse_or()?;
+                self.expect(&Token::RightParen)?;
+                Ok(expr)
+            }
+            Some(Token::LeftBracket) => Ok(Expr::List(self.parse_list(&Token::RightBracket)?)),
+            Some(Token::Identifier(name)) => {
+                if self.eat(&Token::LeftParen) {
+                    check_function(&name)?;
+                    return Ok(Expr::Call(name, self.parse_list(&Token::RightParen)?));
+                }
+
+                if !VARIABLES.contains(&name.as_str()) {
+                    return Err(format!(
+                        "unknown variable '{}', expected one of: {}",
+                        name,
+                        VARIABLES.join(", ")
+                    ));
+                }
+                Ok(Expr::Variable(name))
+            }
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("unexpected end of the expression".to_string()),
+        }
+    }
+
+    /// Parses a comma-separated list of expressions, up to and including the given closing token.
+    fn parse_list(&mut self, close: &Token) -> Result<Vec<Expr>, String> {
+        let mut items = Vec::new();
+        if self.eat(close) {
+            return Ok(items);
+        }
+
+        loop {
+            items.push(self.parse_or()?);
+            if self.eat(close) {
+                return Ok(items);
+            }
+            self.expect(&Token::Comma)?;
+        }
+    }
+}
+
+fn check_function(name: &str) -> Result<(), String> {
+    if FUNCTIONS.contains(&name) {
+        Ok(())
+    } else {
+        Err(format!(
+            "unknown function '{}', expected one of: {}",
+            name,
+            FUNCTIONS.join(", ")
+        ))
+    }
+}
+
+fn evaluate<'a>(
+    expr: &'a Expr,
+    variables: &'a Map<String, Value>,
+) -> Result<Cow<'a, Value>, String> {
+    match expr {
+        Expr::Literal(value) => Ok(Cow::Borrowed(value)),
+        Expr::List(items) => items
+            .iter()
+            .map(|item| evaluate(item, variables).map(Cow::into_owned))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|items| Cow::Owned(Value::Array(items))),
+        Expr::Variable(name) => variables
+            .get(name)
+            .map(Cow::Borrowed)
+            .ok_or_else(|| format!("no such variable '{}'", name)),
+        Expr::Member(target, name) => {
+            let target = evaluate(target, variables)?;
+            member(target, name)
+        }
+        Expr::Index(target, index) => {
+            let target = evaluate(target, variables)?;
+            let index = evaluate(index, variables)?;
+            match (target.as_ref(), index.as_ref()) {
+                (Value::Object(_), Value::String(key)) => member(target, key),
+                (Value::Array(items), Value::Number(index)) => index
+                    .as_u64()
+                    .and_then(|index| items.get(index as usize))
+                    .cloned()
+                    .map(Cow::Owned)
+                    .ok_or_else(|| format!("no such index {}", index)),
+                (target, index) => Err(format!(
+                    "can't index {} with {}",
+                    type_name(target),
+                    type_name(index)
+                )),
+            }
+        }
+        Expr::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, variables))
+                .collect::<Result<Vec<_>, _>>()?;
+            call(name, &args).map(Cow::Owned)
+        }
+        Expr::Not(expr) => {
+            let value = as_bool(evaluate(expr, variables)?.as_ref())?;
+            Ok(Cow::Owned(Value::Bool(!value)))
+        }
+        Expr::And(left, right) => logical(left, right, false, variables),
+        Expr::Or(left, right) => logical(left, right, true, variables),
+        Expr::Compare(op, left, right) => {
+            let left = evaluate(left, variables)?;
+            let right = evaluate(right, variables)?;
+            let result = match op {
+                CompareOp::Eq => values_equal(&left, &right),
+                CompareOp::Ne => !values_equal(&left, &right),
+                CompareOp::Lt => compare(&left, &right)? == Ordering::Less,
+                CompareOp::Le => compare(&left, &right)? != Ordering::Greater,
+                CompareOp::Gt => compare(&left, &right)? == Ordering::Greater,
+                CompareOp::Ge => compare(&left, &right)? != Ordering::Less,
+            };
+            Ok(Cow::Owned(Value::Bool(result)))
+        }
+        Expr::In(item, container) => {
+            let item = evaluate(item, variables)?;
+            let container = evaluate(container, variables)?;
+            let result = match (item.as_ref(), container.as_ref()) {
+                (item, Value::Array(items)) => items.iter().any(|other| values_equal(item, other)),
+                (Value::String(key), Value::Object(map)) => map.contains_key(key),
+                (item, container) => {
+                    return Err(format!(
+                        "can't look for {} in {}",
+                        type_name(item),
+                        type_name(container)
+                    ))
+                }
+            };
+            Ok(Cow::Owned(Value::Bool(result)))
+        }
+    }
+}
+
+/// Evaluates `&&`, when `decisive` is `false`, or `||`, when `decisive` is `true`.
+///
+/// If either side evaluates to the decisive value, that's the result, even if the other side is an
+/// error.
+fn logical<'a>(
+    left: &'a Expr,
+    right: &'a Expr,
+    decisive: bool,
+    variables: &'a Map<String, Value>,
+) -> Result<Cow<'a, Value>, String> {
+    let left = evaluate(left, variables).and_then(|value| as_bool(&value));
+    if left == Ok(decisive) {
+        return Ok(Cow::Owned(Value::Bool(decisive)));
+    }
+
+    let right = evaluate(right, variables).and_then(|value| as_bool(&value));
+    match (left, right) {
+        (_, Ok(right)) if right == decisive => Ok(Cow::Owned(Value::Bool(decisive))),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+        (Ok(_), Ok(_)) => Ok(Cow::Owned(Value::Bool(!decisive))),
+    }
+}
+
+fn member<'a>(target: Cow<'a, Value>, name: &str) -> Result<Cow<'a, Value>, String> {
+    let missing = || format!("no such key '{}'", name);
+    match target {
+        Cow::Borrowed(Value::Object(map)) => map.get(name).map(Cow::Borrowed).ok_or_else(missing),
+        Cow::Owned(Value::Object(mut map)) => map.remove(name).map(Cow::Owned).ok_or_else(missing),
+        target => Err(format!(
+            "can't get '{}' from {}",
+            name,
+            type_name(target.as_ref())
+        )),
+    }
+}
+
+fn call(name: &str, args: &[Cow<'_, Value>]) -> Result<Value, String> {
+    let args = args.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    match (name, args.as_slice()) {
+        ("contains", [Value::String(s), Value::String(other)]) => {
+            Ok(Value::Bool(s.contains(other.as_str())))
+        }
+        ("endsWith", [Value::String(s), Value::String(suffix)]) => {
+            Ok(Value::Bool(s.ends_with(suffix.as_str())))
+        }
+        ("startsWith", [Value::String(s), Value::String(prefix)]) => {
+            Ok(Value::Bool(s.starts_with(prefix.as_str())))
+        }
+        ("lowerAscii", [Value::String(s)]) => Ok(Value::String(s.to_ascii_lowercase())),
+        ("size", [Value::String(s)]) => Ok(Value::from(s.chars().count())),
+        ("size", [Value::Array(items)]) => Ok(Value::from(items.len())),
+        ("size", [Value::Object(map)]) => Ok(Value::from(map.len())),
+        (name, args) => Err(format!(
+            "no overload of '{}' for ({})",
+            name,
+            args.iter()
+                .map(|arg| type_name(arg))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn as_bool(value: &Value) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("expected a boolean, found {}", type_name(value)))
+}
+
+/// Compares two values for equality, treating numbers as equal if they have the same value,
+/// regardless of how they're represented.
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64() == right.as_f64(),
+        (Value::Array(left), Value::Array(right)) => {
+            left.len() == right.len()
+                && left
+                    .iter()
+                    .zip(right)
+                    .all(|(left, right)| values_equal(left, right))
+        }
+        (left, right) => left == right,
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Result<Ordering, String> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left
+            .as_f64()
+            .zip(right.as_f64())
+            .and_then(|(left, right)| left.partial_cmp(&right))
+            .ok_or_else(|| "can't compare numbers".to_string()),
+        (Value::String(left), Value::String(right)) => Ok(left.cmp(right)),
+        (left, right) => Err(format!(
+            "can't compare {} with {}",
+            type_name(left),
+            type_name(right)
+        )),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "a map",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("", "unexpected end of the expression"),
+            ("claims.email ==", "unexpected end of the expression"),
+            ("claims.email == 'a", "unterminated string at 16"),
+            (r#""\x""#, "invalid escape in string at 0"),
+            ("claims # 1", "unexpected character '#' at 7"),
+            ("1.2.3 == 1", "invalid number '1.2.3' at 0"),
+            (
+                "user.email",
+                "unknown variable 'user', expected one of: claims, request",
+            ),
+            (
+                "claims.email.matches('a')",
+                "unknown function 'matches', expected one of:",
+            ),
+            ("claims.", "expected a name after '.'"),
+            ("claims.(1)", "expected a name after '.', found '('"),
+            ("(true", "expected ')', found the end of the expression"),
+            ("[1, 2", "expected ',', found the end of the expression"),
+            ("claims[0 1]", "expected ']', found number"),
+            (
+                "true false",
+                "unexpected 'false' after the end of the expression",
+            ),
+            ("claims & true", "unexpected character '&' at 7"),
+            (") || true", "unexpected ')'"),
+        ];
+        for (source, expected) in cases {
+            match Expression::parse(source) {
+                Ok(_) => panic!("'{}' should not parse", source),
+                Err(e) => assert!(
+                    e.starts_with(expected),
+                    "'{}' failed with '{}', not '{}'",
+                    source,
+                    e,
+                    expected
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |open: &str, close: &str, depth: usize| {
+            format!("{}true{}", open.repeat(depth), close.repeat(depth))
+        };
+        // The expression itself counts as one level.
+        let cases = [
+            (nested("(", ")", MAX_DEPTH - 1), true),
+            (nested("(", ")", MAX_DEPTH), false),
+            (nested("!", "", MAX_DEPTH - 1), true),
+            (nested("!", "", MAX_DEPTH), false),
+            (nested("[", "]", MAX_DEPTH - 1), true),
+            (nested("[", "]", MAX_DEPTH), false),
+            (nested("size(", ")", MAX_DEPTH - 1), true),
+            (nested("size(", ")", MAX_DEPTH), false),
+            // Deep enough to overflow the stack, if it weren't for the limit.
+            (nested("(", ")", 100_000), false),
+            (nested("!", "", 100_000), false),
+        ];
+        for (source, parses) in cases {
+            match Expression::parse(&source) {
+                Ok(_) => assert!(parses, "'{}' should not parse", source),
+                Err(e) => {
+                    assert!(!parses, "'{}' failed with '{}'", source, e);
+                    assert_eq!(e, "expression is nested too deeply");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn evaluation() {
+        let variables = json!({
+            "claims": {"email": "alice@corp.com", "groups": ["sre", "dev"], "level": 3},
+            "request": {"path": "/public/index.html"},
+        });
+        let variables = variables.as_object().unwrap();
+        let cases = [
+            (
+                r#"claims.email.endsWith("@corp.com") && "sre" in claims.groups"#,
+                Ok(()),
+            ),
+            (
+                r#"claims.groups[1] == "dev" && size(claims.groups) == 2"#,
+                Ok(()),
+            ),
+            (
+                "claims.level >= 3 && claims.level < 3.5 && !(claims.level == 4)",
+                Ok(()),
+            ),
+            // The missing claim doesn't matter, as the other side decides the result.
+            (
+                r#"claims.missing == 1 || request.path.startsWith("/public")"#,
+                Ok(()),
+            ),
+            (r#""ops" in claims.groups"#, Err("evaluated to false")),
+            (
+                "claims.level",
+                Err("evaluated to a number, rather than a boolean"),
+            ),
+            ("claims.missing == 1 && true", Err("no such key 'missing'")),
+            (
+                "claims.email < 1",
+                Err("can't compare a string with a number"),
+            ),
+            (
+                "claims.groups.endsWith('v')",
+                Err("no overload of 'endsWith' for (a list, a string)"),
+            ),
+        ];
+        for (source, expected) in cases {
+            let result = Expression::parse(source).unwrap().evaluate(variables);
+            assert_eq!(result, expected.map_err(str::to_string), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::time::interval;
+use tracing::debug;
+
+use crate::telemetry;
+
+/// An in-memory cache that's swept periodically, so that it doesn't slowly grow without bound.
+pub trait SweepableCache: Send + Sync {
+    /// The name of the cache, used to configure it and to label its metrics.
+    fn name(&self) -> &'static str;
+
+    /// Removes every entry that's no longer worth keeping, and then evicts the least recently used
+    /// entries until the cache fits within `limits`.
+    fn sweep(&self, limits: SweepLimits) -> SweepOutcome;
+}
+
+/// The state of a cache after a sweep.
+#[derive(Debug, Default)]
+pub struct SweepOutcome {
+    /// How many entries are left.
+    pub entries: usize,
+
+    /// Roughly how much memory the entries that are left use, in bytes.
+    pub memory_bytes: usize,
+
+    /// How many entries were removed because they were no longer worth keeping.
+    pub expired: u64,
+
+    /// How many entries were evicted to stay within the entry limit.
+    pub evicted_for_entries: u64,
+
+    /// How many entries were evicted to stay within the memory limit.
+    pub evicted_for_memory: u64,
+}
+
+impl SweepOutcome {
+    /// Gets the outcome of a sweep that left the given entries, using the given memory, after
+    /// removing `expired` entries and then evicting `evicted` ones, the first `excess_entries` of
+    /// which were over the entry limit.
+    pub fn new(
+        entries: usize,
+        memory_bytes: usize,
+        expired: u64,
+        evicted: u64,
+        excess_entries: usize,
+    ) -> Self {
+        let evicted_for_entries = evicted.min(excess_entries as u64);
+        Self {
+            entries,
+            memory_bytes,
+            expired,
+            evicted_for_entries,
+            evicted_for_memory: evicted - evicted_for_entries,
+        }
+    }
+}
+
+/// How big a cache may get, which is enforced by evicting entries whenever it's swept.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SweepLimits {
+    /// Roughly how much memory the cache may use, in bytes, if there's a limit.
+    pub max_memory_bytes: Option<usize>,
+
+    /// How many entries the cache may hold, if there's a limit.
+    pub max_entries: Option<usize>,
+}
+
+impl SweepLimits {
+    /// Whether or not a cache with the given number of entries, using the given memory, is over
+    /// either limit.
+    pub fn exceeded(&self, entries: usize, memory_bytes: usize) -> bool {
+        self.excess_entries(entries) > 0
+            || self
+                .max_memory_bytes
+                .map_or(false, |max_memory_bytes| memory_bytes > max_memory_bytes)
+    }
+
+    /// Gets how many of the given number of entries are over the entry limit.
+    pub fn excess_entries(&self, entries: usize) -> usize {
+        self.max_entries
+            .map_or(0, |max_entries| entries.saturating_sub(max_entries))
+    }
+}
+
+/// How a single cache is swept.
+#[derive(Clone, Copy, Debug)]
+pub struct SweepSettings {
+    /// How often to sweep the cache.
+    pub interval: Duration,
+
+    /// How big the cache may get.
+    pub limits: SweepLimits,
+}
+
+/// Sweeps the given cache on a fixed interval, for as long as the service runs.
+pub async fn sweep_periodically(cache: Arc<dyn SweepableCache>, settings: SweepSettings) {
+    let mut sweeps = interval(settings.interval);
+    sweeps.tick().await;
+
+    loop {
+        sweeps.tick().await;
+
+        let outcome = cache.sweep(settings.limits);
+        telemetry::record_cache_sweep(cache.name(), &outcome);
+        if outcome.expired > 0 || outcome.evicted_for_entries > 0 || outcome.evicted_for_memory > 0
+        {
+            debug!(
+                cache = cache.name(),
+                entries = outcome.entries,
+                memory_bytes = outcome.memory_bytes,
+                expired = outcome.expired,
+                evicted_for_entries = outcome.evicted_for_entries,
+                evicted_for_memory = outcome.evicted_for_memory,
+                "Swept cache."
+            );
+        }
+    }
+}
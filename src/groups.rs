@@ -0,0 +1,115 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use hyper::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+use tokio::time::interval;
+use tracing::{debug, error, info};
+
+use crate::{cloudflare::CloudflareApi, config::GroupNamesConfig};
+
+/// Resolves Access group IDs, as found in a token's group claim, into their names.
+///
+/// The names of every group in the account are fetched from the Cloudflare API, and then refreshed
+/// periodically, so resolving names never waits on the API. The group claim itself is left as-is,
+/// so policies keep matching on the IDs.
+pub struct GroupNameResolver {
+    api: Arc<CloudflareApi>,
+    header: HeaderName,
+    names: ArcSwap<HashMap<String, String>>,
+}
+
+impl GroupNameResolver {
+    pub fn new(config: &GroupNamesConfig, api: Arc<CloudflareApi>) -> Result<Self, String> {
+        let header = HeaderName::from_bytes(config.header.as_bytes()).map_err(|_| {
+            format!(
+                "Group names header '{}' is not a valid header name.",
+                config.header
+            )
+        })?;
+
+        Ok(Self {
+            api,
+            header,
+            names: ArcSwap::from_pointee(HashMap::new()),
+        })
+    }
+
+    /// Fetches the names of every group in the account, replacing the ones currently in use.
+    ///
+    /// Returns the number of groups fetched.
+    pub async fn refresh(&self) -> Result<usize, String> {
+        let groups = self.api.list("/access/groups").await?;
+        let names = groups
+            .iter()
+            .filter_map(|group| {
+                let id = group.get("id")?.as_str()?;
+                let name = group.get("name")?.as_str()?;
+                Some((id.to_string(), name.to_string()))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let groups = names.len();
+        self.names.store(Arc::new(names));
+        Ok(groups)
+    }
+
+    /// Gets the header to emit the names of the given groups in.
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// Resolves the given group claim, either a list of group IDs or a single one, into a header
+    /// value listing their names, separated by commas.
+    ///
+    /// Groups whose names aren't known, such as ones created since the last refresh, are listed by
+    /// ID instead.
+    pub fn resolve(&self, groups: &Value) -> Option<HeaderValue> {
+        let ids = claim_groups(groups);
+        if ids.is_empty() {
+            return None;
+        }
+
+        let names = self.names.load();
+        let resolved = ids
+            .iter()
+            .map(|id| names.get(*id).map_or(*id, String::as_str))
+            .collect::<Vec<_>>();
+
+        match HeaderValue::from_str(&resolved.join(",")) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                debug!("Resolved group names are not a valid header value.");
+                None
+            }
+        }
+    }
+}
+
+/// Gets the groups in the given group claim, which is either a list of groups or a single one.
+pub fn claim_groups(groups: &Value) -> Vec<&str> {
+    match groups {
+        Value::Array(groups) => groups.iter().filter_map(Value::as_str).collect(),
+        Value::String(group) => vec![group.as_str()],
+        _ => Vec::new(),
+    }
+}
+
+/// Refreshes the group names on the given interval, forever, starting right away.
+///
+/// If refreshing fails, the names from the last successful refresh are kept.
+pub async fn refresh_group_names(resolver: Arc<GroupNameResolver>, refresh_interval: Duration) {
+    let mut refresh_interval = interval(refresh_interval);
+
+    loop {
+        refresh_interval.tick().await;
+
+        match resolver.refresh().await {
+            Ok(groups) => info!(groups, "Refreshed Access group names."),
+            Err(e) => error!(
+                error = e,
+                "Failed to refresh Access group names. Keeping the current ones."
+            ),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    body::to_bytes,
+    header::{self, HeaderName, HeaderValue},
+    Body, HeaderMap, Request, Uri,
+};
+use openidconnect::IssuerUrl;
+use serde_json::Value;
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::{
+    config::IdentityEnrichmentConfig,
+    gc::{SweepLimits, SweepOutcome, SweepableCache},
+    telemetry,
+    validation::HttpClient,
+};
+
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+
+#[cfg(feature = "redis")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "redis")]
+use crate::shared_cache::SharedCache;
+
+/// Adds details about the identity behind a token, from Cloudflare Access' get-identity endpoint,
+/// to successful validation responses.
+///
+/// This covers what the token itself doesn't carry unless it's configured as an OIDC claim, such as
+/// the user's groups, their identity provider, and their device. Identities are looked up with the
+/// user's own token, on the team domain that issued it, and cached per subject for a while, so only
+/// the first request from each user in that time waits on Cloudflare. If the lookup fails, the
+/// request is still let through, just without the extra headers.
+pub struct IdentityEnricher {
+    http_client: HttpClient,
+    headers: Vec<(HeaderName, String)>,
+    cache_ttl: Duration,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, CachedIdentity>>,
+    #[cfg(feature = "redis")]
+    shared_cache: Option<Arc<SharedCache>>,
+}
+
+struct CachedIdentity {
+    headers: HeaderMap,
+    fetched_at: Instant,
+    last_used: Instant,
+}
+
+impl CachedIdentity {
+    /// Roughly how much memory the cached identity for the given key uses, in bytes.
+    fn memory_bytes(&self, key: &str) -> usize {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+        std::mem::size_of::<String>() + key.len() + std::mem::size_of::<Self>() + headers
+    }
+}
+
+impl IdentityEnricher {
+    pub fn new(config: &IdentityEnrichmentConfig, http_client: HttpClient) -> Result<Self, String> {
+        let headers = config
+            .headers
+            .iter()
+            .map(|(header_name, pointer)| {
+                HeaderName::from_bytes(header_name.as_bytes())
+                    .map(|header_name| (header_name, pointer.clone()))
+                    .map_err(|_| {
+                        format!(
+                            "Identity header '{}' is not a valid header name.",
+                            header_name
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            http_client,
+            headers,
+            cache_ttl: config.cache_ttl(),
+            timeout: config.timeout(),
+            cache: Mutex::new(HashMap::new()),
+            #[cfg(feature = "redis")]
+            shared_cache: None,
+        })
+    }
+
+    /// Shares identities with other replicas through the given Redis cache.
+    #[cfg(feature = "redis")]
+    pub fn with_shared_cache(mut self, shared_cache: Arc<SharedCache>) -> Self {
+        self.shared_cache = Some(shared_cache);
+        self
+    }
+
+    /// Adds the configured details of the identity behind the given token to `headers`.
+    ///
+    /// Headers that are already set are left alone, so nothing derived from the token, or
+    /// configured for the audience, is ever overridden.
+    pub async fn enrich(
+        &self,
+        issuer_url: &IssuerUrl,
+        subject: &str,
+        access_token: &str,
+        headers: &mut HeaderMap,
+    ) {
+        // Subjects are only unique within a team domain.
+        let key = format!("{}|{}", issuer_url.as_str(), subject);
+        let cached = self.cached(&key);
+        telemetry::record_cache_lookup(self.name(), cached.is_some());
+        let identity_headers = match cached {
+            Some(identity_headers) => identity_headers,
+            None => match self.look_up(&key, issuer_url, access_token).await {
+                Ok(identity_headers) => {
+                    let now = Instant::now();
+                    let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+                    cache.insert(
+                        key,
+                        CachedIdentity {
+                            headers: identity_headers.clone(),
+                            fetched_at: now,
+                            last_used: now,
+                        },
+                    );
+                    identity_headers
+                }
+                Err(e) => {
+                    warn!(error = e, "Failed to look up identity.");
+                    return;
+                }
+            },
+        };
+
+        for (header_name, header_value) in identity_headers.iter() {
+            if !headers.contains_key(header_name) {
+                headers.insert(header_name.clone(), header_value.clone());
+            }
+        }
+    }
+
+    fn cached(&self, key: &str) -> Option<HeaderMap> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let cached = cache.get_mut(key)?;
+        if cached.fetched_at.elapsed() >= self.cache_ttl {
+            return None;
+        }
+
+        cached.last_used = Instant::now();
+        Some(cached.headers.clone())
+    }
+
+    /// Looks up the identity cached under the given key, from Redis if another replica has already
+    /// fetched it.
+    #[cfg(feature = "redis")]
+    async fn look_up(
+        &self,
+        key: &str,
+        issuer_url: &IssuerUrl,
+        access_token: &str,
+    ) -> Result<HeaderMap, String> {
+        let shared_cache = match &self.shared_cache {
+            Some(shared_cache) => shared_cache,
+            None => return self.fetch(issuer_url, access_token).await,
+        };
+
+        let key = shared_cache.key("identity", &Sha256::digest(key.as_bytes()));
+        let identity_headers = shared_cache.get_headers(key.clone()).await;
+        telemetry::record_cache_lookup("redis_identity_enrichment", identity_headers.is_some());
+        if let Some(identity_headers) = identity_headers {
+            return Ok(identity_headers);
+        }
+
+        let identity_headers = self.fetch(issuer_url, access_token).await?;
+        shared_cache.set_headers(key, &identity_headers, self.cache_ttl);
+        Ok(identity_headers)
+    }
+
+    #[cfg(not(feature = "redis"))]
+    async fn look_up(
+        &self,
+        _key: &str,
+        issuer_url: &IssuerUrl,
+        access_token: &str,
+    ) -> Result<HeaderMap, String> {
+        self.fetch(issuer_url, access_token).await
+    }
+
+    async fn fetch(&self, issuer_url: &IssuerUrl, access_token: &str) -> Result<HeaderMap, String> {
+        let url = format!(
+            "{}/cdn-cgi/access/get-identity",
+            issuer_url.as_str().trim_end_matches('/')
+        );
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|e| format!("Invalid get-identity URL: {}", e))?;
+        let request = Request::get(uri)
+            .header(header::COOKIE, format!("CF_Authorization={}", access_token))
+            .body(Body::empty())
+            .map_err(|e| format!("Failed to build get-identity request: {}", e))?;
+
+        let response = timeout(self.timeout, self.http_client.request(request))
+            .await
+            .map_err(|_| "get-identity request timed out".to_string())?
+            .map_err(|e| format!("get-identity request failed: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("get-identity responded with {}", status));
+        }
+
+        let body = to_bytes(response.into_body())
+            .await
+            .map_err(|e| format!("Failed to read get-identity response: {}", e))?;
+        let identity = serde_json::from_slice::<Value>(&body)
+            .map_err(|e| format!("Failed to parse get-identity response: {}", e))?;
+
+        let mut headers = HeaderMap::new();
+        for (header_name, pointer) in &self.headers {
+            let value = match identity.pointer(pointer).and_then(render) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(header_name.clone(), value);
+            }
+        }
+
+        Ok(headers)
+    }
+}
+
+/// Renders a value from the identity as a header value.
+///
+/// Lists are rendered as their items separated by commas, where items that are objects with a name,
+/// like groups, are rendered as the name. Any other object is rendered as JSON.
+fn render(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        Value::Bool(_) | Value::Number(_) => Some(value.to_string()),
+        Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(|item| match item.get("name") {
+                    Some(name) => render(name),
+                    None => render(item),
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(items.join(","))
+        }
+        Value::Object(_) => Some(value.to_string()),
+    }
+}
+
+impl SweepableCache for IdentityEnricher {
+    fn name(&self) -> &'static str {
+        "identity_enrichment"
+    }
+
+    /// Removes identities older than the cache TTL, and then the least recently used identities
+    /// until the cache fits within `limits`.
+    fn sweep(&self, limits: SweepLimits) -> SweepOutcome {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+
+        let entries_before = cache.len();
+        cache.retain(|_, cached| cached.fetched_at.elapsed() < self.cache_ttl);
+        let expired = (entries_before - cache.len()) as u64;
+
+        let mut memory_bytes = cache
+            .iter()
+            .map(|(key, cached)| cached.memory_bytes(key))
+            .sum::<usize>();
+
+        let excess_entries = limits.excess_entries(cache.len());
+        let mut evicted = 0;
+        if limits.exceeded(cache.len(), memory_bytes) {
+            let mut by_last_use = cache
+                .iter()
+                .map(|(key, cached)| (cached.last_used, key.clone()))
+                .collect::<Vec<_>>();
+            by_last_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+            for (_, key) in by_last_use {
+                if !limits.exceeded(cache.len(), memory_bytes) {
+                    break;
+                }
+
+                if let Some(cached) = cache.remove(&key) {
+                    memory_bytes -= cached.memory_bytes(&key);
+                    evicted += 1;
+                }
+            }
+        }
+
+        SweepOutcome::new(cache.len(), memory_bytes, expired, evicted, excess_entries)
+    }
+}
@@ -0,0 +1,165 @@
+use std::{path::Path, sync::Mutex};
+
+use chrono::Utc;
+use hyper::header::{HeaderName, HeaderValue};
+use openidconnect::{
+    core::{
+        CoreGenderClaim, CoreJsonWebKeySet, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey,
+    },
+    AdditionalClaims, Audience, EndUserEmail, IdToken, IdTokenClaims, IssuerUrl, JsonWebKeyId,
+    PrivateSigningKey, StandardClaims, SubjectIdentifier,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use zeroize::Zeroizing;
+
+use crate::config::InternalTokenConfig;
+
+type InternalIdToken = IdToken<
+    InternalClaims,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+>;
+
+/// The claims of an internal token beyond the standard ones.
+#[derive(Debug, Deserialize, Serialize)]
+struct InternalClaims {
+    /// The client ID of the service token the original token was issued to, if any.
+    #[serde(rename = "common_name", skip_serializing_if = "Option::is_none")]
+    service_token_id: Option<String>,
+
+    /// The configured subset of the custom claims of the original token.
+    #[serde(flatten)]
+    custom: Map<String, Value>,
+}
+
+impl AdditionalClaims for InternalClaims {}
+
+/// The identity that an internal token is minted for.
+pub struct InternalIdentity<'a> {
+    pub audience: &'a str,
+    pub subject: &'a str,
+    pub email: Option<&'a str>,
+    pub service_token_id: Option<&'a str>,
+    pub custom: Map<String, Value>,
+}
+
+/// Mints short-lived tokens of our own, carrying the validated identity, for upstreams to verify
+/// instead of trusting loose identity headers.
+///
+/// Tokens are signed with RS256, and the public key is published as JWKS, so upstreams can verify
+/// them with any JWT library. Each token's audience is the audience it was validated against.
+pub struct InternalTokenIssuer {
+    issuer_url: IssuerUrl,
+
+    // The signing key can be sent between threads, but not shared between them.
+    signing_key: Mutex<CoreRsaPrivateSigningKey>,
+    jwks: CoreJsonWebKeySet,
+    ttl: chrono::Duration,
+    header: HeaderName,
+}
+
+impl InternalTokenIssuer {
+    /// Creates an issuer from the given configuration, loading the signing key from its key file.
+    ///
+    /// The key file must contain an RSA private key, PEM-encoded in PKCS#1 format.
+    pub fn from_config(config: &InternalTokenConfig) -> Result<Self, String> {
+        let issuer_url = IssuerUrl::new(config.issuer.clone()).map_err(|e| {
+            format!(
+                "Internal token issuer '{}' is invalid: {}",
+                config.issuer, e
+            )
+        })?;
+        let signing_key = load_signing_key(&config.key_file, &config.key_id)?;
+        let jwks = CoreJsonWebKeySet::new(vec![signing_key.as_verification_key()]);
+        let ttl = chrono::Duration::from_std(config.ttl())
+            .map_err(|_| "Internal token TTL is too long.".to_string())?;
+        let header = HeaderName::from_bytes(config.header.as_bytes()).map_err(|_| {
+            format!(
+                "Internal token header '{}' is not a valid header name.",
+                config.header
+            )
+        })?;
+
+        Ok(Self {
+            issuer_url,
+            signing_key: Mutex::new(signing_key),
+            jwks,
+            ttl,
+            header,
+        })
+    }
+
+    /// Gets the header to add the internal token as.
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// Gets the JWKS data for verifying internal tokens.
+    pub fn jwks(&self) -> &CoreJsonWebKeySet {
+        &self.jwks
+    }
+
+    /// Mints an internal token for the given identity, as a header value.
+    pub fn mint(&self, identity: InternalIdentity<'_>) -> Result<HeaderValue, String> {
+        let issued_at = Utc::now();
+        let standard_claims =
+            StandardClaims::new(SubjectIdentifier::new(identity.subject.to_string())).set_email(
+                identity
+                    .email
+                    .map(|email| EndUserEmail::new(email.to_string())),
+            );
+        let additional_claims = InternalClaims {
+            service_token_id: identity.service_token_id.map(str::to_string),
+            custom: identity.custom,
+        };
+        let claims = IdTokenClaims::new(
+            self.issuer_url.clone(),
+            vec![Audience::new(identity.audience.to_string())],
+            issued_at + self.ttl,
+            issued_at,
+            standard_claims,
+            additional_claims,
+        );
+
+        let signing_key = self.signing_key.lock().unwrap_or_else(|e| e.into_inner());
+        let token = InternalIdToken::new(
+            claims,
+            &*signing_key,
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            None,
+            None,
+        )
+        .map_err(|e| format!("Failed to sign internal token: {}", e))?;
+
+        let mut header_value = HeaderValue::from_str(&token.to_string())
+            .map_err(|_| "Internal token is not a valid header value.".to_string())?;
+        header_value.set_sensitive(true);
+        Ok(header_value)
+    }
+}
+
+fn load_signing_key(path: &Path, key_id: &str) -> Result<CoreRsaPrivateSigningKey, String> {
+    let pem = std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            format!(
+                "Failed to read internal token key file '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+
+    CoreRsaPrivateSigningKey::from_pem(&pem, Some(JsonWebKeyId::new(key_id.to_string()))).map_err(
+        |e| {
+            format!(
+                "Internal token key file '{}' is not a valid RSA private key: {}",
+                path.display(),
+                e
+            )
+        },
+    )
+}
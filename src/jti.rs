@@ -0,0 +1,242 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::JtiReplayMode,
+    gc::{SweepLimits, SweepOutcome, SweepableCache},
+};
+
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+
+#[cfg(feature = "redis")]
+use crate::shared_cache::SharedCache;
+
+/// Records the `jti` of tokens accepted for audiences with replay protection, until each token
+/// expires, so that a token presented again is caught as a replay.
+///
+/// Tokens are recorded by a hash of the audience and their `jti`, so the same token is only a
+/// replay when presented for the same audience again.
+pub struct JtiTracker {
+    seen: Mutex<HashMap<[u8; 32], SeenToken>>,
+    #[cfg(feature = "redis")]
+    shared_cache: Option<Arc<SharedCache>>,
+}
+
+struct SeenToken {
+    expires_at: Instant,
+    last_used: Instant,
+}
+
+impl SeenToken {
+    /// Roughly how much memory a recorded token uses, in bytes.
+    fn memory_bytes() -> usize {
+        std::mem::size_of::<[u8; 32]>() + std::mem::size_of::<Self>()
+    }
+}
+
+impl JtiTracker {
+    pub fn new() -> Self {
+        Self {
+            seen: Mutex::new(HashMap::new()),
+            #[cfg(feature = "redis")]
+            shared_cache: None,
+        }
+    }
+
+    /// Records tokens for audiences using the `redis` mode in the given Redis cache, so replays
+    /// are caught across replicas.
+    #[cfg(feature = "redis")]
+    pub fn with_shared_cache(mut self, shared_cache: Arc<SharedCache>) -> Self {
+        self.shared_cache = Some(shared_cache);
+        self
+    }
+
+    /// Records the token with the given `jti`, expiring at the given time, as presented for the
+    /// given audience, returning whether it had already been presented for it. Nothing is recorded
+    /// if the mode is `disabled`.
+    pub async fn is_replay(
+        &self,
+        mode: JtiReplayMode,
+        audience: &str,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+    ) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(audience.as_bytes());
+        hasher.update([0]);
+        hasher.update(jti.as_bytes());
+        let hash: [u8; 32] = hasher.finalize().into();
+
+        // Tokens are recorded until they expire, after which they're rejected as expired anyway.
+        let ttl = (expires_at - Utc::now()).to_std().unwrap_or_default();
+
+        match mode {
+            JtiReplayMode::Disabled => false,
+            #[cfg(feature = "redis")]
+            JtiReplayMode::Redis => match self.is_shared_replay(&hash, ttl).await {
+                Some(is_replay) => is_replay,
+                None => self.is_local_replay(hash, ttl),
+            },
+            _ => self.is_local_replay(hash, ttl),
+        }
+    }
+
+    /// Records the token with the given hash in memory, returning whether it was already recorded.
+    fn is_local_replay(&self, hash: [u8; 32], ttl: Duration) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if let Some(seen_token) = seen.get_mut(&hash) {
+            if seen_token.expires_at > now {
+                seen_token.last_used = now;
+                return true;
+            }
+        }
+
+        seen.insert(
+            hash,
+            SeenToken {
+                expires_at: now + ttl,
+                last_used: now,
+            },
+        );
+        false
+    }
+
+    /// Records the token with the given hash in Redis, returning whether it was already recorded.
+    ///
+    /// Returns `None` if there's no Redis cache, or it's unavailable.
+    #[cfg(feature = "redis")]
+    async fn is_shared_replay(&self, hash: &[u8; 32], ttl: Duration) -> Option<bool> {
+        let shared_cache = self.shared_cache.as_ref()?;
+        let key = shared_cache.key("jti", hash);
+        shared_cache
+            .set_if_absent(key, ttl)
+            .await
+            .map(|was_set| !was_set)
+    }
+}
+
+impl Default for JtiTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SweepableCache for JtiTracker {
+    fn name(&self) -> &'static str {
+        "jti"
+    }
+
+    /// Forgets tokens that have expired, and then the least recently presented tokens until the
+    /// records fit within `limits`.
+    ///
+    /// Tokens forgotten before they expire can be replayed, so the limits for this cache should be
+    /// generous enough to hold every token accepted within the lifetime of a token.
+    fn sweep(&self, limits: SweepLimits) -> SweepOutcome {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = Instant::now();
+        let entries_before = seen.len();
+        seen.retain(|_, seen_token| seen_token.expires_at > now);
+        let expired = (entries_before - seen.len()) as u64;
+
+        let mut memory_bytes = seen.len() * SeenToken::memory_bytes();
+
+        let excess_entries = limits.excess_entries(seen.len());
+        let mut evicted = 0;
+        if limits.exceeded(seen.len(), memory_bytes) {
+            let mut by_last_use = seen
+                .iter()
+                .map(|(hash, seen_token)| (seen_token.last_used, *hash))
+                .collect::<Vec<_>>();
+            by_last_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+            for (_, hash) in by_last_use {
+                if !limits.exceeded(seen.len(), memory_bytes) {
+                    break;
+                }
+
+                if seen.remove(&hash).is_some() {
+                    memory_bytes -= SeenToken::memory_bytes();
+                    evicted += 1;
+                }
+            }
+        }
+
+        SweepOutcome::new(seen.len(), memory_bytes, expired, evicted, excess_entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::JtiReplayMode::{Disabled, Memory, Redis};
+
+    #[tokio::test]
+    async fn replays_are_caught_until_expiry() {
+        let tracker = JtiTracker::new();
+        let in_a_minute = Utc::now() + chrono::Duration::minutes(1);
+        let a_minute_ago = Utc::now() - chrono::Duration::minutes(1);
+
+        // Each case is presented in turn, against the same tracker.
+        let cases = [
+            (Memory, "app-1", "jti-1", in_a_minute, false),
+            (Memory, "app-1", "jti-1", in_a_minute, true),
+            // Without Redis, the `redis` mode falls back to memory.
+            (Redis, "app-1", "jti-1", in_a_minute, true),
+            (Memory, "app-2", "jti-1", in_a_minute, false),
+            (Memory, "app-1", "jti-2", in_a_minute, false),
+            (Disabled, "app-1", "jti-1", in_a_minute, false),
+            (Disabled, "app-3", "jti-1", in_a_minute, false),
+            (Memory, "app-3", "jti-1", in_a_minute, false),
+            // Expired tokens are never a replay, as they're rejected as expired anyway.
+            (Memory, "app-4", "jti-1", a_minute_ago, false),
+            (Memory, "app-4", "jti-1", a_minute_ago, false),
+            (Memory, "app-4", "jti-1", in_a_minute, false),
+            (Memory, "app-4", "jti-1", in_a_minute, true),
+        ];
+        for (i, (mode, audience, jti, expires_at, replay)) in cases.into_iter().enumerate() {
+            assert_eq!(
+                tracker.is_replay(mode, audience, jti, expires_at).await,
+                replay,
+                "case {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn recorded_tokens_expire() {
+        let tracker = JtiTracker::new();
+        let cases = [
+            (Duration::ZERO, false),
+            (Duration::from_millis(1), false),
+            (Duration::from_secs(60), true),
+        ];
+        for (i, (ttl, replay_after_sleep)) in cases.into_iter().enumerate() {
+            let hash = [i as u8; 32];
+            assert!(!tracker.is_local_replay(hash, ttl));
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(
+                tracker.is_local_replay(hash, ttl),
+                replay_after_sleep,
+                "{:?}",
+                ttl
+            );
+        }
+
+        // Sweeping forgets the expired records, even though they were recorded again.
+        std::thread::sleep(Duration::from_millis(5));
+        let outcome = tracker.sweep(SweepLimits::default());
+        assert_eq!(outcome.expired, 2);
+        assert_eq!(outcome.entries, 1);
+        assert_eq!(outcome.memory_bytes, SeenToken::memory_bytes());
+    }
+}
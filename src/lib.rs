@@ -0,0 +1,28 @@
+pub mod anomaly;
+pub mod basic_auth;
+pub mod cloudflare;
+pub mod config;
+pub mod diagnostics;
+pub mod enrichment;
+pub mod expression;
+pub mod gc;
+pub mod groups;
+pub mod identity;
+pub mod internal_token;
+pub mod jti;
+pub mod opa;
+pub mod path_rules;
+pub mod policy;
+pub mod redaction;
+pub mod replay;
+#[cfg(feature = "redis")]
+pub mod shared_cache;
+pub mod signing;
+pub mod supervisor;
+pub mod telemetry;
+pub mod template;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod validation;
+pub mod watch;
+pub mod web;
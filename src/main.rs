@@ -1,58 +1,133 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
 
-use openidconnect::IssuerUrl;
-use tracing::error;
-use tracing_subscriber::{filter::LevelFilter, EnvFilter};
-
-pub mod validation;
-pub mod web;
-use self::validation::{
-    manage_jwks_refreshing, service_auth::ServiceAuthTokenHeaderMap, SignatureState,
+use clap::Parser;
+use cloudflare_access_forwardauth::{
+    anomaly::AnomalyDetector,
+    config::{self, Config, MetricsMode},
+    diagnostics,
+    enrichment::UserEnricher,
+    gc::{self, SweepableCache},
+    groups::{self, GroupNameResolver},
+    identity::IdentityEnricher,
+    jti::JtiTracker,
+    policy::{AudiencePolicies, AudiencePolicyStore},
+    redaction::{self, RedactionRules},
+    replay,
+    supervisor::Supervisor,
+    telemetry,
+    validation::{
+        manage_jwks_refreshing, new_http_client,
+        service_auth::{self, ServiceTokenMapStore},
+        SignatureStates,
+    },
+    watch::watch_file,
+    web::{run_api_endpoint, ApiState, ValidationCache},
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
-use self::web::run_api_endpoint;
+
+mod cli;
+use self::cli::{Cli, Command, ConfigArgs, ReplayArgs};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
+    let cli = Cli::parse();
+
     // Initialize the tracing/logging layer.
-    tracing_subscriber::fmt()
-        .json()
-        .with_env_filter(
+    let subscriber = tracing_subscriber::registry()
+        .with(
             EnvFilter::builder()
                 .with_default_directive(LevelFilter::INFO.into())
                 .from_env_lossy(),
         )
-        .init();
+        .with(tracing_subscriber::fmt::layer().json());
+
+    // If configured, also export spans over OTLP. Logging isn't set up yet if this fails, so the
+    // error can only go to stderr.
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(match telemetry::otel::layer() {
+        Ok(layer) => layer,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    });
+
+    subscriber.init();
+
+    if cli.print_env_mapping {
+        print_env_mapping();
+        return;
+    }
+
+    let result = match cli.command {
+        None => serve(cli.serve).await,
+        Some(Command::Serve(args)) => serve(args).await,
+        Some(Command::CheckConfig(args)) => check_config(args),
+        Some(Command::Replay(args)) => replay(args).await,
+        Some(Command::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+    };
 
-    // Run the application, logging any unrecoverable errors.
-    if let Err(e) = run().await {
+    #[cfg(feature = "otel")]
+    telemetry::otel::shutdown();
+
+    // Log any unrecoverable errors, and make sure we exit with a non-zero status code.
+    if let Err(e) = result {
         error!(error = e, "Failed with unrecoverable error. Exiting.");
+        std::process::exit(1);
     }
 }
 
-async fn run() -> Result<(), String> {
-    // Read all the relevant configuration variables.
-    let listen_address: SocketAddr = std::env::var("LISTEN_ADDR")
-        .map_err(|_| {
-            "Listen address must be specified via `LISTEN_ADDR` (example: 127.0.0.1:9000)".into()
-        })
-        .and_then(|s| {
-            s.parse()
-                .map_err(|e| format!("Listen address was invalid: {}", e))
-        })?;
-
-    let issuer_url = std::env::var("CF_AUTH_DOMAIN")
-        .map_err(|_| "Cloudflare Access team domain must be specified via `CF_AUTH_DOMAIN` (example: https://your-team-name.cloudflareaccess.com)".to_string())
-        .and_then(|s| IssuerUrl::new(s).map_err(|e| format!("Authentication domain was invalid: {}", e)))?;
-
-    let token_map = std::env::var("SERVICE_TOKEN_AUTH_MAPPING_FILE")
-        .ok()
-        .map(|s| {
-            ServiceAuthTokenHeaderMap::from_mapping_file(&s)
-                .map_err(|e| format!("Failed to load service token auth mapping file: {}", e))
-                .map(Arc::new)
-        })
-        .transpose()?
-        .unwrap_or_default();
+async fn serve(args: ConfigArgs) -> Result<(), String> {
+    // Load our configuration, from the configuration file if one was given, and then from any
+    // environment variables or command-line arguments that override it.
+    let config = args.load_config()?;
+    let listen_address = config.require_listen_address()?;
+    let issuer_url = config.require_auth_domain()?;
+    let token_map = config.load_service_token_map()?;
+    let message_signer = config.load_message_signer()?.map(Arc::new);
+    let internal_token = config.load_internal_token()?.map(Arc::new);
+    let admin_token = config.load_admin_token()?.map(Arc::new);
+    let proxy_secret = config.load_proxy_secret()?.map(Arc::new);
+    let error_pages = config.load_error_pages()?.map(Arc::new);
+    #[cfg(feature = "redis")]
+    let shared_cache = config.load_shared_cache()?.map(Arc::new);
+    let validation_cache = config.validation_cache.as_ref().map(|validation_cache| {
+        // Held to the same limits it's swept with, even in between sweeps.
+        let limits = config.cache_gc.for_cache("validation").limits;
+        let validation_cache = ValidationCache::new(validation_cache, limits);
+        #[cfg(feature = "redis")]
+        let validation_cache = match &shared_cache {
+            Some(shared_cache) => validation_cache.with_shared_cache(Arc::clone(shared_cache)),
+            None => validation_cache,
+        };
+        Arc::new(validation_cache)
+    });
+    diagnostics::log_startup_summary(&config, &listen_address, &token_map);
+    let policies = Arc::new(AudiencePolicyStore::new(AudiencePolicies::compile(
+        &config,
+    )?));
+    let token_map = Arc::new(ServiceTokenMapStore::new(token_map));
+
+    // Claim values matching any of these patterns are only ever logged as hashes.
+    redaction::set_rules(RedactionRules::from_patterns(
+        config.redacted_claims.iter().cloned(),
+    ));
+
+    // Metrics must never carry identifying labels in private mode, so it's enabled before anything
+    // can be recorded.
+    if config.metrics_mode == MetricsMode::Private {
+        telemetry::privacy::enable(config.metrics_min_count);
+    }
+
+    // Install the metrics recorder before anything starts recording metrics.
+    let metrics_handle = telemetry::install_recorder()?;
 
     // Ensure that the root certificate trust store is already present/configured, and if not, try
     // finding it and configuring the environment to allow OpenSSL to locate it.
@@ -61,12 +136,393 @@ async fn run() -> Result<(), String> {
     }
 
     // Create all the application configuration and shared state.
-    let signature_state = SignatureState::from_issuer_url(issuer_url).map(Arc::new)?;
+    let http_client = new_http_client();
+    let anomaly_detector = config
+        .anomaly_detection
+        .as_ref()
+        .map(|anomaly_detection| AnomalyDetector::new(anomaly_detection, http_client.clone()))
+        .transpose()?
+        .map(Arc::new);
+    let remote_token_map = config.load_remote_token_map(http_client.clone())?;
+    let cloudflare_api = config
+        .load_cloudflare_api(http_client.clone())?
+        .map(Arc::new);
+    let opa = config.load_opa(http_client.clone())?.map(Arc::new);
+    let user_enricher = match (&config.user_enrichment, &cloudflare_api) {
+        (Some(user_enrichment), Some(cloudflare_api)) => Some(UserEnricher::new(
+            user_enrichment,
+            Arc::clone(cloudflare_api),
+        )?),
+        _ => None,
+    };
+    let identity_enricher = config
+        .identity_enrichment
+        .as_ref()
+        .map(|identity_enrichment| IdentityEnricher::new(identity_enrichment, http_client.clone()))
+        .transpose()?;
+    #[cfg(feature = "redis")]
+    let (user_enricher, identity_enricher) = match &shared_cache {
+        Some(shared_cache) => (
+            user_enricher
+                .map(|user_enricher| user_enricher.with_shared_cache(Arc::clone(shared_cache))),
+            identity_enricher.map(|identity_enricher| {
+                identity_enricher.with_shared_cache(Arc::clone(shared_cache))
+            }),
+        ),
+        None => (user_enricher, identity_enricher),
+    };
+    let user_enricher = user_enricher.map(Arc::new);
+    let identity_enricher = identity_enricher.map(Arc::new);
+
+    // Replay protection can be enabled for an audience whenever the policies are reloaded, so
+    // there's always somewhere to record tokens.
+    let jti_tracker = JtiTracker::new();
+    #[cfg(feature = "redis")]
+    let jti_tracker = match &shared_cache {
+        Some(shared_cache) => jti_tracker.with_shared_cache(Arc::clone(shared_cache)),
+        None => jti_tracker,
+    };
+    let jti_tracker = Arc::new(jti_tracker);
+    let group_names = match (&config.group_names, &cloudflare_api) {
+        (Some(group_names), Some(cloudflare_api)) => Some(Arc::new(GroupNameResolver::new(
+            group_names,
+            Arc::clone(cloudflare_api),
+        )?)),
+        _ => None,
+    };
+    let signature_states =
+        SignatureStates::new(issuer_url, &config.issuers, http_client, &config.jwks_fetch)
+            .map(Arc::new)?;
+
+    // Every background task runs under the supervisor, which restarts any that panic, and stops
+    // all of them together on shutdown.
+    let mut supervisor = Supervisor::new();
 
-    // Run a background task that refreshes the signatures used for the given authentication domain,
-    // including the initial load that establishes readiness for this server.
-    tokio::spawn(manage_jwks_refreshing(Arc::clone(&signature_state)));
+    // Run a background task for each issuer that refreshes the signatures used for its
+    // authentication domain, including the initial load that establishes readiness for this server.
+    for signature_state in signature_states.iter() {
+        let signature_state = Arc::clone(signature_state);
+        let refresh_interval = config.jwks_refresh_interval();
+        let refresh_jitter = config.jwks_refresh_jitter();
+        supervisor.spawn(
+            format!("jwks_refresh:{}", signature_state.issuer_url().as_str()),
+            move || {
+                manage_jwks_refreshing(
+                    Arc::clone(&signature_state),
+                    refresh_interval,
+                    refresh_jitter,
+                )
+            },
+        );
+    }
+
+    // Fetch the centrally managed service token auth mappings before serving any requests, and then
+    // keep them up to date.
+    if let Some(remote_token_map) = remote_token_map {
+        token_map.set_remote(remote_token_map.fetch().await?);
+        let remote_token_map = Arc::new(remote_token_map);
+        let token_map = Arc::clone(&token_map);
+        let refresh_interval = config.service_auth_map_refresh_interval();
+        supervisor.spawn("remote_token_map_refresh", move || {
+            service_auth::refresh_remote_token_map(
+                Arc::clone(&remote_token_map),
+                Arc::clone(&token_map),
+                refresh_interval,
+            )
+        });
+    }
+
+    // Keep the Access group names up to date, starting right away.
+    if let (Some(group_names), Some(group_names_config)) = (&group_names, &config.group_names) {
+        let group_names = Arc::clone(group_names);
+        let refresh_interval = group_names_config.refresh_interval();
+        supervisor.spawn("group_names_refresh", move || {
+            groups::refresh_group_names(Arc::clone(&group_names), refresh_interval)
+        });
+    }
+
+    // Evaluate validation failure rates for spikes as each window ends.
+    let mut caches: Vec<Arc<dyn SweepableCache>> = Vec::new();
+    if let Some(anomaly_detector) = &anomaly_detector {
+        let detector = Arc::clone(anomaly_detector);
+        supervisor.spawn("anomaly_detection", move || Arc::clone(&detector).run());
+        caches.push(Arc::clone(anomaly_detector) as Arc<dyn SweepableCache>);
+    }
+    if let Some(user_enricher) = &user_enricher {
+        caches.push(Arc::clone(user_enricher) as Arc<dyn SweepableCache>);
+    }
+    if let Some(identity_enricher) = &identity_enricher {
+        caches.push(Arc::clone(identity_enricher) as Arc<dyn SweepableCache>);
+    }
+    if let Some(validation_cache) = &validation_cache {
+        caches.push(Arc::clone(validation_cache) as Arc<dyn SweepableCache>);
+    }
+    caches.push(Arc::clone(&jti_tracker) as Arc<dyn SweepableCache>);
+
+    // Sweep each of the in-memory caches, so they don't grow without bound.
+    for cache in caches {
+        let settings = config.cache_gc.for_cache(cache.name());
+        supervisor.spawn(format!("cache_gc:{}", cache.name()), move || {
+            gc::sweep_periodically(Arc::clone(&cache), settings)
+        });
+    }
+
+    // Dump a diagnostic snapshot to the logs whenever we receive SIGUSR1.
+    let config = Arc::new(config);
+    #[cfg(unix)]
+    {
+        let config = Arc::clone(&config);
+        let signature_states = Arc::clone(&signature_states);
+        supervisor.spawn("diagnostics_dump", move || {
+            dump_diagnostics_on_signal(Arc::clone(&config), Arc::clone(&signature_states))
+        });
+    }
+
+    // Reload the service token auth mapping file, and the audience policies from the configuration
+    // file, whenever either changes, or we receive SIGHUP.
+    let args = Arc::new(args);
+    {
+        let config = Arc::clone(&config);
+        let token_map = Arc::clone(&token_map);
+        supervisor.spawn("mapping_file_watch", move || {
+            service_auth::watch_mapping_file(Arc::clone(&config), Arc::clone(&token_map))
+        });
+    }
+    if let Some(path) = args.config.clone() {
+        let args = Arc::clone(&args);
+        let signature_states = Arc::clone(&signature_states);
+        let policies = Arc::clone(&policies);
+        supervisor.spawn("config_file_watch", move || {
+            let path = path.clone();
+            let args = Arc::clone(&args);
+            let signature_states = Arc::clone(&signature_states);
+            let policies = Arc::clone(&policies);
+            async move {
+                watch_file(path, "configuration file", || {
+                    reload_policies(&args, &signature_states, &policies)
+                })
+                .await
+            }
+        });
+    }
+    #[cfg(unix)]
+    {
+        let config = Arc::clone(&config);
+        let token_map = Arc::clone(&token_map);
+        let signature_states = Arc::clone(&signature_states);
+        let policies = Arc::clone(&policies);
+        supervisor.spawn("reload_on_signal", move || {
+            reload_on_signal(
+                Arc::clone(&args),
+                Arc::clone(&config),
+                Arc::clone(&token_map),
+                Arc::clone(&signature_states),
+                Arc::clone(&policies),
+            )
+        });
+    }
+
+    // Shut down gracefully on SIGTERM, which is how Kubernetes stops pods, or SIGINT.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    // Run the API endpoint until it has drained after shutdown was requested.
+    let api_state = ApiState {
+        states: signature_states,
+        token_map,
+        message_signer,
+        user_enricher,
+        identity_enricher,
+        jti_tracker: Some(jti_tracker),
+        internal_token,
+        group_names,
+        admin_token,
+        proxy_secret,
+        validation_cache,
+        error_pages,
+        anomaly_detector,
+        opa,
+        config,
+        policies,
+        metrics_handle,
+        task_statuses: supervisor.statuses(),
+    };
+    let shutdown_timeout = api_state.config.shutdown_timeout();
+    let result = run_api_endpoint(&listen_address, api_state, shutdown_rx).await;
+
+    // Nothing is left to validate tokens for, so stop every background task.
+    supervisor.shutdown(shutdown_timeout).await;
+    info!("Shut down.");
+
+    result
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut signals) => {
+                signals.recv().await;
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to install SIGTERM handler. Only SIGINT will shut down gracefully.");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!(error = %e, "Failed to listen for SIGINT.");
+                std::future::pending::<()>().await;
+            }
+        }
+        _ = terminate => {}
+    }
+}
+
+fn print_env_mapping() {
+    for env_var in config::ENV_VARS {
+        if env_var.deprecated_names.is_empty() {
+            println!("{}\t{}", env_var.name, env_var.setting);
+        } else {
+            println!(
+                "{}\t{}\t(deprecated: {})",
+                env_var.name,
+                env_var.setting,
+                env_var.deprecated_names.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn dump_diagnostics_on_signal(config: Arc<Config>, signature_states: Arc<SignatureStates>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!(error = %e, "Failed to install SIGUSR1 handler. Diagnostic dumps are unavailable.");
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        diagnostics::dump(&config, &signature_states);
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_signal(
+    args: Arc<ConfigArgs>,
+    config: Arc<Config>,
+    token_map: Arc<ServiceTokenMapStore>,
+    signature_states: Arc<SignatureStates>,
+    policies: Arc<AudiencePolicyStore>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!(error = %e, "Failed to install SIGHUP handler. Service token auth mappings and audience policies can't be reloaded.");
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        service_auth::reload_token_map(&config, &token_map);
+        reload_policies(&args, &signature_states, &policies);
+    }
+}
+
+/// Reloads the audience policies from the configuration, keeping the current ones if the new
+/// configuration is invalid.
+///
+/// Only the audience policies (`audiences`, `hosts`, `restrict_audiences` and `missing_token`) are
+/// reloaded. Changes to any other settings take effect on restart.
+fn reload_policies(
+    args: &ConfigArgs,
+    signature_states: &SignatureStates,
+    policies: &AudiencePolicyStore,
+) {
+    let result = args.load_config().and_then(|config| {
+        // Issuers are only set up at startup, so audiences can't be moved to one that's new.
+        let new_policies = AudiencePolicies::compile(&config)?;
+        if let Some(issuer) = new_policies
+            .issuers()
+            .find(|issuer| signature_states.get(Some(*issuer)).is_none())
+        {
+            return Err(format!(
+                "Issuer '{}' was added since startup, which requires a restart.",
+                issuer
+            ));
+        }
+
+        Ok((config.audiences.len(), new_policies))
+    });
+
+    match result {
+        Ok((audiences, new_policies)) => {
+            policies.store(new_policies);
+            info!(audiences, "Reloaded audience policies.");
+        }
+        Err(e) => error!(
+            error = e,
+            "Failed to reload audience policies. Keeping the current ones."
+        ),
+    }
+}
+
+fn check_config(args: ConfigArgs) -> Result<(), String> {
+    let config = args.load_config()?;
+    let listen_address = config.require_listen_address()?;
+    let issuer_url = config.require_auth_domain()?;
+    let token_map = config.load_service_token_map()?;
+    config.load_message_signer()?;
+    config.load_internal_token()?;
+    config.load_admin_token()?;
+    config.load_proxy_secret()?;
+    config.load_error_pages()?;
+    #[cfg(feature = "redis")]
+    config.load_shared_cache()?;
+    config.load_remote_token_map(new_http_client())?;
+    config.load_cloudflare_api(new_http_client())?;
+    config.load_opa(new_http_client())?;
+    if let Some(identity_enrichment) = &config.identity_enrichment {
+        IdentityEnricher::new(identity_enrichment, new_http_client())?;
+    }
+    if let Some(anomaly_detection) = &config.anomaly_detection {
+        AnomalyDetector::new(anomaly_detection, new_http_client())?;
+    }
+    AudiencePolicies::compile(&config)?;
+    SignatureStates::new(
+        issuer_url,
+        &config.issuers,
+        new_http_client(),
+        &config.jwks_fetch,
+    )?;
+
+    diagnostics::log_startup_summary(&config, &listen_address, &token_map);
+    info!("Configuration is valid.");
+    Ok(())
+}
+
+async fn replay(args: ReplayArgs) -> Result<(), String> {
+    let config = args.config.load_config()?;
+    let decisions = replay::replay(config, &args.jwks_dir, &args.requests).await?;
+    for decision in decisions {
+        println!("{}", decision);
+    }
 
-    // Run the API endpoint.
-    run_api_endpoint(&listen_address, signature_state, token_map).await
+    Ok(())
 }
@@ -0,0 +1,126 @@
+use std::{str::FromStr, time::Duration};
+
+use hyper::{
+    body::to_bytes,
+    header::{self, HeaderName, HeaderValue},
+    Body, HeaderMap, Method, Request, Uri,
+};
+use serde_json::{json, Value};
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::{config::OpaConfig, validation::HttpClient};
+
+/// Asks Open Policy Agent whether to let a request through, once its token has been validated.
+///
+/// Anything other than an explicit decision to allow the request, including OPA being unreachable
+/// or not answering in time, is treated as a denial.
+pub struct OpaClient {
+    http_client: HttpClient,
+    url: Uri,
+    timeout: Duration,
+}
+
+/// A decision made by OPA.
+pub struct OpaDecision {
+    /// Whether or not the request is allowed.
+    pub allow: bool,
+
+    /// Why the request was denied, if OPA said.
+    pub reason: Option<String>,
+
+    /// Headers to add to the response, if the request is allowed.
+    pub headers: HeaderMap,
+}
+
+impl OpaClient {
+    pub fn from_config(config: &OpaConfig, http_client: HttpClient) -> Result<Self, String> {
+        let url = Uri::from_str(&config.url).map_err(|e| format!("Invalid OPA URL: {}", e))?;
+
+        Ok(Self {
+            http_client,
+            url,
+            timeout: config.timeout(),
+        })
+    }
+
+    /// Queries the decision for the given input.
+    ///
+    /// The decision may either be a boolean, or an object such as
+    /// `{"allow": true, "headers": {"X-Tenant": "acme"}}`, optionally with a `reason` for denials.
+    pub async fn decide(&self, input: Value) -> Result<OpaDecision, String> {
+        let body = json!({ "input": input });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|e| format!("Failed to build OPA request: {}", e))?;
+
+        let response = timeout(self.timeout, self.http_client.request(request))
+            .await
+            .map_err(|_| "OPA request timed out".to_string())?
+            .map_err(|e| format!("OPA request failed: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("OPA responded with {}", status));
+        }
+
+        let body = to_bytes(response.into_body())
+            .await
+            .map_err(|e| format!("Failed to read OPA response: {}", e))?;
+        let response = serde_json::from_slice::<Value>(&body)
+            .map_err(|e| format!("Failed to parse OPA response: {}", e))?;
+
+        // A policy that doesn't define the decision at all leaves out the result entirely.
+        match response.get("result") {
+            Some(Value::Bool(allow)) => Ok(OpaDecision {
+                allow: *allow,
+                reason: None,
+                headers: HeaderMap::new(),
+            }),
+            Some(Value::Object(decision)) => Ok(OpaDecision {
+                allow: decision.get("allow").and_then(Value::as_bool) == Some(true),
+                reason: decision
+                    .get("reason")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                headers: decision
+                    .get("headers")
+                    .map(decision_headers)
+                    .unwrap_or_default(),
+            }),
+            Some(_) => Err("OPA decision is neither a boolean nor an object".to_string()),
+            None => Err("OPA decision is undefined".to_string()),
+        }
+    }
+}
+
+fn decision_headers(headers: &Value) -> HeaderMap {
+    let mut header_map = HeaderMap::new();
+    let headers = match headers.as_object() {
+        Some(headers) => headers,
+        None => {
+            warn!("Ignoring OPA decision headers that aren't an object.");
+            return header_map;
+        }
+    };
+
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes()).ok();
+        let header_value = value
+            .as_str()
+            .and_then(|value| HeaderValue::from_str(value).ok());
+        match (header_name, header_value) {
+            (Some(name), Some(value)) => {
+                header_map.insert(name, value);
+            }
+            _ => warn!(
+                header = name.as_str(),
+                "Ignoring invalid OPA decision header."
+            ),
+        }
+    }
+
+    header_map
+}
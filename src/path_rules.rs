@@ -0,0 +1,306 @@
+use crate::config::{PathRuleConfig, PrincipalType};
+
+/// A rule for requests to matching paths, and methods, of an audience.
+///
+/// The path and method come from the `X-Forwarded-Uri` and `X-Forwarded-Method` headers sent by the
+/// proxy, and the rule is only checked once the token itself has been validated.
+#[derive(Clone, Debug)]
+pub struct PathRule {
+    pattern: String,
+    segments: Vec<PatternSegment>,
+    methods: Vec<String>,
+    allowed_principals: Vec<PrincipalType>,
+    required_groups: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+enum PatternSegment {
+    /// Matches a segment exactly.
+    Literal(String),
+
+    /// Matches any single segment, written as `*`.
+    Any,
+
+    /// Matches any number of segments, including none, written as `**`.
+    AnyDepth,
+}
+
+impl PathRule {
+    /// Compiles the given rule.
+    ///
+    /// Invalid patterns are rejected when the configuration is validated.
+    pub fn compile(config: &PathRuleConfig) -> Self {
+        let segments = pattern_segments(&config.path)
+            .map(|segment| match segment {
+                "*" => PatternSegment::Any,
+                "**" => PatternSegment::AnyDepth,
+                literal => PatternSegment::Literal(literal.to_string()),
+            })
+            .collect();
+
+        Self {
+            pattern: config.path.clone(),
+            segments,
+            methods: config
+                .methods
+                .iter()
+                .map(|method| method.to_ascii_uppercase())
+                .collect(),
+            allowed_principals: config.allowed_principals.clone(),
+            required_groups: config.required_groups.clone(),
+        }
+    }
+
+    /// Gets the path pattern the rule was configured with.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether or not the rule applies to a request with the given method and normalized path.
+    ///
+    /// If the method isn't known, rules for specific methods still apply, so that leaving out the
+    /// method can't be used to get around them.
+    pub fn matches(&self, method: Option<&str>, path: &[String]) -> bool {
+        let method_matches = match method {
+            Some(method) => {
+                self.methods.is_empty()
+                    || self
+                        .methods
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(method))
+            }
+            None => true,
+        };
+
+        method_matches && segments_match(&self.segments, path)
+    }
+
+    /// Whether or not the given kind of principal, as a member of the given groups, is accepted.
+    pub fn allows(&self, principal: PrincipalType, groups: &[&str]) -> bool {
+        let principal_allowed =
+            self.allowed_principals.is_empty() || self.allowed_principals.contains(&principal);
+        let groups_allowed = self.required_groups.is_empty()
+            || self
+                .required_groups
+                .iter()
+                .any(|required| groups.contains(&required.as_str()));
+
+        principal_allowed && groups_allowed
+    }
+}
+
+/// Gets the first of the given rules matching a request with the given method and normalized path,
+/// if any.
+///
+/// Rules are checked in the order they're configured in, so more specific rules must come before
+/// broader ones that would otherwise match the same requests.
+pub fn first_match<'a>(
+    rules: &'a [PathRule],
+    method: Option<&str>,
+    path: &[String],
+) -> Option<&'a PathRule> {
+    rules.iter().find(|rule| rule.matches(method, path))
+}
+
+/// Checks that the given path pattern is valid.
+pub fn validate_pattern(pattern: &str) -> Result<(), String> {
+    if !pattern.starts_with('/') {
+        return Err("must start with '/'".to_string());
+    }
+
+    for segment in pattern_segments(pattern) {
+        if segment != "*" && segment != "**" && segment.contains('*') {
+            return Err("wildcards must be a whole segment, either '*' or '**'".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn pattern_segments(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn segments_match(pattern: &[PatternSegment], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((PatternSegment::AnyDepth, rest)) => {
+            (0..=path.len()).any(|skipped| segments_match(rest, &path[skipped..]))
+        }
+        Some((segment, rest)) => match path.split_first() {
+            Some((first, path_rest)) => {
+                let segment_matches = match segment {
+                    PatternSegment::Literal(literal) => literal == first,
+                    _ => true,
+                };
+                segment_matches && segments_match(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Normalizes the path of the given request target, as sent in `X-Forwarded-Uri`, into its
+/// segments, returning `None` if it isn't a valid path.
+///
+/// The path is percent-decoded, and then empty and `.` segments are dropped and `..` segments
+/// resolved, so that the same path always matches the same rules however it was written. Encoded
+/// slashes, and backslashes, are treated as separators too, since some applications treat them
+/// that way.
+pub fn normalize_path(uri: &str) -> Option<Vec<String>> {
+    let path = uri
+        .split(|c| c == '?' || c == '#')
+        .next()
+        .unwrap_or_default();
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let decoded = percent_decode_path(path)?;
+    let mut segments = Vec::new();
+    for segment in decoded.split(|c| c == '/' || c == '\\') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment.to_string()),
+        }
+    }
+
+    Some(segments)
+}
+
+fn percent_decode_path(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b => decoded.push(b),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str, methods: &[&str], required_groups: &[&str]) -> PathRule {
+        PathRule::compile(&PathRuleConfig {
+            path: path.to_string(),
+            methods: methods.iter().map(|method| method.to_string()).collect(),
+            allowed_principals: Vec::new(),
+            required_groups: required_groups
+                .iter()
+                .map(|group| group.to_string())
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rules = [
+            rule("/admin/reports/*", &["GET"], &[]),
+            rule("/admin/**", &["post", "DELETE"], &["ops"]),
+            rule("/admin/**", &[], &["admins"]),
+            rule("/**/health", &[], &[]),
+        ];
+        let cases = [
+            (Some("GET"), "/admin/reports/daily", Some(0)),
+            (Some("get"), "/admin/reports/daily?format=csv", Some(0)),
+            // `*` only matches a single segment.
+            (Some("GET"), "/admin/reports/daily/raw", Some(2)),
+            (Some("GET"), "/admin/reports", Some(2)),
+            (Some("POST"), "/admin/reports/daily", Some(1)),
+            (Some("DELETE"), "/admin", Some(1)),
+            (Some("GET"), "/admin", Some(2)),
+            // Without a method, rules for specific methods still apply.
+            (None, "/admin/reports/daily", Some(0)),
+            (None, "/admin/users", Some(1)),
+            (Some("GET"), "/health", Some(3)),
+            (Some("GET"), "/api/v1/health", Some(3)),
+            (Some("GET"), "/admin/health", Some(2)),
+            (Some("GET"), "/public/index.html", None),
+            (Some("GET"), "/", None),
+            // Paths are normalized before they're matched.
+            (Some("GET"), "/public/../admin/reports/daily", Some(0)),
+            (Some("GET"), "//admin/./users", Some(2)),
+            (Some("GET"), "/%61dmin/users", Some(2)),
+            (Some("GET"), "/public%2F..%2Fadmin", Some(2)),
+            (Some("GET"), "/public\\..\\admin", Some(2)),
+        ];
+        for (method, uri, expected) in cases {
+            let path = normalize_path(uri).unwrap();
+            let matched = first_match(&rules, method, &path).map(|matched| {
+                rules
+                    .iter()
+                    .position(|rule| std::ptr::eq(rule, matched))
+                    .unwrap()
+            });
+            assert_eq!(matched, expected, "{:?} {}", method, uri);
+        }
+    }
+
+    #[test]
+    fn matching_rule_checks_principal_and_groups() {
+        let mut services_only = rule("/internal/**", &[], &[]);
+        services_only.allowed_principals = vec![PrincipalType::Service];
+        let ops = rule("/admin/**", &[], &["ops", "sre"]);
+        let cases: [(&PathRule, PrincipalType, &[&str], bool); 5] = [
+            (&services_only, PrincipalType::Service, &[], true),
+            (&services_only, PrincipalType::User, &[], false),
+            (&ops, PrincipalType::User, &["dev", "sre"], true),
+            (&ops, PrincipalType::User, &["dev"], false),
+            (&ops, PrincipalType::Service, &[], false),
+        ];
+        for (rule, principal, groups, allowed) in cases {
+            assert_eq!(
+                rule.allows(principal, groups),
+                allowed,
+                "{} {:?} {:?}",
+                rule.pattern(),
+                principal,
+                groups
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_paths_and_patterns() {
+        for uri in ["admin", "", "/admin%2", "/admin%zz", "/%ff"] {
+            assert_eq!(normalize_path(uri), None, "{}", uri);
+        }
+
+        let cases = [
+            ("/admin/**", Ok(())),
+            ("/*/reports", Ok(())),
+            ("admin/**", Err("must start with '/'")),
+            (
+                "/admin*",
+                Err("wildcards must be a whole segment, either '*' or '**'"),
+            ),
+            (
+                "/admin/***",
+                Err("wildcards must be a whole segment, either '*' or '**'"),
+            ),
+        ];
+        for (pattern, expected) in cases {
+            assert_eq!(
+                validate_pattern(pattern),
+                expected.map_err(str::to_string),
+                "{}",
+                pattern
+            );
+        }
+    }
+}
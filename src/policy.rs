@@ -0,0 +1,521 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use arc_swap::ArcSwap;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    basic_auth::BasicAuth,
+    config::{ClaimFilterConfig, Config, JtiReplayMode, PrincipalType, TokenType},
+    expression::Expression,
+    path_rules::{self, PathRule},
+    template::Template,
+    web::MissingTokenBehavior,
+};
+
+/// Maximum length of an audience.
+///
+/// Cloudflare Access AUD tags are 64 hex characters, so anything longer is not a real AUD tag.
+const MAX_AUDIENCE_LEN: usize = 64;
+
+/// The reason an audience was rejected as invalid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidAudienceReason {
+    /// The audience was empty, or only whitespace.
+    Empty,
+
+    /// The audience was longer than any AUD tag would be.
+    TooLong,
+
+    /// The audience contained characters other than hex digits.
+    InvalidCharacters,
+
+    /// The audience was given more than once, such as in both the path and the query string, with
+    /// different values.
+    Ambiguous,
+}
+
+impl InvalidAudienceReason {
+    /// Gets a short, stable identifier for this reason, suitable for logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::TooLong => "too_long",
+            Self::InvalidCharacters => "invalid_characters",
+            Self::Ambiguous => "ambiguous",
+        }
+    }
+}
+
+/// Normalizes the given audience into the canonical form of an AUD tag.
+///
+/// Surrounding whitespace is trimmed, and hex digits are lowercased, so that the same AUD tag always
+/// maps to the same policy, verifier, and log/metric values regardless of how it was written.
+pub fn normalize_audience(audience: &str) -> Result<String, InvalidAudienceReason> {
+    let audience = audience.trim();
+    if audience.is_empty() {
+        return Err(InvalidAudienceReason::Empty);
+    }
+
+    if audience.len() > MAX_AUDIENCE_LEN {
+        return Err(InvalidAudienceReason::TooLong);
+    }
+
+    if !audience.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(InvalidAudienceReason::InvalidCharacters);
+    }
+
+    Ok(audience.to_ascii_lowercase())
+}
+
+/// Policies for every configured audience.
+///
+/// These are compiled once, when the configuration is loaded, into a form that's cheap to evaluate
+/// so that handling a request never involves interpreting the raw configuration.
+#[derive(Debug)]
+pub struct AudiencePolicies {
+    default: AudiencePolicy,
+    audiences: HashMap<String, AudiencePolicy>,
+    hosts: HashMap<String, String>,
+    restrict_audiences: bool,
+
+    /// Which policies these are, out of every set of policies that's been in effect, so anything
+    /// derived from them can tell when they've been replaced.
+    version: u64,
+}
+
+impl AudiencePolicies {
+    /// Compiles the policies for all audiences in the given configuration.
+    ///
+    /// Any secrets the policies refer to are loaded as well, which is the only way this can fail.
+    pub fn compile(config: &Config) -> Result<Self, String> {
+        let mut default = AudiencePolicy {
+            issuer: None,
+            missing_token: config.missing_token.default_behavior(),
+            allowed_token_types: TokenTypeSet::default(),
+            allowed_principals: Vec::new(),
+            required_groups: Vec::new(),
+            allowed_emails: Vec::new(),
+            allowed_email_domains: Vec::new(),
+            path_rules: Vec::new(),
+            expression: None,
+            basic_auth: None,
+            static_headers: HeaderMap::new(),
+            claim_header_names: HashMap::new(),
+            forwarded_claims: ClaimFilterConfig::default(),
+            header_templates: Vec::new(),
+            jti_replay: JtiReplayMode::Disabled,
+            fingerprint: [0; 32],
+        };
+        default.fingerprint = default.compute_fingerprint();
+
+        // An audience may have settings in more than one place, so gather up every audience that
+        // has any settings at all, and then compile each of them with the defaults filled in.
+        let audience_names = config
+            .audiences
+            .keys()
+            .map(String::as_str)
+            .chain(config.missing_token.audiences());
+
+        let mut audiences = HashMap::new();
+        for audience in audience_names {
+            let mut policy = AudiencePolicy {
+                missing_token: config.missing_token.behavior_for(audience),
+                ..default.clone()
+            };
+
+            if let Some(audience_config) = config.audience(audience) {
+                policy.issuer = audience_config.issuer.clone();
+                for token_type in &audience_config.allowed_token_types {
+                    policy.allowed_token_types.insert(*token_type);
+                }
+                policy.allowed_principals = audience_config.allowed_principals.clone();
+                policy.required_groups = audience_config.required_groups.clone();
+
+                // Emails are compared case-insensitively, so they're lowercased up front.
+                policy.allowed_emails = audience_config
+                    .allowed_emails
+                    .iter()
+                    .map(|email| email.trim().to_ascii_lowercase())
+                    .collect();
+                policy.allowed_email_domains = audience_config
+                    .allowed_email_domains
+                    .iter()
+                    .map(|domain| domain.trim().to_ascii_lowercase())
+                    .collect();
+                policy.path_rules = audience_config
+                    .path_rules
+                    .iter()
+                    .map(PathRule::compile)
+                    .collect();
+                policy.expression = audience_config.expression.as_deref().map(|expression| {
+                    Expression::parse(expression)
+                        .expect("expressions are validated when loading the configuration")
+                });
+                policy.basic_auth = audience_config
+                    .basic_auth
+                    .as_ref()
+                    .map(|basic_auth| BasicAuth::from_config(basic_auth).map(Arc::new))
+                    .transpose()
+                    .map_err(|e| format!("Basic auth for audience '{}': {}", audience, e))?;
+
+                // Invalid headers are rejected when the configuration is validated.
+                for (header_name, header_value) in &audience_config.static_headers {
+                    if let (Ok(header_name), Ok(header_value)) = (
+                        HeaderName::from_bytes(header_name.as_bytes()),
+                        HeaderValue::from_str(header_value),
+                    ) {
+                        policy.static_headers.insert(header_name, header_value);
+                    }
+                }
+                policy.claim_header_names = audience_config
+                    .claim_header_names
+                    .iter()
+                    .filter_map(|(claim_name, header_name)| {
+                        HeaderName::from_bytes(header_name.as_bytes())
+                            .ok()
+                            .map(|header_name| (claim_name.clone(), header_name))
+                    })
+                    .collect();
+                policy.forwarded_claims = audience_config.forwarded_claims.clone();
+
+                // Invalid templates are rejected when the configuration is validated.
+                policy.header_templates = audience_config
+                    .header_templates
+                    .iter()
+                    .filter_map(|(header_name, template)| {
+                        let header_name = HeaderName::from_bytes(header_name.as_bytes()).ok()?;
+                        let template = Template::parse(template).ok()?;
+                        Some((header_name, template))
+                    })
+                    .collect();
+                policy.jti_replay = audience_config.jti_replay;
+            }
+
+            policy.fingerprint = policy.compute_fingerprint();
+            audiences.insert(audience.to_string(), policy);
+        }
+
+        // Hosts are matched case-insensitively, as they are in general.
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|(host, audience)| (host.to_ascii_lowercase(), audience.clone()))
+            .collect();
+
+        Ok(Self {
+            default,
+            audiences,
+            hosts,
+            restrict_audiences: config.restrict_audiences,
+            version: 0,
+        })
+    }
+
+    /// Gets the version of these policies, which changes every time the policies are replaced.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Gets an iterator over the names of every issuer that some audience accepts tokens from.
+    pub fn issuers(&self) -> impl Iterator<Item = &str> {
+        self.audiences.values().filter_map(AudiencePolicy::issuer)
+    }
+
+    /// Gets the policy for the given audience.
+    ///
+    /// If audiences are restricted to those that are configured, and the given audience is not
+    /// configured, `None` is returned.
+    pub fn for_audience(&self, audience: &str) -> Option<&AudiencePolicy> {
+        match self.audiences.get(audience) {
+            Some(policy) => Some(policy),
+            None if self.restrict_audiences => None,
+            None => Some(&self.default),
+        }
+    }
+
+    /// Gets the label to use for the given audience in metrics.
+    ///
+    /// Only configured audiences are labeled as themselves, so that requests for arbitrary audiences
+    /// can't create an unbounded number of metric series.
+    pub fn audience_label(&self, audience: &str) -> String {
+        if self.audiences.contains_key(audience) {
+            audience.to_string()
+        } else {
+            "unconfigured".to_string()
+        }
+    }
+
+    /// Gets the audience mapped to the given host, if any.
+    ///
+    /// If the host has no mapping of its own, but includes a port, the mapping for the host without
+    /// the port is used.
+    pub fn audience_for_host(&self, host: &str) -> Option<&str> {
+        let host = host.trim().to_ascii_lowercase();
+        self.hosts
+            .get(&host)
+            .or_else(|| {
+                host.rsplit_once(':')
+                    .and_then(|(host, _port)| self.hosts.get(host))
+            })
+            .map(String::as_str)
+    }
+}
+
+/// The audience policies currently in effect.
+///
+/// The policies can be replaced at any time, such as when the configuration file changes, while
+/// requests already being handled keep using the policies they started with.
+pub struct AudiencePolicyStore {
+    policies: ArcSwap<AudiencePolicies>,
+    last_version: AtomicU64,
+}
+
+impl AudiencePolicyStore {
+    pub fn new(policies: AudiencePolicies) -> Self {
+        Self {
+            policies: ArcSwap::from_pointee(policies),
+            last_version: AtomicU64::new(0),
+        }
+    }
+
+    /// Gets the policies currently in effect.
+    pub fn load(&self) -> Arc<AudiencePolicies> {
+        self.policies.load_full()
+    }
+
+    /// Replaces the policies currently in effect.
+    pub fn store(&self, mut policies: AudiencePolicies) {
+        policies.version = self.last_version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.policies.store(Arc::new(policies));
+    }
+}
+
+/// The policy for a single audience.
+#[derive(Clone, Debug)]
+pub struct AudiencePolicy {
+    issuer: Option<String>,
+    missing_token: MissingTokenBehavior,
+    allowed_token_types: TokenTypeSet,
+    allowed_principals: Vec<PrincipalType>,
+    required_groups: Vec<String>,
+    allowed_emails: Vec<String>,
+    allowed_email_domains: Vec<String>,
+    path_rules: Vec<PathRule>,
+    expression: Option<Expression>,
+    basic_auth: Option<Arc<BasicAuth>>,
+    static_headers: HeaderMap,
+    claim_header_names: HashMap<String, HeaderName>,
+    forwarded_claims: ClaimFilterConfig,
+    header_templates: Vec<(HeaderName, Template)>,
+    jti_replay: JtiReplayMode,
+
+    /// A hash of everything above, which is the same for the same policy in any process.
+    fingerprint: [u8; 32],
+}
+
+impl AudiencePolicy {
+    /// Gets the name of the issuer whose tokens are accepted, or `None` for the default issuer.
+    pub fn issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    /// Gets the behavior for requests that carry no access token.
+    pub fn missing_token_behavior(&self) -> MissingTokenBehavior {
+        self.missing_token
+    }
+
+    /// Gets how to build Basic auth credentials for successful validation responses, if at all.
+    pub fn basic_auth(&self) -> Option<&BasicAuth> {
+        self.basic_auth.as_deref()
+    }
+
+    /// Gets the static headers to add to successful validation responses.
+    pub fn static_headers(&self) -> &HeaderMap {
+        &self.static_headers
+    }
+
+    /// Gets the headers to add to successful validation responses, templated from the claims.
+    pub fn header_templates(&self) -> &[(HeaderName, Template)] {
+        &self.header_templates
+    }
+
+    /// Whether or not the given claim may be added as a header.
+    pub fn forwards_claim(&self, claim_name: &str) -> bool {
+        self.forwarded_claims.allows(claim_name)
+    }
+
+    /// Gets the exact header name to add the given claim as, if it's mapped to one.
+    pub fn claim_header_name(&self, claim_name: &str) -> Option<&HeaderName> {
+        self.claim_header_names.get(claim_name)
+    }
+
+    /// Whether or not a token with the given `type` claim is accepted.
+    pub fn allows_token_type(&self, token_type: Option<&str>) -> bool {
+        if self.allowed_token_types.is_empty() {
+            return true;
+        }
+
+        token_type
+            .and_then(TokenType::from_claim)
+            .map_or(false, |token_type| {
+                self.allowed_token_types.contains(token_type)
+            })
+    }
+
+    /// Gets the groups that the principal must be a member of at least one of, if any.
+    pub fn required_groups(&self) -> &[String] {
+        &self.required_groups
+    }
+
+    /// Whether or not a principal that's a member of the given groups is accepted.
+    pub fn allows_groups(&self, groups: &[&str]) -> bool {
+        self.required_groups.is_empty()
+            || self
+                .required_groups
+                .iter()
+                .any(|required| groups.contains(&required.as_str()))
+    }
+
+    /// Whether or not only specific emails, or email domains, are accepted.
+    pub fn restricts_emails(&self) -> bool {
+        !self.allowed_emails.is_empty() || !self.allowed_email_domains.is_empty()
+    }
+
+    /// Whether or not a token with the given email claim is accepted.
+    pub fn allows_email(&self, email: Option<&str>) -> bool {
+        if !self.restricts_emails() {
+            return true;
+        }
+
+        let email = match email {
+            Some(email) => email.to_ascii_lowercase(),
+            None => return false,
+        };
+        if self.allowed_emails.contains(&email) {
+            return true;
+        }
+
+        email.rsplit_once('@').map_or(false, |(_, domain)| {
+            self.allowed_email_domains
+                .iter()
+                .any(|allowed| allowed == domain)
+        })
+    }
+
+    /// Whether or not there are any rules for specific paths.
+    pub fn has_path_rules(&self) -> bool {
+        !self.path_rules.is_empty()
+    }
+
+    /// Gets the first rule matching a request with the given method and normalized path, if any.
+    pub fn path_rule_for(&self, method: Option<&str>, path: &[String]) -> Option<&PathRule> {
+        path_rules::first_match(&self.path_rules, method, path)
+    }
+
+    /// Gets the expression that requests must satisfy, if any.
+    pub fn expression(&self) -> Option<&Expression> {
+        self.expression.as_ref()
+    }
+
+    /// Gets where the `jti` of accepted tokens are recorded, to reject replays, if at all.
+    pub fn jti_replay(&self) -> JtiReplayMode {
+        self.jti_replay
+    }
+
+    /// Whether or not a token issued to the given kind of principal is accepted.
+    pub fn allows_principal(&self, principal: PrincipalType) -> bool {
+        self.allowed_principals.is_empty() || self.allowed_principals.contains(&principal)
+    }
+
+    /// Gets a hash of the policy, which is the same for the same policy in any process, so that
+    /// replicas sharing validations can tell whether they're enforcing the same policy.
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    fn compute_fingerprint(&self) -> [u8; 32] {
+        // Maps are hashed in sorted order, as their iteration order differs between processes.
+        let mut static_headers = self
+            .static_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_bytes()))
+            .collect::<Vec<_>>();
+        static_headers.sort_unstable();
+        let claim_header_names = self
+            .claim_header_names
+            .iter()
+            .map(|(claim_name, header_name)| (claim_name.as_str(), header_name.as_str()))
+            .collect::<BTreeMap<_, _>>();
+        let mut header_templates = self
+            .header_templates
+            .iter()
+            .map(|(header_name, template)| (header_name.as_str(), format!("{:?}", template)))
+            .collect::<Vec<_>>();
+        header_templates.sort_unstable();
+
+        // Basic auth credentials are sensitive, so validations using them are never shared, and
+        // only whether there are any matters.
+        let parts: [&dyn Debug; 15] = [
+            &self.issuer,
+            &self.missing_token,
+            &self.allowed_token_types,
+            &self.allowed_principals,
+            &self.required_groups,
+            &self.allowed_emails,
+            &self.allowed_email_domains,
+            &self.path_rules,
+            &self.expression,
+            &self.basic_auth.is_some(),
+            &static_headers,
+            &claim_header_names,
+            &self.forwarded_claims,
+            &header_templates,
+            &self.jti_replay,
+        ];
+
+        // Each part is length-prefixed, so that no two different sets of parts hash the same.
+        let mut hasher = Sha256::new();
+        for part in parts {
+            let part = format!("{:?}", part);
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// A set of token types, represented as a bitmask.
+#[derive(Clone, Copy, Debug, Default)]
+struct TokenTypeSet(u8);
+
+impl TokenTypeSet {
+    fn bit(token_type: TokenType) -> u8 {
+        match token_type {
+            TokenType::App => 1 << 0,
+            TokenType::Org => 1 << 1,
+        }
+    }
+
+    fn insert(&mut self, token_type: TokenType) {
+        self.0 |= Self::bit(token_type);
+    }
+
+    fn contains(&self, token_type: TokenType) -> bool {
+        self.0 & Self::bit(token_type) != 0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
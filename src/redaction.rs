@@ -0,0 +1,129 @@
+use std::{borrow::Cow, fmt, sync::Arc};
+
+use arc_swap::ArcSwapOption;
+use sha2::{Digest, Sha256};
+
+/// The process-wide redaction rules.
+///
+/// These are consulted whenever a [`ClaimValue`] is formatted, which means that any claim value
+/// that ends up in a log statement is redacted according to the configured rules without the log
+/// statement itself having to do anything.
+static REDACTION_RULES: ArcSwapOption<RedactionRules> = ArcSwapOption::const_empty();
+
+/// Installs the given redaction rules, replacing any existing rules.
+pub fn set_rules(rules: RedactionRules) {
+    REDACTION_RULES.store(Some(Arc::new(rules)));
+}
+
+/// Claim names whose values must never be logged in the clear.
+#[derive(Debug, Default)]
+pub struct RedactionRules {
+    patterns: Vec<String>,
+}
+
+impl RedactionRules {
+    /// Creates a new set of redaction rules from the given claim name patterns.
+    ///
+    /// Patterns are matched against the claim name, and may use `*` as a wildcard for any number of
+    /// characters (e.g. `email`, `*_token`, `secret*`).
+    pub fn from_patterns<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            patterns: patterns
+                .into_iter()
+                .map(Into::into)
+                .filter(|pattern| !pattern.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Whether or not the value of the given claim must be redacted.
+    pub fn is_redacted(&self, claim_name: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| wildcard_match(pattern, claim_name))
+    }
+}
+
+/// The value of a claim.
+///
+/// When formatted via `Display` or `Debug`, the value is replaced by its SHA-256 hash if the claim
+/// matches any of the configured redaction rules. The raw value can only be accessed through
+/// [`ClaimValue::expose`], which should only be used when building the response headers.
+#[derive(Clone)]
+pub struct ClaimValue<'a> {
+    name: Cow<'a, str>,
+    value: Cow<'a, str>,
+}
+
+impl<'a> ClaimValue<'a> {
+    pub fn new(name: impl Into<Cow<'a, str>>, value: impl Into<Cow<'a, str>>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Gets the raw value of the claim.
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    fn is_redacted(&self) -> bool {
+        REDACTION_RULES
+            .load()
+            .as_ref()
+            .map_or(false, |rules| rules.is_redacted(&self.name))
+    }
+}
+
+impl fmt::Display for ClaimValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_redacted() {
+            write!(f, "sha256:{:x}", Sha256::digest(self.value.as_bytes()))
+        } else {
+            f.write_str(&self.value)
+        }
+    }
+}
+
+impl fmt::Debug for ClaimValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Matches `value` against `pattern`, where `*` in the pattern matches any number of characters.
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    // The first part must be a prefix of the value, unless the pattern starts with a wildcard.
+    let first = parts.next().unwrap_or_default();
+    let mut remaining = match value.strip_prefix(first) {
+        Some(remaining) => remaining,
+        None => return false,
+    };
+
+    // If there was no wildcard at all, the pattern must have matched exactly.
+    let mut parts = parts.peekable();
+    if parts.peek().is_none() {
+        return remaining.is_empty();
+    }
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // The last part must be a suffix of whatever is left.
+            return remaining.ends_with(part);
+        }
+
+        match remaining.find(part) {
+            Some(idx) => remaining = &remaining[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
@@ -0,0 +1,196 @@
+use std::{collections::HashMap, convert::Infallible, future::poll_fn, path::Path, sync::Arc};
+
+use axum::Router;
+use hyper::{body::to_bytes, service::Service, Body, Request, Response};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::Config,
+    policy::{AudiencePolicies, AudiencePolicyStore},
+    redaction::{self, RedactionRules},
+    supervisor::TaskStatuses,
+    telemetry,
+    validation::{new_http_client, service_auth::ServiceTokenMapStore, SignatureStates},
+    web::{api_router, ApiState},
+};
+
+/// A validation request recorded in production.
+#[derive(Deserialize)]
+struct RecordedRequest {
+    /// The audience the request was made for, as in `/validate/<aud>`. Without one, the request is
+    /// made to `/validate`, where the audience comes from the `X-Forwarded-Host` header.
+    #[serde(default)]
+    audience: Option<String>,
+
+    /// The headers the request was made with.
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+/// Replays recorded validation requests through the validation pipeline, entirely offline,
+/// returning the decision made for each one.
+///
+/// `requests_file` holds one recorded request per line, as a JSON object such as
+/// `{"audience": "<aud>", "headers": {"cf-access-jwt-assertion": "<token>"}}`, and `jwks_dir` holds
+/// the JWKS data for each issuer, named the same way as for `jwks_fetch.static_dir`. A JWKS cache
+/// directory can be used as-is.
+///
+/// Tokens are still checked against the current time, so recorded tokens that have since expired
+/// are rejected as such.
+pub async fn replay(
+    config: Config,
+    jwks_dir: &Path,
+    requests_file: &Path,
+) -> Result<Vec<Value>, String> {
+    let mut app = offline_router(config, jwks_dir).await?;
+
+    let contents = std::fs::read_to_string(requests_file).map_err(|e| {
+        format!(
+            "Failed to read recorded requests '{}': {}",
+            requests_file.display(),
+            e
+        )
+    })?;
+
+    let mut decisions = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let recorded = serde_json::from_str::<RecordedRequest>(line).map_err(|e| {
+            format!(
+                "Failed to parse recorded request on line {}: {}",
+                line_number, e
+            )
+        })?;
+        let request = build_request(&recorded)
+            .map_err(|e| format!("Invalid recorded request on line {}: {}", line_number, e))?;
+
+        let response = call(&mut app, request).await;
+        decisions.push(decision(line_number, &recorded, response).await);
+    }
+
+    Ok(decisions)
+}
+
+/// Builds the API router for validating requests offline, using the JWKS data in `jwks_dir` rather
+/// than fetching it.
+pub(crate) async fn offline_router(mut config: Config, jwks_dir: &Path) -> Result<Router, String> {
+    // Use the JWKS snapshot in place of fetching over HTTPS, and leave any JWKS cache alone.
+    config.jwks_fetch.static_dir = Some(jwks_dir.to_path_buf());
+    config.jwks_fetch.cache_dir = None;
+    // Nothing is refreshing the JWKS data, so waiting on a refresh would only ever time out.
+    config.jwks_retry_on_unknown_key = false;
+
+    redaction::set_rules(RedactionRules::from_patterns(
+        config.redacted_claims.iter().cloned(),
+    ));
+
+    let issuer_url = config.require_auth_domain()?;
+    let states = SignatureStates::new(
+        issuer_url,
+        &config.issuers,
+        new_http_client(),
+        &config.jwks_fetch,
+    )
+    .map(Arc::new)?;
+    for state in states.iter() {
+        state.refresh().await?;
+    }
+
+    let api_state = ApiState {
+        states,
+        token_map: Arc::new(ServiceTokenMapStore::new(config.load_service_token_map()?)),
+        message_signer: config.load_message_signer()?.map(Arc::new),
+        internal_token: config.load_internal_token()?.map(Arc::new),
+        error_pages: config.load_error_pages()?.map(Arc::new),
+        // Replays are offline, so they can't look up user details or group names.
+        user_enricher: None,
+        identity_enricher: None,
+        group_names: None,
+        // Recorded requests were already checked for replays when they were made, and the same
+        // token is often recorded more than once.
+        jti_tracker: None,
+        admin_token: None,
+        // Recorded requests come from behind the proxies, and the secret is never recorded.
+        proxy_secret: None,
+        // Every recorded request is replayed from scratch.
+        validation_cache: None,
+        anomaly_detector: None,
+        // OPA's decisions depend on its policies and data at the time, so replays only cover the
+        // decisions made here.
+        opa: None,
+        policies: Arc::new(AudiencePolicyStore::new(AudiencePolicies::compile(
+            &config,
+        )?)),
+        // Offline runs may happen more than once per process, so they can't install the global
+        // recorder.
+        metrics_handle: telemetry::detached_recorder_handle(),
+        // Nothing runs in the background while replaying.
+        task_statuses: Arc::new(TaskStatuses::default()),
+        config: Arc::new(config),
+    };
+    Ok(api_router(api_state))
+}
+
+fn build_request(recorded: &RecordedRequest) -> Result<Request<Body>, String> {
+    let uri = match &recorded.audience {
+        Some(audience) => format!("/validate/{}", audience),
+        None => "/validate".to_string(),
+    };
+
+    let mut builder = Request::get(uri);
+    for (name, value) in &recorded.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+
+    builder.body(Body::empty()).map_err(|e| e.to_string())
+}
+
+pub(crate) async fn call(
+    app: &mut Router,
+    request: Request<Body>,
+) -> Response<axum::body::BoxBody> {
+    let result: Result<_, Infallible> = async {
+        poll_fn(|cx| app.poll_ready(cx)).await?;
+        app.call(request).await
+    }
+    .await;
+
+    match result {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+async fn decision(
+    line_number: usize,
+    recorded: &RecordedRequest,
+    response: Response<axum::body::BoxBody>,
+) -> Value {
+    let (parts, body) = response.into_parts();
+
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_string(), Value::String(value))
+        })
+        .collect::<Map<_, _>>();
+    let body = to_bytes(body)
+        .await
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+        .unwrap_or_default();
+
+    json!({
+        "line": line_number,
+        "audience": recorded.audience,
+        "status": parts.status.as_u16(),
+        "headers": headers,
+        "body": body,
+    })
+}
@@ -0,0 +1,219 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use redis::{aio::ConnectionManager, Client, IntoConnectionInfo, RedisResult};
+use tokio::time::{error::Elapsed, timeout};
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::config::RedisConfig;
+
+/// How long to leave Redis alone for once a command has failed, before trying it again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A cache shared between replicas, in Redis, backing the in-memory caches.
+///
+/// Entries found here are copied into the in-memory caches, so each one only costs a round trip the
+/// first time a replica needs it. Redis is only ever an optimization: every command is bounded by
+/// the configured timeout, and once one fails, Redis is left alone for a few seconds, with the
+/// in-memory caches carrying on by themselves, so an outage never fails a request.
+pub struct SharedCache {
+    client: Client,
+    connection: tokio::sync::Mutex<Option<ConnectionManager>>,
+    key_prefix: String,
+    timeout: Duration,
+    max_ttl: Option<Duration>,
+
+    /// When Redis is next worth trying, if a command has failed since the last one that succeeded.
+    retry_at: Mutex<Option<Instant>>,
+}
+
+impl SharedCache {
+    pub fn from_config(config: &RedisConfig) -> Result<Self, String> {
+        let mut connection_info = config
+            .url
+            .as_str()
+            .into_connection_info()
+            .map_err(|e| format!("Invalid Redis URL: {}", e))?;
+
+        if let Some(password_file) = &config.password_file {
+            let contents = std::fs::read_to_string(password_file)
+                .map(Zeroizing::new)
+                .map_err(|e| {
+                    format!(
+                        "Failed to read Redis password file '{}': {}",
+                        password_file.display(),
+                        e
+                    )
+                })?;
+            connection_info.redis.password = Some(contents.trim().to_string());
+        }
+
+        // Connecting waits for the first command, so Redis being down doesn't hold up startup.
+        let client = Client::open(connection_info)
+            .map_err(|e| format!("Failed to create Redis client: {}", e))?;
+
+        Ok(Self {
+            client,
+            connection: tokio::sync::Mutex::new(None),
+            key_prefix: config.key_prefix.clone(),
+            timeout: config.timeout(),
+            max_ttl: config.max_ttl(),
+            retry_at: Mutex::new(None),
+        })
+    }
+
+    /// Gets the key for the entry in the given namespace identified by the given hash.
+    ///
+    /// Keys are built from hashes, rather than the tokens or emails they identify entries by, so
+    /// none of those are ever stored in Redis.
+    pub fn key(&self, namespace: &str, hash: &[u8]) -> String {
+        let hash = hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        format!("{}{}:{}", self.key_prefix, namespace, hash)
+    }
+
+    /// Gets the values of the given keys, each of which is `None` if the key isn't set.
+    ///
+    /// Returns `None` if Redis is unavailable.
+    pub async fn get(&self, keys: &[String]) -> Option<Vec<Option<Vec<u8>>>> {
+        let mut connection = self.connection().await?;
+        let mut command = redis::cmd("MGET");
+        command.arg(keys);
+        self.outcome(timeout(self.timeout, command.query_async(&mut connection)).await)
+    }
+
+    /// Sets the given key to the given value, for the given TTL, or the configured maximum TTL if
+    /// that's shorter.
+    ///
+    /// This happens in the background, so nothing waits on Redis for it.
+    pub fn set(self: &Arc<Self>, key: String, value: Vec<u8>, ttl: Duration) {
+        let ttl_ms = self.ttl(ttl).as_millis() as u64;
+        if ttl_ms == 0 {
+            return;
+        }
+
+        let shared_cache = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Some(mut connection) = shared_cache.connection().await {
+                let mut command = redis::cmd("SET");
+                command.arg(key).arg(value).arg("PX").arg(ttl_ms);
+                let set = command.query_async::<_, ()>(&mut connection);
+                shared_cache.outcome(timeout(shared_cache.timeout, set).await);
+            }
+        });
+    }
+
+    /// Sets the given key for the given TTL, unless it's already set, returning whether it was.
+    ///
+    /// Unlike [`set`](Self::set), this waits on Redis, and the TTL isn't capped, since the key
+    /// records something rather than caching it. Returns `None` if Redis is unavailable.
+    pub async fn set_if_absent(&self, key: String, ttl: Duration) -> Option<bool> {
+        let ttl_ms = (ttl.as_millis() as u64).max(1);
+        let mut connection = self.connection().await?;
+        let mut command = redis::cmd("SET");
+        command.arg(key).arg(1).arg("NX").arg("PX").arg(ttl_ms);
+        let set = command.query_async::<_, Option<String>>(&mut connection);
+        self.outcome(timeout(self.timeout, set).await)
+            .map(|set| set.is_some())
+    }
+
+    /// Gets how long an entry cached for the given TTL is shared for.
+    pub fn ttl(&self, ttl: Duration) -> Duration {
+        self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl))
+    }
+
+    /// Gets the headers stored under the given key, if there are any.
+    pub async fn get_headers(&self, key: String) -> Option<HeaderMap> {
+        let value = self.get(&[key]).await?.pop()??;
+        let pairs = serde_json::from_slice(&value).ok()?;
+        headers_from_pairs(pairs)
+    }
+
+    /// Stores the given headers under the given key, for the given TTL, in the background.
+    pub fn set_headers(self: &Arc<Self>, key: String, headers: &HeaderMap, ttl: Duration) {
+        let value = header_pairs(headers).and_then(|pairs| serde_json::to_vec(&pairs).ok());
+        if let Some(value) = value {
+            self.set(key, value, ttl);
+        }
+    }
+
+    /// Gets a connection to Redis, connecting first if there isn't one yet.
+    ///
+    /// Returns `None` if Redis is being left alone after a failure, or can't be connected to.
+    async fn connection(&self) -> Option<ConnectionManager> {
+        let retry_at = *self.retry_at.lock().unwrap_or_else(|e| e.into_inner());
+        if retry_at.map_or(false, |retry_at| Instant::now() < retry_at) {
+            return None;
+        }
+
+        // The connection manager reconnects by itself, so there's only ever one.
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Some(connection.clone());
+        }
+
+        let new_connection = ConnectionManager::new(self.client.clone());
+        let new_connection = self.outcome(timeout(self.timeout, new_connection).await)?;
+        *connection = Some(new_connection.clone());
+        Some(new_connection)
+    }
+
+    /// Takes the outcome of a Redis command, noting whether Redis is available.
+    fn outcome<T>(&self, result: Result<RedisResult<T>, Elapsed>) -> Option<T> {
+        let error = match result {
+            Ok(Ok(value)) => {
+                let mut retry_at = self.retry_at.lock().unwrap_or_else(|e| e.into_inner());
+                if retry_at.take().is_some() {
+                    info!("Redis is available again.");
+                }
+                return Some(value);
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+
+        // Only the first failure is logged, rather than every one until Redis is back.
+        let mut retry_at = self.retry_at.lock().unwrap_or_else(|e| e.into_inner());
+        if retry_at.is_none() {
+            warn!(
+                error = error.as_str(),
+                "Redis is unavailable. Carrying on with the in-memory caches alone."
+            );
+        }
+        *retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        None
+    }
+}
+
+/// Gets the names and values of the given headers, for storing in Redis.
+///
+/// Returns `None` if any of the values aren't visible ASCII, since those can't be stored as strings.
+pub fn header_pairs(headers: &HeaderMap) -> Option<Vec<(String, String)>> {
+    headers
+        .iter()
+        .map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// Builds headers from the names and values stored in Redis.
+///
+/// Returns `None` if any of them aren't valid, which they always are if they came from
+/// [`header_pairs`].
+pub fn headers_from_pairs(pairs: Vec<(String, String)>) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::with_capacity(pairs.len());
+    for (name, value) in pairs {
+        let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+        let value = HeaderValue::from_str(&value).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
@@ -0,0 +1,430 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{header::HeaderName, HeaderMap, HeaderValue};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::config::{MessageSignatureConfig, MessageSignatureFormat};
+
+/// The label that the signature is given in the `Signature-Input` and `Signature` headers.
+const SIGNATURE_LABEL: &str = "forwardauth";
+
+/// The smallest key we accept, in bytes, matching the output size of SHA-256.
+const MIN_KEY_LEN: usize = 32;
+
+/// Signs response headers with HMAC-SHA256, either using HTTP Message Signatures (RFC 9421), or in
+/// a single `X-Auth-Signature` header.
+///
+/// This lets upstream applications verify that the identity headers they receive were set by us,
+/// and weren't added or changed along the way, rather than having to trust that the proxy stripped
+/// any spoofed ones.
+///
+/// While keys are being rotated, every response is signed with the previous keys as well as the
+/// current one, so upstreams can move over to the current key at their own pace.
+pub struct MessageSigner {
+    keys: Vec<(String, Zeroizing<Vec<u8>>)>,
+    headers: Vec<HeaderName>,
+    format: MessageSignatureFormat,
+}
+
+impl MessageSigner {
+    /// Creates a signer from the given configuration, loading the keys from their key files.
+    ///
+    /// The key files must contain the keys, base64-encoded.
+    pub fn from_config(config: &MessageSignatureConfig) -> Result<Self, String> {
+        let mut keys = vec![(config.key_id.clone(), load_key(&config.key_file)?)];
+        for previous_key in &config.previous_keys {
+            keys.push((
+                previous_key.key_id.clone(),
+                load_key(&previous_key.key_file)?,
+            ));
+        }
+
+        let headers = config
+            .headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("Signed header '{}' is not a valid header name.", name))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            keys,
+            headers,
+            format: config.format,
+        })
+    }
+
+    /// Signs the given response headers, adding the signature headers for the configured format.
+    ///
+    /// Only the configured headers that are actually present are covered by the signature, as
+    /// RFC 9421 doesn't allow covering absent headers. The signature parameters list exactly which
+    /// ones are covered.
+    pub fn sign(&self, headers: &mut HeaderMap) {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        let covered = self
+            .headers
+            .iter()
+            .filter(|name| headers.contains_key(*name))
+            .map(HeaderName::as_str)
+            .collect::<Vec<_>>();
+
+        let signature_headers = match self.format {
+            MessageSignatureFormat::Rfc9421 => self.rfc9421_headers(headers, &covered, created),
+            MessageSignatureFormat::XAuthSignature => {
+                self.x_auth_signature_headers(headers, &covered, created)
+            }
+        };
+        for (header_name, header_value) in signature_headers {
+            if let Ok(header_value) = HeaderValue::from_str(&header_value) {
+                headers.insert(HeaderName::from_static(header_name), header_value);
+            }
+        }
+    }
+
+    /// Builds the `Signature-Input` and `Signature` headers, with a signature for each key.
+    ///
+    /// The signature with the current key is labeled `forwardauth`, and those with previous keys
+    /// are labeled `forwardauth-1`, `forwardauth-2`, and so on, in the order they're configured.
+    fn rfc9421_headers(
+        &self,
+        headers: &HeaderMap,
+        covered: &[&str],
+        created: u64,
+    ) -> Vec<(&'static str, String)> {
+        let covered = covered
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect::<Vec<_>>();
+
+        let mut signature_inputs = Vec::with_capacity(self.keys.len());
+        let mut signatures = Vec::with_capacity(self.keys.len());
+        for (i, (key_id, key)) in self.keys.iter().enumerate() {
+            let label = match i {
+                0 => SIGNATURE_LABEL.to_string(),
+                i => format!("{}-{}", SIGNATURE_LABEL, i),
+            };
+            let signature_params = format!(
+                "({});created={};keyid=\"{}\";alg=\"hmac-sha256\"",
+                covered.join(" "),
+                created,
+                key_id
+            );
+
+            let signature = hmac_sha256(
+                key,
+                &signature_base(&self.headers, headers, &signature_params),
+            );
+            signature_inputs.push(format!("{}={}", label, signature_params));
+            signatures.push(format!("{}=:{}:", label, base64::encode(signature)));
+        }
+
+        vec![
+            ("signature-input", signature_inputs.join(", ")),
+            ("signature", signatures.join(", ")),
+        ]
+    }
+
+    /// Builds the `X-Auth-Signature` header, with a signature for each key.
+    ///
+    /// Each signature looks like `keyid="..."; t=<created>; h="<header> ..."; sig="<base64>"`, and
+    /// they're separated by commas. The signed message is a `<header>: <value>` line for each
+    /// covered header, in the order they're listed in, followed by a `t: <created>` line, without
+    /// a trailing newline.
+    fn x_auth_signature_headers(
+        &self,
+        headers: &HeaderMap,
+        covered: &[&str],
+        created: u64,
+    ) -> Vec<(&'static str, String)> {
+        let mut message = Vec::new();
+        for name in covered {
+            message.extend_from_slice(format!("{}: ", name).as_bytes());
+            for (i, value) in headers.get_all(*name).iter().enumerate() {
+                if i > 0 {
+                    message.extend_from_slice(b", ");
+                }
+                message.extend_from_slice(trim_ascii_whitespace(value.as_bytes()));
+            }
+            message.push(b'\n');
+        }
+        message.extend_from_slice(format!("t: {}", created).as_bytes());
+
+        let signatures = self
+            .keys
+            .iter()
+            .map(|(key_id, key)| {
+                format!(
+                    "keyid=\"{}\"; t={}; h=\"{}\"; sig=\"{}\"",
+                    key_id,
+                    created,
+                    covered.join(" "),
+                    base64::encode(hmac_sha256(key, &message))
+                )
+            })
+            .collect::<Vec<_>>();
+
+        vec![("x-auth-signature", signatures.join(", "))]
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Builds the signature base (RFC 9421, section 2.5) covering whichever of `names` are present in
+/// `headers`.
+fn signature_base(names: &[HeaderName], headers: &HeaderMap, signature_params: &str) -> Vec<u8> {
+    let mut base = Vec::new();
+    for name in names {
+        let mut values = headers.get_all(name).iter().peekable();
+        if values.peek().is_none() {
+            continue;
+        }
+
+        base.extend_from_slice(format!("\"{}\": ", name.as_str()).as_bytes());
+        for (i, value) in values.enumerate() {
+            if i > 0 {
+                base.extend_from_slice(b", ");
+            }
+            base.extend_from_slice(trim_ascii_whitespace(value.as_bytes()));
+        }
+        base.push(b'\n');
+    }
+
+    base.extend_from_slice(b"\"@signature-params\": ");
+    base.extend_from_slice(signature_params.as_bytes());
+    base
+}
+
+fn trim_ascii_whitespace(mut value: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = value {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        value = rest;
+    }
+    while let [rest @ .., last] = value {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        value = rest;
+    }
+    value
+}
+
+fn load_key(path: &Path) -> Result<Zeroizing<Vec<u8>>, String> {
+    let encoded = std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            format!(
+                "Failed to read signing key file '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+    let key = base64::decode(encoded.trim())
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            format!(
+                "Signing key file '{}' is not valid base64: {}",
+                path.display(),
+                e
+            )
+        })?;
+
+    if key.len() < MIN_KEY_LEN {
+        return Err(format!(
+            "Signing key in '{}' must be at least {} bytes long.",
+            path.display(),
+            MIN_KEY_LEN
+        ));
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// A signer with a current and a previous key, covering a few identity headers.
+    fn signer(format: MessageSignatureFormat) -> MessageSigner {
+        MessageSigner {
+            keys: vec![
+                ("current".to_string(), Zeroizing::new(vec![0x01; 32])),
+                ("previous".to_string(), Zeroizing::new(vec![0x02; 32])),
+            ],
+            headers: [
+                "x-auth-request-user",
+                "x-auth-request-email",
+                "x-auth-groups",
+                "x-auth-missing",
+            ]
+            .into_iter()
+            .map(HeaderName::from_static)
+            .collect(),
+            format,
+        }
+    }
+
+    fn identity_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-auth-request-user", HeaderValue::from_static("user-1"));
+        headers.insert(
+            "x-auth-request-email",
+            HeaderValue::from_static(" alice@corp.com "),
+        );
+        headers.append("x-auth-groups", HeaderValue::from_static("sre"));
+        headers.append("x-auth-groups", HeaderValue::from_static("dev"));
+        headers.insert("x-unsigned", HeaderValue::from_static("anything"));
+        headers
+    }
+
+    /// RFC 4231, test cases 1, 2 and 6.
+    #[test]
+    fn hmac_sha256_test_vectors() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hex(&hmac_sha256(key, message)), expected);
+        }
+    }
+
+    #[test]
+    fn x_auth_signature_known_value() {
+        let signer = signer(MessageSignatureFormat::XAuthSignature);
+        let headers = identity_headers();
+        let covered = [
+            "x-auth-request-user",
+            "x-auth-request-email",
+            "x-auth-groups",
+        ];
+
+        let signature_headers = signer.x_auth_signature_headers(&headers, &covered, 1700000000);
+        assert_eq!(
+            signature_headers,
+            vec![(
+                "x-auth-signature",
+                "keyid=\"current\"; t=1700000000; \
+                 h=\"x-auth-request-user x-auth-request-email x-auth-groups\"; \
+                 sig=\"c2gqdNz7I8mB1b8e9hq0kPkdlfsQkL+UgoZkIQt4t1w=\", \
+                 keyid=\"previous\"; t=1700000000; \
+                 h=\"x-auth-request-user x-auth-request-email x-auth-groups\"; \
+                 sig=\"6U9sSvo+j3iWa/qWzmtWZV00bXco7VIPflJWQM4pVbw=\""
+                    .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn sign_covers_only_present_headers() {
+        let signer = signer(MessageSignatureFormat::XAuthSignature);
+        let mut headers = identity_headers();
+        signer.sign(&mut headers);
+
+        let signature = headers["x-auth-signature"].to_str().unwrap();
+        assert!(signature.starts_with("keyid=\"current\"; t="));
+        assert!(signature
+            .contains("; h=\"x-auth-request-user x-auth-request-email x-auth-groups\"; sig=\""));
+        assert!(!signature.contains("x-auth-missing"));
+        assert!(!signature.contains("x-unsigned"));
+    }
+
+    /// RFC 9421, appendix B.2.5, which signs a request with `hmac-sha256`.
+    #[test]
+    fn rfc9421_test_vector() {
+        let key = base64::decode(concat!(
+            "uzvJfB4u3N0Jy4T7NZ75MDVcr8zSTInedJtkgcu46YW4XByzNJjxBdtj",
+            "UkdJPBtbmHhIDi6pcl8jsasjlTMtDQ==",
+        ))
+        .unwrap();
+        let signature_base = "\"date\": Tue, 20 Apr 2021 02:07:55 GMT\n\
+                              \"@authority\": example.com\n\
+                              \"content-type\": application/json\n\
+                              \"@signature-params\": (\"date\" \"@authority\" \"content-type\")\
+                              ;created=1618884473;keyid=\"test-shared-secret\"";
+
+        assert_eq!(
+            base64::encode(hmac_sha256(&key, signature_base.as_bytes())),
+            "pxcQw6G3AjtMBQjwo8XzkZf/bws5LelbaMk5rGIGtE8="
+        );
+    }
+
+    #[test]
+    fn rfc9421_known_value() {
+        let signer = signer(MessageSignatureFormat::Rfc9421);
+        let headers = identity_headers();
+        let covered = [
+            "x-auth-request-user",
+            "x-auth-request-email",
+            "x-auth-groups",
+        ];
+
+        let signature_params =
+            "(\"x-auth-request-user\" \"x-auth-request-email\" \"x-auth-groups\")\
+                                ;created=1700000000;keyid=\"current\";alg=\"hmac-sha256\"";
+        assert_eq!(
+            signature_base(&signer.headers, &headers, signature_params),
+            format!(
+                "\"x-auth-request-user\": user-1\n\
+                 \"x-auth-request-email\": alice@corp.com\n\
+                 \"x-auth-groups\": sre, dev\n\
+                 \"@signature-params\": {}",
+                signature_params
+            )
+            .into_bytes()
+        );
+
+        let signature_headers = signer.rfc9421_headers(&headers, &covered, 1700000000);
+        let previous_params = signature_params.replace("\"current\"", "\"previous\"");
+        assert_eq!(
+            signature_headers,
+            vec![
+                (
+                    "signature-input",
+                    format!(
+                        "forwardauth={}, forwardauth-1={}",
+                        signature_params, previous_params
+                    )
+                ),
+                (
+                    "signature",
+                    "forwardauth=:agl18r6So0XQ1whbXkw8sPgjdbaZXR80EPKy3yYlSE8=:, \
+                     forwardauth-1=:jKAHwrUgwUREbEOa9hJV+fQ0cbA6LJDJsSk6PstchGs=:"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::telemetry;
+
+/// How long to wait before restarting a task that panicked for the first time.
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest to wait before restarting a task that keeps panicking.
+///
+/// A task that ran for at least this long before panicking is restarted after the initial delay
+/// again, since it was evidently healthy for a while.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// The state of a supervised task.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TaskState {
+    /// The task is running.
+    Running,
+
+    /// The task panicked, and is waiting to be restarted.
+    Restarting,
+
+    /// The task finished on its own, such as when it has nothing to do with the current
+    /// configuration, and won't be restarted.
+    Stopped,
+}
+
+impl TaskState {
+    /// Gets a short, stable name for the state.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Restarting => "restarting",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
+/// The status of a supervised task.
+#[derive(Clone, Copy, Debug)]
+pub struct TaskStatus {
+    pub state: TaskState,
+
+    /// How many times the task has been restarted after panicking.
+    pub restarts: u64,
+}
+
+/// The status of every supervised task, by name.
+#[derive(Debug, Default)]
+pub struct TaskStatuses(Mutex<BTreeMap<String, TaskStatus>>);
+
+impl TaskStatuses {
+    /// Gets the current status of every task.
+    pub fn snapshot(&self) -> BTreeMap<String, TaskStatus> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Whether or not any task is waiting to be restarted after panicking.
+    pub fn any_restarting(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .any(|status| status.state == TaskState::Restarting)
+    }
+
+    fn set_state(&self, name: &str, state: TaskState) {
+        let mut statuses = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let status = statuses
+            .entry(name.to_string())
+            .or_insert(TaskStatus { state, restarts: 0 });
+        status.state = state;
+    }
+
+    fn record_restart(&self, name: &str) {
+        let mut statuses = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(status) = statuses.get_mut(name) {
+            status.restarts += 1;
+        }
+    }
+}
+
+/// Runs the background tasks of the service, restarting any that panic, and stopping all of them
+/// together on shutdown.
+///
+/// Tasks are given as functions that start them, so that they can be started again from scratch
+/// after a panic.
+pub struct Supervisor {
+    statuses: Arc<TaskStatuses>,
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+    monitors: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Self {
+            statuses: Arc::new(TaskStatuses::default()),
+            shutdown_tx,
+            shutdown_rx,
+            monitors: Vec::new(),
+        }
+    }
+
+    /// Gets the status of every supervised task, which stays up to date as the tasks run.
+    pub fn statuses(&self) -> Arc<TaskStatuses> {
+        Arc::clone(&self.statuses)
+    }
+
+    /// Starts the task with the given name, restarting it whenever it panics until shutdown.
+    pub fn spawn<F, Fut>(&mut self, name: impl Into<String>, start: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        self.statuses.set_state(&name, TaskState::Running);
+
+        let monitor = supervise(
+            name,
+            start,
+            Arc::clone(&self.statuses),
+            self.shutdown_rx.clone(),
+        );
+        self.monitors.push(tokio::spawn(monitor));
+    }
+
+    /// Stops every supervised task, waiting up to `grace_period` for them to be stopped.
+    pub async fn shutdown(self, grace_period: Duration) {
+        let _ = self.shutdown_tx.send(true);
+
+        let stopped = async {
+            for monitor in self.monitors {
+                let _ = monitor.await;
+            }
+        };
+        if timeout(grace_period, stopped).await.is_err() {
+            warn!("Timed out waiting for background tasks to stop.");
+        }
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn supervise<F, Fut>(
+    name: String,
+    start: F,
+    statuses: Arc<TaskStatuses>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut restart_delay = INITIAL_RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+        let mut task = tokio::spawn(start());
+
+        let result = tokio::select! {
+            result = &mut task => result,
+            _ = shutdown.changed() => {
+                task.abort();
+                let _ = task.await;
+                debug!(task = name.as_str(), "Stopped background task.");
+                return;
+            }
+        };
+
+        let panic = match result {
+            Ok(()) => {
+                info!(task = name.as_str(), "Background task finished.");
+                statuses.set_state(&name, TaskState::Stopped);
+                return;
+            }
+            Err(e) if e.is_panic() => e.into_panic(),
+            // The task is only ever cancelled on shutdown, which is handled above.
+            Err(_) => return,
+        };
+
+        if started.elapsed() >= MAX_RESTART_DELAY {
+            restart_delay = INITIAL_RESTART_DELAY;
+        }
+
+        error!(
+            task = name.as_str(),
+            panic = panic_message(panic.as_ref()),
+            restart_delay_secs = restart_delay.as_secs(),
+            "Background task panicked. Restarting it."
+        );
+        statuses.set_state(&name, TaskState::Restarting);
+
+        tokio::select! {
+            _ = sleep(restart_delay) => {}
+            _ = shutdown.changed() => return,
+        }
+
+        restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+        statuses.record_restart(&name);
+        statuses.set_state(&name, TaskState::Running);
+        telemetry::record_task_restart(&name);
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown"
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram,
+    increment_counter, Unit,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::gc::SweepOutcome;
+
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod privacy;
+
+const REQUESTS_TOTAL: &str = "forwardauth_requests_total";
+const REQUEST_DURATION_SECONDS: &str = "forwardauth_request_duration_seconds";
+const VALIDATIONS_TOTAL: &str = "forwardauth_validations_total";
+const CREDENTIALS_TOTAL: &str = "forwardauth_credentials_total";
+const JWKS_REFRESHES_TOTAL: &str = "forwardauth_jwks_refreshes_total";
+const JWKS_CONSECUTIVE_FAILURES: &str = "forwardauth_jwks_consecutive_failures";
+const CACHE_ENTRIES: &str = "forwardauth_cache_entries";
+const CACHE_MEMORY_BYTES: &str = "forwardauth_cache_memory_bytes";
+const CACHE_EVICTIONS_TOTAL: &str = "forwardauth_cache_evictions_total";
+const CACHE_LOOKUPS_TOTAL: &str = "forwardauth_cache_lookups_total";
+const TASK_RESTARTS_TOTAL: &str = "forwardauth_task_restarts_total";
+
+/// Histogram buckets for request durations, in seconds.
+///
+/// Validation is CPU-bound and typically takes well under a millisecond, so the buckets are skewed
+/// heavily towards the low end.
+const DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+];
+
+/// Creates a metrics handle that isn't backed by the global recorder.
+///
+/// Nothing recorded ends up in it, which suits running the API without serving metrics.
+pub fn detached_recorder_handle() -> PrometheusHandle {
+    PrometheusBuilder::new().build_recorder().handle()
+}
+
+/// Installs the global metrics recorder, returning a handle for rendering the metrics in the
+/// Prometheus exposition format.
+pub fn install_recorder() -> Result<PrometheusHandle, String> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION_SECONDS.to_string()),
+            DURATION_BUCKETS,
+        )
+        .and_then(PrometheusBuilder::install_recorder)
+        .map_err(|e| format!("Failed to install metrics recorder: {}", e))?;
+
+    describe_counter!(REQUESTS_TOTAL, "Requests handled, by route and status.");
+    describe_histogram!(
+        REQUEST_DURATION_SECONDS,
+        Unit::Seconds,
+        "Time spent handling requests, by route."
+    );
+    describe_counter!(
+        VALIDATIONS_TOTAL,
+        "Validation requests, by audience and outcome."
+    );
+    describe_counter!(
+        CREDENTIALS_TOTAL,
+        "Credentials checked during validation, by source and outcome."
+    );
+    describe_counter!(
+        JWKS_REFRESHES_TOTAL,
+        "JWKS refreshes, by issuer and outcome."
+    );
+    describe_gauge!(
+        JWKS_CONSECUTIVE_FAILURES,
+        "JWKS refreshes that have failed in a row, by issuer."
+    );
+    describe_gauge!(
+        CACHE_ENTRIES,
+        "Entries in each in-memory cache, as of its last sweep."
+    );
+    describe_gauge!(
+        CACHE_MEMORY_BYTES,
+        Unit::Bytes,
+        "Approximate memory used by each in-memory cache, as of its last sweep."
+    );
+    describe_counter!(
+        CACHE_EVICTIONS_TOTAL,
+        "Entries removed from each in-memory cache, by reason."
+    );
+    describe_counter!(
+        CACHE_LOOKUPS_TOTAL,
+        "Lookups in each cache, by whether they were a hit or a miss."
+    );
+    describe_counter!(
+        TASK_RESTARTS_TOTAL,
+        "Background tasks restarted after panicking, by task."
+    );
+
+    Ok(handle)
+}
+
+/// Renders the metrics in the Prometheus exposition format.
+pub fn render(handle: &PrometheusHandle) -> String {
+    privacy::suppress_low_counts(handle.render())
+}
+
+/// Records a handled request.
+pub fn record_request(route: &str, status: u16, duration: Duration) {
+    let labels = privacy::labels([("route", route.to_string()), ("status", status.to_string())]);
+    increment_counter!(REQUESTS_TOTAL, labels);
+    let labels = privacy::labels([("route", route.to_string())]);
+    histogram!(REQUEST_DURATION_SECONDS, duration, labels);
+}
+
+/// Records the outcome of a validation request.
+pub fn record_validation(audience_label: String, outcome: &'static str) {
+    let labels = privacy::labels([
+        ("audience", audience_label),
+        ("outcome", outcome.to_string()),
+    ]);
+    increment_counter!(VALIDATIONS_TOTAL, labels);
+}
+
+/// Records the outcome of checking a single credential presented with a validation request.
+pub fn record_credential(source: &'static str, outcome: &'static str) {
+    let labels = privacy::labels([
+        ("source", source.to_string()),
+        ("outcome", outcome.to_string()),
+    ]);
+    increment_counter!(CREDENTIALS_TOTAL, labels);
+}
+
+/// Records the outcome of a JWKS refresh, along with how many refreshes have now failed in a row.
+pub fn record_jwks_refresh(issuer: &str, success: bool, consecutive_failures: u32) {
+    let outcome = if success { "success" } else { "failure" };
+    let labels = privacy::labels([
+        ("issuer", issuer.to_string()),
+        ("outcome", outcome.to_string()),
+    ]);
+    increment_counter!(JWKS_REFRESHES_TOTAL, labels);
+    let labels = privacy::labels([("issuer", issuer.to_string())]);
+    gauge!(
+        JWKS_CONSECUTIVE_FAILURES,
+        f64::from(consecutive_failures),
+        labels
+    );
+}
+
+/// Records the state of a cache after a sweep, along with the entries the sweep removed.
+pub fn record_cache_sweep(cache: &'static str, outcome: &SweepOutcome) {
+    let labels = privacy::labels([("cache", cache.to_string())]);
+    gauge!(CACHE_ENTRIES, outcome.entries as f64, labels.clone());
+    gauge!(CACHE_MEMORY_BYTES, outcome.memory_bytes as f64, labels);
+    let labels = privacy::labels([
+        ("cache", cache.to_string()),
+        ("reason", "expired".to_string()),
+    ]);
+    counter!(CACHE_EVICTIONS_TOTAL, outcome.expired, labels);
+    let labels = privacy::labels([
+        ("cache", cache.to_string()),
+        ("reason", "entries".to_string()),
+    ]);
+    counter!(CACHE_EVICTIONS_TOTAL, outcome.evicted_for_entries, labels);
+    let labels = privacy::labels([
+        ("cache", cache.to_string()),
+        ("reason", "memory".to_string()),
+    ]);
+    counter!(CACHE_EVICTIONS_TOTAL, outcome.evicted_for_memory, labels);
+}
+
+/// Records an entry evicted from a cache as soon as it grew past the given limit, `entries` or
+/// `memory`, rather than by a sweep.
+pub fn record_cache_eviction(cache: &'static str, reason: &'static str) {
+    let labels = privacy::labels([("cache", cache.to_string()), ("reason", reason.to_string())]);
+    increment_counter!(CACHE_EVICTIONS_TOTAL, labels);
+}
+
+/// Records a lookup in a cache, and whether it was a hit or a miss.
+///
+/// Lookups in Redis are recorded under the name of the in-memory cache it backs, prefixed with
+/// `redis_`.
+pub fn record_cache_lookup(cache: &'static str, hit: bool) {
+    let outcome = if hit { "hit" } else { "miss" };
+    let labels = privacy::labels([
+        ("cache", cache.to_string()),
+        ("outcome", outcome.to_string()),
+    ]);
+    increment_counter!(CACHE_LOOKUPS_TOTAL, labels);
+}
+
+/// Records a background task being restarted after panicking.
+pub fn record_task_restart(task: &str) {
+    let labels = privacy::labels([("task", task.to_string())]);
+    increment_counter!(TASK_RESTARTS_TOTAL, labels);
+}
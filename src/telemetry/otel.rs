@@ -0,0 +1,76 @@
+use hyper::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    runtime::TokioCurrentThread,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The environment variable that enables exporting spans, by setting the OTLP endpoint to export them
+/// to.
+const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Creates the tracing layer that exports spans over OTLP, if an OTLP endpoint is configured.
+///
+/// This has to happen before the configuration is loaded, since it's part of setting up logging, so
+/// it's configured via the standard OpenTelemetry environment variables instead.
+pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, trace::Tracer>>, String>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = match std::env::var(OTLP_ENDPOINT_ENV) {
+        Ok(endpoint) => endpoint,
+        Err(_) => return Ok(None),
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .install_batch(TokioCurrentThread)
+        .map_err(|e| format!("Failed to install OTLP exporter: {}", e))?;
+
+    // Join exported spans to the traces of the requests that the proxy forwards to us.
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Makes the given span a child of the remote span described by the W3C trace context headers in
+/// `headers`, if there is one.
+pub fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Flushes any spans that haven't been exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use arc_swap::ArcSwapOption;
+use metrics::Label;
+use sha2::{Digest, Sha256};
+
+/// The labels that are kept in private mode.
+///
+/// Any other label is dropped, so that a label added later can't end up exported in private mode
+/// without having been considered here first.
+const PRIVATE_LABELS: &[&str] = &[
+    "audience", "cache", "issuer", "outcome", "reason", "route", "source", "status", "task",
+];
+
+/// The labels whose values are only ever exported as hashes in private mode, since they name the
+/// organizations and applications involved. Every other kept label has a fixed set of values.
+const HASHED_LABELS: &[&str] = &["audience", "issuer", "task"];
+
+/// How many hex digits of each hashed label value are kept.
+const HASH_LEN: usize = 16;
+
+/// The process-wide private metrics settings, if private mode is enabled.
+///
+/// These are applied to every label recorded, and to every render of the metrics, so that none of
+/// the code recording metrics has to do anything to comply.
+static PRIVATE_METRICS: ArcSwapOption<PrivateMetrics> = ArcSwapOption::const_empty();
+
+/// Settings for exporting metrics in private mode.
+struct PrivateMetrics {
+    min_count: u64,
+}
+
+/// Enables private mode, where metrics carry no identifying labels, and counts below `min_count`
+/// are left out entirely.
+pub fn enable(min_count: u64) {
+    PRIVATE_METRICS.store(Some(Arc::new(PrivateMetrics { min_count })));
+}
+
+/// Builds the labels for a metric, applying private mode if it's enabled.
+pub(super) fn labels<const N: usize>(labels: [(&'static str, String); N]) -> Vec<Label> {
+    let private = PRIVATE_METRICS.load().is_some();
+    labels
+        .into_iter()
+        .filter(|(key, _)| !private || PRIVATE_LABELS.contains(key))
+        .map(|(key, value)| {
+            if private && HASHED_LABELS.contains(&key) {
+                Label::new(key, hash(&value))
+            } else {
+                Label::new(key, value)
+            }
+        })
+        .collect()
+}
+
+/// Leaves out any series counting fewer than the minimum count from rendered metrics, if private
+/// mode is enabled.
+///
+/// Counters below the minimum are left out, as are histograms and summaries whose count is, along
+/// with all of their buckets or quantiles. Gauges don't count anything, so they're always kept.
+pub(super) fn suppress_low_counts(rendered: String) -> String {
+    let min_count = match PRIVATE_METRICS.load().as_deref() {
+        Some(private) => private.min_count as f64,
+        None => return rendered,
+    };
+
+    // The exposition format declares the type of every metric before its samples.
+    let types = rendered
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.split_once(' '))
+        .collect::<HashMap<_, _>>();
+
+    // Histograms and summaries are suppressed as a whole, based on their count.
+    let suppressed = rendered
+        .lines()
+        .filter_map(parse_sample)
+        .filter(|sample| sample.value < min_count)
+        .filter_map(|sample| {
+            let family = sample.name.strip_suffix("_count")?;
+            matches!(types.get(family), Some(&"histogram") | Some(&"summary"))
+                .then_some((family, sample.series))
+        })
+        .collect::<HashSet<_>>();
+
+    let mut filtered = String::with_capacity(rendered.len());
+    for line in rendered.lines() {
+        let keep = match parse_sample(line) {
+            Some(sample) => match types.get(sample.name) {
+                Some(&"counter") => sample.value >= min_count,
+                Some(_) => true,
+                None => {
+                    let family = ["_bucket", "_sum", "_count"]
+                        .iter()
+                        .find_map(|suffix| sample.name.strip_suffix(suffix))
+                        .unwrap_or(sample.name);
+                    !suppressed.contains(&(family, sample.series))
+                }
+            },
+            None => true,
+        };
+
+        if keep {
+            filtered.push_str(line);
+            filtered.push('\n');
+        }
+    }
+
+    filtered
+}
+
+/// A single sample from rendered metrics.
+struct Sample<'a> {
+    name: &'a str,
+
+    /// The labels of the series the sample belongs to, without any bucket or quantile label.
+    series: &'a str,
+    value: f64,
+}
+
+fn parse_sample(line: &str) -> Option<Sample<'_>> {
+    if line.starts_with('#') || line.is_empty() {
+        return None;
+    }
+
+    let (series, value) = line.rsplit_once(' ')?;
+    let value = value.parse().ok()?;
+    let (name, labels) = match series.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_suffix('}')?),
+        None => (series, ""),
+    };
+
+    // Bucket and quantile labels are always rendered last.
+    let series = [",le=\"", ",quantile=\""]
+        .iter()
+        .find_map(|label| labels.rfind(label).map(|i| &labels[..i]))
+        .or_else(|| {
+            (labels.starts_with("le=\"") || labels.starts_with("quantile=\"")).then_some("")
+        })
+        .unwrap_or(labels);
+
+    Some(Sample {
+        name,
+        series,
+        value,
+    })
+}
+
+fn hash(value: &str) -> String {
+    let mut hash = format!("{:x}", Sha256::digest(value.as_bytes()));
+    hash.truncate(HASH_LEN);
+    hash
+}
@@ -0,0 +1,162 @@
+use serde_json::Value;
+
+use crate::config::ClaimRenderingConfig;
+
+/// A header value template, such as `{{email}} ({{sub}})`, that's filled in from the claims of a
+/// validated token.
+///
+/// Each `{{...}}` placeholder names a claim by the keys leading to it, separated by dots, such as
+/// `email` or `custom.api_token`. As in expressions, custom claims can also be named without the
+/// `custom.` prefix, as long as there's no standard claim of the same name.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Claim(ClaimPath),
+}
+
+#[derive(Clone, Debug)]
+struct ClaimPath {
+    /// The path as written in the template, for error messages.
+    name: String,
+
+    /// The keys leading to the claim.
+    keys: Vec<String>,
+}
+
+impl Template {
+    /// Parses the given template.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+
+            let placeholder = &rest[start + 2..];
+            let end = placeholder
+                .find("}}")
+                .ok_or_else(|| "unclosed placeholder".to_string())?;
+            let name = placeholder[..end].trim();
+            if name.is_empty() || name.split('.').any(str::is_empty) {
+                return Err(format!("invalid placeholder '{{{{{}}}}}'", name));
+            }
+
+            parts.push(Part::Claim(ClaimPath {
+                name: name.to_string(),
+                keys: name.split('.').map(str::to_string).collect(),
+            }));
+            rest = &placeholder[end + 2..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Fills in the template from the given claims.
+    ///
+    /// Claims are rendered according to the given rules, so if any claim is missing, or isn't a
+    /// scalar or an array of them, the name of that claim is returned instead.
+    pub fn render(
+        &self,
+        claims_json: &Value,
+        rendering: &ClaimRenderingConfig,
+    ) -> Result<String, String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Claim(path) => {
+                    let value = path
+                        .resolve(claims_json)
+                        .or_else(|| path.resolve(claims_json.get("custom")?))
+                        .ok_or_else(|| path.name.clone())?;
+
+                    // Rendering rules apply to the name of the claim the path ends at.
+                    let claim_name = path.keys.last().map(String::as_str).unwrap_or_default();
+                    let value = rendering
+                        .render(claim_name, value)
+                        .ok_or_else(|| path.name.clone())?;
+                    rendered.push_str(&value);
+                }
+            }
+        }
+
+        Ok(rendered)
+    }
+}
+
+impl ClaimPath {
+    fn resolve<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.keys
+            .iter()
+            .try_fold(value, |value, key| value.as_object()?.get(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("{{email", "unclosed placeholder"),
+            ("{{email}} ({{sub)", "unclosed placeholder"),
+            ("{{}}", "invalid placeholder '{{}}'"),
+            ("{{  }}", "invalid placeholder '{{}}'"),
+            (
+                "{{custom..token}}",
+                "invalid placeholder '{{custom..token}}'",
+            ),
+            ("{{.email}}", "invalid placeholder '{{.email}}'"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(Template::parse(source).unwrap_err(), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn missing_claims_are_named() {
+        let claims = json!({
+            "email": "alice@corp.com",
+            "sub": "user-1",
+            "custom": {"api_token": "abc", "team": {"name": "sre"}, "tags": [], "note": null},
+        });
+        let rendering = ClaimRenderingConfig::default();
+        let cases = [
+            ("{{email}} ({{ sub }})", Ok("alice@corp.com (user-1)")),
+            ("Bearer {{custom.api_token}}", Ok("Bearer abc")),
+            // Custom claims can be named without the prefix.
+            ("{{team.name}}", Ok("sre")),
+            ("no placeholders", Ok("no placeholders")),
+            ("{{name}}", Err("name")),
+            ("{{email}} ({{custom.missing}})", Err("custom.missing")),
+            ("{{email.domain}}", Err("email.domain")),
+            ("{{team.name.first}}", Err("team.name.first")),
+            // Claims that can't be rendered are treated as missing.
+            ("{{team}}", Err("team")),
+            ("{{tags}}", Err("tags")),
+            ("{{custom.note}}", Err("custom.note")),
+        ];
+        for (source, expected) in cases {
+            let rendered = Template::parse(source).unwrap().render(&claims, &rendering);
+            assert_eq!(
+                rendered,
+                expected.map(str::to_string).map_err(str::to_string),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use openidconnect::{core::CoreJsonWebKeySet, IssuerUrl};
+
+/// Gets the name of the file holding the JWKS data for the given issuer, such as
+/// `your-team-name.cloudflareaccess.com.jwks.json`.
+///
+/// Cached and static JWKS files are named the same way, so a cache directory can be copied as-is
+/// into an environment that has to use static JWKS files.
+pub fn jwks_file_name(issuer_url: &IssuerUrl) -> String {
+    // Team domains are plain hostnames, which are always safe to use as file names.
+    let host = issuer_url.url().host_str().unwrap_or("default");
+    format!("{}.jwks.json", host)
+}
+
+/// Reads JWKS data from the given file.
+pub fn read_jwks_file(path: &Path) -> Result<CoreJsonWebKeySet, String> {
+    let data = fs::read(path)
+        .map_err(|e| format!("Failed to read JWKS file '{}': {}", path.display(), e))?;
+    serde_json::from_slice(&data)
+        .map_err(|e| format!("Failed to parse JWKS file '{}': {}", path.display(), e))
+}
+
+/// An on-disk copy of the JWKS data for a single issuer.
+///
+/// The cache is written after every successful refresh, and read once at startup, so that a
+/// restart doesn't have to wait on Cloudflare before it can validate tokens again.
+pub struct JwksCache {
+    path: PathBuf,
+    max_age: Duration,
+}
+
+impl JwksCache {
+    /// Creates the cache for the given issuer, stored in `dir`.
+    ///
+    /// Cached JWKS data older than `max_age` is never loaded.
+    pub fn for_issuer(dir: &Path, issuer_url: &IssuerUrl, max_age: Duration) -> Self {
+        Self {
+            path: dir.join(jwks_file_name(issuer_url)),
+            max_age,
+        }
+    }
+
+    /// Loads the cached JWKS data, along with how long ago it was fetched.
+    ///
+    /// Returns `Ok(None)` if there is no cached data, or if it's too old to use.
+    pub fn load(&self) -> Result<Option<(CoreJsonWebKeySet, Duration)>, String> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(self.error("read", e)),
+        };
+
+        // The cache is rewritten after every successful refresh, so its modification time is when
+        // the data was last fetched.
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+        if age > self.max_age {
+            return Ok(None);
+        }
+
+        read_jwks_file(&self.path).map(|jwks| Some((jwks, age)))
+    }
+
+    /// Stores the given JWKS data in the cache.
+    ///
+    /// The data is written to a temporary file first, and then moved into place, so that a crash
+    /// midway never leaves a truncated cache behind.
+    pub fn store(&self, jwks: &CoreJsonWebKeySet) -> Result<(), String> {
+        let data = serde_json::to_vec(jwks).map_err(|e| self.error("serialize", e))?;
+
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, data).map_err(|e| self.error("write", e))?;
+        fs::rename(&temp_path, &self.path).map_err(|e| self.error("write", e))
+    }
+
+    fn error(&self, action: &str, e: impl std::fmt::Display) -> String {
+        format!(
+            "Failed to {} JWKS cache '{}': {}",
+            action,
+            self.path.display(),
+            e
+        )
+    }
+}
@@ -1,35 +1,158 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use arc_swap::ArcSwapOption;
-use hyper::{body::to_bytes, Body, Client, Request};
+use hyper::{body::to_bytes, client::HttpConnector, Body, Client, Request};
 use hyper_tls::HttpsConnector;
 use openidconnect::{
-    core::CoreJsonWebKeySet, HttpRequest, HttpResponse, IssuerUrl, JsonWebKeySetUrl,
+    core::CoreJsonWebKeySet, HttpRequest, HttpResponse, IssuerUrl, JsonWebKey, JsonWebKeySetUrl,
+};
+use rand::Rng;
+use tokio::{
+    sync::Notify,
+    task::JoinSet,
+    time::{sleep, timeout},
 };
-use tokio::time::{interval, sleep};
-use tracing::{error, info};
+use tracing::{error, info, info_span, warn, Instrument};
 
+use crate::{config::JwksFetchConfig, diagnostics, telemetry};
+
+mod cache;
 pub mod service_auth;
 pub mod token;
 
+pub(crate) use self::cache::jwks_file_name;
+use self::cache::{read_jwks_file, JwksCache};
+
+/// How long to wait before retrying after the first failed JWKS refresh.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest to wait before retrying a failed JWKS refresh, no matter how many have failed.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// The shortest time between two refreshes requested outside of the regular schedule.
+///
+/// Anyone can present a token with a made-up key ID, so this keeps them from using it to make us
+/// hammer Cloudflare.
+const REQUESTED_REFRESH_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// The HTTP client used for all outbound requests.
+///
+/// Clients are cheap to clone, and clones share the same connection pool, so a single client should
+/// be created and then shared by everything that makes outbound requests.
+pub type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Creates a new HTTP client.
+pub fn new_http_client() -> HttpClient {
+    Client::builder().build(HttpsConnector::new())
+}
+
 pub struct SignatureState {
+    http_client: HttpClient,
     issuer_url: IssuerUrl,
-    jwks_url: JsonWebKeySetUrl,
+    jwks_urls: Vec<JsonWebKeySetUrl>,
+    static_file: Option<PathBuf>,
+    hedge_delay: Duration,
+    cache: Option<JwksCache>,
     jwks: ArcSwapOption<CoreJsonWebKeySet>,
+    last_refreshed: ArcSwapOption<Instant>,
+    last_requested_refresh: Mutex<Option<Instant>>,
+    refresh_pending: AtomicBool,
+    consecutive_failures: AtomicU32,
+    refresh_requested: Notify,
+    refreshed: Notify,
 }
 
 impl SignatureState {
-    pub fn from_issuer_url(issuer_url: IssuerUrl) -> Result<Self, String> {
-        let jwks_url = issuer_url
-            .join("cdn-cgi/access/certs")
-            .map_err(|e| format!("Failed to construct JWKS URL from issuer: {}", e))
-            .map(JsonWebKeySetUrl::from_url)?;
+    pub fn from_issuer_url(
+        issuer_url: IssuerUrl,
+        http_client: HttpClient,
+        jwks_fetch: &JwksFetchConfig,
+    ) -> Result<Self, String> {
+        // Static JWKS files replace fetching over HTTPS entirely.
+        if let Some(static_dir) = &jwks_fetch.static_dir {
+            let static_file = static_dir.join(jwks_file_name(&issuer_url));
+            return Ok(Self::new(
+                http_client,
+                issuer_url,
+                Vec::new(),
+                Some(static_file),
+                jwks_fetch,
+            ));
+        }
+
+        let mut jwks_urls = Vec::new();
+        if jwks_fetch.direct {
+            let jwks_url = issuer_url
+                .join("cdn-cgi/access/certs")
+                .map_err(|e| format!("Failed to construct JWKS URL from issuer: {}", e))
+                .map(JsonWebKeySetUrl::from_url)?;
+            jwks_urls.push(jwks_url);
+        }
 
-        Ok(Self {
+        // Proxy endpoints are expected to forward `<proxy>/<team domain>/<path>` to the team domain.
+        let issuer_host = issuer_url
+            .url()
+            .host_str()
+            .ok_or_else(|| format!("Issuer '{}' has no host.", issuer_url.as_str()))?;
+        for proxy in &jwks_fetch.proxies {
+            let jwks_url = format!(
+                "{}/{}/cdn-cgi/access/certs",
+                proxy.trim_end_matches('/'),
+                issuer_host
+            );
+            let jwks_url = JsonWebKeySetUrl::new(jwks_url)
+                .map_err(|e| format!("Invalid JWKS proxy endpoint '{}': {}", proxy, e))?;
+            jwks_urls.push(jwks_url);
+        }
+
+        if jwks_urls.is_empty() {
+            return Err("At least one source for JWKS data must be configured.".to_string());
+        }
+
+        Ok(Self::new(
+            http_client,
             issuer_url,
-            jwks_url,
+            jwks_urls,
+            None,
+            jwks_fetch,
+        ))
+    }
+
+    fn new(
+        http_client: HttpClient,
+        issuer_url: IssuerUrl,
+        jwks_urls: Vec<JsonWebKeySetUrl>,
+        static_file: Option<PathBuf>,
+        jwks_fetch: &JwksFetchConfig,
+    ) -> Self {
+        let cache = jwks_fetch
+            .cache_dir
+            .as_deref()
+            .map(|dir| JwksCache::for_issuer(dir, &issuer_url, jwks_fetch.cache_max_age()));
+
+        Self {
+            http_client,
+            issuer_url,
+            jwks_urls,
+            static_file,
+            hedge_delay: jwks_fetch.hedge_delay(),
+            cache,
             jwks: ArcSwapOption::const_empty(),
-        })
+            last_refreshed: ArcSwapOption::const_empty(),
+            last_requested_refresh: Mutex::new(None),
+            refresh_pending: AtomicBool::new(false),
+            consecutive_failures: AtomicU32::new(0),
+            refresh_requested: Notify::new(),
+            refreshed: Notify::new(),
+        }
     }
 
     pub fn issuer_url(&self) -> IssuerUrl {
@@ -40,61 +163,369 @@ impl SignatureState {
         self.jwks.load().is_some()
     }
 
-    pub fn jwks(&self) -> Option<CoreJsonWebKeySet> {
-        self.jwks.load().as_ref().map(|jwks| jwks.as_ref().clone())
+    /// Gets the currently loaded JWKS data, if any.
+    ///
+    /// This is a cheap snapshot: refreshes replace the JWKS data wholesale, rather than modifying it
+    /// in place, so the snapshot stays consistent even if a refresh happens while it's in use.
+    pub fn jwks(&self) -> Option<Arc<CoreJsonWebKeySet>> {
+        self.jwks.load_full()
+    }
+
+    /// Gets the key IDs of the currently loaded JWKS data.
+    pub fn key_ids(&self) -> Vec<String> {
+        self.jwks
+            .load()
+            .as_ref()
+            .map(|jwks| {
+                jwks.keys()
+                    .iter()
+                    .filter_map(|key| key.key_id())
+                    .map(|key_id| key_id.as_str().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Gets how long ago the JWKS data was last successfully fetched, if it ever was.
+    pub fn jwks_age(&self) -> Option<Duration> {
+        self.last_refreshed
+            .load()
+            .as_ref()
+            .map(|last_refreshed| last_refreshed.elapsed())
+    }
+
+    /// Whether or not the JWKS data was last refreshed longer ago than `max_staleness`.
+    ///
+    /// JWKS data that was never loaded at all is considered stale.
+    pub fn is_stale(&self, max_staleness: Duration) -> bool {
+        self.jwks_age().map_or(true, |age| age > max_staleness)
+    }
+
+    /// Fetches the JWKS data, replacing the loaded data if it has changed.
+    ///
+    /// Returns whether or not the loaded data changed.
+    pub async fn refresh(&self) -> Result<bool, String> {
+        let result = fetch_jwks(self)
+            .instrument(info_span!(
+                "jwks_refresh",
+                issuer = self.issuer_url.as_str()
+            ))
+            .await;
+
+        // Whether it worked or not, anyone waiting on a requested refresh can stop waiting.
+        self.refresh_pending.store(false, Ordering::SeqCst);
+        self.refreshed.notify_waiters();
+
+        let new_jwks = match result {
+            Ok(new_jwks) => new_jwks,
+            Err(e) => {
+                let consecutive_failures =
+                    self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+                telemetry::record_jwks_refresh(
+                    self.issuer_url.as_str(),
+                    false,
+                    consecutive_failures,
+                );
+                diagnostics::record_error(
+                    "jwks_refresh",
+                    format!("{}: {}", self.issuer_url.as_str(), e),
+                );
+                return Err(e);
+            }
+        };
+
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+        telemetry::record_jwks_refresh(self.issuer_url.as_str(), true, 0);
+        self.last_refreshed.store(Some(Arc::new(Instant::now())));
+
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.store(&new_jwks) {
+                warn!(
+                    issuer_url = self.issuer_url.as_str(),
+                    error = %e,
+                    "Failed to cache JWKS data."
+                );
+            }
+        }
+
+        let should_update = match self.jwks.load().as_ref() {
+            None => true,
+            Some(existing_jwks) => existing_jwks.as_ref() != &new_jwks,
+        };
+
+        if should_update {
+            self.jwks.store(Some(Arc::new(new_jwks)));
+            info!(
+                issuer_url = self.issuer_url.as_str(),
+                "Refreshed JWKS data."
+            );
+        }
+
+        Ok(should_update)
+    }
+
+    /// Asks for the JWKS data to be refreshed right away, rather than at the next scheduled refresh.
+    ///
+    /// Requests are ignored if another one was made too recently. Returns whether or not the
+    /// request was accepted.
+    pub fn request_refresh(&self) -> bool {
+        let mut last_requested_refresh = self
+            .last_requested_refresh
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(last_requested_refresh) = *last_requested_refresh {
+            if last_requested_refresh.elapsed() < REQUESTED_REFRESH_COOLDOWN {
+                return false;
+            }
+        }
+
+        *last_requested_refresh = Some(Instant::now());
+        self.refresh_pending.store(true, Ordering::SeqCst);
+        self.refresh_requested.notify_one();
+        true
+    }
+
+    /// Waits until a requested refresh has been attempted, for at most `max_wait`.
+    ///
+    /// Returns immediately if no refresh is pending.
+    pub async fn wait_for_requested_refresh(&self, max_wait: Duration) {
+        // Register interest before checking, so a refresh finishing in between isn't missed.
+        let refreshed = self.refreshed.notified();
+        if self.refresh_pending.load(Ordering::SeqCst) {
+            let _ = timeout(max_wait, refreshed).await;
+        }
     }
 }
 
-pub async fn manage_jwks_refreshing(state: Arc<SignatureState>) {
+/// Gets the keys that may have been used to sign a token with the given key ID.
+///
+/// Verifiers need a key set of their own, so this clones only the key matching the key ID rather
+/// than the whole key set. Tokens without a key ID may have been signed by any of the keys.
+pub fn select_signature_keys(jwks: &CoreJsonWebKeySet, key_id: Option<&str>) -> CoreJsonWebKeySet {
+    match key_id {
+        Some(key_id) => CoreJsonWebKeySet::new(
+            jwks.keys()
+                .iter()
+                .filter(|key| key.key_id().map(|id| id.as_str()) == Some(key_id))
+                .cloned()
+                .collect(),
+        ),
+        None => jwks.clone(),
+    }
+}
+
+/// Signature state for every configured issuer.
+///
+/// There is always a default issuer, which is used for any audience not explicitly mapped to one of
+/// the additional, named issuers.
+pub struct SignatureStates {
+    default: Arc<SignatureState>,
+    issuers: HashMap<String, Arc<SignatureState>>,
+}
+
+impl SignatureStates {
+    pub fn new(
+        default_issuer_url: IssuerUrl,
+        issuer_urls: &HashMap<String, IssuerUrl>,
+        http_client: HttpClient,
+        jwks_fetch: &JwksFetchConfig,
+    ) -> Result<Self, String> {
+        let default =
+            SignatureState::from_issuer_url(default_issuer_url, http_client.clone(), jwks_fetch)
+                .map(Arc::new)?;
+        let issuers = issuer_urls
+            .iter()
+            .map(|(name, issuer_url)| {
+                SignatureState::from_issuer_url(issuer_url.clone(), http_client.clone(), jwks_fetch)
+                    .map(|state| (name.clone(), Arc::new(state)))
+                    .map_err(|e| format!("Invalid issuer '{}': {}", name, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { default, issuers })
+    }
+
+    /// Gets the signature state for the given issuer, or the default issuer if no name is given.
+    pub fn get(&self, issuer: Option<&str>) -> Option<&Arc<SignatureState>> {
+        match issuer {
+            None => Some(&self.default),
+            Some(name) => self.issuers.get(name),
+        }
+    }
+
+    /// Gets an iterator over the signature state of all issuers.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<SignatureState>> {
+        std::iter::once(&self.default).chain(self.issuers.values())
+    }
+
+    /// Whether or not JWKS data has been loaded for every issuer.
+    pub fn has_jwks_loaded(&self) -> bool {
+        self.iter().all(|state| state.has_jwks_loaded())
+    }
+
+    /// Whether or not the JWKS data for any issuer was last refreshed longer ago than
+    /// `max_staleness`.
+    pub fn any_stale(&self, max_staleness: Duration) -> bool {
+        self.iter().any(|state| state.is_stale(max_staleness))
+    }
+}
+
+pub async fn manage_jwks_refreshing(
+    state: Arc<SignatureState>,
+    refresh_interval: Duration,
+    refresh_jitter: Duration,
+) {
     info!("Starting background JWKS refresh task.");
 
     // This task manages the refreshing of the JWKS (JSON Web Key Set) data which is used to verify
     // that the given tokens we're being asked to validate come from the configured authentication
     // domain. We specifically handle the initial refresh when the application first starts, as well
     // as periodic refreshes to pull in updates as web keys are rolled, and so on.
+    //
+    // Failed refreshes are retried with exponential backoff, so that an outage doesn't have us
+    // hammering Cloudflare, or flooding our own logs, every few seconds until it's over.
 
-    // Create our interval so that we try and refresh the web keys every hour. `Interval` will
-    // always tick immediately after being created, so we drain the first tick manually.
-    let mut refresh_interval = interval(Duration::from_secs(3600));
-    refresh_interval.tick().await;
-
-    loop {
-        let new_jwks_result =
-            CoreJsonWebKeySet::fetch_async(&state.jwks_url, drive_http_request).await;
-        match new_jwks_result {
-            Err(e) => {
-                error!(
-                    jwks_url = state.jwks_url.as_str(),
-                    error = ?e,
-                    "Error during refreshing JWKS data. Retrying in 5 seconds.",
+    // Start out with the JWKS data cached by a previous run, if there is any, so that we're ready
+    // right away even if the team domain can't be reached at the moment.
+    if let Some(cache) = &state.cache {
+        match cache.load() {
+            Ok(Some((jwks, age))) => {
+                state.jwks.store(Some(Arc::new(jwks)));
+                let fetched_at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                state.last_refreshed.store(Some(Arc::new(fetched_at)));
+                info!(
+                    issuer_url = state.issuer_url.as_str(),
+                    age_secs = age.as_secs(),
+                    "Loaded cached JWKS data."
                 );
-                sleep(Duration::from_secs(5)).await;
-                continue;
-            }
-            Ok(new_jwks) => {
-                let should_update = match state.jwks.load().as_ref() {
-                    None => true,
-                    Some(existing_jwks) => existing_jwks.as_ref() != &new_jwks,
-                };
-
-                if should_update {
-                    state.jwks.store(Some(Arc::new(new_jwks)));
-                    info!(jwks_url = state.jwks_url.as_str(), "Refreshed JWKS data.");
-                }
             }
+            Ok(None) => {}
+            Err(e) => warn!(
+                issuer_url = state.issuer_url.as_str(),
+                error = %e,
+                "Failed to load cached JWKS data."
+            ),
         }
+    }
 
-        // Wait until it's time to refresh the keys.
-        refresh_interval.tick().await;
+    loop {
+        if let Err(e) = state.refresh().await {
+            let consecutive_failures = state.consecutive_failures.load(Ordering::SeqCst);
+            let retry_delay = retry_delay(consecutive_failures);
+            error!(
+                issuer_url = state.issuer_url.as_str(),
+                error = %e,
+                consecutive_failures,
+                "Error during refreshing JWKS data. Retrying in {} seconds.",
+                retry_delay.as_secs(),
+            );
+            sleep(retry_delay).await;
+            continue;
+        }
+
+        // Wait until it's time to refresh the keys, or until a refresh is requested because a token
+        // was signed with a key we don't know about yet. The jitter is picked anew every time, so
+        // that replicas which happened to refresh at the same time drift apart again.
+        tokio::select! {
+            _ = sleep(refresh_interval + jitter(refresh_jitter)) => {},
+            _ = state.refresh_requested.notified() => info!(
+                issuer_url = state.issuer_url.as_str(),
+                "Refreshing JWKS data early after seeing an unknown key ID."
+            ),
+        }
     }
 }
 
-pub async fn drive_http_request(mut request: HttpRequest) -> Result<HttpResponse, hyper::Error> {
-    let client = {
-        let https = HttpsConnector::new();
-        Client::builder().build(https)
+/// Gets how long to wait before retrying after the given number of failed refreshes in a row.
+///
+/// The delay doubles with every failure, starting from `INITIAL_RETRY_DELAY`, up to
+/// `MAX_RETRY_DELAY`.
+fn retry_delay(consecutive_failures: u32) -> Duration {
+    let doublings = consecutive_failures.saturating_sub(1).min(31);
+    INITIAL_RETRY_DELAY
+        .checked_mul(1 << doublings)
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+/// Picks a random duration between zero and `max`.
+fn jitter(max: Duration) -> Duration {
+    rand::thread_rng().gen_range(Duration::ZERO..=max)
+}
+
+/// Fetches the JWKS data from whichever configured source responds successfully first, or reads it
+/// from the static JWKS file, if there is one.
+///
+/// Sources are tried in order. If no hedge delay is configured, every source is raced at once.
+/// Otherwise, the next source is tried as soon as a source fails, or once the hedge delay passes
+/// without a response since the last source was tried.
+async fn fetch_jwks(state: &SignatureState) -> Result<CoreJsonWebKeySet, String> {
+    if let Some(static_file) = &state.static_file {
+        return read_jwks_file(static_file);
+    }
+
+    let mut pending = state.jwks_urls.iter();
+    let mut in_flight = JoinSet::new();
+    let mut errors = Vec::new();
+
+    let initial = if state.hedge_delay.is_zero() {
+        pending.len()
+    } else {
+        1
     };
+    for jwks_url in pending.by_ref().take(initial) {
+        spawn_jwks_fetch(&mut in_flight, state.http_client.clone(), jwks_url.clone());
+    }
+
+    let hedge = sleep(state.hedge_delay);
+    tokio::pin!(hedge);
+
+    loop {
+        if in_flight.is_empty() {
+            return Err(errors.join("; "));
+        }
+
+        let has_pending = pending.len() > 0;
+        tokio::select! {
+            Some(result) = in_flight.join_next() => match result {
+                Ok((_, Ok(jwks))) => return Ok(jwks),
+                Ok((jwks_url, Err(e))) => errors.push(format!("{}: {}", jwks_url.as_str(), e)),
+                Err(e) => errors.push(format!("JWKS fetch task failed: {}", e)),
+            },
+            _ = &mut hedge, if has_pending => {},
+        }
+
+        // Either a source failed, or the hedge delay passed, so the next source is tried, and the
+        // hedge delay starts over.
+        if let Some(jwks_url) = pending.next() {
+            spawn_jwks_fetch(&mut in_flight, state.http_client.clone(), jwks_url.clone());
+            hedge
+                .as_mut()
+                .reset(tokio::time::Instant::now() + state.hedge_delay);
+        }
+    }
+}
+
+type JwksFetchResult = (JsonWebKeySetUrl, Result<CoreJsonWebKeySet, String>);
+
+fn spawn_jwks_fetch(
+    in_flight: &mut JoinSet<JwksFetchResult>,
+    http_client: HttpClient,
+    jwks_url: JsonWebKeySetUrl,
+) {
+    in_flight.spawn(async move {
+        let result = CoreJsonWebKeySet::fetch_async(&jwks_url, |request| {
+            drive_http_request(http_client, request)
+        })
+        .await
+        .map_err(|e| format!("{:?}", e));
+        (jwks_url, result)
+    });
+}
 
+pub async fn drive_http_request(
+    client: HttpClient,
+    mut request: HttpRequest,
+) -> Result<HttpResponse, hyper::Error> {
     let mut request_builder = Request::builder()
         .method(request.method)
         .uri(request.url.as_str());
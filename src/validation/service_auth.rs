@@ -1,38 +1,421 @@
-use std::{collections::HashMap, path::Path, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
+use arc_swap::ArcSwap;
 use axum::{headers::HeaderName, http::HeaderValue};
-use hyper::HeaderMap;
+use hyper::{body::to_bytes, header, Body, HeaderMap, Request, Uri};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::time::interval;
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
 
-#[derive(Debug, Default)]
+use super::HttpClient;
+use crate::{config::Config, policy::normalize_audience, watch::watch_file};
+
+/// The headers mapped for a single service token, as written in the mapping file.
+///
+/// Either a plain map of headers, which are set for every audience, or a map with a `default`
+/// section, set for every audience, and an `audiences` section with the headers to set for specific
+/// audiences, keyed by AUD tag. Headers set for an audience take precedence over the default ones.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RawTokenMapping {
+    Scoped {
+        #[serde(default)]
+        default: HashMap<String, String>,
+        audiences: HashMap<String, HashMap<String, String>>,
+    },
+    Global(HashMap<String, String>),
+}
+
+/// The headers mapped for a single service token.
+#[derive(Clone, Debug, Default)]
+struct TokenHeaders {
+    default: HeaderMap,
+    audiences: HashMap<String, HeaderMap>,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct ServiceAuthTokenHeaderMap {
-    token_map: HashMap<String, HeaderMap>,
+    token_map: HashMap<String, TokenHeaders>,
+
+    /// Which mappings these are, out of every set of mappings that's been in effect, so anything
+    /// derived from them can tell when they've been replaced.
+    version: u64,
+
+    /// A hash of the mappings, which is the same for the same mappings in any process.
+    fingerprint: [u8; 32],
 }
 
 impl ServiceAuthTokenHeaderMap {
     pub fn from_mapping_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
         // Open the path as a file and deserialize it with serde_yaml.
         let file = std::fs::File::open(path).map_err(|e| format!("Failed to open file: {}", e))?;
-        let raw_token_map: HashMap<String, HashMap<String, String>> = serde_yaml::from_reader(file)
+        let raw_token_map: HashMap<String, RawTokenMapping> = serde_yaml::from_reader(file)
             .map_err(|e| format!("Failed to deserialize YAML: {}", e))?;
 
+        Self::from_raw(raw_token_map)
+    }
+
+    /// Creates the mappings from service token client IDs to the headers to set for them.
+    pub fn from_raw(raw_token_map: HashMap<String, RawTokenMapping>) -> Result<Self, String> {
         // Convert the deserialized map into a map of HeaderMaps.
         let mut token_map = HashMap::new();
-        for (token_client_id, raw_header_map) in raw_token_map {
-            let mut header_map = HeaderMap::new();
-            for (key, value) in raw_header_map {
-                let key = HeaderName::from_str(&key)
-                    .map_err(|e| format!("Failed to parse header key '{}': {}", key, e))?;
-                let value = HeaderValue::from_str(&value)
-                    .map_err(|e| format!("Failed to parse header value '{}': {}", value, e))?;
-                header_map.insert(key, value);
+        for (token_client_id, raw_mapping) in raw_token_map {
+            let token_headers = match raw_mapping {
+                RawTokenMapping::Global(raw_header_map) => TokenHeaders {
+                    default: parse_header_map(raw_header_map)?,
+                    audiences: HashMap::new(),
+                },
+                RawTokenMapping::Scoped { default, audiences } => {
+                    let mut audience_header_maps = HashMap::new();
+                    for (audience, raw_header_map) in audiences {
+                        // Audiences are looked up in their normalized form.
+                        let normalized = normalize_audience(&audience).map_err(|reason| {
+                            format!(
+                                "Invalid audience '{}' for service token '{}': {}",
+                                audience,
+                                token_client_id,
+                                reason.as_str()
+                            )
+                        })?;
+                        audience_header_maps.insert(normalized, parse_header_map(raw_header_map)?);
+                    }
+
+                    TokenHeaders {
+                        default: parse_header_map(default)?,
+                        audiences: audience_header_maps,
+                    }
+                }
+            };
+            token_map.insert(token_client_id, token_headers);
+        }
+
+        Ok(Self {
+            token_map,
+            version: 0,
+            fingerprint: [0; 32],
+        })
+    }
+
+    /// Merges the given mappings into these ones.
+    ///
+    /// Headers mapped for the same service token, and the same audience, in both are taken from
+    /// `other`.
+    pub fn merge(&mut self, other: Self) {
+        for (token_client_id, other_headers) in other.token_map {
+            let token_headers = self.token_map.entry(token_client_id).or_default();
+            token_headers.default.extend(other_headers.default);
+            for (audience, header_map) in other_headers.audiences {
+                token_headers
+                    .audiences
+                    .entry(audience)
+                    .or_default()
+                    .extend(header_map);
             }
-            token_map.insert(token_client_id, header_map);
         }
+    }
+
+    /// Gets the headers to set for the given service token when validating for the given audience,
+    /// if the service token is mapped at all.
+    ///
+    /// The default headers come first, followed by the headers for the audience, so that inserting
+    /// them in order lets the latter take precedence.
+    pub fn get_headers_for_token<'a>(
+        &'a self,
+        token_client_id: &str,
+        audience: &str,
+    ) -> Option<impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)>> {
+        let token_headers = self.token_map.get(token_client_id)?;
+        let audience_headers = token_headers.audiences.get(audience);
 
-        Ok(Self { token_map })
+        Some(
+            token_headers
+                .default
+                .iter()
+                .chain(audience_headers.into_iter().flat_map(HeaderMap::iter)),
+        )
     }
 
-    pub fn get_header_map_for_token(&self, token_client_id: &str) -> Option<&HeaderMap> {
-        self.token_map.get(token_client_id)
+    /// Gets the version of these mappings, which changes every time the mappings are replaced.
+    pub fn version(&self) -> u64 {
+        self.version
     }
+
+    /// Gets a hash of these mappings, which is the same for the same mappings in any process, so
+    /// that replicas sharing validations can tell whether they're using the same mappings.
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    fn compute_fingerprint(&self) -> [u8; 32] {
+        // Maps are hashed in sorted order, as their iteration order differs between processes.
+        let mut token_map = self.token_map.iter().collect::<Vec<_>>();
+        token_map.sort_unstable_by_key(|(token_client_id, _)| *token_client_id);
+
+        let mut hasher = Sha256::new();
+        for (token_client_id, token_headers) in token_map {
+            hash_part(&mut hasher, token_client_id.as_bytes());
+            hash_headers(&mut hasher, &token_headers.default);
+
+            let mut audiences = token_headers.audiences.iter().collect::<Vec<_>>();
+            audiences.sort_unstable_by_key(|(audience, _)| *audience);
+            hasher.update((audiences.len() as u64).to_be_bytes());
+            for (audience, header_map) in audiences {
+                hash_part(&mut hasher, audience.as_bytes());
+                hash_headers(&mut hasher, header_map);
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    /// Gets the number of service tokens with mapped headers.
+    pub fn len(&self) -> usize {
+        self.token_map.len()
+    }
+
+    /// Whether or not no service tokens have mapped headers.
+    pub fn is_empty(&self) -> bool {
+        self.token_map.is_empty()
+    }
+}
+
+/// Hashes the given part, length-prefixed, so that no two different sets of parts hash the same.
+fn hash_part(hasher: &mut Sha256, part: &[u8]) {
+    hasher.update((part.len() as u64).to_be_bytes());
+    hasher.update(part);
+}
+
+fn hash_headers(hasher: &mut Sha256, header_map: &HeaderMap) {
+    let mut headers = header_map
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+        .collect::<Vec<_>>();
+    headers.sort_unstable();
+
+    hasher.update((headers.len() as u64).to_be_bytes());
+    for (name, value) in headers {
+        hash_part(hasher, name.as_bytes());
+        hash_part(hasher, value);
+    }
+}
+
+fn parse_header_map(raw_header_map: HashMap<String, String>) -> Result<HeaderMap, String> {
+    let mut header_map = HeaderMap::new();
+    for (key, value) in raw_header_map {
+        let key = HeaderName::from_str(&key)
+            .map_err(|e| format!("Failed to parse header key '{}': {}", key, e))?;
+        let value = HeaderValue::from_str(&value)
+            .map_err(|e| format!("Failed to parse header value '{}': {}", value, e))?;
+        header_map.insert(key, value);
+    }
+
+    Ok(header_map)
+}
+
+/// The service token auth mappings in effect.
+///
+/// These are built from the local mappings, given inline and in the mapping file, on top of the
+/// mappings fetched from the remote URL, if one is configured. Either source can be reloaded on its
+/// own, and the merged mappings are swapped in atomically, so requests in flight keep using the
+/// mappings they started with, while new requests pick up the new ones.
+pub struct ServiceTokenMapStore {
+    sources: Mutex<TokenMapSources>,
+    merged: ArcSwap<ServiceAuthTokenHeaderMap>,
+    last_version: AtomicU64,
+}
+
+struct TokenMapSources {
+    local: ServiceAuthTokenHeaderMap,
+    remote: ServiceAuthTokenHeaderMap,
+}
+
+impl ServiceTokenMapStore {
+    /// Creates the store, starting out with only the given local mappings.
+    pub fn new(local: ServiceAuthTokenHeaderMap) -> Self {
+        let mut merged = local.clone();
+        merged.fingerprint = merged.compute_fingerprint();
+        Self {
+            merged: ArcSwap::from_pointee(merged),
+            sources: Mutex::new(TokenMapSources {
+                local,
+                remote: ServiceAuthTokenHeaderMap::default(),
+            }),
+            last_version: AtomicU64::new(0),
+        }
+    }
+
+    /// Gets the mappings currently in effect.
+    pub fn load(&self) -> Arc<ServiceAuthTokenHeaderMap> {
+        self.merged.load_full()
+    }
+
+    /// Replaces the local mappings.
+    pub fn set_local(&self, local: ServiceAuthTokenHeaderMap) {
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        sources.local = local;
+        self.merge(&sources);
+    }
+
+    /// Replaces the mappings fetched from the remote URL.
+    pub fn set_remote(&self, remote: ServiceAuthTokenHeaderMap) {
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        sources.remote = remote;
+        self.merge(&sources);
+    }
+
+    fn merge(&self, sources: &TokenMapSources) {
+        // Local mappings win, so that a single deployment can override the central mappings.
+        let mut merged = sources.remote.clone();
+        merged.merge(sources.local.clone());
+        merged.version = self.last_version.fetch_add(1, Ordering::Relaxed) + 1;
+        merged.fingerprint = merged.compute_fingerprint();
+        self.merged.store(Arc::new(merged));
+    }
+}
+
+/// Fetches service token auth mappings, in the same shape as the mapping file, from a remote URL.
+pub struct RemoteTokenMap {
+    http_client: HttpClient,
+    url: Uri,
+    bearer_token: Option<Zeroizing<String>>,
+}
+
+impl RemoteTokenMap {
+    /// Creates the fetcher for the given URL, optionally authenticating with the Bearer token in
+    /// the given file.
+    pub fn new(
+        http_client: HttpClient,
+        url: &str,
+        bearer_token_file: Option<&Path>,
+    ) -> Result<Self, String> {
+        let url = Uri::from_str(url)
+            .map_err(|e| format!("Invalid service auth map URL '{}': {}", url, e))?;
+
+        let bearer_token = bearer_token_file
+            .map(|path| {
+                let contents = std::fs::read_to_string(path)
+                    .map(Zeroizing::new)
+                    .map_err(|e| {
+                        format!(
+                            "Failed to read service auth map token file '{}': {}",
+                            path.display(),
+                            e
+                        )
+                    })?;
+                Ok::<_, String>(Zeroizing::new(contents.trim().to_string()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            http_client,
+            url,
+            bearer_token,
+        })
+    }
+
+    /// Fetches the mappings.
+    pub async fn fetch(&self) -> Result<ServiceAuthTokenHeaderMap, String> {
+        let mut request = Request::get(self.url.clone()).header(header::ACCEPT, "application/json");
+        if let Some(bearer_token) = &self.bearer_token {
+            request = request.header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", bearer_token.as_str()),
+            );
+        }
+        let request = request
+            .body(Body::empty())
+            .map_err(|e| format!("Failed to build service auth map request: {}", e))?;
+
+        let response = self
+            .http_client
+            .request(request)
+            .await
+            .map_err(|e| format!("Failed to fetch service auth map: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!(
+                "Failed to fetch service auth map: server responded with {}",
+                status
+            ));
+        }
+        let body = to_bytes(response.into_body())
+            .await
+            .map_err(|e| format!("Failed to fetch service auth map: {}", e))?;
+
+        // YAML is a superset of JSON, so either works.
+        let raw_token_map: HashMap<String, RawTokenMapping> = serde_yaml::from_slice(&body)
+            .map_err(|e| format!("Failed to deserialize service auth map: {}", e))?;
+        ServiceAuthTokenHeaderMap::from_raw(raw_token_map)
+    }
+}
+
+/// Re-fetches the mappings from the remote URL on the given interval, forever.
+///
+/// If fetching fails, the mappings from the last successful fetch are kept.
+pub async fn refresh_remote_token_map(
+    remote: Arc<RemoteTokenMap>,
+    token_map: Arc<ServiceTokenMapStore>,
+    refresh_interval: Duration,
+) {
+    let mut refresh_interval = interval(refresh_interval);
+    refresh_interval.tick().await;
+
+    loop {
+        refresh_interval.tick().await;
+
+        match remote.fetch().await {
+            Ok(remote_token_map) => {
+                let service_tokens = remote_token_map.len();
+                token_map.set_remote(remote_token_map);
+                info!(
+                    service_tokens,
+                    "Refreshed remote service token auth mappings."
+                );
+            }
+            Err(e) => error!(
+                error = e,
+                "Failed to refresh remote service token auth mappings. Keeping the current ones."
+            ),
+        }
+    }
+}
+
+/// Reloads the local service token auth mappings, from the inline mappings and the mapping file.
+///
+/// If they can't be loaded, the current mappings are kept.
+pub fn reload_token_map(config: &Config, token_map: &ServiceTokenMapStore) {
+    match config.load_service_token_map() {
+        Ok(new_token_map) => {
+            let service_tokens = new_token_map.len();
+            token_map.set_local(new_token_map);
+            info!(service_tokens, "Reloaded service token auth mappings.");
+        }
+        Err(e) => error!(
+            error = e,
+            "Failed to reload service token auth mappings. Keeping the current ones."
+        ),
+    }
+}
+
+/// Watches the mapping file, reloading the mappings whenever its contents change.
+pub async fn watch_mapping_file(config: Arc<Config>, token_map: Arc<ServiceTokenMapStore>) {
+    let path = match &config.service_token_auth_mapping_file {
+        Some(path) => path.clone(),
+        None => return,
+    };
+
+    watch_file(path, "service token auth mapping file", || {
+        reload_token_map(&config, &token_map)
+    })
+    .await;
 }
@@ -1,18 +1,22 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap};
 
 use axum::{
-    headers,
-    http::{header::HeaderName, HeaderValue},
+    headers::{self, HeaderMapExt},
+    http::{header, HeaderMap},
 };
+use chrono::{DateTime, TimeZone, Utc};
 use openidconnect::{
     core::{
         CoreGenderClaim, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm,
         CoreJwsSigningAlgorithm,
     },
-    AccessToken, AdditionalClaims, IdToken,
+    AdditionalClaims, IdToken,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use zeroize::Zeroizing;
+
+use crate::{config::ClaimRenderingConfig, redaction::ClaimValue};
 
 pub type CloudflareAccessIdToken = IdToken<
     CloudflareAccessCustomClaims,
@@ -38,50 +42,326 @@ pub struct CloudflareAccessCustomClaims {
     /// "common name" in the JWT to identify which service token was used.
     #[serde(rename = "common_name")]
     service_token_id: Option<String>,
+
+    /// The token type.
+    ///
+    /// Cloudflare Access issues application tokens (`app`) when authenticating to a specific
+    /// application, and organization tokens (`org`) for the team domain itself.
+    #[serde(rename = "type")]
+    token_type: Option<String>,
+
+    /// The country the principal was in when the token was issued, as an ISO 3166-1 alpha-2 code.
+    country: Option<String>,
+
+    /// The unique identifier of the token, for detecting replays.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+
+    /// The time before which the token must not be accepted, in seconds since the Unix epoch.
+    #[serde(rename = "nbf", skip_serializing_if = "Option::is_none")]
+    not_before: Option<serde_json::Number>,
 }
 
 impl CloudflareAccessCustomClaims {
     /// Gets an iterator for visiting all custom claim mapping pairs, in arbitrary order.
-    pub fn claims(&self) -> impl Iterator<Item = (&str, &str)> {
-        self.custom
-            .iter()
-            .filter_map(|(k, v)| v.as_str().map(|v| (k.as_str(), v)))
+    ///
+    /// Booleans, numbers, and arrays are rendered according to the given rules. Objects are
+    /// flattened into a claim for each of their values, named by the keys leading to it separated by
+    /// dots, up to `flatten_depth` levels deep. Any other claim is skipped.
+    pub fn claims<'a>(
+        &'a self,
+        rendering: &'a ClaimRenderingConfig,
+        flatten_depth: usize,
+    ) -> impl Iterator<Item = (Cow<'a, str>, ClaimValue<'a>)> {
+        let mut claims = Vec::with_capacity(self.custom.len());
+        for (name, value) in &self.custom {
+            let name = Cow::Borrowed(name.as_str());
+            flatten_claim(name, value, rendering, flatten_depth, &mut claims);
+        }
+        claims.into_iter()
+    }
+
+    /// Gets an iterator over the custom claim values found at the given extraction paths.
+    ///
+    /// Each path is a JSON pointer into the custom claims, such as `/github/email`, and is paired
+    /// with the claim name to report the value under. Paths that don't resolve to a scalar, or an
+    /// array of them, are skipped.
+    pub fn extracted_claims<'a>(
+        &'a self,
+        paths: &'a HashMap<String, String>,
+        rendering: &'a ClaimRenderingConfig,
+    ) -> impl Iterator<Item = (Cow<'a, str>, ClaimValue<'a>)> {
+        paths.iter().filter_map(move |(name, path)| {
+            self.custom_pointer(path)
+                .and_then(|v| rendering.render(name, v))
+                .map(|v| {
+                    (
+                        Cow::Borrowed(name.as_str()),
+                        ClaimValue::new(name.as_str(), v),
+                    )
+                })
+        })
+    }
+
+    /// Gets the custom claim value at the given JSON pointer, such as `/github/email`.
+    pub fn custom_pointer(&self, pointer: &str) -> Option<&Value> {
+        // The custom claims are a map rather than a `Value`, so resolve the first reference token
+        // against the map ourselves and let `serde_json` handle the rest.
+        let pointer = pointer.strip_prefix('/')?;
+        let (key, rest) = match pointer.find('/') {
+            Some(idx) => pointer.split_at(idx),
+            None => (pointer, ""),
+        };
+        let key = key.replace("~1", "/").replace("~0", "~");
+
+        self.custom.get(&key)?.pointer(rest)
+    }
+
+    /// Gets the custom claim of the given name, if it exists.
+    pub fn custom_claim(&self, name: &str) -> Option<&Value> {
+        self.custom.get(name)
     }
 
     /// Gets the service token ID, if it exists.
     pub fn get_service_token_id(&self) -> Option<&str> {
         self.service_token_id.as_deref()
     }
+
+    /// Gets the token type, if it exists.
+    pub fn get_token_type(&self) -> Option<&str> {
+        self.token_type.as_deref()
+    }
+
+    /// Gets the country, if it exists.
+    pub fn get_country(&self) -> Option<&str> {
+        self.country.as_deref()
+    }
+
+    /// Gets the token ID, if it exists.
+    pub fn get_jti(&self) -> Option<&str> {
+        self.jti.as_deref()
+    }
+
+    /// Gets the time before which the token must not be accepted, if it exists and is valid.
+    pub fn get_not_before(&self) -> Option<DateTime<Utc>> {
+        let not_before = self.not_before.as_ref()?;
+        let secs = match not_before.as_i64() {
+            Some(secs) => secs,
+            None => not_before.as_f64()? as i64,
+        };
+        Utc.timestamp_opt(secs, 0).single()
+    }
 }
 
 impl AdditionalClaims for CloudflareAccessCustomClaims {}
 
-/// The [`Cf-Access-Jwt-Assertion`][1] header sent by Cloudflare Access.
+/// The most levels of nested objects in the custom claims that can be flattened.
+pub const MAX_CLAIM_FLATTEN_DEPTH: usize = 8;
+
+/// Renders the given claim into `claims`, flattening objects up to `depth` levels deep.
+fn flatten_claim<'a>(
+    name: Cow<'a, str>,
+    value: &'a Value,
+    rendering: &ClaimRenderingConfig,
+    depth: usize,
+    claims: &mut Vec<(Cow<'a, str>, ClaimValue<'a>)>,
+) {
+    match value {
+        Value::Object(fields) if depth > 0 => {
+            for (k, v) in fields {
+                let name = Cow::Owned(format!("{}.{}", name, k));
+                flatten_claim(name, v, rendering, depth - 1, claims);
+            }
+        }
+        _ => {
+            if let Some(rendered) = rendering.render(&name, value) {
+                let claim_value = ClaimValue::new(name.clone(), rendered);
+                claims.push((name, claim_value));
+            }
+        }
+    }
+}
+
+/// Maximum length of an assertion that we'll attempt to parse.
+///
+/// Cloudflare Access tokens are typically well under 2KB, even with a generous amount of custom
+/// claims, so anything larger than this is almost certainly not a real token.
+const MAX_ASSERTION_LEN: usize = 16 * 1024;
+
+/// Maximum length of the encoded JOSE header segment of an assertion.
+const MAX_JOSE_HEADER_LEN: usize = 1024;
+
+/// The reason an assertion was rejected as structurally invalid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MalformedReason {
+    /// The assertion was larger than any legitimate token would be.
+    TooLarge,
+
+    /// The assertion did not consist of three dot-separated segments.
+    WrongSegmentCount,
+
+    /// One of the segments was empty or not valid base64url.
+    InvalidEncoding,
+
+    /// The JOSE header could not be decoded as a JSON object with an `alg` field.
+    InvalidHeader,
+
+    /// The `Authorization` header used the `Bearer` scheme, but not the syntax RFC 6750 requires.
+    InvalidBearer,
+}
+
+impl MalformedReason {
+    /// Gets a short, stable identifier for this reason, suitable for logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TooLarge => "too_large",
+            Self::WrongSegmentCount => "wrong_segment_count",
+            Self::InvalidEncoding => "invalid_encoding",
+            Self::InvalidHeader => "invalid_header",
+            Self::InvalidBearer => "invalid_bearer",
+        }
+    }
+}
+
+/// Cheaply checks that the given assertion is at least structurally a JWT.
+///
+/// This is done before handing the assertion to the OIDC library, so that garbage (scanners, broken
+/// clients, etc) can be rejected early and reported separately from genuine validation failures.
+///
+/// If the assertion is well-formed, the key ID (`kid`) from its JOSE header is returned, if present.
+pub fn check_assertion_structure(assertion: &str) -> Result<Option<String>, MalformedReason> {
+    if assertion.len() > MAX_ASSERTION_LEN {
+        return Err(MalformedReason::TooLarge);
+    }
+
+    let segments = assertion.split('.').collect::<Vec<_>>();
+    if segments.len() != 3 {
+        return Err(MalformedReason::WrongSegmentCount);
+    }
+
+    let is_base64url = |segment: &str| {
+        !segment.is_empty()
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    if !segments.iter().all(|segment| is_base64url(segment)) {
+        return Err(MalformedReason::InvalidEncoding);
+    }
+
+    let header = segments[0];
+    if header.len() > MAX_JOSE_HEADER_LEN {
+        return Err(MalformedReason::TooLarge);
+    }
+
+    let decoded_header = base64::decode_config(header, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| MalformedReason::InvalidEncoding)?;
+    let header_json: Value =
+        serde_json::from_slice(&decoded_header).map_err(|_| MalformedReason::InvalidHeader)?;
+    match header_json.get("alg") {
+        Some(Value::String(_)) => {}
+        _ => return Err(MalformedReason::InvalidHeader),
+    }
+
+    Ok(header_json
+        .get("kid")
+        .and_then(Value::as_str)
+        .map(str::to_string))
+}
+
+/// The name of the cookie Cloudflare Access stores the token in.
+const AUTHORIZATION_COOKIE_NAME: &str = "CF_Authorization";
+
+/// The name of the header Cloudflare Access sends the token in.
+pub const DEFAULT_TOKEN_HEADER: &str = "cf-access-jwt-assertion";
+
+/// A Cloudflare Access token, as sent in the [`Cf-Access-Jwt-Assertion`][1] header by Cloudflare
+/// Access.
+///
+/// The raw token is held in a zeroizing buffer so that it is wiped from memory once the request has
+/// been handled. It intentionally does not implement `Debug`, to avoid it ending up in logs.
 ///
 /// [1]: https://developers.cloudflare.com/cloudflare-one/identity/authorization-cookie/validating-json/
-pub struct CloudflareAccessOIDCAccessToken(pub AccessToken);
+pub struct CloudflareAccessOIDCAccessToken(Zeroizing<String>);
 
-impl headers::Header for CloudflareAccessOIDCAccessToken {
-    fn name() -> &'static HeaderName {
-        static HEADER_NAME: HeaderName = HeaderName::from_static("cf-access-jwt-assertion");
-        &HEADER_NAME
+impl CloudflareAccessOIDCAccessToken {
+    /// Gets the token from the given header, if present.
+    ///
+    /// The value may optionally use the `Bearer` scheme, as in `Authorization: Bearer <token>`, in
+    /// which case the scheme is stripped.
+    pub fn from_header(headers: &HeaderMap, name: &str) -> Result<Option<Self>, MalformedReason> {
+        let value = match headers.get(name) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| MalformedReason::InvalidEncoding)?,
+            None => return Ok(None),
+        };
+
+        let token = match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            _ => value,
+        };
+
+        Ok(Some(CloudflareAccessOIDCAccessToken(Zeroizing::new(
+            token.to_string(),
+        ))))
     }
 
-    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
-    where
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        let value = values.next().ok_or_else(headers::Error::invalid)?;
+    /// Gets the token from a standard `Authorization: Bearer <token>` header, if present.
+    ///
+    /// This is strict about the syntax in [RFC 6750][1]: the scheme must be `Bearer` (in any case),
+    /// followed by one or more spaces and a single `b64token`. `Authorization` headers using any
+    /// other scheme are ignored, as they're meant for something else.
+    ///
+    /// [1]: https://www.rfc-editor.org/rfc/rfc6750#section-2.1
+    pub fn from_bearer_authorization(headers: &HeaderMap) -> Result<Option<Self>, MalformedReason> {
+        let value = match headers.get(header::AUTHORIZATION) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| MalformedReason::InvalidEncoding)?,
+            None => return Ok(None),
+        };
 
-        value
-            .to_str()
-            .map(|s| CloudflareAccessOIDCAccessToken(AccessToken::new(s.to_string())))
-            .map_err(|_| headers::Error::invalid())
+        let (scheme, token) = value.split_once(' ').unwrap_or((value, ""));
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Ok(None);
+        }
+
+        let token = token.trim_start_matches(' ');
+        if !is_b64token(token) {
+            return Err(MalformedReason::InvalidBearer);
+        }
+
+        Ok(Some(CloudflareAccessOIDCAccessToken(Zeroizing::new(
+            token.to_string(),
+        ))))
+    }
+
+    /// Gets the token from the [`CF_Authorization`][1] cookie, if present.
+    ///
+    /// Cloudflare Access sets this cookie alongside the assertion header, which makes it useful as a
+    /// fallback when only cookies survive the hops between Cloudflare and the proxy.
+    ///
+    /// [1]: https://developers.cloudflare.com/cloudflare-one/identity/authorization-cookie/
+    pub fn from_cookies(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .typed_get::<headers::Cookie>()?
+            .get(AUTHORIZATION_COOKIE_NAME)
+            .map(|value| CloudflareAccessOIDCAccessToken(Zeroizing::new(value.to_string())))
     }
 
-    fn encode<E>(&self, _: &mut E)
-    where
-        E: Extend<HeaderValue>,
-    {
+    /// Gets the raw token.
+    pub fn secret(&self) -> &str {
+        self.0.as_str()
     }
 }
+
+/// Whether the given value is a `b64token`, as defined by RFC 6750: one or more characters from
+/// the base64 and base64url alphabets, optionally followed by `=` padding.
+fn is_b64token(value: &str) -> bool {
+    let token = value.trim_end_matches('=');
+    !token.is_empty()
+        && token.bytes().all(|b| {
+            b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~' | b'+' | b'/')
+        })
+}
@@ -0,0 +1,71 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use notify::{Event, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
+use tokio::{sync::mpsc, time::sleep};
+use tracing::{error, info, warn};
+
+/// How long to let a burst of file system events settle before reloading a watched file.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watches the given file, described by `name` in logs, calling `on_change` whenever its contents
+/// change.
+///
+/// The directory holding the file is watched, rather than the file itself, so that the file being
+/// replaced is noticed as well as it being modified in place. This includes Kubernetes swapping
+/// the `..data` symlink of a mounted ConfigMap.
+pub async fn watch_file<F>(path: PathBuf, name: &'static str, mut on_change: F)
+where
+    F: FnMut(),
+{
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+        Ok(event) if !event.kind.is_access() => {
+            let _ = changes_tx.send(());
+        }
+        Ok(_) => {}
+        Err(e) => warn!(error = %e, file = name, "Error while watching file."),
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!(error = %e, file = name, "Failed to watch file. Changes will only be picked up on SIGHUP.");
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        error!(error = %e, file = name, "Failed to watch file. Changes will only be picked up on SIGHUP.");
+        return;
+    }
+
+    info!(path = %path.display(), file = name, "Watching file for changes.");
+
+    // Other files in the same directory trigger events too, and a single change can be reported
+    // in any number of ways, so only reload when the contents have actually changed.
+    let mut last_digest = file_digest(&path);
+    while changes_rx.recv().await.is_some() {
+        // Writing or replacing a file usually produces a burst of events, so let it settle first.
+        sleep(WATCH_DEBOUNCE).await;
+        while changes_rx.try_recv().is_ok() {}
+
+        let digest = file_digest(&path);
+        if digest != last_digest {
+            last_digest = digest;
+            on_change();
+        }
+    }
+}
+
+fn file_digest(path: &Path) -> Option<Vec<u8>> {
+    std::fs::read(path)
+        .ok()
+        .map(|contents| Sha256::digest(contents).to_vec())
+}
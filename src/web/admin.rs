@@ -0,0 +1,99 @@
+use std::{path::Path, sync::Arc};
+
+use axum::{
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{header, HeaderMap, StatusCode};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::validation::SignatureStates;
+
+/// The token that must be presented, as a Bearer token, to use the admin endpoints.
+pub struct AdminToken(Zeroizing<String>);
+
+impl AdminToken {
+    /// Loads the admin token from the given file.
+    ///
+    /// Leading and trailing whitespace is ignored, so the file may end with a newline.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map(Zeroizing::new)
+            .map_err(|e| {
+                format!(
+                    "Failed to read admin token file '{}': {}",
+                    path.display(),
+                    e
+                )
+            })?;
+
+        let token = contents.trim();
+        if token.is_empty() {
+            return Err(format!("Admin token file '{}' is empty.", path.display()));
+        }
+
+        Ok(Self(Zeroizing::new(token.to_string())))
+    }
+
+    /// Whether or not the request was made with the admin token.
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let presented = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+
+        // Compare hashes, rather than the tokens themselves, so the comparison doesn't leak how much
+        // of the token was right through its timing.
+        presented.map_or(false, |presented| {
+            Sha256::digest(presented.as_bytes()) == Sha256::digest(self.0.as_bytes())
+        })
+    }
+}
+
+/// Refreshes the JWKS data for every issuer right away, responding with the outcome for each.
+///
+/// This lets operators pick up rotated keys without waiting for the next scheduled refresh, or
+/// restarting.
+pub async fn refresh_jwks(
+    headers: HeaderMap,
+    Extension(admin_token): Extension<Arc<AdminToken>>,
+    Extension(states): Extension<Arc<SignatureStates>>,
+) -> Response {
+    if !admin_token.is_authorized(&headers) {
+        warn!("Rejected admin request without a valid admin token.");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    info!("Refreshing JWKS data at the request of an operator.");
+
+    let mut all_succeeded = true;
+    let mut issuers = Vec::new();
+    for state in states.iter() {
+        let (outcome, error) = match state.refresh().await {
+            Ok(true) => ("updated", None),
+            Ok(false) => ("unchanged", None),
+            Err(e) => {
+                all_succeeded = false;
+                ("failed", Some(e))
+            }
+        };
+
+        issuers.push(json!({
+            "issuer": state.issuer_url().as_str(),
+            "outcome": outcome,
+            "error": error,
+            "key_ids": state.key_ids(),
+        }));
+    }
+
+    let status = if all_succeeded {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+    (status, Json(json!({ "issuers": issuers }))).into_response()
+}
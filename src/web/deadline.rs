@@ -0,0 +1,71 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use hyper::HeaderMap;
+use tokio::time::timeout;
+
+/// The point by which a validation request must be answered, after which the proxy will have given
+/// up on it anyway.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// Gets the deadline for a request received just now, from the timeout in the given header.
+    ///
+    /// Without a header, or a valid timeout in it, there's no deadline.
+    pub(crate) fn from_headers(header_name: Option<&str>, headers: &HeaderMap) -> Self {
+        let timeout = header_name
+            .and_then(|header_name| headers.get(header_name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_timeout);
+        Self(timeout.and_then(|timeout| Instant::now().checked_add(timeout)))
+    }
+
+    /// Gets how long is left until the deadline, up to `max`.
+    pub(crate) fn remaining(&self, max: Duration) -> Duration {
+        match self.0 {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(max),
+            None => max,
+        }
+    }
+
+    /// Runs the given future until it finishes, or the deadline passes, whichever is first.
+    ///
+    /// Returns `None` if the deadline passed first.
+    pub(crate) async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        match self.0 {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                timeout(remaining, future).await.ok()
+            }
+            None => Some(future.await),
+        }
+    }
+}
+
+/// Parses a request timeout, either as a number of milliseconds, such as `1500`, or in the format
+/// of the `grpc-timeout` header, with a unit, such as `1500m` or `2S`.
+fn parse_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (amount, unit) = match value.bytes().last() {
+        Some(unit) if unit.is_ascii_alphabetic() => (&value[..value.len() - 1], Some(unit)),
+        _ => (value, None),
+    };
+
+    if amount.is_empty() || !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount = amount.parse::<u64>().ok()?;
+
+    match unit {
+        None | Some(b'm') => Some(Duration::from_millis(amount)),
+        Some(b'H') => amount.checked_mul(3600).map(Duration::from_secs),
+        Some(b'M') => amount.checked_mul(60).map(Duration::from_secs),
+        Some(b'S') => Some(Duration::from_secs(amount)),
+        Some(b'u') => Some(Duration::from_micros(amount)),
+        Some(b'n') => Some(Duration::from_nanos(amount)),
+        Some(_) => None,
+    }
+}
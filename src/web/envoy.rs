@@ -0,0 +1,83 @@
+use axum::{response::Response, Router};
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    service::Service,
+    Body, Method, Request, StatusCode,
+};
+
+/// The path prefix that Envoy is configured to call, with the path of the request being checked
+/// appended to it.
+pub const ENVOY_PATH_PREFIX: &str = "/envoy";
+
+/// Lists the headers Envoy removes from an allowed request before sending it upstream.
+const HEADERS_TO_REMOVE_HEADER: &str = "x-envoy-auth-headers-to-remove";
+
+/// Checks a request for Envoy's HTTP `ext_authz` filter, by validating it exactly like a request to
+/// `/validate`, with the given router.
+///
+/// Envoy doesn't send `X-Forwarded-*` headers, but calls us with the method of the request being
+/// checked, its path appended to our path prefix, and its host. Those are translated into the
+/// headers a forward auth proxy would have sent, overriding any sent by the client, since Envoy
+/// passes the client's headers along. The audience comes from the host, as configured in `hosts`.
+///
+/// Envoy only allows requests that get a `200 OK`, so successful responses are always made one, and
+/// list the given headers for Envoy to remove from the request. Any other response denies the
+/// request, and is passed back to the client as is.
+pub async fn check(
+    mut app: Router,
+    headers_to_remove: Option<HeaderValue>,
+    request: Request<Body>,
+) -> Response {
+    let (parts, body) = request.into_parts();
+
+    // The prefix has already been stripped, leaving the path of the request being checked.
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let forwarded = [
+        ("x-forwarded-method", Some(parts.method.as_str())),
+        ("x-forwarded-uri", Some(path)),
+        (
+            "x-forwarded-host",
+            parts
+                .headers
+                .get(header::HOST)
+                .and_then(|v| v.to_str().ok()),
+        ),
+    ];
+
+    let mut headers = parts.headers.clone();
+    for (name, value) in forwarded {
+        headers.remove(name);
+        if let Some(value) = value.and_then(|value| HeaderValue::from_str(value).ok()) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+
+    // This is a new request, rather than the original one modified, so that nothing the router put
+    // in the extensions of the original, like its path parameters, carries over.
+    let mut validation_request = Request::new(body);
+    *validation_request.method_mut() = Method::GET;
+    *validation_request.uri_mut() = "/validate".parse().expect("path should be a valid URI");
+    *validation_request.headers_mut() = headers;
+
+    // Routers are always ready to handle requests, so there's no need to wait for them to be.
+    let mut response = match app.call(validation_request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    };
+
+    if response.status().is_success() {
+        *response.status_mut() = StatusCode::OK;
+        if let Some(headers_to_remove) = headers_to_remove {
+            response.headers_mut().insert(
+                HeaderName::from_static(HEADERS_TO_REMOVE_HEADER),
+                headers_to_remove,
+            );
+        }
+    }
+
+    response
+}
@@ -0,0 +1,393 @@
+use std::{borrow::Cow, time::Duration};
+
+use axum::{
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{header, StatusCode};
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::PrincipalType, diagnostics, policy::InvalidAudienceReason, redaction::ClaimValue,
+    validation::token::MalformedReason,
+};
+
+/// The header that carries the error code of a rejected validation request.
+pub const ERROR_HEADER: &str = "x-auth-error";
+
+/// Reasons a validation request can be rejected.
+#[derive(Clone, Debug)]
+pub enum AuthError {
+    /// No access token was present on the request.
+    MissingToken,
+
+    /// An access token was present, but it was structurally invalid.
+    MalformedToken(MalformedReason),
+
+    /// The access token could not be parsed or verified.
+    InvalidToken(String),
+
+    /// The access token was verified, but it has expired.
+    ///
+    /// This is kept apart from other invalid tokens, as it's routine for users who were simply away
+    /// for a while, rather than a sign of anything wrong.
+    ExpiredToken(String),
+
+    /// The access token was verified, but it has been revoked, by being listed in Redis.
+    RevokedToken,
+
+    /// The access token was verified, but it has already been presented for the requested
+    /// audience, which only accepts each token once.
+    ReplayedToken,
+
+    /// The access token was signed with a key, identified by the given key ID, that isn't in the
+    /// loaded JWKS data.
+    ///
+    /// This happens for forged tokens, but also when Cloudflare has rotated its keys since the JWKS
+    /// data was last refreshed.
+    UnknownSigningKey(String),
+
+    /// The requested audience is not a valid AUD tag.
+    InvalidAudience(InvalidAudienceReason),
+
+    /// The audience could not be determined from the forwarded host, either because the
+    /// `X-Forwarded-Host` header was missing, or because the host isn't mapped to an audience.
+    UnmappedHost(Option<String>),
+
+    /// The requested audience is not one of the configured audiences.
+    UnknownAudience(String),
+
+    /// The access token was valid, but it has no `jti` claim, which the requested audience requires
+    /// to detect replays.
+    MissingJti,
+
+    /// The access token was valid, but its type is not accepted for the requested audience.
+    TokenTypeNotAllowed(Option<String>),
+
+    /// The access token was valid, but was issued to a kind of principal that's not accepted for
+    /// the requested audience.
+    PrincipalNotAllowed(PrincipalType),
+
+    /// The access token was valid, but the principal isn't a member of any of the groups required
+    /// for the requested audience, which are given.
+    MissingRequiredGroup(Vec<String>),
+
+    /// The access token was valid, but its email isn't on the allowlist for the requested audience.
+    /// The domain of the email, if there is one, is given, so that the email itself isn't logged.
+    EmailNotAllowed(Option<String>),
+
+    /// The access token was valid, but the principal isn't accepted by the rule, identified by its
+    /// path pattern, for the path of the original request.
+    PathNotAllowed(String),
+
+    /// The access token was valid, but the request didn't satisfy the expression for the requested
+    /// audience, for the given reason.
+    ExpressionNotSatisfied(String),
+
+    /// Rules apply to specific paths of the requested audience, but the original request's path,
+    /// from the `X-Forwarded-Uri` header, was missing or invalid.
+    InvalidForwardedUri,
+
+    /// The access token was valid, but was issued for a service token, identified by the given
+    /// client ID, that has no header mappings, while only mapped service tokens are accepted.
+    UnmappedServiceToken(String),
+
+    /// The access token was valid, but Basic auth credentials for the upstream couldn't be built
+    /// from it, for the given reason.
+    BasicAuthUnavailable(String),
+
+    /// The validation request didn't carry the secret shared with the proxies, so it didn't come
+    /// through one of them.
+    InvalidProxySecret,
+
+    /// The access token was valid, but OPA denied the request, for the given reason if it gave one.
+    OpaDenied(Option<String>),
+
+    /// The access token was valid, but OPA couldn't be asked for a decision, for the given reason,
+    /// so the request is denied.
+    OpaUnavailable(String),
+
+    /// JWKS data has not been loaded yet, so no access token can be verified.
+    ///
+    /// This is a transient condition, so clients are told when to retry.
+    NotReady { retry_after: Duration },
+}
+
+impl AuthError {
+    /// Gets a short, stable name for the kind of error.
+    ///
+    /// This is also the error code sent to clients, so it must never change for an existing kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MissingToken => "missing_token",
+            Self::MalformedToken(_) => "malformed_token",
+            Self::InvalidToken(_) => "invalid_token",
+            Self::ExpiredToken(_) => "expired_token",
+            Self::RevokedToken => "revoked_token",
+            Self::ReplayedToken => "replayed_token",
+            Self::UnknownSigningKey(_) => "unknown_key",
+            Self::InvalidAudience(_) => "invalid_audience",
+            Self::UnmappedHost(_) => "unmapped_host",
+            Self::UnknownAudience(_) => "unknown_audience",
+            Self::MissingJti => "missing_jti",
+            Self::TokenTypeNotAllowed(_) => "token_type_not_allowed",
+            Self::PrincipalNotAllowed(_) => "principal_not_allowed",
+            Self::MissingRequiredGroup(_) => "missing_required_group",
+            Self::EmailNotAllowed(_) => "email_not_allowed",
+            Self::PathNotAllowed(_) => "path_not_allowed",
+            Self::ExpressionNotSatisfied(_) => "expression_not_satisfied",
+            Self::InvalidForwardedUri => "invalid_forwarded_uri",
+            Self::UnmappedServiceToken(_) => "unmapped_service_token",
+            Self::BasicAuthUnavailable(_) => "basic_auth_unavailable",
+            Self::InvalidProxySecret => "invalid_proxy_secret",
+            Self::OpaDenied(_) => "opa_denied",
+            Self::OpaUnavailable(_) => "opa_unavailable",
+            Self::NotReady { .. } => "not_ready",
+        }
+    }
+
+    /// Gets a short, human-readable description of the kind of error.
+    ///
+    /// This is sent to clients, so it never includes any details of the request, the token or the
+    /// configuration. Those are only logged.
+    pub fn detail(&self) -> &'static str {
+        match self {
+            Self::MissingToken => "The request has no access token.",
+            Self::MalformedToken(_) => "The access token is not a well-formed JWT.",
+            Self::InvalidToken(_) => "The access token could not be verified.",
+            Self::ExpiredToken(_) => "The access token has expired.",
+            Self::RevokedToken => "The access token has been revoked.",
+            Self::ReplayedToken => "The access token has already been used.",
+            Self::UnknownSigningKey(_) => "The access token was signed with an unknown key.",
+            Self::InvalidAudience(_) => "The requested audience is not a valid AUD tag.",
+            Self::UnmappedHost(_) => "The forwarded host is missing or has no audience.",
+            Self::UnknownAudience(_) => "The requested audience is not configured.",
+            Self::MissingJti => "The access token has no ID, which the audience requires.",
+            Self::TokenTypeNotAllowed(_) => {
+                "The access token's type is not allowed for the audience."
+            }
+            Self::PrincipalNotAllowed(_) => {
+                "The access token's kind of principal is not allowed for the audience."
+            }
+            Self::MissingRequiredGroup(_) => {
+                "The principal is not a member of any group required for the audience."
+            }
+            Self::EmailNotAllowed(_) => "The principal's email is not allowed for the audience.",
+            Self::PathNotAllowed(_) => "The principal is not allowed to access the path.",
+            Self::ExpressionNotSatisfied(_) => {
+                "The request does not satisfy the expression for the audience."
+            }
+            Self::InvalidForwardedUri => "The forwarded URI is missing or invalid.",
+            Self::UnmappedServiceToken(_) => "The service token has no header mappings.",
+            Self::BasicAuthUnavailable(_) => {
+                "Basic auth credentials could not be built from the access token."
+            }
+            Self::InvalidProxySecret => "The request did not come through a trusted proxy.",
+            Self::OpaDenied(_) => "The request was denied by policy.",
+            Self::OpaUnavailable(_) => "No policy decision could be made for the request.",
+            Self::NotReady { .. } => "Access tokens can't be verified yet.",
+        }
+    }
+
+    /// Gets the error code header and JSON body describing the error, to respond with.
+    ///
+    /// The error itself is added to the response's extensions, for rendering error pages.
+    pub(super) fn details(&self) -> impl IntoResponse {
+        (
+            [(ERROR_HEADER, self.kind())],
+            Extension(self.clone()),
+            Json(json!({ "error": self.kind(), "detail": self.detail() })),
+        )
+    }
+
+    /// Whether or not the error is about the access token itself, rather than what it grants.
+    ///
+    /// Logging in again fixes these, just like for a missing token.
+    pub fn is_invalid_token(&self) -> bool {
+        matches!(
+            self,
+            Self::MalformedToken(_)
+                | Self::InvalidToken(_)
+                | Self::ExpiredToken(_)
+                | Self::RevokedToken
+                | Self::ReplayedToken
+                | Self::UnknownSigningKey(_)
+        )
+    }
+
+    /// Gets the status code to respond with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::MissingToken
+            | Self::MalformedToken(_)
+            | Self::InvalidToken(_)
+            | Self::ExpiredToken(_)
+            | Self::RevokedToken
+            | Self::ReplayedToken
+            | Self::UnknownSigningKey(_) => StatusCode::UNAUTHORIZED,
+            Self::InvalidAudience(_) | Self::InvalidForwardedUri => StatusCode::BAD_REQUEST,
+            Self::UnmappedHost(_) | Self::UnknownAudience(_) => StatusCode::NOT_FOUND,
+            Self::MissingJti
+            | Self::TokenTypeNotAllowed(_)
+            | Self::PrincipalNotAllowed(_)
+            | Self::MissingRequiredGroup(_)
+            | Self::EmailNotAllowed(_)
+            | Self::PathNotAllowed(_)
+            | Self::ExpressionNotSatisfied(_)
+            | Self::UnmappedServiceToken(_)
+            | Self::BasicAuthUnavailable(_)
+            | Self::InvalidProxySecret
+            | Self::OpaDenied(_) => StatusCode::FORBIDDEN,
+            Self::OpaUnavailable(_) | Self::NotReady { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Logs the error, and records it for diagnostics.
+    pub(super) fn log(&self) {
+        match self {
+            Self::MissingToken => info!("Validation request made without an access token."),
+            Self::MalformedToken(reason) => info!(
+                reason = reason.as_str(),
+                "Rejected structurally invalid access token."
+            ),
+            Self::InvalidToken(e) => error!(error = %e, "Failed to verify access token."),
+            Self::ExpiredToken(e) => info!(error = %e, "Rejected expired access token."),
+            Self::RevokedToken => warn!("Rejected revoked access token."),
+            Self::ReplayedToken => warn!("Rejected replayed access token."),
+            Self::UnknownSigningKey(key_id) => warn!(
+                key_id = key_id.as_str(),
+                "Rejected access token signed with an unknown key."
+            ),
+            Self::InvalidAudience(reason) => info!(
+                reason = reason.as_str(),
+                "Rejected validation request with an invalid audience."
+            ),
+            Self::UnmappedHost(host) => warn!(
+                host = host.as_deref().unwrap_or("none"),
+                "Validation request made for a host without a mapped audience."
+            ),
+            Self::UnknownAudience(audience) => warn!(
+                audience = audience.as_str(),
+                "Validation request made for an unknown audience."
+            ),
+            Self::MissingJti => info!(
+                "Rejected access token without a `jti` claim, which the audience requires."
+            ),
+            Self::TokenTypeNotAllowed(token_type) => info!(
+                token_type = token_type.as_deref().unwrap_or("none"),
+                "Rejected access token with a type not allowed for the audience."
+            ),
+            Self::PrincipalNotAllowed(principal) => info!(
+                principal = principal.as_str(),
+                "Rejected access token issued to a kind of principal not allowed for the audience."
+            ),
+            Self::MissingRequiredGroup(required_groups) => info!(
+                required_groups = required_groups.join(",").as_str(),
+                "Rejected access token for a principal without any group required for the audience."
+            ),
+            Self::EmailNotAllowed(domain) => info!(
+                email_domain = domain.as_deref().unwrap_or("none"),
+                "Rejected access token with an email not allowed for the audience."
+            ),
+            Self::PathNotAllowed(pattern) => info!(
+                path_rule = pattern.as_str(),
+                "Rejected access token for a principal not allowed by the rule for the path."
+            ),
+            Self::ExpressionNotSatisfied(reason) => info!(
+                reason = reason.as_str(),
+                "Rejected access token for a request not satisfying the expression for the audience."
+            ),
+            Self::InvalidForwardedUri => warn!(
+                "Validation request made without a valid `X-Forwarded-Uri` header, which path rules \
+                 require."
+            ),
+            Self::UnmappedServiceToken(service_token_id) => warn!(
+                service_token_id = service_token_id.as_str(),
+                "Rejected access token for a service token without header mappings."
+            ),
+            Self::BasicAuthUnavailable(reason) => warn!(
+                reason = reason.as_str(),
+                "Rejected access token that Basic auth credentials couldn't be built from."
+            ),
+            Self::InvalidProxySecret => {
+                warn!("Rejected validation request without a valid proxy secret.")
+            }
+            Self::OpaDenied(reason) => info!(
+                reason = reason.as_deref().unwrap_or("none"),
+                "Rejected access token for a request denied by OPA."
+            ),
+            Self::OpaUnavailable(e) => error!(
+                error = e.as_str(),
+                "Failed to get a decision from OPA. Denying the request."
+            ),
+            // This is logged as a warning, rather than an error, as it's expected during startup
+            // and shouldn't count towards alerts meant for genuine failures.
+            Self::NotReady { .. } => {
+                warn!("Validation request made before JWKS data was refreshed.")
+            }
+        }
+
+        // Missing tokens are routine, so they'd only drown out the errors worth looking at.
+        if !matches!(self, Self::MissingToken) {
+            diagnostics::record_error(self.kind(), self.diagnostic_detail());
+        }
+    }
+
+    /// Gets the detail of the error recorded for diagnostics.
+    ///
+    /// Claim values are redacted by the configured rules, as the recorded errors are dumped on
+    /// `SIGUSR1`.
+    fn diagnostic_detail(&self) -> Cow<'_, str> {
+        let claim =
+            |name: &'static str, value: &str| Cow::Owned(ClaimValue::new(name, value).to_string());
+        match self {
+            Self::MissingToken => Cow::Borrowed("no token"),
+            Self::MalformedToken(reason) => Cow::Borrowed(reason.as_str()),
+            Self::InvalidToken(e) | Self::ExpiredToken(e) => Cow::Borrowed(e.as_str()),
+            Self::RevokedToken => Cow::Borrowed("token revoked"),
+            Self::ReplayedToken => Cow::Borrowed("token replayed"),
+            Self::UnknownSigningKey(key_id) => Cow::Borrowed(key_id.as_str()),
+            Self::InvalidAudience(reason) => Cow::Borrowed(reason.as_str()),
+            Self::UnmappedHost(host) => Cow::Borrowed(host.as_deref().unwrap_or("none")),
+            Self::UnknownAudience(audience) => Cow::Borrowed(audience.as_str()),
+            Self::MissingJti => Cow::Borrowed("no jti claim"),
+            Self::TokenTypeNotAllowed(token_type) => {
+                Cow::Borrowed(token_type.as_deref().unwrap_or("none"))
+            }
+            Self::PrincipalNotAllowed(principal) => Cow::Borrowed(principal.as_str()),
+            Self::MissingRequiredGroup(required_groups) => {
+                claim("groups", &required_groups.join(","))
+            }
+            Self::EmailNotAllowed(Some(domain)) => claim("email", domain),
+            Self::EmailNotAllowed(None) => Cow::Borrowed("none"),
+            Self::PathNotAllowed(pattern) => Cow::Borrowed(pattern.as_str()),
+            Self::ExpressionNotSatisfied(reason) => Cow::Borrowed(reason.as_str()),
+            Self::InvalidForwardedUri => Cow::Borrowed("missing or invalid X-Forwarded-Uri"),
+            Self::UnmappedServiceToken(service_token_id) => claim("common_name", service_token_id),
+            Self::BasicAuthUnavailable(reason) => Cow::Borrowed(reason.as_str()),
+            Self::InvalidProxySecret => Cow::Borrowed("proxy secret missing or wrong"),
+            Self::OpaDenied(reason) => Cow::Borrowed(reason.as_deref().unwrap_or("none")),
+            Self::OpaUnavailable(e) => Cow::Borrowed(e.as_str()),
+            Self::NotReady { .. } => Cow::Borrowed("JWKS data not loaded"),
+        }
+    }
+
+    /// Builds the response for the error, without logging it.
+    pub(super) fn response(&self) -> Response {
+        let mut response = (self.status_code(), self.details()).into_response();
+        if let Self::NotReady { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.as_secs().into());
+        }
+        response
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        self.log();
+        self.response()
+    }
+}
@@ -0,0 +1,187 @@
+use std::{collections::HashMap, ffi::OsStr, sync::Arc};
+
+use axum::{
+    body::{boxed, Full},
+    middleware::Next,
+    response::Response,
+};
+use hyper::{
+    header::{self, HeaderValue},
+    Request, StatusCode,
+};
+
+use super::error::AuthError;
+use crate::config::ErrorPageConfig;
+
+/// The audience a validation request was made for, as added to its response, so error pages can
+/// say which application access was denied to.
+#[derive(Clone)]
+pub(super) struct RequestedAudience(pub String);
+
+/// How values are escaped when rendered into a template.
+enum Escaping {
+    Html,
+    Json,
+    None,
+}
+
+/// A template for the responses with a given status code.
+struct ErrorPage {
+    template: String,
+    content_type: HeaderValue,
+    escaping: Escaping,
+}
+
+/// Templates for the bodies of rejected validation requests, keyed by status code, so users
+/// behind a proxy that passes rejections on to them see a proper page rather than a bare error.
+///
+/// Templates can refer to `{{ status }}`, `{{ error }}`, `{{ detail }}` and `{{ audience }}`, which
+/// are the status code, the error code and its description, and the audience the request was made
+/// for, or empty if any of them isn't known. Values are escaped for HTML and JSON templates.
+pub struct ErrorPages {
+    pages: HashMap<StatusCode, ErrorPage>,
+}
+
+impl ErrorPages {
+    /// Loads the templates for the given status codes.
+    ///
+    /// Unless given, the content type of each template is inferred from the extension of its file,
+    /// which is JSON for `.json` files, and HTML for anything else.
+    pub fn from_config(config: &HashMap<u16, ErrorPageConfig>) -> Result<Self, String> {
+        let mut pages = HashMap::with_capacity(config.len());
+        for (status, page) in config {
+            let status = StatusCode::from_u16(*status)
+                .map_err(|_| format!("Error page status code '{}' is invalid.", status))?;
+            let template = std::fs::read_to_string(&page.path).map_err(|e| {
+                format!(
+                    "Failed to read error page template '{}': {}",
+                    page.path.display(),
+                    e
+                )
+            })?;
+
+            let is_json = page.path.extension() == Some(OsStr::new("json"));
+            let content_type = match &page.content_type {
+                Some(content_type) => content_type.as_str(),
+                None if is_json => "application/json",
+                None => "text/html; charset=utf-8",
+            };
+            let escaping = if content_type.contains("html") {
+                Escaping::Html
+            } else if content_type.contains("json") {
+                Escaping::Json
+            } else {
+                Escaping::None
+            };
+            let content_type = HeaderValue::from_str(content_type).map_err(|_| {
+                format!(
+                    "Error page content type '{}' is not a valid header value.",
+                    content_type
+                )
+            })?;
+
+            pages.insert(
+                status,
+                ErrorPage {
+                    template,
+                    content_type,
+                    escaping,
+                },
+            );
+        }
+
+        Ok(Self { pages })
+    }
+}
+
+/// Replaces the body of validation responses with the configured template for their status code,
+/// if there is one.
+///
+/// This runs before any adaptation for nginx, so templates are picked by the status code the
+/// request was actually rejected with.
+pub(super) async fn render<B>(request: Request<B>, next: Next<B>) -> Response {
+    let error_pages = request
+        .extensions()
+        .get::<Option<Arc<ErrorPages>>>()
+        .cloned()
+        .flatten();
+    let response = next.run(request).await;
+    let page = match &error_pages {
+        Some(error_pages) => error_pages.pages.get(&response.status()),
+        None => None,
+    };
+    let page = match page {
+        Some(page) => page,
+        None => return response,
+    };
+
+    let (mut parts, _) = response.into_parts();
+    let error = parts.extensions.get::<AuthError>();
+    let audience = parts.extensions.get::<RequestedAudience>();
+    let variables = [
+        ("status", parts.status.as_str()),
+        ("error", error.map_or("", AuthError::kind)),
+        ("detail", error.map_or("", AuthError::detail)),
+        (
+            "audience",
+            audience.map_or("", |audience| audience.0.as_str()),
+        ),
+    ];
+    let body = page.render(&variables);
+
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, page.content_type.clone());
+    Response::from_parts(parts, boxed(Full::from(body)))
+}
+
+impl ErrorPage {
+    /// Renders the template with the given variables, leaving unknown ones as they are.
+    fn render(&self, variables: &[(&str, &str)]) -> String {
+        let mut rendered = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            let after_start = &rest[start + 2..];
+            let end = match after_start.find("}}") {
+                Some(end) => end,
+                None => break,
+            };
+
+            rendered.push_str(&rest[..start]);
+            let name = after_start[..end].trim();
+            match variables.iter().find(|(variable, _)| *variable == name) {
+                Some((_, value)) => self.escaping.push_escaped(&mut rendered, value),
+                None => rendered.push_str(&rest[start..start + end + 4]),
+            }
+            rest = &after_start[end + 2..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+impl Escaping {
+    fn push_escaped(&self, rendered: &mut String, value: &str) {
+        match self {
+            Self::Html => {
+                for c in value.chars() {
+                    match c {
+                        '&' => rendered.push_str("&amp;"),
+                        '<' => rendered.push_str("&lt;"),
+                        '>' => rendered.push_str("&gt;"),
+                        '"' => rendered.push_str("&quot;"),
+                        '\'' => rendered.push_str("&#39;"),
+                        c => rendered.push(c),
+                    }
+                }
+            }
+            Self::Json => {
+                // Values go inside the quotes of a JSON string, so only their contents are added.
+                if let Ok(quoted) = serde_json::to_string(value) {
+                    rendered.push_str(&quoted[1..quoted.len() - 1]);
+                }
+            }
+            Self::None => rendered.push_str(value),
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::SocketAddr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::Router;
+use hyper::{
+    body::to_bytes,
+    header::{self, HeaderName, HeaderValue},
+    Body, HeaderMap, Request, Response, StatusCode, Uri,
+};
+use tokio::{sync::watch, time::timeout};
+use tonic::{
+    codec::ProstCodec,
+    codegen::{empty_body, BoxFuture, Service, StdError},
+    server::{Grpc, NamedService, UnaryService},
+    transport::Server,
+};
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use self::proto::{
+    CheckRequest, CheckResponse, DeniedHttpResponse, HeaderValueOption, HttpResponse, HttpStatus,
+    OkHttpResponse, Status,
+};
+use super::shutdown_requested;
+
+mod proto;
+
+/// The context extension that names the audience to validate requests against.
+///
+/// Without it, the audience is resolved from the host of the request, as with `/validate`.
+const AUDIENCE_EXTENSION: &str = "audience";
+
+/// gRPC metadata that describes the check call itself, rather than the request being checked.
+const GRPC_METADATA: &[&str] = &["content-type", "te", "user-agent"];
+
+/// Response headers that describe the body of the validation response, rather than the identity.
+const RESPONSE_ONLY_HEADERS: &[HeaderName] = &[header::CONTENT_LENGTH, header::CONTENT_TYPE];
+
+/// Serves Envoy's `envoy.service.auth.v3.Authorization` gRPC service, for Envoy's `ext_authz`
+/// filter, using the given router.
+///
+/// Each check is translated into a validation request, from the attributes of the request being
+/// checked, and run through the router exactly like a request to `/validate`, so it's validated,
+/// authorized, enriched and recorded the same way.
+pub async fn run_ext_authz_endpoint(
+    listen_address: SocketAddr,
+    app: Router,
+    shutdown: watch::Receiver<bool>,
+    shutdown_timeout: Duration,
+) -> Result<(), String> {
+    info!("Listening for ext_authz checks on {}.", listen_address);
+
+    let server = Server::builder()
+        .add_service(AuthorizationServer { app })
+        .serve_with_shutdown(listen_address, shutdown_requested(shutdown.clone()));
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => {
+            return result.map_err(|e| format!("Failed to serve ext_authz: {}", e));
+        }
+        _ = shutdown_requested(shutdown) => {}
+    }
+
+    match timeout(shutdown_timeout, server).await {
+        Ok(result) => result.map_err(|e| format!("Failed to serve ext_authz: {}", e)),
+        Err(_) => {
+            warn!("Timed out waiting for in-flight ext_authz checks to finish. Dropping them.");
+            Ok(())
+        }
+    }
+}
+
+/// The gRPC service, routing calls to `Check`, its only method.
+#[derive(Clone)]
+struct AuthorizationServer {
+    app: Router,
+}
+
+impl NamedService for AuthorizationServer {
+    const NAME: &'static str = "envoy.service.auth.v3.Authorization";
+}
+
+impl<B> Service<Request<B>> for AuthorizationServer
+where
+    B: hyper::body::HttpBody + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        if request.uri().path() != "/envoy.service.auth.v3.Authorization/Check" {
+            // As for any other unknown method, respond with `UNIMPLEMENTED`.
+            return Box::pin(async {
+                Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("grpc-status", "12")
+                    .header(header::CONTENT_TYPE, "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            });
+        }
+
+        let check = Check {
+            app: self.app.clone(),
+        };
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::default());
+            Ok(grpc.unary(check, request).await)
+        })
+    }
+}
+
+/// Handles a single call to `Check`.
+struct Check {
+    app: Router,
+}
+
+impl UnaryService<CheckRequest> for Check {
+    type Response = CheckResponse;
+    type Future = BoxFuture<tonic::Response<CheckResponse>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<CheckRequest>) -> Self::Future {
+        let app = self.app.clone();
+        Box::pin(async move {
+            let validation_request =
+                validation_request(request).map_err(tonic::Status::invalid_argument)?;
+            let response = match app.oneshot(validation_request).await {
+                Ok(response) => response,
+                Err(infallible) => match infallible {},
+            };
+
+            Ok(tonic::Response::new(check_response(response).await))
+        })
+    }
+}
+
+/// Builds the validation request for the request being checked.
+///
+/// The proxy's `X-Forwarded-*` headers are set from the attributes of the request, overriding any
+/// sent by the client. gRPC metadata, such as the proxy secret, if Envoy is configured to send it as
+/// initial metadata, overrides the headers of the request.
+fn validation_request(request: tonic::Request<CheckRequest>) -> Result<Request<Body>, String> {
+    let metadata = request.metadata().clone().into_headers();
+    let attributes = request
+        .into_inner()
+        .attributes
+        .ok_or_else(|| "missing request attributes".to_string())?;
+    let http = attributes
+        .request
+        .and_then(|request| request.http)
+        .ok_or_else(|| "missing HTTP request attributes".to_string())?;
+
+    let uri = match attributes.context_extensions.get(AUDIENCE_EXTENSION) {
+        Some(audience) => format!("/validate/{}", audience),
+        None => "/validate".to_string(),
+    };
+    let uri = uri
+        .parse::<Uri>()
+        .map_err(|_| "invalid audience context extension".to_string())?;
+
+    let mut headers = HeaderMap::new();
+    let header_map = http.header_map.map(|header_map| header_map.headers);
+    let request_headers = header_map.into_iter().flatten().map(|header| {
+        let value = if header.raw_value.is_empty() {
+            header.value.into_bytes()
+        } else {
+            header.raw_value
+        };
+        (header.key, value)
+    });
+    let request_headers = request_headers.chain(
+        http.headers
+            .into_iter()
+            .map(|(key, value)| (key, value.into_bytes())),
+    );
+    for (key, value) in request_headers {
+        // Pseudo-headers, like `:path`, are covered by the attributes below.
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_bytes(&value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+
+    let forwarded = [
+        ("x-forwarded-method", http.method),
+        ("x-forwarded-proto", http.scheme),
+        ("x-forwarded-host", http.host),
+        ("x-forwarded-uri", http.path),
+    ];
+    for (name, value) in forwarded {
+        headers.remove(name);
+        match HeaderValue::from_str(&value) {
+            Ok(value) if !value.is_empty() => {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+            _ => {}
+        }
+    }
+
+    for (name, value) in metadata.iter() {
+        if !GRPC_METADATA.contains(&name.as_str()) && !name.as_str().starts_with("grpc-") {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+
+    let mut validation_request = Request::get(uri)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    *validation_request.headers_mut() = headers;
+    Ok(validation_request)
+}
+
+/// Builds the check response for the given validation response.
+///
+/// Successful responses allow the request, with their identity headers added to it, which are also
+/// set as dynamic metadata, except for sensitive ones like the access token. Any other response is
+/// passed back to the client as is, to deny the request, with its body, which Envoy sets the length
+/// of itself.
+async fn check_response(response: axum::response::Response) -> CheckResponse {
+    let (parts, body) = response.into_parts();
+
+    if parts.status.is_success() {
+        let headers = parts
+            .headers
+            .iter()
+            .filter(|(name, _)| !RESPONSE_ONLY_HEADERS.contains(name))
+            .map(|(name, value)| HeaderValueOption::overwrite(name.as_str(), value.as_bytes()))
+            .collect();
+        let dynamic_metadata = parts
+            .headers
+            .iter()
+            .filter(|(name, value)| !RESPONSE_ONLY_HEADERS.contains(name) && !value.is_sensitive())
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?.to_string();
+                Some((
+                    name.as_str().to_string(),
+                    prost_types::Value {
+                        kind: Some(prost_types::value::Kind::StringValue(value)),
+                    },
+                ))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        return CheckResponse {
+            status: Some(Status {
+                code: tonic::Code::Ok as i32,
+                message: String::new(),
+            }),
+            http_response: Some(HttpResponse::OkResponse(OkHttpResponse { headers })),
+            dynamic_metadata: Some(prost_types::Struct {
+                fields: dynamic_metadata,
+            }),
+        };
+    }
+
+    let body = match to_bytes(body).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(e) => {
+            debug!(error = %e, "Failed to buffer validation response body.");
+            String::new()
+        }
+    };
+
+    let headers = parts
+        .headers
+        .iter()
+        .filter(|(name, _)| *name != header::CONTENT_LENGTH)
+        .map(|(name, value)| HeaderValueOption::overwrite(name.as_str(), value.as_bytes()))
+        .collect();
+    let code = match parts.status {
+        StatusCode::UNAUTHORIZED => tonic::Code::Unauthenticated,
+        StatusCode::BAD_REQUEST => tonic::Code::InvalidArgument,
+        StatusCode::SERVICE_UNAVAILABLE => tonic::Code::Unavailable,
+        _ => tonic::Code::PermissionDenied,
+    };
+    CheckResponse {
+        status: Some(Status {
+            code: code as i32,
+            message: parts
+                .status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+        }),
+        http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+            status: Some(HttpStatus {
+                code: i32::from(parts.status.as_u16()),
+            }),
+            headers,
+            body,
+        })),
+        dynamic_metadata: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::web::insert_oauth2_proxy_headers;
+
+    #[tokio::test]
+    async fn oauth2_proxy_token_is_not_dynamic_metadata() {
+        let mut headers = HeaderMap::new();
+        insert_oauth2_proxy_headers(
+            &mut headers,
+            "user-1",
+            Some("user@example.com"),
+            &[],
+            "header.payload.signature",
+        );
+        let response = check_response((StatusCode::OK, headers).into_response()).await;
+
+        // The token is still passed upstream, as it would be by oauth2-proxy.
+        let allowed = match response.http_response {
+            Some(HttpResponse::OkResponse(allowed)) => allowed,
+            _ => panic!("check should be allowed"),
+        };
+        let allowed_headers = allowed
+            .headers
+            .into_iter()
+            .filter_map(|header| header.header)
+            .map(|header| (header.key, header.value))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(
+            allowed_headers.get("authorization").map(String::as_str),
+            Some("Bearer header.payload.signature")
+        );
+
+        let metadata = response.dynamic_metadata.unwrap().fields;
+        assert!(metadata.contains_key("x-auth-request-user"));
+        assert!(!metadata.contains_key("authorization"));
+    }
+}
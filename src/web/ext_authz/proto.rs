@@ -0,0 +1,168 @@
+//! The messages of Envoy's `envoy.service.auth.v3.Authorization` service.
+//!
+//! Only the fields we read or set are declared, with the same tags as upstream, so the rest are
+//! skipped when decoding, like any other unknown field.
+
+use std::collections::HashMap;
+
+/// `envoy.service.auth.v3.CheckRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckRequest {
+    #[prost(message, optional, tag = "1")]
+    pub attributes: Option<AttributeContext>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttributeContext {
+    #[prost(message, optional, tag = "4")]
+    pub request: Option<Request>,
+
+    /// Set per route in Envoy, to pass things like the audience to check requests against.
+    #[prost(map = "string, string", tag = "10")]
+    pub context_extensions: HashMap<String, String>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext.Request`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Request {
+    #[prost(message, optional, tag = "2")]
+    pub http: Option<HttpRequest>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext.HttpRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpRequest {
+    #[prost(string, tag = "2")]
+    pub method: String,
+
+    /// The request headers, with the values of repeated headers joined by commas.
+    #[prost(map = "string, string", tag = "3")]
+    pub headers: HashMap<String, String>,
+
+    /// The path of the request, including its query string.
+    #[prost(string, tag = "4")]
+    pub path: String,
+    #[prost(string, tag = "5")]
+    pub host: String,
+    #[prost(string, tag = "6")]
+    pub scheme: String,
+
+    /// The request headers, as sent by Envoy instead of `headers` if configured to.
+    #[prost(message, optional, tag = "13")]
+    pub header_map: Option<HeaderMap>,
+}
+
+/// `envoy.config.core.v3.HeaderMap`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderMap {
+    #[prost(message, repeated, tag = "1")]
+    pub headers: Vec<HeaderValue>,
+}
+
+/// `envoy.config.core.v3.HeaderValue`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub raw_value: Vec<u8>,
+}
+
+/// `envoy.config.core.v3.HeaderValueOption`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValueOption {
+    #[prost(message, optional, tag = "1")]
+    pub header: Option<HeaderValue>,
+
+    /// Older versions of Envoy only look at this, rather than `append_action`.
+    #[prost(message, optional, tag = "2")]
+    pub append: Option<bool>,
+    #[prost(enumeration = "HeaderAppendAction", tag = "3")]
+    pub append_action: i32,
+}
+
+impl HeaderValueOption {
+    /// A header that replaces any other value the header has.
+    pub fn overwrite(key: &str, value: &[u8]) -> Self {
+        Self {
+            header: Some(HeaderValue {
+                key: key.to_string(),
+                value: String::from_utf8_lossy(value).into_owned(),
+                raw_value: Vec::new(),
+            }),
+            append: Some(false),
+            append_action: HeaderAppendAction::OverwriteIfExistsOrAdd as i32,
+        }
+    }
+}
+
+/// `envoy.config.core.v3.HeaderValueOption.HeaderAppendAction`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, prost::Enumeration)]
+#[repr(i32)]
+pub enum HeaderAppendAction {
+    AppendIfExistsOrAdd = 0,
+    AddIfAbsent = 1,
+    OverwriteIfExistsOrAdd = 2,
+    OverwriteIfExists = 3,
+}
+
+/// `envoy.service.auth.v3.CheckResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckResponse {
+    #[prost(message, optional, tag = "1")]
+    pub status: Option<Status>,
+    #[prost(oneof = "HttpResponse", tags = "2, 3")]
+    pub http_response: Option<HttpResponse>,
+
+    /// Made available to Envoy's other filters, under the `envoy.filters.http.ext_authz` namespace.
+    #[prost(message, optional, tag = "4")]
+    pub dynamic_metadata: Option<prost_types::Struct>,
+}
+
+/// The `http_response` of `envoy.service.auth.v3.CheckResponse`.
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum HttpResponse {
+    #[prost(message, tag = "2")]
+    DeniedResponse(DeniedHttpResponse),
+    #[prost(message, tag = "3")]
+    OkResponse(OkHttpResponse),
+}
+
+/// `envoy.service.auth.v3.DeniedHttpResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeniedHttpResponse {
+    #[prost(message, optional, tag = "1")]
+    pub status: Option<HttpStatus>,
+    #[prost(message, repeated, tag = "2")]
+    pub headers: Vec<HeaderValueOption>,
+    #[prost(string, tag = "3")]
+    pub body: String,
+}
+
+/// `envoy.service.auth.v3.OkHttpResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OkHttpResponse {
+    /// Headers to add to the request before it's sent upstream.
+    #[prost(message, repeated, tag = "2")]
+    pub headers: Vec<HeaderValueOption>,
+}
+
+/// `envoy.type.v3.HttpStatus`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpStatus {
+    /// The status code, which is only valid if it's a known one, and never zero.
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+}
+
+/// `google.rpc.Status`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
@@ -0,0 +1,446 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{rejection::PathRejection, FromRequest, Path, RequestParts},
+};
+
+use tracing::debug;
+
+use super::error::AuthError;
+use crate::{
+    config::{BearerMode, Config, CredentialMode},
+    policy::{normalize_audience, AudiencePolicyStore, InvalidAudienceReason},
+    telemetry,
+    validation::token::{CloudflareAccessOIDCAccessToken, MalformedReason},
+};
+
+/// Where a credential was taken from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CredentialSource {
+    /// The configured token header.
+    Header,
+
+    /// A standard `Authorization: Bearer` header.
+    Bearer,
+
+    /// The `CF_Authorization` cookie.
+    Cookie,
+}
+
+impl CredentialSource {
+    /// Gets a short, stable identifier for this source, suitable for logs and metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Header => "header",
+            Self::Bearer => "bearer",
+            Self::Cookie => "cookie",
+        }
+    }
+}
+
+/// An access token presented with a validation request.
+pub struct Credential {
+    pub source: CredentialSource,
+    pub token: CloudflareAccessOIDCAccessToken,
+}
+
+/// Extracts the Cloudflare Access tokens to validate from a validation request.
+///
+/// Tokens are taken from the configured token header (`Cf-Access-Jwt-Assertion` by default), an
+/// `Authorization: Bearer` header if enabled, and the `CF_Authorization` cookie. Depending on the
+/// configured credential mode, either only the preferred one is used, or all of them are, in the
+/// order they should be tried.
+///
+/// Unlike `TypedHeader`, rejections are surfaced as [`AuthError`], so a missing or malformed token is
+/// handled, logged, and responded to the same way as every other reason a request can be rejected.
+pub struct AccessTokens(pub Vec<Credential>);
+
+#[async_trait]
+impl<B> FromRequest<B> for AccessTokens
+where
+    B: Send,
+{
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let config = req
+            .extensions()
+            .get::<Arc<Config>>()
+            .expect("configuration should always be present");
+
+        // When trying every credential, a malformed one is only skipped, since any of the others
+        // may still validate. It's only reported if there's nothing else to try.
+        let try_all = config.credential_mode == CredentialMode::TryAll;
+        let mut malformed = None;
+
+        let header = candidate(
+            CredentialSource::Header,
+            CloudflareAccessOIDCAccessToken::from_header(req.headers(), &config.token_header),
+            try_all,
+            &mut malformed,
+        )?;
+        let bearer = match config.bearer_mode {
+            BearerMode::Disabled => None,
+            BearerMode::AfterHeader | BearerMode::BeforeHeader => candidate(
+                CredentialSource::Bearer,
+                CloudflareAccessOIDCAccessToken::from_bearer_authorization(req.headers()),
+                try_all,
+                &mut malformed,
+            )?,
+        };
+        let cookie =
+            CloudflareAccessOIDCAccessToken::from_cookies(req.headers()).map(|token| Credential {
+                source: CredentialSource::Cookie,
+                token,
+            });
+
+        // Both headers count as the token header as far as the credential mode is concerned, in
+        // the order given by the bearer mode.
+        let (first_header, second_header) = match config.bearer_mode {
+            BearerMode::BeforeHeader => (bearer, header),
+            BearerMode::Disabled | BearerMode::AfterHeader => (header, bearer),
+        };
+        let candidates = match config.credential_mode {
+            CredentialMode::HeaderFirst | CredentialMode::TryAll => {
+                [first_header, second_header, cookie]
+            }
+            CredentialMode::CookieFirst => [cookie, first_header, second_header],
+        };
+
+        let credentials = match config.credential_mode {
+            CredentialMode::HeaderFirst | CredentialMode::CookieFirst => {
+                candidates.into_iter().flatten().take(1).collect()
+            }
+            CredentialMode::TryAll => candidates.into_iter().flatten().collect::<Vec<_>>(),
+        };
+
+        if credentials.is_empty() {
+            Err(malformed.unwrap_or(AuthError::MissingToken))
+        } else {
+            Ok(Self(credentials))
+        }
+    }
+}
+
+/// Takes the credential presented in the given source, if there is one.
+///
+/// If it's malformed, and every credential is to be tried, it's recorded and skipped, with the
+/// first such error kept in `malformed`. Otherwise, the request is rejected outright.
+fn candidate(
+    source: CredentialSource,
+    token: Result<Option<CloudflareAccessOIDCAccessToken>, MalformedReason>,
+    try_all: bool,
+    malformed: &mut Option<AuthError>,
+) -> Result<Option<Credential>, AuthError> {
+    match token {
+        Ok(token) => Ok(token.map(|token| Credential { source, token })),
+        Err(reason) if try_all => {
+            let e = AuthError::MalformedToken(reason);
+            debug!(
+                source = source.as_str(),
+                error = e.kind(),
+                "Rejected credential."
+            );
+            telemetry::record_credential(source.as_str(), e.kind());
+            malformed.get_or_insert(e);
+            Ok(None)
+        }
+        Err(reason) => Err(AuthError::MalformedToken(reason)),
+    }
+}
+
+/// Extracts the normalized audience of a validation request.
+///
+/// The audience comes from the `:audience` path segment if the route has one, or the `aud` query
+/// parameter, for proxies that can only append query parameters to the validation URL. If neither
+/// is given, it comes from the configured mapping of the host in the `X-Forwarded-Host` header.
+///
+/// The path segment and query parameter can both be given, but only if they agree, so there's never
+/// any doubt about which audience a request was validated against.
+///
+/// Anything that isn't plausibly an AUD tag is rejected up front, so that junk never makes its way
+/// into verifiers, logs, or metrics.
+pub struct Audience(pub String);
+
+#[async_trait]
+impl<B> FromRequest<B> for Audience
+where
+    B: Send,
+{
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        // Both `/validate` and `/validate/:audience` are matched routes, so the path parameters are
+        // always present, just empty for the former.
+        let path_audience = match Path::<HashMap<String, String>>::from_request(req).await {
+            Ok(Path(mut params)) => match params.remove("audience") {
+                Some(audience) => {
+                    Some(normalize_audience(&audience).map_err(AuthError::InvalidAudience)?)
+                }
+                None => None,
+            },
+            Err(PathRejection::MissingPathParams(_)) => None,
+            // The path segment is already percent-decoded, so the only other way this fails is if
+            // it didn't decode to valid UTF-8, which can't be a valid AUD tag either.
+            Err(_) => {
+                return Err(AuthError::InvalidAudience(
+                    InvalidAudienceReason::InvalidCharacters,
+                ))
+            }
+        };
+        let query_audience = audience_from_query(req.uri().query())?;
+
+        match (path_audience, query_audience) {
+            (Some(path_audience), Some(query_audience)) if path_audience != query_audience => {
+                Err(AuthError::InvalidAudience(InvalidAudienceReason::Ambiguous))
+            }
+            (Some(audience), _) | (None, Some(audience)) => Ok(Self(audience)),
+            (None, None) => audience_from_host(req),
+        }
+    }
+}
+
+/// Gets the normalized audience from the `aud` query parameter, if there is one.
+///
+/// The parameter may be repeated, but only with the same audience each time.
+fn audience_from_query(query: Option<&str>) -> Result<Option<String>, AuthError> {
+    let mut audience = None;
+    for pair in query.unwrap_or_default().split('&') {
+        let value = match pair.split_once('=') {
+            Some(("aud", value)) => value,
+            _ => continue,
+        };
+
+        let value = percent_decode(value).ok_or(AuthError::InvalidAudience(
+            InvalidAudienceReason::InvalidCharacters,
+        ))?;
+        let normalized = normalize_audience(&value).map_err(AuthError::InvalidAudience)?;
+        match &audience {
+            Some(audience) if *audience != normalized => {
+                return Err(AuthError::InvalidAudience(InvalidAudienceReason::Ambiguous))
+            }
+            _ => audience = Some(normalized),
+        }
+    }
+
+    Ok(audience)
+}
+
+/// Decodes a percent-encoded query string value, returning `None` if it isn't valid.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn audience_from_host<B>(req: &RequestParts<B>) -> Result<Audience, AuthError> {
+    let host = req
+        .headers()
+        .get("x-forwarded-host")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(AuthError::UnmappedHost(None))?;
+
+    let policies = req
+        .extensions()
+        .get::<Arc<AudiencePolicyStore>>()
+        .expect("audience policies should always be present")
+        .load();
+
+    // Hosts are only mapped to audiences that were already normalized when loading the
+    // configuration.
+    policies
+        .audience_for_host(host)
+        .map(|audience| Audience(audience.to_string()))
+        .ok_or_else(|| AuthError::UnmappedHost(Some(host.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::BoxBody, http::HeaderValue, routing::get, Extension, Router};
+    use hyper::{body::to_bytes, Body, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::{policy::AudiencePolicies, replay, web::error::ERROR_HEADER};
+
+    const AUDIENCE: &str = "4714c1358e65fe4b408ad6d432a5f878f08194bdb4752441fd56faefa9b2b6f2";
+
+    fn router() -> Router {
+        let mut config = Config::default();
+        config
+            .hosts
+            .insert("app.example.com".to_string(), AUDIENCE.to_string());
+        let policies = AudiencePolicies::compile(&config).expect("policies should compile");
+
+        Router::new()
+            .route(
+                "/validate",
+                get(|Audience(audience)| async move { audience }),
+            )
+            .route(
+                "/validate/:audience",
+                get(|Audience(audience)| async move { audience }),
+            )
+            .layer(Extension(Arc::new(AudiencePolicyStore::new(policies))))
+    }
+
+    /// Gets the sources of the credentials extracted from a request with the given headers, in
+    /// the order they'd be tried, or the error code it was rejected with.
+    async fn extract(
+        credential_mode: CredentialMode,
+        headers: &[(&str, &[u8])],
+    ) -> Result<String, String> {
+        let mut config = Config::default();
+        config.credential_mode = credential_mode;
+        config.bearer_mode = BearerMode::AfterHeader;
+
+        let mut app = Router::new()
+            .route(
+                "/validate",
+                get(|AccessTokens(credentials)| async move {
+                    let sources = credentials
+                        .iter()
+                        .map(|credential| credential.source.as_str())
+                        .collect::<Vec<_>>();
+                    sources.join(",")
+                }),
+            )
+            .layer(Extension(Arc::new(config)));
+
+        let mut builder = Request::get("/validate");
+        for (name, value) in headers {
+            builder = builder.header(*name, HeaderValue::from_bytes(value).unwrap());
+        }
+        let request = builder.body(Body::empty()).unwrap();
+
+        response_body(replay::call(&mut app, request).await).await
+    }
+
+    async fn response_body(response: Response<BoxBody>) -> Result<String, String> {
+        if response.status() != StatusCode::OK {
+            let error = response.headers().get(ERROR_HEADER).unwrap();
+            return Err(error.to_str().unwrap().to_string());
+        }
+
+        let body = to_bytes(response.into_body()).await.unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Gets the audience a request was resolved to, or the error code it was rejected with.
+    async fn resolve(uri: &str, headers: &[(&str, &str)]) -> Result<String, String> {
+        let mut builder = Request::get(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let request = builder.body(Body::empty()).unwrap();
+
+        response_body(replay::call(&mut router(), request).await).await
+    }
+
+    #[tokio::test]
+    async fn try_all_skips_malformed_credentials() {
+        let cookie: (&str, &[u8]) = ("cookie", b"CF_Authorization=header.payload.signature");
+        let bad_header: (&str, &[u8]) = ("cf-access-jwt-assertion", b"\xffheader.payload");
+        let bad_bearer: (&str, &[u8]) = ("authorization", b"Bearer not a b64token");
+
+        assert_eq!(
+            extract(CredentialMode::TryAll, &[bad_header, cookie]).await,
+            Ok("cookie".to_string())
+        );
+        assert_eq!(
+            extract(CredentialMode::TryAll, &[bad_bearer, cookie]).await,
+            Ok("cookie".to_string())
+        );
+
+        // With nothing else to try, the malformed credential is what's reported.
+        assert_eq!(
+            extract(CredentialMode::TryAll, &[bad_header]).await,
+            Err("malformed_token".to_string())
+        );
+        assert_eq!(
+            extract(CredentialMode::TryAll, &[]).await,
+            Err("missing_token".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn preferred_credential_modes_reject_malformed_credentials() {
+        let cookie: (&str, &[u8]) = ("cookie", b"CF_Authorization=header.payload.signature");
+        let bad_header: (&str, &[u8]) = ("cf-access-jwt-assertion", b"\xffheader.payload");
+
+        assert_eq!(
+            extract(CredentialMode::HeaderFirst, &[bad_header, cookie]).await,
+            Err("malformed_token".to_string())
+        );
+        assert_eq!(
+            extract(CredentialMode::HeaderFirst, &[cookie]).await,
+            Ok("cookie".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn audience_from_path() {
+        let uri = format!("/validate/{}", AUDIENCE.to_ascii_uppercase());
+        assert_eq!(resolve(&uri, &[]).await, Ok(AUDIENCE.to_string()));
+
+        let uri = format!("/validate/{}?aud={}", AUDIENCE, AUDIENCE);
+        assert_eq!(resolve(&uri, &[]).await, Ok(AUDIENCE.to_string()));
+
+        let uri = format!("/validate/{}?aud=abcd", AUDIENCE);
+        assert_eq!(
+            resolve(&uri, &[]).await,
+            Err("invalid_audience".to_string())
+        );
+
+        assert_eq!(
+            resolve("/validate/not-an-aud-tag", &[]).await,
+            Err("invalid_audience".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn audience_from_query() {
+        let uri = format!("/validate?aud={}", AUDIENCE);
+        assert_eq!(resolve(&uri, &[]).await, Ok(AUDIENCE.to_string()));
+
+        // The query parameter takes precedence over the forwarded host.
+        let uri = format!("/validate?aud={}", AUDIENCE);
+        let headers = [("x-forwarded-host", "other.example.com")];
+        assert_eq!(resolve(&uri, &headers).await, Ok(AUDIENCE.to_string()));
+    }
+
+    #[tokio::test]
+    async fn audience_from_host() {
+        let headers = [("x-forwarded-host", "App.Example.com:443")];
+        assert_eq!(
+            resolve("/validate", &headers).await,
+            Ok(AUDIENCE.to_string())
+        );
+
+        let headers = [("x-forwarded-host", "other.example.com")];
+        assert_eq!(
+            resolve("/validate", &headers).await,
+            Err("unmapped_host".to_string())
+        );
+
+        assert_eq!(
+            resolve("/validate", &[]).await,
+            Err("unmapped_host".to_string())
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::Router;
+#[cfg(feature = "spiffe")]
+use axum::{
+    http::{HeaderName, HeaderValue},
+    response::Response,
+};
+use hyper::{
+    body::{to_bytes, Bytes},
+    Body, Request,
+};
+use quinn::{Endpoint, ServerConfig};
+use rustls::{server::AllowAnyAuthenticatedClient, Certificate, RootCertStore};
+use tokio::{sync::watch, time::timeout};
+use tower::ServiceExt;
+#[cfg(feature = "spiffe")]
+use tower_http::set_header::SetResponseHeaderLayer;
+#[cfg(feature = "spiffe")]
+use tracing::error;
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "spiffe")]
+use super::spiffe;
+use super::{shutdown_requested, tls::ReloadableCertResolver};
+use crate::config::{Http3Config, TlsSource};
+
+/// Checks the peer of a connection, once its handshake is done.
+type PeerVerifier = Arc<dyn Fn(&quinn::Connection) -> Result<(), String> + Send + Sync>;
+
+/// Serves the given router over HTTP/3.
+///
+/// This is experimental. Validation requests carry no body, so requests are handled as if they had
+/// none, and responses are buffered in full before being sent.
+pub async fn run_http3_endpoint(
+    config: &Http3Config,
+    app: Router,
+    shutdown: watch::Receiver<bool>,
+    shutdown_timeout: Duration,
+) -> Result<(), String> {
+    let cert_resolver = ReloadableCertResolver::load(&config.tls)
+        .await
+        .map(Arc::new)?;
+    match &config.tls {
+        #[cfg(feature = "spiffe")]
+        TlsSource::Spiffe {
+            socket, spiffe_id, ..
+        } => {
+            let cert_resolver = Arc::clone(&cert_resolver);
+            let (socket, spiffe_id) = (socket.clone(), spiffe_id.clone());
+            tokio::spawn(async move {
+                cert_resolver
+                    .follow_workload_api(socket.as_deref(), spiffe_id.as_deref())
+                    .await
+            });
+        }
+        source if config.tls_reload_interval_secs > 0 => {
+            let cert_resolver = Arc::clone(&cert_resolver);
+            let source = source.clone();
+            let reload_interval = Duration::from_secs(config.tls_reload_interval_secs);
+            tokio::spawn(async move {
+                cert_resolver
+                    .reload_periodically(&source, reload_interval)
+                    .await
+            });
+        }
+        _ => {}
+    }
+
+    // With an SVID, the validator can tell the proxy who it is, and require the proxy to prove who
+    // it is in turn, with an SVID of its own that's verified against the current trust bundle.
+    #[cfg(feature = "spiffe")]
+    let (app, peer_verifier, client_roots) = match &config.tls {
+        TlsSource::Spiffe {
+            id_header,
+            peer_spiffe_id,
+            ..
+        } => {
+            let identity = cert_resolver.identity();
+            let client_roots = peer_spiffe_id.as_ref().map(|_| {
+                identity
+                    .borrow()
+                    .as_ref()
+                    .map(|identity| identity.bundle.clone())
+                    .unwrap_or_default()
+            });
+            let peer_verifier = peer_spiffe_id.clone().map(|peer_spiffe_id| {
+                Arc::new(move |connection: &quinn::Connection| {
+                    spiffe::verify_peer(connection, &peer_spiffe_id)
+                }) as PeerVerifier
+            });
+            let app = match id_header {
+                Some(id_header) => {
+                    let id_header = HeaderName::from_bytes(id_header.as_bytes()).map_err(|_| {
+                        format!(
+                            "SPIFFE ID header '{}' is not a valid header name.",
+                            id_header
+                        )
+                    })?;
+                    app.layer(SetResponseHeaderLayer::overriding(
+                        id_header,
+                        move |_: &Response| {
+                            identity.borrow().as_ref().and_then(|identity| {
+                                HeaderValue::from_str(&identity.spiffe_id).ok()
+                            })
+                        },
+                    ))
+                }
+                None => app,
+            };
+            (app, peer_verifier, client_roots)
+        }
+        _ => (app, None, None),
+    };
+    #[cfg(not(feature = "spiffe"))]
+    let (peer_verifier, client_roots): (Option<PeerVerifier>, Option<Vec<Certificate>>) =
+        (None, None);
+
+    let tls_config = tls_config(Arc::clone(&cert_resolver), client_roots.as_deref())?;
+    let endpoint = Endpoint::server(
+        ServerConfig::with_crypto(Arc::new(tls_config)),
+        config.listen_address,
+    )
+    .map_err(|e| format!("Failed to bind HTTP/3 listener: {}", e))?;
+
+    // Rotations of the trust bundle only apply to new connections, like those of the certificate.
+    #[cfg(feature = "spiffe")]
+    if peer_verifier.is_some() {
+        let (endpoint, cert_resolver) = (endpoint.clone(), Arc::clone(&cert_resolver));
+        let mut identity = cert_resolver.identity();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            while identity.changed().await.is_ok() && !*shutdown.borrow() {
+                let bundle = identity
+                    .borrow()
+                    .as_ref()
+                    .map(|identity| identity.bundle.clone())
+                    .unwrap_or_default();
+                match tls_config(Arc::clone(&cert_resolver), Some(&bundle)) {
+                    Ok(tls_config) => {
+                        endpoint.set_server_config(Some(ServerConfig::with_crypto(Arc::new(
+                            tls_config,
+                        ))));
+                        info!("Loaded SPIFFE trust bundle.");
+                    }
+                    Err(e) => error!(
+                        error = %e,
+                        "Failed to load SPIFFE trust bundle. Keeping the current one."
+                    ),
+                }
+            }
+        });
+    }
+
+    info!("Listening for HTTP/3 on {}.", config.listen_address);
+
+    let shutdown_requested = shutdown_requested(shutdown);
+    tokio::pin!(shutdown_requested);
+
+    loop {
+        let connecting = tokio::select! {
+            connecting = endpoint.accept() => match connecting {
+                Some(connecting) => connecting,
+                None => return Ok(()),
+            },
+            _ = &mut shutdown_requested => break,
+        };
+
+        let (app, peer_verifier) = (app.clone(), peer_verifier.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(connecting, app, peer_verifier).await {
+                debug!(error = %e, "HTTP/3 connection closed with error.");
+            }
+        });
+    }
+
+    // Stop accepting new connections, but give existing ones a chance to finish their requests.
+    endpoint.set_server_config(None);
+    if timeout(shutdown_timeout, endpoint.wait_idle())
+        .await
+        .is_err()
+    {
+        warn!("Timed out waiting for HTTP/3 connections to finish. Closing them.");
+        endpoint.close(0u32.into(), b"shutting down");
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(
+    connecting: quinn::Connecting,
+    app: Router,
+    peer_verifier: Option<PeerVerifier>,
+) -> Result<(), String> {
+    let connection = connecting.await.map_err(|e| e.to_string())?;
+    if let Some(verify_peer) = peer_verifier {
+        if let Err(e) = verify_peer(&connection) {
+            connection.close(0u32.into(), b"unauthorized peer");
+            return Err(e);
+        }
+    }
+    let mut connection =
+        h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection))
+            .await
+            .map_err(|e| e.to_string())?;
+
+    while let Some((request, mut stream)) = connection.accept().await.map_err(|e| e.to_string())? {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let (parts, ()) = request.into_parts();
+            let request = Request::from_parts(parts, Body::empty());
+
+            let response = match app.oneshot(request).await {
+                Ok(response) => response,
+                Err(infallible) => match infallible {},
+            };
+            let (parts, body) = response.into_parts();
+            let body = match to_bytes(body).await {
+                Ok(body) => body,
+                Err(e) => {
+                    debug!(error = %e, "Failed to buffer HTTP/3 response body.");
+                    return;
+                }
+            };
+
+            let result = async {
+                stream
+                    .send_response(hyper::Response::from_parts(parts, ()))
+                    .await?;
+                if !body.is_empty() {
+                    stream.send_data(body).await?;
+                }
+                stream.finish().await
+            };
+            if let Err(e) = result.await {
+                debug!(error = %e, "Failed to send HTTP/3 response.");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Builds the TLS configuration, asking clients for a certificate that chains to the given roots,
+/// if any.
+fn tls_config(
+    cert_resolver: Arc<ReloadableCertResolver>,
+    client_roots: Option<&[Certificate]>,
+) -> Result<rustls::ServerConfig, String> {
+    let builder = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| format!("Failed to configure TLS: {}", e))?;
+    let builder = match client_roots {
+        Some(client_roots) => {
+            let mut roots = RootCertStore::empty();
+            let client_roots = client_roots
+                .iter()
+                .map(|cert| cert.0.clone())
+                .collect::<Vec<_>>();
+            if roots.add_parsable_certificates(&client_roots).0 == 0 {
+                return Err("No trust bundle to verify client certificates against.".to_string());
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls_config = builder.with_cert_resolver(cert_resolver);
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    tls_config.max_early_data_size = u32::MAX;
+
+    Ok(tls_config)
+}
@@ -0,0 +1,1838 @@
+use std::{
+    collections::HashMap,
+    future::ready,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{boxed, Full},
+    extract::MatchedPath,
+    headers::HeaderName,
+    http::HeaderValue,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use convert_case::{Case, Casing};
+use hyper::{body::to_bytes, header, Body, HeaderMap, Request, StatusCode};
+use metrics_exporter_prometheus::PrometheusHandle;
+use openidconnect::{
+    core::CoreGenderClaim, ClaimsVerificationError, ClientId, IdTokenClaims, IdTokenVerifier,
+    IssuerUrl, Nonce,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::{sync::watch, time::timeout};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use zeroize::Zeroizing;
+
+mod admin;
+mod deadline;
+mod envoy;
+mod error;
+mod error_page;
+#[cfg(feature = "ext-authz")]
+mod ext_authz;
+mod extract;
+#[cfg(feature = "http3")]
+mod http3;
+mod nginx;
+mod proxy_secret;
+#[cfg(feature = "spiffe")]
+mod spiffe;
+#[cfg(feature = "http3")]
+mod tls;
+mod trace_context;
+mod validation_cache;
+pub use self::admin::AdminToken;
+use self::deadline::Deadline;
+use self::error::{AuthError, ERROR_HEADER};
+pub use self::error_page::ErrorPages;
+use self::error_page::RequestedAudience;
+use self::extract::{AccessTokens, Audience, Credential};
+pub use self::proxy_secret::ProxySecret;
+pub use self::validation_cache::ValidationCache;
+
+use crate::anomaly::AnomalyDetector;
+use crate::config::{
+    ClaimHeaderCase, ClaimsHeaderMode, Config, HeaderPreset, JtiReplayMode, PrincipalType,
+};
+use crate::enrichment::UserEnricher;
+use crate::groups::{claim_groups, GroupNameResolver};
+use crate::identity::IdentityEnricher;
+use crate::internal_token::{InternalIdentity, InternalTokenIssuer};
+use crate::jti::JtiTracker;
+use crate::opa::OpaClient;
+use crate::path_rules::normalize_path;
+use crate::policy::{AudiencePolicies, AudiencePolicy, AudiencePolicyStore};
+use crate::redaction::ClaimValue;
+use crate::signing::MessageSigner;
+use crate::supervisor::TaskStatuses;
+use crate::telemetry;
+use crate::validation::{
+    select_signature_keys,
+    service_auth::{ServiceAuthTokenHeaderMap, ServiceTokenMapStore},
+    token::{check_assertion_structure, CloudflareAccessCustomClaims, CloudflareAccessIdToken},
+    SignatureStates,
+};
+
+/// How long to wait for the JWKS refresh triggered by a token signed with an unknown key, before
+/// validating the token again.
+const UNKNOWN_KEY_RETRY_MAX_WAIT: Duration = Duration::from_secs(2);
+
+/// Details of the identity a successfully validated token was issued to, passed along with the
+/// response so that it can be enriched once authorization is done.
+#[derive(Clone)]
+struct VerifiedIdentity {
+    email: Option<String>,
+    groups: Option<Value>,
+    claims: Option<Value>,
+    subject: String,
+    issuer_url: IssuerUrl,
+    expires_at: DateTime<Utc>,
+
+    /// Which of the presented credentials is the validated token, which is only kept if the
+    /// identity behind it is to be looked up.
+    ///
+    /// The token itself is taken from the request whenever it's needed, so it's never held, or
+    /// cached, for any longer than the request that presented it.
+    access_token: Option<usize>,
+
+    service_token_id: Option<String>,
+
+    /// The custom claims to carry over into an internal token, which are only kept if one is to be
+    /// minted.
+    internal_claims: Option<serde_json::Map<String, Value>>,
+
+    /// The ID of the validated token, which is only kept if replays are checked for.
+    jti: Option<String>,
+}
+
+/// The enrichers that add details about the identity to successful validation responses.
+///
+/// These are passed to `validate` together, as it's already taking as many extractors as axum
+/// allows.
+#[derive(Clone)]
+struct Enrichers {
+    user: Option<Arc<UserEnricher>>,
+    identity: Option<Arc<IdentityEnricher>>,
+}
+
+/// What to do when a validation request carries no access token at all.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingTokenBehavior {
+    /// Respond with `401 Unauthorized` and a `WWW-Authenticate` challenge.
+    Unauthorized,
+
+    /// Redirect to the Cloudflare Access login page for the application.
+    ///
+    /// The application domain and original URI are taken from the `X-Forwarded-Host` and
+    /// `X-Forwarded-Uri` headers sent by the proxy. If the proxy doesn't send them, this behaves
+    /// like `Unauthorized`.
+    Redirect,
+
+    /// Allow the request through anonymously, without any identity headers.
+    Allow,
+}
+
+impl FromStr for MissingTokenBehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unauthorized" => Ok(Self::Unauthorized),
+            "redirect" => Ok(Self::Redirect),
+            "allow" => Ok(Self::Allow),
+            other => Err(format!(
+                "unknown missing token behavior '{}' (expected one of: unauthorized, redirect, allow)",
+                other
+            )),
+        }
+    }
+}
+
+/// The behavior to use for requests without an access token, optionally overridden per audience.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MissingTokenPolicy {
+    default: MissingTokenBehavior,
+    audiences: HashMap<String, MissingTokenBehavior>,
+}
+
+impl MissingTokenPolicy {
+    /// Parses a policy from its specification.
+    ///
+    /// The specification is a comma-separated list of entries, where each entry is either a bare
+    /// behavior, which sets the default behavior, or `<audience>=<behavior>`, which overrides the
+    /// behavior for a specific audience. For example: `unauthorized,0123abcd=allow`.
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        let mut policy = Self::default();
+        for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match entry.split_once('=') {
+                Some((audience, behavior)) => {
+                    policy
+                        .audiences
+                        .insert(audience.trim().to_string(), behavior.trim().parse()?);
+                }
+                None => policy.default = entry.parse()?,
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Gets the default behavior, used for any audience without an override.
+    pub fn default_behavior(&self) -> MissingTokenBehavior {
+        self.default
+    }
+
+    /// Gets the behavior for the given audience.
+    pub fn behavior_for(&self, audience: &str) -> MissingTokenBehavior {
+        self.audiences
+            .get(audience)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Gets an iterator over all audiences with an overridden behavior.
+    pub fn audiences(&self) -> impl Iterator<Item = &str> {
+        self.audiences.keys().map(String::as_str)
+    }
+}
+
+impl Default for MissingTokenPolicy {
+    fn default() -> Self {
+        Self {
+            default: MissingTokenBehavior::Unauthorized,
+            audiences: HashMap::new(),
+        }
+    }
+}
+
+fn missing_token_response(
+    behavior: MissingTokenBehavior,
+    audience: &str,
+    issuer_url: &IssuerUrl,
+    headers: &HeaderMap,
+) -> Response {
+    match behavior {
+        MissingTokenBehavior::Allow => StatusCode::OK.into_response(),
+        MissingTokenBehavior::Redirect => match login_url(audience, issuer_url, headers) {
+            Some(login_url) => (
+                StatusCode::FOUND,
+                [(header::LOCATION, login_url)],
+                [(ERROR_HEADER, AuthError::MissingToken.kind())],
+            )
+                .into_response(),
+            None => {
+                debug!("Cannot redirect to login page without `X-Forwarded-Host` header.");
+                missing_token_response(
+                    MissingTokenBehavior::Unauthorized,
+                    audience,
+                    issuer_url,
+                    headers,
+                )
+            }
+        },
+        MissingTokenBehavior::Unauthorized => {
+            let challenge = format!("Bearer realm=\"{}\"", issuer_url.as_str());
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, challenge)],
+                AuthError::MissingToken.details(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Builds the response for a request with an invalid access token, such as an expired one.
+///
+/// Logging in again fixes an invalid token just like a missing one, so requests are redirected to
+/// the login page if requests without a token would be. Otherwise, they're rejected with a
+/// challenge saying why, since an invalid token is never let through.
+fn invalid_token_response(
+    error: &AuthError,
+    behavior: MissingTokenBehavior,
+    audience: &str,
+    issuer_url: &IssuerUrl,
+    headers: &HeaderMap,
+) -> Response {
+    let kind = error.kind();
+    let mut response = error.response();
+
+    if behavior == MissingTokenBehavior::Redirect {
+        if let Some(login_url) = login_url(audience, issuer_url, headers) {
+            return (
+                StatusCode::FOUND,
+                [(header::LOCATION, login_url)],
+                [(ERROR_HEADER, kind)],
+            )
+                .into_response();
+        }
+    }
+
+    let challenge = format!(
+        "Bearer realm=\"{}\", error=\"invalid_token\"",
+        issuer_url.as_str()
+    );
+    if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+/// Builds the Cloudflare Access login URL for the application the proxied request was meant for.
+fn login_url(audience: &str, issuer_url: &IssuerUrl, headers: &HeaderMap) -> Option<String> {
+    let forwarded_header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let host = forwarded_header("x-forwarded-host")?;
+    let original_uri = forwarded_header("x-forwarded-uri").unwrap_or("/");
+
+    let mut login_url = issuer_url
+        .join(&format!("cdn-cgi/access/login/{}", host))
+        .ok()?;
+    login_url
+        .query_pairs_mut()
+        .append_pair("kid", audience)
+        .append_pair("redirect_url", original_uri);
+
+    Some(login_url.to_string())
+}
+
+async fn readiness(
+    Extension(states): Extension<Arc<SignatureStates>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(task_statuses): Extension<Arc<TaskStatuses>>,
+) -> Response<Body> {
+    // A replica whose refreshes have been failing for long enough may be missing rotated keys, so
+    // take it out of rotation in favor of replicas with fresh keys.
+    let is_stale = config
+        .jwks_max_staleness()
+        .map_or(false, |max_staleness| states.any_stale(max_staleness));
+
+    // Likewise for a replica with a background task that's down until it's restarted.
+    let status = if !states.has_jwks_loaded() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else if is_stale || task_statuses.any_restarting() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    let tasks = task_statuses
+        .snapshot()
+        .into_iter()
+        .map(|(name, status)| {
+            let status = json!({
+                "state": status.state.as_str(),
+                "restarts": status.restarts,
+            });
+            (name, status)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let body = json!({ "tasks": tasks });
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn validate(
+    Audience(audience): Audience,
+    access_tokens: Result<AccessTokens, AuthError>,
+    request_headers: HeaderMap,
+    Extension(states): Extension<Arc<SignatureStates>>,
+    Extension(token_map): Extension<Arc<ServiceTokenMapStore>>,
+    Extension(message_signer): Extension<Option<Arc<MessageSigner>>>,
+    Extension(enrichers): Extension<Enrichers>,
+    Extension(jti_tracker): Extension<Option<Arc<JtiTracker>>>,
+    Extension(internal_token): Extension<Option<Arc<InternalTokenIssuer>>>,
+    Extension(group_names): Extension<Option<Arc<GroupNameResolver>>>,
+    Extension(anomaly_detector): Extension<Option<Arc<AnomalyDetector>>>,
+    Extension(proxy_secret): Extension<Option<Arc<ProxySecret>>>,
+    Extension(opa): Extension<Option<Arc<OpaClient>>>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(policies): Extension<Arc<AudiencePolicyStore>>,
+    Extension(validation_cache): Extension<Option<Arc<ValidationCache>>>,
+) -> Response {
+    // If the proxy says how long it'll wait for a response, don't wait on anything for any longer,
+    // since the response would only be thrown away.
+    let deadline = Deadline::from_headers(config.deadline_header.as_deref(), &request_headers);
+
+    let span = info_span!(
+        "validate",
+        audience = audience.as_str(),
+        outcome = field::Empty,
+        subject_hash = field::Empty,
+    );
+    let credentials = access_tokens.map(|AccessTokens(credentials)| credentials);
+    let token_map = token_map.load();
+    let policies = policies.load();
+    let run_authorize = || {
+        span.in_scope(|| {
+            authorize(
+                &audience,
+                credentials.as_deref().map_err(AuthError::clone),
+                &request_headers,
+                &states,
+                &token_map,
+                &config,
+                &policies,
+            )
+        })
+    };
+
+    // Tokens that were recently validated against the same audience, under the same policies, are
+    // taken as they were then, without verifying them again, whether they passed or failed.
+    let cache_key = match (&validation_cache, &credentials) {
+        (Some(validation_cache), Ok(credentials)) => {
+            validation_cache.key(&audience, credentials, &policies, &token_map)
+        }
+        _ => None,
+    };
+    let cached = match (&validation_cache, &cache_key) {
+        (Some(validation_cache), Some(cache_key)) => validation_cache.get(cache_key),
+        _ => None,
+    };
+
+    // Revocations, and validations cached by other replicas, are only known to Redis.
+    #[cfg(feature = "redis")]
+    let cached = match (&validation_cache, &cache_key, &credentials) {
+        (Some(validation_cache), Some(cache_key), Ok(credentials)) => {
+            validation_cache
+                .get_shared(cache_key, credentials, cached)
+                .instrument(span.clone())
+                .await
+        }
+        _ => cached,
+    };
+    let mut cache_hit = false;
+
+    // Requests that didn't come through one of our proxies aren't authorized at all, but they're
+    // still recorded like any other rejection.
+    let mut result = match &proxy_secret {
+        Some(proxy_secret) if !proxy_secret.is_presented(&request_headers) => {
+            Err(AuthError::InvalidProxySecret)
+        }
+        _ => match cached {
+            Some(Ok(response)) => {
+                cache_hit = true;
+                if let Some(identity) = response.extensions().get::<VerifiedIdentity>() {
+                    let subject_hash = Sha256::digest(identity.subject.as_bytes());
+                    span.record("subject_hash", format!("{:x}", subject_hash).as_str());
+                }
+                debug!("Reused cached validation.");
+                Ok(("success", response))
+            }
+            // Revocations aren't cached failures, so they're logged like any other rejection.
+            Some(Err(AuthError::RevokedToken)) => Err(AuthError::RevokedToken),
+            Some(Err(e)) => {
+                cache_hit = true;
+                Err(e)
+            }
+            None => run_authorize(),
+        },
+    };
+
+    // A token signed with an unknown key has already triggered a JWKS refresh, in case the key is
+    // new, so if configured to, give it one more chance once that refresh is done.
+    if config.jwks_retry_on_unknown_key && matches!(result, Err(AuthError::UnknownSigningKey(_))) {
+        let state = policies
+            .for_audience(&audience)
+            .and_then(|policy| states.get(policy.issuer()));
+        if let Some(state) = state {
+            state
+                .wait_for_requested_refresh(deadline.remaining(UNKNOWN_KEY_RETRY_MAX_WAIT))
+                .instrument(span.clone())
+                .await;
+            result = run_authorize();
+        }
+    }
+
+    if let (Some(validation_cache), Some(cache_key), false) =
+        (&validation_cache, cache_key, cache_hit)
+    {
+        validation_cache.insert(cache_key, &result);
+        #[cfg(feature = "redis")]
+        if let Ok(credentials) = &credentials {
+            validation_cache.insert_shared(&cache_key, credentials, &result);
+        }
+    }
+
+    // Replays are checked for even when the validation was cached, as a token presented again is
+    // exactly what's being looked for.
+    let jti_replay = policies
+        .for_audience(&audience)
+        .map_or(JtiReplayMode::Disabled, AudiencePolicy::jti_replay);
+    let presented = match &result {
+        Ok(("success", response)) if jti_replay != JtiReplayMode::Disabled => response
+            .extensions()
+            .get::<VerifiedIdentity>()
+            .map(|identity| (identity.jti.clone(), identity.expires_at)),
+        _ => None,
+    };
+    // Tokens are still accepted for a while after they expire, so they're recorded until then.
+    let leeway = config.clock_skew_leeway();
+    let replay = match (&jti_tracker, presented) {
+        (Some(jti_tracker), Some((Some(jti), expires_at))) => jti_tracker
+            .is_replay(jti_replay, &audience, &jti, expires_at + leeway)
+            .instrument(span.clone())
+            .await
+            .then_some(AuthError::ReplayedToken),
+        (Some(_), Some((None, _))) => Some(AuthError::MissingJti),
+        _ => None,
+    };
+    if let Some(replay) = replay {
+        result = Err(replay);
+    }
+
+    // Ask OPA for its decision before anything else, since there's no point enriching a response
+    // that's about to be rejected.
+    let mut denial = None;
+    if let (Some(opa), Ok(("success", response))) = (&opa, &mut result) {
+        let claims = response
+            .extensions()
+            .get::<VerifiedIdentity>()
+            .and_then(|identity| identity.claims.clone());
+        let input = json!({
+            "claims": claims,
+            "request": forwarded_request(&audience, &request_headers),
+        });
+        let decision = deadline
+            .run(opa.decide(input).instrument(span.clone()))
+            .await
+            .unwrap_or_else(|| Err("the request's deadline passed".to_string()));
+        match decision {
+            // Headers from OPA come from policy, so they win over anything derived from the token.
+            Ok(decision) if decision.allow => response.headers_mut().extend(decision.headers),
+            Ok(decision) => denial = Some(AuthError::OpaDenied(decision.reason)),
+            Err(e) => denial = Some(AuthError::OpaUnavailable(e)),
+        }
+    }
+
+    // The response can't be enriched further, or signed, once it's been rejected.
+    if let Some(denial) = denial {
+        result = Err(denial);
+    }
+
+    if let Ok(("success", response)) = &mut result {
+        // Looking up user details means waiting on the Cloudflare API, so it's only done once the
+        // token has been fully authorized.
+        if let Some(identity) = response.extensions_mut().remove::<VerifiedIdentity>() {
+            if let (Some(user_enricher), Some(email)) = (&enrichers.user, &identity.email) {
+                let enrich = user_enricher
+                    .enrich(email, response.headers_mut())
+                    .instrument(span.clone());
+                if deadline.run(enrich).await.is_none() {
+                    debug!("Skipped looking up user details, as the request's deadline passed.");
+                }
+            }
+
+            // Service tokens have no subject, and so nothing to look up. The token is taken from
+            // the request, including when the validation itself was cached.
+            let access_token = identity.access_token.and_then(|index| {
+                let credentials = credentials.as_ref().ok()?;
+                Some(credentials.get(index)?.token.secret())
+            });
+            if let (Some(identity_enricher), Some(access_token), false) = (
+                &enrichers.identity,
+                access_token,
+                identity.subject.is_empty(),
+            ) {
+                let enrich = identity_enricher
+                    .enrich(
+                        &identity.issuer_url,
+                        &identity.subject,
+                        access_token,
+                        response.headers_mut(),
+                    )
+                    .instrument(span.clone());
+                if deadline.run(enrich).await.is_none() {
+                    debug!("Skipped looking up identity, as the request's deadline passed.");
+                }
+            }
+
+            // As with user details, headers that are already set are left alone.
+            if let (Some(group_names), Some(groups)) = (&group_names, &identity.groups) {
+                if !response.headers().contains_key(group_names.header()) {
+                    if let Some(names) = group_names.resolve(groups) {
+                        response
+                            .headers_mut()
+                            .insert(group_names.header().clone(), names);
+                    }
+                }
+            }
+
+            // The internal token only carries the identity, so it's minted once that's complete.
+            if let Some(internal_token) = &internal_token {
+                // Service tokens have no subject, so they're identified by their client ID instead.
+                let subject = match identity.service_token_id.as_deref() {
+                    Some(service_token_id) if identity.subject.is_empty() => service_token_id,
+                    _ => identity.subject.as_str(),
+                };
+                let internal_identity = InternalIdentity {
+                    audience: &audience,
+                    subject,
+                    email: identity.email.as_deref(),
+                    service_token_id: identity.service_token_id.as_deref(),
+                    custom: identity.internal_claims.unwrap_or_default(),
+                };
+                match internal_token.mint(internal_identity) {
+                    Ok(token) => {
+                        response
+                            .headers_mut()
+                            .insert(internal_token.header().clone(), token);
+                    }
+                    Err(e) => warn!(error = e, "Failed to mint internal token."),
+                }
+            }
+        }
+
+        // Sign the identity headers last, so the signature covers their final values.
+        if let Some(message_signer) = &message_signer {
+            message_signer.sign(response.headers_mut());
+        }
+    }
+
+    let outcome = match &result {
+        Ok((outcome, _)) => *outcome,
+        Err(e) => e.kind(),
+    };
+    span.record("outcome", outcome);
+
+    let audience_label = policies.audience_label(&audience);
+    if let Some(anomaly_detector) = &anomaly_detector {
+        anomaly_detector.record(&audience_label, outcome);
+    }
+    telemetry::record_validation(audience_label, outcome);
+
+    let mut response = match result {
+        Ok((_, response)) => response,
+        Err(e) => {
+            // Failures taken from the cache were logged when they first happened, so a client
+            // retrying with the same bad token doesn't flood the logs.
+            if cache_hit {
+                debug!(
+                    error = e.kind(),
+                    "Rejected token that recently failed validation."
+                );
+            } else {
+                e.log();
+            }
+
+            let policy = policies.for_audience(&audience);
+            let state = policy.and_then(|policy| states.get(policy.issuer()));
+            match (policy, state) {
+                (Some(policy), Some(state)) if e.is_invalid_token() => invalid_token_response(
+                    &e,
+                    policy.missing_token_behavior(),
+                    &audience,
+                    &state.issuer_url(),
+                    &request_headers,
+                ),
+                _ => e.response(),
+            }
+        }
+    };
+    response
+        .extensions_mut()
+        .insert(RequestedAudience(audience));
+    response
+}
+
+/// Authorizes a validation request for the given audience.
+///
+/// Requests that aren't rejected outright are returned along with their outcome, which is either
+/// `success` or `missing_token`, since requests without a token may still be let through.
+fn authorize(
+    audience: &str,
+    credentials: Result<&[Credential], AuthError>,
+    request_headers: &HeaderMap,
+    states: &SignatureStates,
+    token_map: &ServiceAuthTokenHeaderMap,
+    config: &Config,
+    policies: &AudiencePolicies,
+) -> Result<(&'static str, Response), AuthError> {
+    let policy = policies
+        .for_audience(audience)
+        .ok_or_else(|| AuthError::UnknownAudience(audience.to_string()))?;
+    let state = states
+        .get(policy.issuer())
+        .expect("audience issuers are validated when loading the configuration");
+
+    // Requests without any token at all are handled separately from requests with an invalid
+    // token, as they're typically just users who haven't logged in yet.
+    let credentials = match credentials {
+        Ok(credentials) => credentials,
+        Err(AuthError::MissingToken) => {
+            let behavior = policy.missing_token_behavior();
+            info!(
+                ?behavior,
+                "Validation request made without an access token."
+            );
+            return Ok((
+                "missing_token",
+                missing_token_response(behavior, audience, &state.issuer_url(), request_headers),
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+
+    // If we have no JWKS data yet, we can't validate anything.
+    let jwks = state.jwks().ok_or(AuthError::NotReady {
+        retry_after: config.not_ready_retry_after(),
+    })?;
+
+    // We don't bother validating the nonce.
+    // TODO: _Can_ we actually validate it? Does it matter? Not clear.
+    let nonce_verifier = |_: Option<&Nonce>| Ok(());
+
+    // Time-based claims are checked with some leeway, so a host with a slightly drifted clock
+    // doesn't reject tokens that are actually still valid.
+    let leeway = config.clock_skew_leeway();
+
+    // Parse all of the credentials up front, and then use the first one that verifies. Unless
+    // we're configured to try all presented credentials, there's only ever one.
+    //
+    // If none of them verify, the error for the first one that failed is reported.
+    let mut first_error = None;
+    let mut id_tokens = Vec::with_capacity(credentials.len());
+    for (index, credential) in credentials.iter().enumerate() {
+        match parse_access_token(credential.token.secret()) {
+            Ok((key_id, id_token)) => id_tokens.push((index, credential, key_id, id_token)),
+            Err(e) => {
+                debug!(
+                    source = credential.source.as_str(),
+                    error = e.kind(),
+                    "Rejected credential."
+                );
+                telemetry::record_credential(credential.source.as_str(), e.kind());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    let mut verified = None;
+    for (index, credential, key_id, id_token) in &id_tokens {
+        let source = credential.source;
+        // If the token claims to be signed with a key we don't have, Cloudflare may have rotated its
+        // keys since we last refreshed them, so ask for them to be refreshed early.
+        let signature_keys = select_signature_keys(&jwks, key_id.as_deref());
+        if signature_keys.keys().is_empty() {
+            let key_id = key_id.clone().unwrap_or_default();
+            debug!(
+                source = source.as_str(),
+                key_id = key_id.as_str(),
+                "Rejected credential signed with an unknown key."
+            );
+            telemetry::record_credential(source.as_str(), "unknown_key");
+            if state.request_refresh() {
+                info!(
+                    key_id = key_id.as_str(),
+                    "Requested JWKS refresh after seeing an unknown key ID."
+                );
+            }
+            first_error.get_or_insert(AuthError::UnknownSigningKey(key_id));
+            continue;
+        }
+
+        // Now construct the validator, with only the key the token claims to be signed with.
+        let verifier = IdTokenVerifier::new_public_client(
+            ClientId::new(audience.to_string()),
+            state.issuer_url(),
+            signature_keys,
+        )
+        .set_time_fn(move || Utc::now() - leeway);
+
+        // Expiry is only checked once the signature has been verified.
+        let claims = match id_token.claims(&verifier, &nonce_verifier) {
+            Ok(claims) => check_time_claims(claims, leeway).map(|()| claims),
+            Err(e @ ClaimsVerificationError::Expired(_)) => {
+                Err(AuthError::ExpiredToken(e.to_string()))
+            }
+            Err(e) => Err(AuthError::InvalidToken(e.to_string())),
+        };
+        match claims {
+            Ok(claims) => {
+                debug!(source = source.as_str(), "Verified credential.");
+                telemetry::record_credential(source.as_str(), "verified");
+                verified = Some((claims, *index, credential.token.secret()));
+                break;
+            }
+            Err(e) => {
+                debug!(source = source.as_str(), error = ?e, "Rejected credential.");
+                telemetry::record_credential(source.as_str(), e.kind());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    let (claims, access_token_index, access_token) = match verified {
+        Some(verified) => verified,
+        None => return Err(first_error.unwrap_or(AuthError::MissingToken)),
+    };
+    let cf_claims = claims.additional_claims();
+
+    // The subject is only ever recorded as a hash, so traces can be correlated by user without
+    // identifying them.
+    Span::current().record(
+        "subject_hash",
+        format!("{:x}", Sha256::digest(claims.subject().as_bytes())).as_str(),
+    );
+
+    // Make sure the token type is one that's accepted for this audience.
+    let token_type = cf_claims.get_token_type();
+    if !policy.allows_token_type(token_type) {
+        return Err(AuthError::TokenTypeNotAllowed(
+            token_type.map(str::to_string),
+        ));
+    }
+
+    // Make sure the token was issued to a kind of principal that's accepted for this audience.
+    let principal = PrincipalType::from_service_token_id(cf_claims.get_service_token_id());
+    if !policy.allows_principal(principal) {
+        return Err(AuthError::PrincipalNotAllowed(principal));
+    }
+
+    // Make sure the principal is a member of one of the groups required for this audience, if any.
+    let groups = cf_claims
+        .custom_pointer(&config.groups_claim)
+        .map(claim_groups)
+        .unwrap_or_default();
+    if !policy.allows_groups(&groups) {
+        return Err(AuthError::MissingRequiredGroup(
+            policy.required_groups().to_vec(),
+        ));
+    }
+
+    // Apply the first rule matching the path, and method, of the original request, if any. Without
+    // the path, there's no telling which rules apply, so the request is rejected outright.
+    if policy.has_path_rules() {
+        let forwarded_header = |name: &str| request_headers.get(name).and_then(|v| v.to_str().ok());
+        let path = forwarded_header("x-forwarded-uri")
+            .and_then(normalize_path)
+            .ok_or(AuthError::InvalidForwardedUri)?;
+        let method = forwarded_header("x-forwarded-method");
+        if let Some(rule) = policy.path_rule_for(method, &path) {
+            if !rule.allows(principal, &groups) {
+                return Err(AuthError::PathNotAllowed(rule.pattern().to_string()));
+            }
+        }
+    }
+
+    // Serializing the full set of claims is relatively costly, so it's only done if something
+    // actually needs them.
+    let needs_claims_json = policy.expression().is_some()
+        || policy.basic_auth().is_some()
+        || !policy.header_templates().is_empty()
+        || !config.claim_headers.is_empty()
+        || config.opa.is_some()
+        || config.claims_header != ClaimsHeaderMode::Disabled;
+    let claims_json = if needs_claims_json {
+        match serde_json::to_value(&claims) {
+            Ok(claims_json) => Some(claims_json),
+            Err(e) => {
+                debug!(error = %e, "Failed to serialize claims.");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Make sure the request satisfies the expression for this audience, if there is one.
+    if let Some(expression) = policy.expression() {
+        let claims_json = claims_json.as_ref().ok_or_else(|| {
+            AuthError::ExpressionNotSatisfied("claims could not be serialized".to_string())
+        })?;
+        let variables = expression_variables(audience, claims_json, request_headers);
+        expression
+            .evaluate(&variables)
+            .map_err(AuthError::ExpressionNotSatisfied)?;
+    }
+
+    // Make sure the email is on the allowlist for this audience, if there is one.
+    let email = claims.email().map(|email| email.as_str());
+    if !policy.allows_email(email) {
+        let domain = email
+            .and_then(|email| email.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_ascii_lowercase());
+        return Err(AuthError::EmailNotAllowed(domain));
+    }
+
+    // Our own claim headers are left out entirely if the preset replaces them.
+    let native_headers = config.header_preset.includes_native();
+
+    let mut headers = HeaderMap::new();
+    if let Some(token_type) = token_type.and_then(|s| HeaderValue::from_str(s).ok()) {
+        if native_headers {
+            headers.insert(HeaderName::from_static("x-auth-token-type"), token_type);
+        }
+    }
+
+    // Standard claims come first, so that custom claims, and anything else derived from the token,
+    // can override them. Neither is added if the claims are only sent as a single header.
+    if native_headers && config.claims_header != ClaimsHeaderMode::Exclusive {
+        insert_standard_claim_headers(&mut headers, claims, config, policy);
+        insert_custom_claim_headers(&mut headers, cf_claims, config, policy);
+    }
+
+    if config.claims_header != ClaimsHeaderMode::Disabled {
+        if let Some(claims_json) = &claims_json {
+            insert_claims_header(&mut headers, config, policy, claims_json);
+        }
+    }
+
+    // Service tokens have no subject, so other proxies' user headers name their client ID instead.
+    let user = match cf_claims.get_service_token_id() {
+        Some(service_token_id) if claims.subject().as_str().is_empty() => service_token_id,
+        _ => claims.subject().as_str(),
+    };
+    if config.header_preset.includes_oauth2_proxy() {
+        insert_oauth2_proxy_headers(&mut headers, user, email, &groups, access_token);
+    }
+
+    if config.header_preset == HeaderPreset::Authelia {
+        let name = match cf_claims.custom_claim("name") {
+            Some(Value::String(name)) => Some(name.as_str()),
+            _ => None,
+        };
+        insert_authelia_headers(&mut headers, user, email, name, &groups);
+    }
+
+    if config
+        .nginx
+        .as_ref()
+        .map_or(false, |nginx| nginx.identity_headers)
+    {
+        insert_nginx_identity_headers(&mut headers, user, email, &groups);
+    }
+
+    // Header mappings against the full set of claims are an escape hatch, so the claims are only
+    // serialized for them if there actually are any.
+    if let Some(claims_json) = &claims_json {
+        insert_claim_headers(&mut headers, config, claims_json);
+    }
+
+    // If we have a service auth token, add any mapped headers to the header map. In strict mode,
+    // only service tokens that have been explicitly mapped are let through at all.
+    if let Some(service_auth_token_id) = cf_claims.get_service_token_id() {
+        match token_map.get_headers_for_token(service_auth_token_id, audience) {
+            Some(mapped_headers) => {
+                for (header_name, header_value) in mapped_headers {
+                    headers.insert(header_name.clone(), header_value.clone());
+                }
+            }
+            None if config.strict_service_tokens => {
+                return Err(AuthError::UnmappedServiceToken(
+                    service_auth_token_id.to_string(),
+                ));
+            }
+            None => {}
+        }
+    }
+
+    // Templated headers are configured by the operator for the audience, so they win over anything
+    // else derived from the token.
+    if let Some(claims_json) = &claims_json {
+        for (header_name, template) in policy.header_templates() {
+            let rendered = match template.render(claims_json, &config.claim_rendering) {
+                Ok(rendered) => rendered,
+                Err(claim_name) => {
+                    debug!(
+                        "Skipped header template '{}', as claim '{}' could not be rendered.",
+                        header_name, claim_name
+                    );
+                    continue;
+                }
+            };
+
+            match HeaderValue::from_str(&rendered) {
+                Ok(header_value) => {
+                    headers.insert(header_name.clone(), header_value);
+                }
+                Err(_) => debug!(
+                    "Rendered invalid header value for header template '{}'.",
+                    header_name
+                ),
+            }
+        }
+    }
+
+    // Tell the application which audience the token was validated against, so it can check that
+    // the proxy sent the request to the right place. This is set after every header derived from
+    // the token, so that none of them can override it.
+    if let Ok(audience) = HeaderValue::from_str(audience) {
+        headers.insert(HeaderName::from_static("x-auth-aud"), audience);
+    }
+
+    // Basic auth credentials replace any `Authorization` header derived from the token. If they
+    // can't be built, the request is rejected, rather than letting whatever `Authorization` header
+    // the client sent through to an upstream that trusts it.
+    if let Some(basic_auth) = policy.basic_auth() {
+        let header_value = claims_json
+            .as_ref()
+            .ok_or_else(|| "claims could not be serialized".to_string())
+            .and_then(|claims_json| {
+                basic_auth.header_value(claims_json, cf_claims.get_service_token_id())
+            })
+            .map_err(AuthError::BasicAuthUnavailable)?;
+        headers.insert(header::AUTHORIZATION, header_value);
+    }
+
+    // Static headers for the audience are configured by the operator, so they win over anything
+    // derived from the token.
+    for (header_name, header_value) in policy.static_headers() {
+        headers.insert(header_name.clone(), header_value.clone());
+    }
+
+    let mut response = (StatusCode::OK, headers).into_response();
+    response.extensions_mut().insert(VerifiedIdentity {
+        email: email.map(str::to_string),
+        groups: if config.group_names.is_some() {
+            cf_claims.custom_pointer(&config.groups_claim).cloned()
+        } else {
+            None
+        },
+        claims: claims_json.filter(|_| config.opa.is_some()),
+        subject: claims.subject().as_str().to_string(),
+        issuer_url: state.issuer_url(),
+        expires_at: claims.expiration(),
+        access_token: config
+            .identity_enrichment
+            .as_ref()
+            .map(|_| access_token_index),
+        service_token_id: cf_claims.get_service_token_id().map(str::to_string),
+        internal_claims: config.internal_token.as_ref().map(|internal_token| {
+            internal_token
+                .claims
+                .iter()
+                .filter_map(|name| Some((name.clone(), cf_claims.custom_claim(name)?.clone())))
+                .collect()
+        }),
+        jti: cf_claims
+            .get_jti()
+            .filter(|_| policy.jti_replay() != JtiReplayMode::Disabled)
+            .map(str::to_string),
+    });
+
+    Ok(("success", response))
+}
+
+/// Adds the custom claims of a token as headers.
+///
+/// Each claim is turned into an `X-Foo-Bar`-style header, unless the audience maps it to a header
+/// of its own. These are the claims specified in the "OIDC Claims" section of the OIDC
+/// authentication settings, which is where "basic" claims like username or group have to be
+/// specified too.
+///
+/// Claims extracted from nested values are handled the same way, and take precedence over a flat
+/// claim of the same name. Flattened claims are named with dots, which separate words in the
+/// header name just like underscores do.
+fn insert_custom_claim_headers(
+    headers: &mut HeaderMap,
+    cf_claims: &CloudflareAccessCustomClaims,
+    config: &Config,
+    policy: &AudiencePolicy,
+) {
+    let custom_claims = cf_claims
+        .claims(&config.claim_rendering, config.claim_flatten_depth)
+        .chain(cf_claims.extracted_claims(&config.custom_claim_paths, &config.claim_rendering));
+    for (claim_name, claim_value) in custom_claims {
+        if !policy.forwards_claim(&claim_name) {
+            continue;
+        }
+
+        // Claims mapped to a header of their own are only added as that header.
+        if let Some(header_name) = policy.claim_header_name(&claim_name) {
+            match HeaderValue::from_str(claim_value.expose()) {
+                Ok(header_value) => {
+                    headers.insert(header_name.clone(), header_value);
+                }
+                Err(_) => debug!(
+                    "Received invalid header value '{}' as part of custom claims.",
+                    claim_value
+                ),
+            }
+            continue;
+        }
+
+        let claim_header_name = claim_header_name(config, &claim_name);
+        let header_name = match HeaderName::from_str(&claim_header_name) {
+            Ok(header_name) => header_name,
+            Err(_) => {
+                debug!(
+                    "Received invalid header name '{}' as part of custom claims.",
+                    claim_name
+                );
+                continue;
+            }
+        };
+
+        let header_value = match HeaderValue::from_str(claim_value.expose()) {
+            Ok(header_value) => header_value,
+            Err(_) => {
+                debug!(
+                    "Received invalid header value '{}' as part of custom claims.",
+                    claim_value
+                );
+                continue;
+            }
+        };
+
+        headers.insert(header_name, header_value);
+    }
+}
+
+/// Adds the validated claims as a single header, holding their JSON, base64url-encoded.
+///
+/// Claims the audience doesn't forward are left out, by their name at the top level of the claims,
+/// or of the custom claims. If the header would be larger than allowed, it's left out entirely, as
+/// a truncated set of claims could be mistaken for the full set.
+fn insert_claims_header(
+    headers: &mut HeaderMap,
+    config: &Config,
+    policy: &AudiencePolicy,
+    claims_json: &Value,
+) {
+    let mut claims = claims_json.as_object().cloned().unwrap_or_default();
+    claims.retain(|name, _| name == "custom" || policy.forwards_claim(name));
+    if let Some(Value::Object(custom)) = claims.get_mut("custom") {
+        custom.retain(|name, _| policy.forwards_claim(name));
+    }
+
+    let encoded = match serde_json::to_vec(&claims) {
+        Ok(json) => base64::encode_config(json, base64::URL_SAFE_NO_PAD),
+        Err(e) => {
+            debug!(error = %e, "Failed to serialize claims.");
+            return;
+        }
+    };
+    if encoded.len() > config.claims_header_max_bytes {
+        warn!(
+            size = encoded.len(),
+            max_size = config.claims_header_max_bytes,
+            "Left out the claims header, as it would be too large."
+        );
+        return;
+    }
+
+    if let Ok(header_value) = HeaderValue::from_str(&encoded) {
+        headers.insert(HeaderName::from_static("x-auth-claims"), header_value);
+    }
+}
+
+/// Builds the name of the header to add the given custom claim as, according to the configured
+/// prefix and casing.
+fn claim_header_name(config: &Config, claim_name: &str) -> String {
+    let claim_name = match config.claim_header_case {
+        ClaimHeaderCase::Train => claim_name.replace('.', "-").to_case(Case::Train),
+        ClaimHeaderCase::Kebab => claim_name.replace('.', "-").to_case(Case::Kebab),
+        ClaimHeaderCase::Lowercase => claim_name.to_lowercase(),
+        ClaimHeaderCase::Passthrough => claim_name.to_string(),
+    };
+    format!("{}{}", config.claim_header_prefix, claim_name)
+}
+
+/// Adds the standard claims of a token, which aren't part of its custom claims, as headers.
+///
+/// Claims the audience maps to a header of their own are always added, as that header. The rest are
+/// only added, as `X-Auth-*` headers, if standard claim headers are enabled.
+fn insert_standard_claim_headers(
+    headers: &mut HeaderMap,
+    claims: &IdTokenClaims<CloudflareAccessCustomClaims, CoreGenderClaim>,
+    config: &Config,
+    policy: &AudiencePolicy,
+) {
+    let standard_claims = [
+        (
+            "email",
+            "x-auth-email",
+            claims.email().map(|email| email.as_str().to_string()),
+        ),
+        (
+            "sub",
+            "x-auth-sub",
+            Some(claims.subject().as_str().to_string()),
+        ),
+        (
+            "iat",
+            "x-auth-issued-at",
+            Some(claims.issue_time().timestamp().to_string()),
+        ),
+        (
+            "exp",
+            "x-auth-expires",
+            Some(claims.expiration().timestamp().to_string()),
+        ),
+        (
+            "country",
+            "x-auth-country",
+            claims.additional_claims().get_country().map(str::to_string),
+        ),
+    ];
+
+    // Service tokens have an empty subject, which isn't worth passing on.
+    for (claim_name, default_header_name, value) in standard_claims {
+        if !policy.forwards_claim(claim_name) {
+            continue;
+        }
+
+        let header_name = match policy.claim_header_name(claim_name) {
+            Some(header_name) => header_name.clone(),
+            None if config.standard_claim_headers => HeaderName::from_static(default_header_name),
+            None => continue,
+        };
+
+        let header_value = match value.filter(|value| !value.is_empty()) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if let Ok(header_value) = HeaderValue::from_str(&header_value) {
+            headers.insert(header_name, header_value);
+        }
+    }
+}
+
+/// Parses an access token, without verifying it, along with the ID of the key it was signed with.
+fn parse_access_token(
+    access_token: &str,
+) -> Result<(Option<String>, CloudflareAccessIdToken), AuthError> {
+    // Reject anything that isn't even shaped like a JWT before doing any real work.
+    let key_id = check_assertion_structure(access_token).map_err(AuthError::MalformedToken)?;
+
+    // The parse error is not logged verbatim, as it may quote fragments of the raw token.
+    let id_token = CloudflareAccessIdToken::from_str(access_token).map_err(|e| {
+        AuthError::InvalidToken(format!("failed to parse access token ({:?})", e.classify()))
+    })?;
+
+    Ok((key_id, id_token))
+}
+
+/// Checks that the token was issued, and is valid from, no later than now, give or take the given
+/// leeway.
+///
+/// `openidconnect` checks the expiry itself, but doesn't check either of these.
+fn check_time_claims(
+    claims: &IdTokenClaims<CloudflareAccessCustomClaims, CoreGenderClaim>,
+    leeway: chrono::Duration,
+) -> Result<(), AuthError> {
+    let latest = Utc::now() + leeway;
+    if claims.issue_time() > latest {
+        return Err(AuthError::InvalidToken(format!(
+            "token issued in the future, at {}",
+            claims.issue_time()
+        )));
+    }
+
+    match claims.additional_claims().get_not_before() {
+        Some(not_before) if not_before > latest => Err(AuthError::InvalidToken(format!(
+            "token not valid until {}",
+            not_before
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Builds the variables that audience expressions are evaluated against.
+///
+/// `claims` holds every claim, with the custom claims also available at the top level, unless
+/// they'd shadow a standard claim, so that `claims.groups` works as well as `claims.custom.groups`.
+/// `request` describes the original request.
+fn expression_variables(
+    audience: &str,
+    claims_json: &Value,
+    request_headers: &HeaderMap,
+) -> serde_json::Map<String, Value> {
+    let mut claims = claims_json.as_object().cloned().unwrap_or_default();
+    if let Some(Value::Object(custom)) = claims_json.get("custom") {
+        for (name, value) in custom {
+            claims.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    let mut variables = serde_json::Map::new();
+    variables.insert("claims".to_string(), Value::Object(claims));
+    variables.insert(
+        "request".to_string(),
+        forwarded_request(audience, request_headers),
+    );
+    variables
+}
+
+/// Describes the original request, as forwarded by the proxy, for policies to make decisions on.
+///
+/// This holds the audience, along with the method, host and normalized path of the original
+/// request, if the proxy sent them.
+fn forwarded_request(audience: &str, request_headers: &HeaderMap) -> Value {
+    let forwarded_header = |name: &str| request_headers.get(name).and_then(|v| v.to_str().ok());
+    let mut request = serde_json::Map::new();
+    request.insert("audience".to_string(), Value::from(audience));
+    if let Some(method) = forwarded_header("x-forwarded-method") {
+        request.insert(
+            "method".to_string(),
+            Value::from(method.to_ascii_uppercase()),
+        );
+    }
+    if let Some(host) = forwarded_header("x-forwarded-host") {
+        request.insert("host".to_string(), Value::from(host.to_ascii_lowercase()));
+    }
+    if let Some(path) = forwarded_header("x-forwarded-uri").and_then(normalize_path) {
+        request.insert(
+            "path".to_string(),
+            Value::from(format!("/{}", path.join("/"))),
+        );
+    }
+
+    Value::Object(request)
+}
+
+/// Sets each of the given identity headers that has a value, for the given preset of headers.
+fn insert_preset_headers(
+    headers: &mut HeaderMap,
+    preset_name: &str,
+    values: &[(&'static str, Option<String>)],
+) {
+    for &(header_name, ref value) in values {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+        match HeaderValue::from_str(value) {
+            Ok(header_value) => {
+                headers.insert(HeaderName::from_static(header_name), header_value);
+            }
+            Err(_) => debug!(
+                header = header_name,
+                "Skipped {} header with an invalid value.", preset_name
+            ),
+        }
+    }
+}
+
+/// Sets the identity headers that oauth2-proxy would, so applications behind it don't notice it
+/// being replaced.
+fn insert_oauth2_proxy_headers(
+    headers: &mut HeaderMap,
+    user: &str,
+    email: Option<&str>,
+    groups: &[&str],
+    access_token: &str,
+) {
+    let values = [
+        ("x-auth-request-user", Some(user.to_string())),
+        ("x-auth-request-email", email.map(str::to_string)),
+        (
+            "x-auth-request-groups",
+            (!groups.is_empty()).then(|| groups.join(",")),
+        ),
+    ];
+    insert_preset_headers(headers, "oauth2-proxy", &values);
+
+    // The token is a credential, so it's marked as sensitive, which keeps it out of Redis and
+    // ext_authz dynamic metadata, like any other credential for the upstream.
+    let authorization = Zeroizing::new(format!("Bearer {}", access_token));
+    match HeaderValue::from_str(&authorization) {
+        Ok(mut header_value) => {
+            header_value.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, header_value);
+        }
+        Err(_) => debug!(
+            header = "authorization",
+            "Skipped oauth2-proxy header with an invalid value."
+        ),
+    }
+}
+
+/// Sets the identity headers that Authelia would, so applications behind it don't notice it being
+/// replaced.
+fn insert_authelia_headers(
+    headers: &mut HeaderMap,
+    user: &str,
+    email: Option<&str>,
+    name: Option<&str>,
+    groups: &[&str],
+) {
+    let values = [
+        ("remote-user", Some(user.to_string())),
+        ("remote-email", email.map(str::to_string)),
+        ("remote-name", name.map(str::to_string)),
+        (
+            "remote-groups",
+            (!groups.is_empty()).then(|| groups.join(",")),
+        ),
+    ];
+    insert_preset_headers(headers, "Authelia", &values);
+}
+
+/// Sets the identity headers that nginx configurations commonly read, so they can be the same
+/// regardless of how the claims are configured.
+fn insert_nginx_identity_headers(
+    headers: &mut HeaderMap,
+    user: &str,
+    email: Option<&str>,
+    groups: &[&str],
+) {
+    let values = [
+        ("x-auth-user", Some(user.to_string())),
+        ("x-auth-email", email.map(str::to_string)),
+        (
+            "x-auth-groups",
+            (!groups.is_empty()).then(|| groups.join(",")),
+        ),
+    ];
+    insert_preset_headers(headers, "nginx identity", &values);
+}
+
+/// Sets each of the given headers to the value its JSON pointer resolves to in the claims.
+fn insert_claim_headers(headers: &mut HeaderMap, config: &Config, claims_json: &Value) {
+    for (header_name, pointer) in &config.claim_headers {
+        let value = match claims_json.pointer(pointer) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        // Redaction and rendering rules apply to the name of the claim the pointer ends at.
+        let claim_name = pointer.rsplit('/').next().unwrap_or_default();
+        let rendered = config
+            .claim_rendering
+            .render(claim_name, value)
+            .unwrap_or_else(|| value.to_string().into());
+        let claim_value = ClaimValue::new(claim_name, rendered);
+
+        let header_name = match HeaderName::from_str(header_name) {
+            Ok(header_name) => header_name,
+            Err(_) => continue,
+        };
+
+        match HeaderValue::from_str(claim_value.expose()) {
+            Ok(header_value) => {
+                headers.insert(header_name, header_value);
+            }
+            Err(_) => debug!(
+                "Resolved invalid header value '{}' for claim header '{}'.",
+                claim_value, header_name
+            ),
+        }
+    }
+}
+
+/// Records how long it took to handle a validation request in the `X-Auth-Duration-Ms` header.
+///
+/// This lets the proxy (and anything downstream of it) attribute latency to the authentication hop
+/// without having to correlate our logs.
+async fn record_auth_duration<B>(request: Request<B>, next: Next<B>) -> Response {
+    let started = Instant::now();
+    let mut response = next.run(request).await;
+
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    if let Ok(header_value) = HeaderValue::from_str(&format!("{:.3}", elapsed_ms)) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-auth-duration-ms"), header_value);
+    }
+
+    response
+}
+
+/// Records the route, status, and duration of every routed request in the metrics.
+async fn record_request_metrics<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    telemetry::record_request(&route, response.status().as_u16(), started.elapsed());
+
+    response
+}
+
+async fn metrics(Extension(handle): Extension<PrometheusHandle>) -> String {
+    telemetry::render(&handle)
+}
+
+/// Buffers every response body and sets an explicit `Content-Length` header, except on `1xx`,
+/// `204` and `304` responses, which mustn't have one.
+///
+/// This guarantees that responses are never sent with chunked transfer encoding, which some older
+/// proxies mishandle for forward auth responses. Every response we send is tiny, so buffering them
+/// costs next to nothing, and doing it here makes it a property of every endpoint rather than
+/// something each handler has to get right.
+async fn set_content_length<B>(request: Request<B>, next: Next<B>) -> Response {
+    let (mut parts, body) = next.run(request).await.into_parts();
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!(error = %e, "Failed to buffer response body.");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                [(header::CONTENT_LENGTH, HeaderValue::from(0))],
+            )
+                .into_response();
+        }
+    };
+
+    parts.headers.remove(header::TRANSFER_ENCODING);
+
+    // These responses can't have a body, so they mustn't have a `Content-Length` either.
+    let bodyless = parts.status.is_informational()
+        || parts.status == StatusCode::NO_CONTENT
+        || parts.status == StatusCode::NOT_MODIFIED;
+    if bodyless {
+        parts.headers.remove(header::CONTENT_LENGTH);
+    } else {
+        parts
+            .headers
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Response::from_parts(parts, boxed(Full::from(body)))
+}
+
+/// Everything the API endpoint shares between requests.
+pub struct ApiState {
+    pub states: Arc<SignatureStates>,
+    pub token_map: Arc<ServiceTokenMapStore>,
+    pub message_signer: Option<Arc<MessageSigner>>,
+    pub user_enricher: Option<Arc<UserEnricher>>,
+    pub identity_enricher: Option<Arc<IdentityEnricher>>,
+    pub jti_tracker: Option<Arc<JtiTracker>>,
+    pub internal_token: Option<Arc<InternalTokenIssuer>>,
+    pub group_names: Option<Arc<GroupNameResolver>>,
+    pub admin_token: Option<Arc<AdminToken>>,
+    pub proxy_secret: Option<Arc<ProxySecret>>,
+    pub validation_cache: Option<Arc<ValidationCache>>,
+    pub error_pages: Option<Arc<ErrorPages>>,
+    pub anomaly_detector: Option<Arc<AnomalyDetector>>,
+    pub opa: Option<Arc<OpaClient>>,
+    pub config: Arc<Config>,
+    pub policies: Arc<AudiencePolicyStore>,
+    pub metrics_handle: PrometheusHandle,
+    pub task_statuses: Arc<TaskStatuses>,
+}
+
+/// Builds the router for the API endpoint.
+pub(crate) fn api_router(api_state: ApiState) -> Router {
+    let ApiState {
+        states,
+        token_map,
+        message_signer,
+        user_enricher,
+        identity_enricher,
+        jti_tracker,
+        internal_token,
+        group_names,
+        admin_token,
+        proxy_secret,
+        validation_cache,
+        error_pages,
+        anomaly_detector,
+        opa,
+        config,
+        policies,
+        metrics_handle,
+        task_statuses,
+    } = api_state;
+
+    // Names are validated when loading the configuration, so they always make a valid list.
+    let envoy_headers_to_remove = config.envoy.as_ref().map(|envoy| {
+        HeaderValue::from_str(&envoy.headers_to_remove.join(", "))
+            .ok()
+            .filter(|headers_to_remove| !headers_to_remove.is_empty())
+    });
+
+    let mut app = Router::new()
+        .route("/health/ready", get(readiness))
+        .route("/health/live", get(|| ready(())))
+        .route("/metrics", get(metrics))
+        .route(
+            "/validate",
+            get(validate)
+                .route_layer(middleware::from_fn(record_auth_duration))
+                .route_layer(middleware::from_fn(error_page::render))
+                .route_layer(middleware::from_fn(nginx::adapt_response)),
+        )
+        .route(
+            "/validate/:audience",
+            get(validate)
+                .route_layer(middleware::from_fn(record_auth_duration))
+                .route_layer(middleware::from_fn(error_page::render))
+                .route_layer(middleware::from_fn(nginx::adapt_response)),
+        );
+
+    // The admin endpoints only exist if there's an admin token to protect them with.
+    if let Some(admin_token) = admin_token {
+        app = app.route(
+            "/admin/jwks/refresh",
+            post(admin::refresh_jwks).layer(Extension(admin_token)),
+        );
+    }
+
+    // Upstreams verify internal tokens against this, so it only exists if they're minted at all.
+    if let Some(internal_token) = &internal_token {
+        let jwks = internal_token.jwks().clone();
+        app = app.route(
+            "/.well-known/jwks.json",
+            get(move || ready(Json(jwks.clone()))),
+        );
+    }
+
+    let app = app
+        .route_layer(middleware::from_fn(record_request_metrics))
+        .layer(Extension(states))
+        .layer(Extension(token_map))
+        .layer(Extension(message_signer))
+        .layer(Extension(Enrichers {
+            user: user_enricher,
+            identity: identity_enricher,
+        }))
+        .layer(Extension(jti_tracker))
+        .layer(Extension(internal_token))
+        .layer(Extension(group_names))
+        .layer(Extension(anomaly_detector))
+        .layer(Extension(proxy_secret))
+        .layer(Extension(validation_cache))
+        .layer(Extension(error_pages))
+        .layer(Extension(opa))
+        .layer(Extension(config))
+        .layer(Extension(policies))
+        .layer(Extension(metrics_handle))
+        .layer(Extension(task_statuses))
+        .layer(middleware::from_fn(set_content_length))
+        .layer(middleware::from_fn(trace_context::echo_trace_context))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request<Body>| trace_context::make_request_span(request))
+                .on_request(|_: &Request<_>, _: &Span| info!("Got request.")),
+        );
+
+    // Envoy's checks are translated into validation requests, which are then handled by everything
+    // above, exactly like any other.
+    match envoy_headers_to_remove {
+        Some(headers_to_remove) => {
+            let validate_app = app.clone();
+            app.nest(
+                envoy::ENVOY_PATH_PREFIX,
+                any(move |request: Request<Body>| {
+                    envoy::check(validate_app.clone(), headers_to_remove.clone(), request)
+                }),
+            )
+        }
+        None => app,
+    }
+}
+
+pub async fn run_api_endpoint(
+    listen_address: &SocketAddr,
+    api_state: ApiState,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), String> {
+    let config = Arc::clone(&api_state.config);
+    let shutdown_timeout = config.shutdown_timeout();
+    let app = api_router(api_state);
+
+    // If enabled, run the ext_authz listener alongside the others, sharing the same router.
+    #[cfg(feature = "ext-authz")]
+    let ext_authz = {
+        let listen_address = config
+            .ext_authz
+            .as_ref()
+            .map(|ext_authz| ext_authz.listen_address);
+        let (app, shutdown) = (app.clone(), shutdown.clone());
+        async move {
+            match listen_address {
+                Some(listen_address) => {
+                    ext_authz::run_ext_authz_endpoint(
+                        listen_address,
+                        app,
+                        shutdown,
+                        shutdown_timeout,
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        }
+    };
+    #[cfg(not(feature = "ext-authz"))]
+    let ext_authz = ready(Ok::<_, String>(()));
+
+    // If enabled, run the HTTP/3 listener alongside the TCP listener, sharing the same router, and
+    // advertise it to clients of the TCP listener.
+    #[cfg(feature = "http3")]
+    if let Some(http3_config) = config.http3.as_ref() {
+        let alt_svc = http3_config
+            .alt_svc()
+            .and_then(|alt_svc| HeaderValue::from_str(&alt_svc).ok());
+        let tcp_app = match alt_svc {
+            Some(alt_svc) => {
+                app.clone()
+                    .layer(tower_http::set_header::SetResponseHeaderLayer::overriding(
+                        header::ALT_SVC,
+                        alt_svc,
+                    ))
+            }
+            None => app.clone(),
+        };
+
+        return tokio::try_join!(
+            serve_tcp(listen_address, tcp_app, shutdown.clone(), shutdown_timeout),
+            http3::run_http3_endpoint(http3_config, app, shutdown, shutdown_timeout),
+            ext_authz,
+        )
+        .map(|_| ());
+    }
+
+    tokio::try_join!(
+        serve_tcp(listen_address, app, shutdown, shutdown_timeout),
+        ext_authz,
+    )
+    .map(|_| ())
+}
+
+async fn serve_tcp(
+    listen_address: &SocketAddr,
+    app: Router,
+    shutdown: watch::Receiver<bool>,
+    shutdown_timeout: Duration,
+) -> Result<(), String> {
+    info!("Listening on {}.", listen_address);
+
+    // Once shutdown is requested, the server stops accepting new connections, and finishes once
+    // every in-flight request has been responded to.
+    let server = axum::Server::bind(listen_address)
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_requested(shutdown.clone()));
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => return result.map_err(|e| format!("Failed to serve HTTP: {}", e)),
+        _ = shutdown_requested(shutdown) => {}
+    }
+
+    info!("Shutting down. Waiting for in-flight requests to finish.");
+    match timeout(shutdown_timeout, server).await {
+        Ok(result) => result.map_err(|e| format!("Failed to serve HTTP: {}", e)),
+        Err(_) => {
+            warn!("Timed out waiting for in-flight requests to finish. Dropping them.");
+            Ok(())
+        }
+    }
+}
+
+/// Waits until shutdown is requested.
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    // If the sender is gone, nothing can request shutdown anymore, which is only the case when
+    // we're already on our way out.
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::Redirect;
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::replay;
+
+    /// Makes a request to the given URI through a router that only sets the content length,
+    /// returning the response's status, `Content-Length` header and body.
+    async fn buffered(uri: &str) -> (StatusCode, Option<String>, Bytes) {
+        let mut app = Router::new()
+            .route(
+                "/streamed",
+                get(|| async {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        for chunk in ["hello, ", "world"] {
+                            let _ = sender.send_data(Bytes::from_static(chunk.as_bytes())).await;
+                        }
+                    });
+                    Response::builder()
+                        .header(header::TRANSFER_ENCODING, "chunked")
+                        .body(body)
+                        .unwrap()
+                }),
+            )
+            .route("/error", get(|| async { AuthError::InvalidProxySecret }))
+            .route("/redirect", get(|| async { Redirect::temporary("/login") }))
+            .route("/empty", get(|| async { StatusCode::NO_CONTENT }))
+            .route("/not-modified", get(|| async { StatusCode::NOT_MODIFIED }))
+            .layer(middleware::from_fn(set_content_length));
+
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = replay::call(&mut app, request).await;
+        assert!(
+            !response.headers().contains_key(header::TRANSFER_ENCODING),
+            "response to '{}' is chunked",
+            uri
+        );
+
+        let (parts, body) = response.into_parts();
+        let content_length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .map(|value| value.to_str().unwrap().to_string());
+        (parts.status, content_length, to_bytes(body).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn content_length_for_streamed_body() {
+        let (status, content_length, body) = buffered("/streamed").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_length.as_deref(), Some("12"));
+        assert_eq!(body, "hello, world");
+    }
+
+    #[tokio::test]
+    async fn content_length_for_error() {
+        let (status, content_length, body) = buffered("/error").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(content_length, Some(body.len().to_string()));
+        assert!(!body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn content_length_for_redirect() {
+        let (status, content_length, body) = buffered("/redirect").await;
+        assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(content_length.as_deref(), Some("0"));
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn content_length_for_empty_body() {
+        let (status, content_length, body) = buffered("/empty").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(content_length, None);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn content_length_for_not_modified() {
+        let (status, content_length, body) = buffered("/not-modified").await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(content_length, None);
+        assert!(body.is_empty());
+    }
+}
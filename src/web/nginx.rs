@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use axum::{middleware::Next, response::Response};
+use hyper::{
+    header::{HeaderMap, HeaderName},
+    Request, StatusCode,
+};
+use tracing::debug;
+
+use crate::config::Config;
+
+/// Adapts validation responses to what nginx's `auth_request` module understands, if configured
+/// to.
+///
+/// `auth_request` only lets requests through on a `2xx`, and only passes `401` and `403` on to the
+/// client, turning anything else into a `500`. So redirects to the login page are made `401`s, with
+/// their `Location` header kept for `auth_request_set`, and any other client error is made a `403`.
+/// Server errors are left alone, as they're failures rather than denials.
+///
+/// Identity headers can only be read through `$upstream_http_*` variables, so every character that
+/// can't appear in a variable name is replaced in their names, making each one predictable.
+pub(super) async fn adapt_response<B>(request: Request<B>, next: Next<B>) -> Response {
+    let enabled = request
+        .extensions()
+        .get::<Arc<Config>>()
+        .map_or(false, |config| config.nginx.is_some());
+    let mut response = next.run(request).await;
+    if !enabled {
+        return response;
+    }
+
+    let status = response.status();
+    if status.is_success() {
+        let headers = std::mem::take(response.headers_mut());
+        *response.headers_mut() = with_variable_names(headers);
+    } else if status.is_redirection() {
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+    } else if status.is_client_error()
+        && status != StatusCode::UNAUTHORIZED
+        && status != StatusCode::FORBIDDEN
+    {
+        *response.status_mut() = StatusCode::FORBIDDEN;
+    }
+
+    response
+}
+
+/// Renames the given headers so their names only contain lowercase letters, digits and dashes,
+/// which nginx maps to `$upstream_http_*` variables by replacing the dashes with underscores.
+///
+/// If renaming a header makes its name clash with a header that was already named that way, the
+/// latter wins.
+fn with_variable_names(headers: HeaderMap) -> HeaderMap {
+    let mut renamed = HeaderMap::with_capacity(headers.len());
+    for (name, value) in &headers {
+        let variable_name = name
+            .as_str()
+            .bytes()
+            .map(|b| match b {
+                b'a'..=b'z' | b'0'..=b'9' | b'-' => b as char,
+                _ => '-',
+            })
+            .collect::<String>();
+        if variable_name == name.as_str() {
+            renamed.append(name.clone(), value.clone());
+            continue;
+        }
+
+        match HeaderName::from_bytes(variable_name.as_bytes()) {
+            Ok(variable_name) if !headers.contains_key(&variable_name) => {
+                renamed.append(variable_name, value.clone());
+            }
+            _ => debug!(
+                header = name.as_str(),
+                "Dropped header whose name clashes with another once renamed for nginx."
+            ),
+        }
+    }
+
+    renamed
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use hyper::HeaderMap;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+/// The header that proxies present the shared secret in.
+const PROXY_SECRET_HEADER: &str = "x-forwardauth-secret";
+
+/// The secret shared with the proxies, which they must present on every validation request.
+///
+/// This ensures that only our proxies can make use of the validation logic, even if the network
+/// policy in front of us lets something else through.
+pub struct ProxySecret(Zeroizing<String>);
+
+impl ProxySecret {
+    /// Loads the proxy secret from the given file.
+    ///
+    /// Leading and trailing whitespace is ignored, so the file may end with a newline.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map(Zeroizing::new)
+            .map_err(|e| {
+                format!(
+                    "Failed to read proxy secret file '{}': {}",
+                    path.display(),
+                    e
+                )
+            })?;
+
+        let secret = contents.trim();
+        if secret.is_empty() {
+            return Err(format!("Proxy secret file '{}' is empty.", path.display()));
+        }
+
+        Ok(Self(Zeroizing::new(secret.to_string())))
+    }
+
+    /// Whether or not the request was made with the proxy secret.
+    pub(crate) fn is_presented(&self, headers: &HeaderMap) -> bool {
+        // Every presented value must match, so a client can't sneak a guess in alongside the real
+        // secret added by the proxy.
+        let mut presented = headers.get_all(PROXY_SECRET_HEADER).iter().peekable();
+        if presented.peek().is_none() {
+            return false;
+        }
+
+        // Compare hashes, rather than the secrets themselves, so the comparison doesn't leak how
+        // much of the secret was right through its timing.
+        let expected = Sha256::digest(self.0.as_bytes());
+        presented.all(|value| Sha256::digest(value.as_bytes()) == expected)
+    }
+}
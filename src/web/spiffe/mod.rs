@@ -0,0 +1,425 @@
+use std::path::PathBuf;
+
+use hyper::{http::uri::PathAndQuery, Uri};
+use rustls::{
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey,
+};
+use tokio::net::UnixStream;
+use tonic::{
+    client::Grpc,
+    codec::{ProstCodec, Streaming},
+    metadata::MetadataValue,
+    transport::Endpoint,
+};
+use tower::service_fn;
+
+use self::proto::{X509Svid, X509SvidRequest, X509SvidResponse};
+
+mod proto;
+
+/// The environment variable holding the address of the Workload API, if none is configured.
+const ENDPOINT_SOCKET_VAR: &str = "SPIFFE_ENDPOINT_SOCKET";
+
+/// The Workload API method that streams X.509-SVIDs, sending new ones whenever they're rotated.
+const FETCH_X509_SVID: &str = "/SpiffeWorkloadAPI/FetchX509SVID";
+
+/// The X.509-SVIDs of this workload, as delivered by the SPIFFE Workload API, such as a SPIRE
+/// agent's.
+pub struct SvidStream {
+    stream: Streaming<X509SvidResponse>,
+    spiffe_id: Option<String>,
+}
+
+impl SvidStream {
+    /// Connects to the Workload API at the given `unix:` address, or the one in
+    /// `SPIFFE_ENDPOINT_SOCKET` if none is given.
+    ///
+    /// If a SPIFFE ID is given, the SVID for it is used, instead of the workload's default one.
+    pub async fn connect(socket: Option<&str>, spiffe_id: Option<&str>) -> Result<Self, String> {
+        let socket = match socket {
+            Some(socket) => socket.to_string(),
+            None => std::env::var(ENDPOINT_SOCKET_VAR).map_err(|_| {
+                format!(
+                    "No Workload API socket is configured, and `{}` is not set.",
+                    ENDPOINT_SOCKET_VAR
+                )
+            })?,
+        };
+        let path = socket_path(&socket)?;
+
+        // Every connection goes to the socket, so the URI is only a placeholder.
+        let channel = Endpoint::from_static("http://localhost")
+            .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to connect to the Workload API at '{}': {}",
+                    socket, e
+                )
+            })?;
+        let mut grpc = Grpc::new(channel);
+        grpc.ready().await.map_err(|e| {
+            format!(
+                "Failed to connect to the Workload API at '{}': {}",
+                socket, e
+            )
+        })?;
+
+        // The Workload API rejects requests without this, so that it can't be reached through SSRF.
+        let mut request = tonic::Request::new(X509SvidRequest {});
+        request
+            .metadata_mut()
+            .insert("workload.spiffe.io", MetadataValue::from_static("true"));
+        let stream = grpc
+            .server_streaming(
+                request,
+                PathAndQuery::from_static(FETCH_X509_SVID),
+                ProstCodec::default(),
+            )
+            .await
+            .map_err(|e| format!("Failed to fetch X.509-SVIDs: {}", e.message()))?
+            .into_inner();
+
+        Ok(Self {
+            stream,
+            spiffe_id: spiffe_id.map(str::to_string),
+        })
+    }
+
+    /// Waits for the next SVID, as the current one is rotated.
+    ///
+    /// The first SVID is sent as soon as the stream is connected. Returns `None` if the Workload
+    /// API closed the stream.
+    pub async fn next(&mut self) -> Result<Option<Svid>, String> {
+        let response = match self.stream.message().await {
+            Ok(Some(response)) => response,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(format!("Failed to fetch X.509-SVIDs: {}", e.message())),
+        };
+
+        let svid = match &self.spiffe_id {
+            Some(spiffe_id) => response
+                .svids
+                .into_iter()
+                .find(|svid| svid.spiffe_id == *spiffe_id)
+                .ok_or_else(|| format!("The Workload API has no SVID for '{}'.", spiffe_id))?,
+            None => response
+                .svids
+                .into_iter()
+                .next()
+                .ok_or_else(|| "The Workload API sent no SVIDs.".to_string())?,
+        };
+
+        Svid::try_from(svid).map(Some)
+    }
+}
+
+/// Gets the path of the socket from a Workload API address, like `unix:///run/spire/agent.sock`.
+fn socket_path(socket: &str) -> Result<PathBuf, String> {
+    socket
+        .strip_prefix("unix://")
+        .or_else(|| socket.strip_prefix("unix:"))
+        .filter(|path| path.starts_with('/'))
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            format!(
+                "Workload API address '{}' is not a `unix:` address with an absolute path.",
+                socket
+            )
+        })
+}
+
+/// An X.509-SVID, ready to be served.
+pub struct Svid {
+    pub key: CertifiedKey,
+    pub identity: SvidIdentity,
+}
+
+/// Who an X.509-SVID identifies, and which CAs its trust domain trusts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvidIdentity {
+    pub spiffe_id: String,
+
+    /// The CA certificates that the SVIDs of peers in the same trust domain are verified against.
+    pub bundle: Vec<Certificate>,
+}
+
+impl TryFrom<X509Svid> for Svid {
+    type Error = String;
+
+    fn try_from(svid: X509Svid) -> Result<Self, String> {
+        let certs = split_certificates(&svid.x509_svid).ok_or_else(|| {
+            format!(
+                "Invalid certificate chain in SVID for '{}'.",
+                svid.spiffe_id
+            )
+        })?;
+        let key = sign::any_supported_type(&PrivateKey(svid.x509_svid_key)).map_err(|e| {
+            format!(
+                "Unsupported private key in SVID for '{}': {}",
+                svid.spiffe_id, e
+            )
+        })?;
+        // The bundle is only needed to verify peers, so an SVID without one can still be served.
+        let bundle = if svid.bundle.is_empty() {
+            Vec::new()
+        } else {
+            split_certificates(&svid.bundle)
+                .ok_or_else(|| format!("Invalid trust bundle in SVID for '{}'.", svid.spiffe_id))?
+        };
+
+        Ok(Self {
+            key: CertifiedKey::new(certs, key),
+            identity: SvidIdentity {
+                spiffe_id: svid.spiffe_id,
+                bundle,
+            },
+        })
+    }
+}
+
+/// Checks that the peer of a connection presented an X.509-SVID for the given SPIFFE ID.
+///
+/// The certificate chain itself is verified against the trust bundle during the handshake, so
+/// this only checks who it was issued to.
+pub fn verify_peer(connection: &quinn::Connection, expected: &str) -> Result<(), String> {
+    let certs = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok())
+        .ok_or_else(|| "Client presented no certificate.".to_string())?;
+    match certs.first().and_then(|leaf| spiffe_id(&leaf.0)) {
+        Some(spiffe_id) if spiffe_id == expected => Ok(()),
+        Some(spiffe_id) => Err(format!(
+            "Client presented an SVID for '{}', not '{}'.",
+            spiffe_id, expected
+        )),
+        None => Err("Client presented a certificate that is not an X.509-SVID.".to_string()),
+    }
+}
+
+/// Splits a certificate chain, as concatenated DER certificates, into each certificate.
+fn split_certificates(mut chain: &[u8]) -> Option<Vec<Certificate>> {
+    let mut certs = Vec::new();
+    while !chain.is_empty() {
+        let (_, rest) = expect_element(chain, SEQUENCE)?;
+        let (cert, rest) = chain.split_at(chain.len() - rest.len());
+        certs.push(Certificate(cert.to_vec()));
+        chain = rest;
+    }
+
+    (!certs.is_empty()).then_some(certs)
+}
+
+/// Gets the SPIFFE ID of a DER X.509-SVID, which is the `spiffe://` URI in its subject alternative
+/// names.
+fn spiffe_id(cert: &[u8]) -> Option<&str> {
+    let (cert, _) = expect_element(cert, SEQUENCE)?;
+    let (mut tbs_certificate, _) = expect_element(cert, SEQUENCE)?;
+
+    // The extensions are the last field of the `TBSCertificate`, and the only one tagged `[3]`.
+    let mut extensions = loop {
+        let (tag, contents, rest) = split_element(tbs_certificate)?;
+        if tag == EXTENSIONS {
+            break expect_element(contents, SEQUENCE)?.0;
+        }
+        tbs_certificate = rest;
+    };
+    while !extensions.is_empty() {
+        let (extension, rest) = expect_element(extensions, SEQUENCE)?;
+        extensions = rest;
+        let (extension_id, extension) = expect_element(extension, OBJECT_IDENTIFIER)?;
+        if extension_id != SUBJECT_ALT_NAME {
+            continue;
+        }
+
+        // `critical` is left out when it's false.
+        let (tag, contents, rest) = split_element(extension)?;
+        let value = match tag {
+            BOOLEAN => expect_element(rest, OCTET_STRING)?.0,
+            OCTET_STRING => contents,
+            _ => return None,
+        };
+        let (mut names, _) = expect_element(value, SEQUENCE)?;
+        while !names.is_empty() {
+            let (tag, name, rest) = split_element(names)?;
+            if tag == URI && name.starts_with(b"spiffe://") {
+                return std::str::from_utf8(name).ok();
+            }
+            names = rest;
+        }
+        return None;
+    }
+
+    None
+}
+
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+/// `[3]`, for the extensions of a `TBSCertificate`.
+const EXTENSIONS: u8 = 0xa3;
+/// `[6]`, for the `uniformResourceIdentifier` of a `GeneralName`.
+const URI: u8 = 0x86;
+
+/// The DER encoding of `id-ce-subjectAltName`, 2.5.29.17.
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Splits the DER element at the start of `der` into its contents and what follows it, if it has
+/// the given tag.
+fn expect_element(der: &[u8], expected_tag: u8) -> Option<(&[u8], &[u8])> {
+    let (tag, contents, rest) = split_element(der)?;
+    (tag == expected_tag).then_some((contents, rest))
+}
+
+/// Splits the DER element at the start of `der` into its tag, its contents, and what follows it.
+fn split_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    // Short lengths are the byte itself, while long ones give the number of big-endian bytes that
+    // follow. Certificates never need more than four.
+    let (len_bytes, content_len) = if len < 0x80 {
+        (0, len as usize)
+    } else {
+        let len_bytes = rest.get(..(len & 0x7f) as usize)?;
+        if len_bytes.is_empty() || len_bytes.len() > 4 {
+            return None;
+        }
+        let content_len = len_bytes
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        (len_bytes.len(), content_len)
+    };
+
+    let rest = &rest[len_bytes..];
+    (content_len <= rest.len()).then(|| {
+        let (contents, rest) = rest.split_at(content_len);
+        (tag, contents, rest)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_path_accepts_unix_addresses() {
+        assert_eq!(
+            socket_path("unix:///run/spire/agent.sock"),
+            Ok(PathBuf::from("/run/spire/agent.sock"))
+        );
+        assert_eq!(
+            socket_path("unix:/run/spire/agent.sock"),
+            Ok(PathBuf::from("/run/spire/agent.sock"))
+        );
+        assert!(socket_path("unix://run/spire/agent.sock").is_err());
+        assert!(socket_path("tcp://127.0.0.1:8081").is_err());
+        assert!(socket_path("/run/spire/agent.sock").is_err());
+    }
+
+    #[test]
+    fn split_certificates_splits_short_and_long_lengths() {
+        let short = [0x30, 0x02, 0x01, 0x02];
+        let mut long = vec![0x30, 0x81, 0x80];
+        long.extend([0xaa; 0x80]);
+        let chain = [&short[..], &long].concat();
+
+        let certs = split_certificates(&chain).unwrap();
+        assert_eq!(certs, vec![Certificate(short.to_vec()), Certificate(long)]);
+    }
+
+    #[test]
+    fn split_certificates_rejects_invalid_chains() {
+        assert_eq!(split_certificates(&[]), None);
+        // Truncated.
+        assert_eq!(split_certificates(&[0x30, 0x03, 0x01, 0x02]), None);
+        assert_eq!(split_certificates(&[0x30, 0x82, 0x01]), None);
+        // Not a `SEQUENCE`.
+        assert_eq!(split_certificates(&[0x04, 0x02, 0x01, 0x02]), None);
+        // Indefinite length.
+        assert_eq!(split_certificates(&[0x30, 0x80, 0x00, 0x00]), None);
+    }
+
+    /// Encodes a DER element, with contents short enough for a one-byte length.
+    fn der(tag: u8, contents: &[&[u8]]) -> Vec<u8> {
+        let contents = contents.concat();
+        [&[tag, contents.len() as u8][..], &contents].concat()
+    }
+
+    /// Encodes a certificate, down to what's needed to find its subject alternative names.
+    fn certificate(extensions: &[&[u8]]) -> Vec<u8> {
+        let tbs_certificate = der(
+            SEQUENCE,
+            &[
+                &der(0xa0, &[&der(0x02, &[&[2]])]),
+                &der(0x02, &[&[1]]),
+                &der(SEQUENCE, &[]),
+                &der(EXTENSIONS, &[&der(SEQUENCE, extensions)]),
+            ],
+        );
+        der(
+            SEQUENCE,
+            &[&tbs_certificate, &der(SEQUENCE, &[]), &der(0x03, &[&[0]])],
+        )
+    }
+
+    fn subject_alt_name(critical: bool, names: &[&[u8]]) -> Vec<u8> {
+        let critical = if critical {
+            der(BOOLEAN, &[&[0xff]])
+        } else {
+            Vec::new()
+        };
+        der(
+            SEQUENCE,
+            &[
+                &der(OBJECT_IDENTIFIER, &[SUBJECT_ALT_NAME]),
+                &critical,
+                &der(OCTET_STRING, &[&der(SEQUENCE, names)]),
+            ],
+        )
+    }
+
+    #[test]
+    fn spiffe_id_is_taken_from_the_uri_san() {
+        let uri = der(URI, &[b"spiffe://example.org/proxy"]);
+        // Basic constraints, which come before the subject alternative names.
+        let basic_constraints = der(
+            SEQUENCE,
+            &[
+                &der(OBJECT_IDENTIFIER, &[&[0x55, 0x1d, 0x13]]),
+                &der(OCTET_STRING, &[&der(SEQUENCE, &[])]),
+            ],
+        );
+        let dns_name = der(0x82, &[b"proxy.example.org"]);
+
+        let cases: &[(Vec<u8>, Option<&str>)] = &[
+            (
+                certificate(&[&subject_alt_name(false, &[&uri])]),
+                Some("spiffe://example.org/proxy"),
+            ),
+            (
+                certificate(&[
+                    &basic_constraints,
+                    &subject_alt_name(true, &[&dns_name, &uri]),
+                ]),
+                Some("spiffe://example.org/proxy"),
+            ),
+            (
+                certificate(&[&subject_alt_name(
+                    false,
+                    &[&der(URI, &[b"https://example.org"])],
+                )]),
+                None,
+            ),
+            (certificate(&[&subject_alt_name(false, &[&dns_name])]), None),
+            (certificate(&[&basic_constraints]), None),
+            (certificate(&[]), None),
+            (vec![0x30, 0x03, 0x30, 0x01], None),
+        ];
+        for (cert, expected) in cases {
+            assert_eq!(spiffe_id(cert), *expected);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! The messages of the SPIFFE Workload API's `SpiffeWorkloadAPI` service.
+//!
+//! Only the messages and fields for fetching X.509-SVIDs are declared, with the same tags as
+//! upstream, so the rest are skipped when decoding, like any other unknown field.
+
+/// `X509SVIDRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct X509SvidRequest {}
+
+/// `X509SVIDResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct X509SvidResponse {
+    /// Every SVID the workload is entitled to, with the default one first.
+    #[prost(message, repeated, tag = "1")]
+    pub svids: Vec<X509Svid>,
+}
+
+/// `X509SVID`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct X509Svid {
+    #[prost(string, tag = "1")]
+    pub spiffe_id: String,
+
+    /// The certificate chain, as concatenated DER certificates, leaf first.
+    #[prost(bytes = "vec", tag = "2")]
+    pub x509_svid: Vec<u8>,
+
+    /// The private key, as PKCS#8 DER.
+    #[prost(bytes = "vec", tag = "3")]
+    pub x509_svid_key: Vec<u8>,
+
+    /// The CA certificates of the SVID's trust domain, as concatenated DER certificates.
+    #[prost(bytes = "vec", tag = "4")]
+    pub bundle: Vec<u8>,
+}
@@ -0,0 +1,278 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use hyper::{body::to_bytes, client::HttpConnector, header, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey,
+};
+use serde::Deserialize;
+use tokio::time::interval;
+#[cfg(feature = "spiffe")]
+use tokio::{sync::watch, time::sleep};
+use tracing::{error, info};
+
+#[cfg(feature = "spiffe")]
+use super::spiffe::{Svid, SvidIdentity, SvidStream};
+use crate::config::TlsSource;
+
+/// Where Kubernetes mounts the credentials of the pod's service account.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// How long to wait before reconnecting to the SPIFFE Workload API after losing it.
+#[cfg(feature = "spiffe")]
+const WORKLOAD_API_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A certificate resolver whose certificate can be swapped out at any time.
+///
+/// New connections pick up the new certificate immediately, while existing connections are
+/// unaffected.
+pub struct ReloadableCertResolver {
+    key: ArcSwap<CertifiedKey>,
+
+    /// The SPIFFE ID and trust bundle of the current certificate, if it's an SVID.
+    #[cfg(feature = "spiffe")]
+    identity: watch::Sender<Option<SvidIdentity>>,
+}
+
+impl ReloadableCertResolver {
+    /// Loads the certificate from the given source.
+    pub async fn load(source: &TlsSource) -> Result<Self, String> {
+        #[cfg(feature = "spiffe")]
+        if let TlsSource::Spiffe {
+            socket, spiffe_id, ..
+        } = source
+        {
+            let svid = load_svid(socket.as_deref(), spiffe_id.as_deref()).await?;
+            return Ok(Self {
+                key: ArcSwap::from_pointee(svid.key),
+                identity: watch::channel(Some(svid.identity)).0,
+            });
+        }
+
+        let key = load_certified_key(source).await?;
+        Ok(Self {
+            key: ArcSwap::from_pointee(key),
+            #[cfg(feature = "spiffe")]
+            identity: watch::channel(None).0,
+        })
+    }
+
+    /// Watches the SPIFFE ID and trust bundle of the current certificate, if it's an SVID.
+    #[cfg(feature = "spiffe")]
+    pub fn identity(&self) -> watch::Receiver<Option<SvidIdentity>> {
+        self.identity.subscribe()
+    }
+
+    /// Reloads the certificate from the given source, on the given interval, forever.
+    ///
+    /// If reloading fails, the current certificate is kept.
+    pub async fn reload_periodically(&self, source: &TlsSource, reload_interval: Duration) {
+        let mut reload_interval = interval(reload_interval);
+        reload_interval.tick().await;
+
+        loop {
+            reload_interval.tick().await;
+
+            match load_certified_key(source).await {
+                Ok(key) => {
+                    self.key.store(Arc::new(key));
+                    info!("Reloaded TLS certificate.");
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to reload TLS certificate. Keeping the current one.")
+                }
+            }
+        }
+    }
+
+    /// Swaps in each new SVID from the SPIFFE Workload API as it's rotated, forever.
+    ///
+    /// If the Workload API is lost, the current certificate is kept until it's reconnected to.
+    #[cfg(feature = "spiffe")]
+    pub async fn follow_workload_api(&self, socket: Option<&str>, spiffe_id: Option<&str>) {
+        loop {
+            let result: Result<(), String> = async {
+                let mut svids = SvidStream::connect(socket, spiffe_id).await?;
+                while let Some(svid) = svids.next().await? {
+                    self.key.store(Arc::new(svid.key));
+                    self.identity.send_if_modified(|identity| {
+                        let modified = identity.as_ref() != Some(&svid.identity);
+                        *identity = Some(svid.identity);
+                        modified
+                    });
+                    info!("Loaded TLS certificate from the SPIFFE Workload API.");
+                }
+                Err("The Workload API closed the stream.".to_string())
+            }
+            .await;
+            if let Err(e) = result {
+                error!(
+                    error = %e,
+                    "Lost the SPIFFE Workload API. Keeping the current TLS certificate."
+                );
+            }
+
+            sleep(WORKLOAD_API_RETRY_INTERVAL).await;
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.load_full())
+    }
+}
+
+async fn load_certified_key(source: &TlsSource) -> Result<CertifiedKey, String> {
+    let (cert_pem, key_pem) = match source {
+        TlsSource::File {
+            cert_file,
+            key_file,
+        } => (read_file(cert_file)?, read_file(key_file)?),
+        TlsSource::Env { cert_var, key_var } => (read_env(cert_var)?, read_env(key_var)?),
+        TlsSource::KubernetesSecret { namespace, name } => {
+            read_kubernetes_secret(namespace.as_deref(), name).await?
+        }
+        #[cfg(feature = "spiffe")]
+        TlsSource::Spiffe {
+            socket, spiffe_id, ..
+        } => {
+            // SVIDs are already in DER, so there's nothing more to parse.
+            return load_svid(socket.as_deref(), spiffe_id.as_deref())
+                .await
+                .map(|svid| svid.key);
+        }
+    };
+
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .map_err(|e| format!("Failed to read certificates: {}", e))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err("No certificates found.".to_string());
+    }
+
+    // Keys may be in either PKCS#8 or PKCS#1 form, so try both.
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
+        .map_err(|e| format!("Failed to read private key: {}", e))?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut key_pem.as_slice())
+            .map_err(|e| format!("Failed to read private key: {}", e))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| "No private key found.".to_string())?;
+    let key =
+        sign::any_supported_type(&key).map_err(|e| format!("Unsupported private key: {}", e))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+#[cfg(feature = "spiffe")]
+async fn load_svid(socket: Option<&str>, spiffe_id: Option<&str>) -> Result<Svid, String> {
+    let mut svids = SvidStream::connect(socket, spiffe_id).await?;
+    svids
+        .next()
+        .await?
+        .ok_or_else(|| "The Workload API closed the stream before sending an SVID.".to_string())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Failed to read '{}': {}", path.display(), e))
+}
+
+fn read_env(name: &str) -> Result<Vec<u8>, String> {
+    std::env::var(name)
+        .map(String::into_bytes)
+        .map_err(|e| format!("Failed to read `{}`: {}", name, e))
+}
+
+#[derive(Deserialize)]
+struct KubernetesSecret {
+    #[serde(default)]
+    data: std::collections::HashMap<String, String>,
+}
+
+/// Reads the certificate chain and private key from a `kubernetes.io/tls` secret.
+async fn read_kubernetes_secret(
+    namespace: Option<&str>,
+    name: &str,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let service_account = Path::new(SERVICE_ACCOUNT_DIR);
+    let read_string = |file: &str| {
+        read_file(&service_account.join(file))
+            .and_then(|s| String::from_utf8(s).map_err(|e| e.to_string()))
+            .map(|s| s.trim().to_string())
+    };
+
+    let token = read_string("token")?;
+    let namespace = match namespace {
+        Some(namespace) => namespace.to_string(),
+        None => read_string("namespace")?,
+    };
+    let host = std::env::var("KUBERNETES_SERVICE_HOST")
+        .map_err(|_| "Not running in Kubernetes: `KUBERNETES_SERVICE_HOST` is not set.")?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+
+    // The API server's certificate is signed by the cluster's own CA.
+    let ca = native_tls::Certificate::from_pem(&read_file(&service_account.join("ca.crt"))?)
+        .map_err(|e| format!("Failed to read cluster CA certificate: {}", e))?;
+    let tls = native_tls::TlsConnector::builder()
+        .add_root_certificate(ca)
+        .build()
+        .map_err(|e| format!("Failed to configure TLS for the Kubernetes API: {}", e))?;
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let client = Client::builder().build::<_, Body>(HttpsConnector::from((http, tls.into())));
+
+    let request = Request::get(format!(
+        "https://{}:{}/api/v1/namespaces/{}/secrets/{}",
+        host, port, namespace, name
+    ))
+    .header(header::AUTHORIZATION, format!("Bearer {}", token))
+    .header(header::ACCEPT, "application/json")
+    .body(Body::empty())
+    .map_err(|e| format!("Failed to build Kubernetes API request: {}", e))?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|e| format!("Failed to read secret '{}/{}': {}", namespace, name, e))?;
+    let status = response.status();
+    let body = to_bytes(response.into_body())
+        .await
+        .map_err(|e| format!("Failed to read secret '{}/{}': {}", namespace, name, e))?;
+    if !status.is_success() {
+        return Err(format!(
+            "Failed to read secret '{}/{}': Kubernetes API responded with {}",
+            namespace, name, status
+        ));
+    }
+
+    let secret: KubernetesSecret = serde_json::from_slice(&body)
+        .map_err(|e| format!("Failed to parse secret '{}/{}': {}", namespace, name, e))?;
+    let decode = |key: &str| {
+        let value = secret
+            .data
+            .get(key)
+            .ok_or_else(|| format!("Secret '{}/{}' has no '{}' key.", namespace, name, key))?;
+        base64::decode(value).map_err(|e| {
+            format!(
+                "Invalid '{}' in secret '{}/{}': {}",
+                key, namespace, name, e
+            )
+        })
+    };
+
+    Ok((decode("tls.crt")?, decode("tls.key")?))
+}
@@ -0,0 +1,117 @@
+use axum::{http::HeaderValue, middleware::Next, response::Response};
+use hyper::{HeaderMap, Request};
+use tracing::{field, info_span, Span};
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// The W3C trace context that the proxy forwarded along with a request.
+///
+/// See <https://www.w3.org/TR/trace-context/>.
+pub struct TraceContext {
+    traceparent: HeaderValue,
+    tracestate: Option<HeaderValue>,
+}
+
+impl TraceContext {
+    /// Gets the trace context from the given headers, if there's a valid `traceparent` header.
+    ///
+    /// A `tracestate` header is only meaningful alongside a valid `traceparent` header, so it's
+    /// ignored otherwise.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?;
+        parse_traceparent(traceparent.to_str().ok()?)?;
+
+        Some(Self {
+            traceparent: traceparent.clone(),
+            tracestate: headers.get(TRACESTATE).cloned(),
+        })
+    }
+
+    /// Gets the trace ID.
+    pub fn trace_id(&self) -> &str {
+        self.parts().0
+    }
+
+    /// Gets the ID of the proxy's span that this request is a child of.
+    pub fn parent_span_id(&self) -> &str {
+        self.parts().1
+    }
+
+    fn parts(&self) -> (&str, &str) {
+        // We've already checked that the header is valid when creating this.
+        self.traceparent
+            .to_str()
+            .ok()
+            .and_then(parse_traceparent)
+            .unwrap_or_default()
+    }
+}
+
+/// Parses a `traceparent` header value into its trace ID and parent span ID.
+///
+/// Future versions of the header are allowed to append fields, so for any version other than `00`,
+/// only the fields we know about are checked.
+fn parse_traceparent(value: &str) -> Option<(&str, &str)> {
+    let mut fields = value.trim().split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+
+    let valid = is_lower_hex(version, 2)
+        && version != "ff"
+        && is_lower_hex(trace_id, 32)
+        && is_lower_hex(parent_id, 16)
+        && is_lower_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0')
+        && (version != "00" || fields.next().is_none());
+
+    valid.then(|| (trace_id, parent_id))
+}
+
+fn is_lower_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Creates the span for a request, joining it to the proxy's trace if the request carries one.
+pub fn make_request_span<B>(request: &Request<B>) -> Span {
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        trace_id = field::Empty,
+        parent_span_id = field::Empty,
+    );
+
+    if let Some(context) = TraceContext::from_headers(request.headers()) {
+        span.record("trace_id", context.trace_id());
+        span.record("parent_span_id", context.parent_span_id());
+    }
+
+    #[cfg(feature = "otel")]
+    crate::telemetry::otel::set_parent_from_headers(&span, request.headers());
+
+    span
+}
+
+/// Echoes the proxy's trace context back in the response, so that it can tie our response to the
+/// request it made.
+pub async fn echo_trace_context<B>(request: Request<B>, next: Next<B>) -> Response {
+    let context = TraceContext::from_headers(request.headers());
+
+    let mut response = next.run(request).await;
+    if let Some(context) = context {
+        let headers = response.headers_mut();
+        headers.insert(TRACEPARENT, context.traceparent);
+        if let Some(tracestate) = context.tracestate {
+            headers.insert(TRACESTATE, tracestate);
+        }
+    }
+
+    response
+}
@@ -0,0 +1,723 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use hyper::{HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+
+use super::{error::AuthError, extract::Credential, VerifiedIdentity};
+use crate::{
+    config::ValidationCacheConfig,
+    gc::{SweepLimits, SweepOutcome, SweepableCache},
+    policy::AudiencePolicies,
+    telemetry,
+    validation::service_auth::ServiceAuthTokenHeaderMap,
+};
+
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+
+#[cfg(feature = "redis")]
+use chrono::TimeZone;
+#[cfg(feature = "redis")]
+use hyper::header::HeaderValue;
+#[cfg(feature = "redis")]
+use openidconnect::IssuerUrl;
+#[cfg(feature = "redis")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "redis")]
+use serde_json::{Map, Value};
+
+#[cfg(feature = "redis")]
+use crate::shared_cache::{header_pairs, headers_from_pairs, SharedCache};
+
+/// Identifies a validation that can be cached.
+///
+/// This is a hash of the presented tokens, so that keys never hold the tokens themselves, along with
+/// the audience they were validated against, and the versions of the policies and service token
+/// mappings that were in effect, so that replacing either of them leaves every cached validation
+/// behind.
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub(super) struct CacheKey {
+    hash: [u8; 32],
+
+    /// Whether or not the validation only depends on the tokens, rather than also on the original
+    /// request, so that it can be cached even if it succeeds.
+    request_independent: bool,
+
+    /// A hash of the presented tokens and the audience, along with the fingerprints of the policy
+    /// and service token mappings, identifying the validation in Redis, since their versions are
+    /// local to each replica.
+    #[cfg(feature = "redis")]
+    shared_hash: [u8; 32],
+}
+
+/// Caches validations, so that repeated requests with the same token don't verify its signature,
+/// and authorize it, all over again.
+///
+/// Successful validations are cached until their token expires, or for the configured maximum TTL,
+/// whichever comes first. Only the headers and identity derived from the token are cached, so OPA,
+/// user and identity enrichment, internal tokens and signatures are still handled for every
+/// request.
+///
+/// Validations that failed because of the token itself, such as an expired one, are cached for the
+/// configured failure TTL, so clients retrying with the same bad token are rejected cheaply.
+///
+/// The cache is held to the same entry and memory limits as when it's swept, by evicting the least
+/// recently used validations as new ones are cached.
+///
+/// If Redis is configured, successful validations are shared with other replicas through it, and
+/// tokens listed in it as revoked are rejected, whether or not their validation is cached.
+pub struct ValidationCache {
+    max_ttl: Duration,
+    failure_ttl: Duration,
+    limits: SweepLimits,
+    entries: Mutex<Entries>,
+    #[cfg(feature = "redis")]
+    shared_cache: Option<Arc<SharedCache>>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<CacheKey, CachedValidation>,
+
+    /// Roughly how much memory every cached validation uses together, in bytes.
+    memory_bytes: usize,
+}
+
+impl Entries {
+    fn insert(&mut self, key: CacheKey, cached: CachedValidation) {
+        self.memory_bytes += cached.memory_bytes();
+        if let Some(replaced) = self.by_key.insert(key, cached) {
+            self.memory_bytes -= replaced.memory_bytes();
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) -> Option<CachedValidation> {
+        let cached = self.by_key.remove(key)?;
+        self.memory_bytes -= cached.memory_bytes();
+        Some(cached)
+    }
+}
+
+struct CachedValidation {
+    result: CachedResult,
+    expires_at: Instant,
+    last_used: Instant,
+}
+
+enum CachedResult {
+    Success {
+        headers: HeaderMap,
+        identity: VerifiedIdentity,
+    },
+    Failure(AuthError),
+}
+
+impl CachedValidation {
+    /// Roughly how much memory the cached validation uses, in bytes.
+    fn memory_bytes(&self) -> usize {
+        let result = match &self.result {
+            CachedResult::Success { headers, identity } => {
+                let headers = headers
+                    .iter()
+                    .map(|(name, value)| name.as_str().len() + value.len())
+                    .sum::<usize>();
+                headers
+                    + identity.subject.len()
+                    + identity.email.as_ref().map_or(0, String::len)
+                    + identity.service_token_id.as_ref().map_or(0, String::len)
+                    + identity.jti.as_ref().map_or(0, String::len)
+            }
+            // Token errors only carry a short reason.
+            CachedResult::Failure(_) => 64,
+        };
+        std::mem::size_of::<CacheKey>() + std::mem::size_of::<Self>() + result
+    }
+}
+
+impl ValidationCache {
+    /// Creates a cache held to the given limits, which should be the same as the ones it's swept
+    /// with.
+    pub fn new(config: &ValidationCacheConfig, limits: SweepLimits) -> Self {
+        Self {
+            max_ttl: config.max_ttl(),
+            failure_ttl: config.failure_ttl(),
+            limits,
+            entries: Mutex::new(Entries::default()),
+            #[cfg(feature = "redis")]
+            shared_cache: None,
+        }
+    }
+
+    /// Shares successful validations with other replicas through the given Redis cache, and checks
+    /// it for revoked tokens.
+    #[cfg(feature = "redis")]
+    pub fn with_shared_cache(mut self, shared_cache: Arc<SharedCache>) -> Self {
+        self.shared_cache = Some(shared_cache);
+        self
+    }
+
+    /// Gets the key to cache the validation of the given tokens against the given audience under.
+    ///
+    /// Returns `None` if the audience isn't known, so there's nothing to validate against.
+    pub(super) fn key(
+        &self,
+        audience: &str,
+        credentials: &[Credential],
+        policies: &AudiencePolicies,
+        token_map: &ServiceAuthTokenHeaderMap,
+    ) -> Option<CacheKey> {
+        // Path rules and expressions depend on the original request.
+        let policy = policies.for_audience(audience)?;
+        let request_independent = !policy.has_path_rules() && policy.expression().is_none();
+
+        // Each part is length-prefixed, so that no two different sets of parts hash the same.
+        let mut hasher = Sha256::new();
+        for credential in credentials {
+            let token = credential.token.secret().as_bytes();
+            hasher.update((token.len() as u64).to_be_bytes());
+            hasher.update(token);
+        }
+        hasher.update((audience.len() as u64).to_be_bytes());
+        hasher.update(audience.as_bytes());
+        // Versions are local to each replica, so validations are shared by what the policy and the
+        // service token mappings actually are instead.
+        #[cfg(feature = "redis")]
+        let shared_hash = {
+            let mut hasher = hasher.clone();
+            hasher.update(policy.fingerprint());
+            hasher.update(token_map.fingerprint());
+            hasher.finalize().into()
+        };
+        hasher.update(policies.version().to_be_bytes());
+        hasher.update(token_map.version().to_be_bytes());
+        Some(CacheKey {
+            hash: hasher.finalize().into(),
+            request_independent,
+            #[cfg(feature = "redis")]
+            shared_hash,
+        })
+    }
+
+    /// Gets the result of the cached validation with the given key, if there is one: either the
+    /// response for a successful validation, or the error it failed with.
+    pub(super) fn get(&self, key: &CacheKey) -> Option<Result<Response, AuthError>> {
+        let cached = self.cached(key);
+        telemetry::record_cache_lookup(self.name(), cached.is_some());
+        cached
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<Result<Response, AuthError>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let cached = entries.by_key.get_mut(key)?;
+        let now = Instant::now();
+        if cached.expires_at <= now {
+            return None;
+        }
+
+        cached.last_used = now;
+        match &cached.result {
+            CachedResult::Success { headers, identity } => {
+                let mut response = (StatusCode::OK, headers.clone()).into_response();
+                response.extensions_mut().insert(identity.clone());
+                Some(Ok(response))
+            }
+            CachedResult::Failure(e) => Some(Err(e.clone())),
+        }
+    }
+
+    /// Caches the result of a validation under the given key, if it can be cached.
+    pub(super) fn insert(&self, key: CacheKey, result: &Result<(&str, Response), AuthError>) {
+        let (result, ttl) = match result {
+            Ok(("success", response)) if key.request_independent => {
+                let identity = match response.extensions().get::<VerifiedIdentity>() {
+                    Some(identity) => identity.clone(),
+                    None => return,
+                };
+                let ttl = self.success_ttl(identity.expires_at);
+                let headers = response.headers().clone();
+                (CachedResult::Success { headers, identity }, ttl)
+            }
+            // Tokens signed with an unknown key are left out, as a JWKS refresh may yet bring the
+            // key in. So are malformed tokens, which are rejected before their signature is ever
+            // checked, so caching them saves nothing, while letting garbage fill the cache.
+            Err(e @ (AuthError::InvalidToken(_) | AuthError::ExpiredToken(_))) => {
+                (CachedResult::Failure(e.clone()), self.failure_ttl)
+            }
+            _ => return,
+        };
+        self.insert_result(key, result, ttl);
+    }
+
+    /// How long to cache a successful validation of a token that expires at the given time for.
+    fn success_ttl(&self, expires_at: DateTime<Utc>) -> Duration {
+        (expires_at - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+            .min(self.max_ttl)
+    }
+
+    fn insert_result(&self, key: CacheKey, result: CachedResult, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.insert(
+            key,
+            CachedValidation {
+                result,
+                expires_at: now + ttl,
+                last_used: now,
+            },
+        );
+
+        // Rather than waiting for the next sweep, the least recently used validations are evicted
+        // right away, so a burst of new tokens can't grow the cache past its limits in between.
+        while self
+            .limits
+            .exceeded(entries.by_key.len(), entries.memory_bytes)
+        {
+            let reason = if self.limits.excess_entries(entries.by_key.len()) > 0 {
+                "entries"
+            } else {
+                "memory"
+            };
+            let least_recently_used = entries
+                .by_key
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| *key);
+            match least_recently_used {
+                Some(key) => {
+                    entries.remove(&key);
+                    telemetry::record_cache_eviction(self.name(), reason);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl ValidationCache {
+    /// Checks Redis for revocations of the given tokens, and, unless the validation with the given
+    /// key is already cached here, for the validation as cached by another replica, which is then
+    /// cached here too.
+    ///
+    /// Otherwise, or if Redis is unavailable, this is what's already cached here.
+    pub(super) async fn get_shared(
+        &self,
+        key: &CacheKey,
+        credentials: &[Credential],
+        cached: Option<Result<Response, AuthError>>,
+    ) -> Option<Result<Response, AuthError>> {
+        let shared_cache = match &self.shared_cache {
+            Some(shared_cache) => shared_cache,
+            None => return cached,
+        };
+
+        let mut keys = credentials
+            .iter()
+            .map(|credential| {
+                let hash = Sha256::digest(credential.token.secret().as_bytes());
+                shared_cache.key("revoked", &hash)
+            })
+            .collect::<Vec<_>>();
+        let look_up = cached.is_none() && key.request_independent;
+        if look_up {
+            keys.push(shared_cache.key("validation", &key.shared_hash));
+        }
+
+        let mut values = match shared_cache.get(&keys).await {
+            Some(values) => values,
+            None => return cached,
+        };
+        let shared = if look_up {
+            let shared = values.pop().flatten();
+            telemetry::record_cache_lookup("redis_validation", shared.is_some());
+            shared
+        } else {
+            None
+        };
+        if values.iter().any(Option::is_some) {
+            return Some(Err(AuthError::RevokedToken));
+        }
+
+        let shared = match shared {
+            Some(shared) => shared,
+            None => return cached,
+        };
+        let (result, until) = serde_json::from_slice::<SharedValidation>(&shared)
+            .ok()?
+            .into_cached(credentials)?;
+        self.insert_result(*key, result, self.success_ttl(until));
+        self.cached(key)
+    }
+
+    /// Shares the result of a validation with other replicas, if it succeeded and can be cached.
+    ///
+    /// Failures are never shared, as they're cheap to come to again, and neither are validations
+    /// with sensitive headers, such as Basic auth credentials for the upstream.
+    pub(super) fn insert_shared(
+        &self,
+        key: &CacheKey,
+        credentials: &[Credential],
+        result: &Result<(&str, Response), AuthError>,
+    ) {
+        let shared_cache = match &self.shared_cache {
+            Some(shared_cache) => shared_cache,
+            None => return,
+        };
+        let response = match result {
+            Ok(("success", response)) if key.request_independent => response,
+            _ => return,
+        };
+        let identity = match response.extensions().get::<VerifiedIdentity>() {
+            Some(identity) => identity,
+            None => return,
+        };
+
+        let ttl = shared_cache.ttl(self.success_ttl(identity.expires_at));
+        let value = SharedValidation::new(response.headers(), identity, credentials, ttl)
+            .and_then(|shared| serde_json::to_vec(&shared).ok());
+        if let Some(value) = value {
+            shared_cache.set(shared_cache.key("validation", &key.shared_hash), value, ttl);
+        }
+    }
+}
+
+/// A successful validation, as shared with other replicas through Redis.
+///
+/// The validated token itself is never shared. Which of the presented tokens it was is shared
+/// instead, so replicas that pick the validation up take it from the request they're validating.
+#[cfg(feature = "redis")]
+#[derive(Deserialize, Serialize)]
+struct SharedValidation {
+    headers: Vec<(String, String)>,
+    email: Option<String>,
+    groups: Option<Value>,
+    claims: Option<Value>,
+    subject: String,
+    issuer_url: String,
+    expires_at: i64,
+    access_token: Option<usize>,
+    service_token_id: Option<String>,
+    internal_claims: Option<Map<String, Value>>,
+    jti: Option<String>,
+
+    /// When the validation stops being shared, so replicas that pick it up don't cache it for any
+    /// longer than that.
+    shared_until: i64,
+}
+
+#[cfg(feature = "redis")]
+impl SharedValidation {
+    /// Builds the shared form of a successful validation, unless it can't be shared.
+    ///
+    /// Validations with sensitive headers, such as credentials for the upstream, can't be, since
+    /// those must never end up in Redis.
+    fn new(
+        headers: &HeaderMap,
+        identity: &VerifiedIdentity,
+        credentials: &[Credential],
+        ttl: Duration,
+    ) -> Option<Self> {
+        if headers.values().any(HeaderValue::is_sensitive) {
+            return None;
+        }
+
+        let shared_until = Utc::now() + chrono::Duration::from_std(ttl).ok()?;
+
+        Some(Self {
+            headers: header_pairs(headers)?,
+            email: identity.email.clone(),
+            groups: identity.groups.clone(),
+            claims: identity.claims.clone(),
+            subject: identity.subject.clone(),
+            issuer_url: identity.issuer_url.as_str().to_string(),
+            expires_at: identity.expires_at.timestamp(),
+            access_token: identity.access_token,
+            service_token_id: identity.service_token_id.clone(),
+            internal_claims: identity.internal_claims.clone(),
+            jti: identity.jti.clone(),
+            shared_until: shared_until.timestamp(),
+        })
+    }
+
+    /// Turns the shared validation back into a cached one, along with when it stops being valid,
+    /// as long as the validated token is among the given tokens.
+    fn into_cached(self, credentials: &[Credential]) -> Option<(CachedResult, DateTime<Utc>)> {
+        if let Some(index) = self.access_token {
+            credentials.get(index)?;
+        }
+        let expires_at = Utc.timestamp_opt(self.expires_at, 0).single()?;
+        let shared_until = Utc.timestamp_opt(self.shared_until, 0).single()?;
+
+        let identity = VerifiedIdentity {
+            email: self.email,
+            groups: self.groups,
+            claims: self.claims,
+            subject: self.subject,
+            issuer_url: IssuerUrl::new(self.issuer_url).ok()?,
+            expires_at,
+            access_token: self.access_token,
+            service_token_id: self.service_token_id,
+            internal_claims: self.internal_claims,
+            jti: self.jti,
+        };
+        let headers = headers_from_pairs(self.headers)?;
+        Some((
+            CachedResult::Success { headers, identity },
+            expires_at.min(shared_until),
+        ))
+    }
+}
+
+impl SweepableCache for ValidationCache {
+    fn name(&self) -> &'static str {
+        "validation"
+    }
+
+    /// Removes validations whose TTL has passed, and then the least recently used validations until
+    /// the cache fits within `limits`.
+    fn sweep(&self, limits: SweepLimits) -> SweepOutcome {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = Instant::now();
+        let entries_before = entries.by_key.len();
+        entries.by_key.retain(|_, cached| cached.expires_at > now);
+        let expired = (entries_before - entries.by_key.len()) as u64;
+
+        // Expired validations were dropped without going through `Entries::remove`.
+        entries.memory_bytes = entries
+            .by_key
+            .values()
+            .map(CachedValidation::memory_bytes)
+            .sum::<usize>();
+
+        let excess_entries = limits.excess_entries(entries.by_key.len());
+        let mut evicted = 0;
+        if limits.exceeded(entries.by_key.len(), entries.memory_bytes) {
+            let mut by_last_use = entries
+                .by_key
+                .iter()
+                .map(|(key, cached)| (cached.last_used, *key))
+                .collect::<Vec<_>>();
+            by_last_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+
+            for (_, key) in by_last_use {
+                if !limits.exceeded(entries.by_key.len(), entries.memory_bytes) {
+                    break;
+                }
+
+                if entries.remove(&key).is_some() {
+                    evicted += 1;
+                }
+            }
+        }
+
+        SweepOutcome::new(
+            entries.by_key.len(),
+            entries.memory_bytes,
+            expired,
+            evicted,
+            excess_entries,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "redis")]
+    use hyper::header;
+
+    #[cfg(feature = "redis")]
+    use super::super::{extract::CredentialSource, insert_oauth2_proxy_headers};
+    use super::*;
+    #[cfg(feature = "redis")]
+    use crate::validation::token::CloudflareAccessOIDCAccessToken;
+    use crate::validation::token::MalformedReason;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn cache(limits: SweepLimits) -> ValidationCache {
+        let config = ValidationCacheConfig {
+            max_ttl_secs: 60,
+            failure_ttl_secs: 10,
+        };
+        ValidationCache::new(&config, limits)
+    }
+
+    fn key(n: u8) -> CacheKey {
+        CacheKey {
+            hash: [n; 32],
+            request_independent: true,
+            #[cfg(feature = "redis")]
+            shared_hash: [n; 32],
+        }
+    }
+
+    fn invalid_token() -> CachedResult {
+        CachedResult::Failure(AuthError::InvalidToken("bad signature".to_string()))
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used_over_entry_limit() {
+        let cache = cache(SweepLimits {
+            max_memory_bytes: None,
+            max_entries: Some(2),
+        });
+        cache.insert_result(key(1), invalid_token(), TTL);
+        cache.insert_result(key(2), invalid_token(), TTL);
+        // Using the first one makes the second the least recently used.
+        assert!(cache.cached(&key(1)).is_some());
+        cache.insert_result(key(3), invalid_token(), TTL);
+
+        assert!(cache.cached(&key(1)).is_some());
+        assert!(cache.cached(&key(2)).is_none());
+        assert!(cache.cached(&key(3)).is_some());
+        assert_eq!(cache.entries.lock().unwrap().by_key.len(), 2);
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used_over_memory_limit() {
+        let entry_bytes = CachedValidation {
+            result: invalid_token(),
+            expires_at: Instant::now(),
+            last_used: Instant::now(),
+        }
+        .memory_bytes();
+        let cache = cache(SweepLimits {
+            max_memory_bytes: Some(entry_bytes * 2),
+            max_entries: None,
+        });
+        for n in 1..=3 {
+            cache.insert_result(key(n), invalid_token(), TTL);
+        }
+
+        assert!(cache.cached(&key(1)).is_none());
+        assert!(cache.cached(&key(2)).is_some());
+        assert!(cache.cached(&key(3)).is_some());
+        assert_eq!(cache.entries.lock().unwrap().memory_bytes, entry_bytes * 2);
+    }
+
+    #[test]
+    fn sweep_keeps_memory_total_in_step() {
+        let cache = cache(SweepLimits::default());
+        cache.insert_result(key(1), invalid_token(), TTL);
+        cache.insert_result(key(2), invalid_token(), Duration::from_nanos(1));
+        std::thread::sleep(Duration::from_millis(1));
+
+        let outcome = cache.sweep(SweepLimits::default());
+        assert_eq!(outcome.entries, 1);
+        assert_eq!(outcome.expired, 1);
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.memory_bytes, entries.by_key[&key(1)].memory_bytes());
+    }
+
+    #[test]
+    fn only_failures_of_the_token_itself_are_cached() {
+        let cases = [
+            (
+                AuthError::MalformedToken(MalformedReason::WrongSegmentCount),
+                false,
+            ),
+            (AuthError::InvalidToken("bad signature".to_string()), true),
+            (AuthError::ExpiredToken("expired".to_string()), true),
+            (AuthError::MissingToken, false),
+        ];
+        for (n, (error, cached)) in cases.into_iter().enumerate() {
+            let cache = cache(SweepLimits::default());
+            let key = key(n as u8);
+            cache.insert(key, &Err(error));
+            assert_eq!(cache.cached(&key).is_some(), cached);
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    fn credential(token: &'static str) -> Credential {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-access-jwt-assertion", HeaderValue::from_static(token));
+        let token =
+            CloudflareAccessOIDCAccessToken::from_header(&headers, "cf-access-jwt-assertion")
+                .unwrap()
+                .unwrap();
+        Credential {
+            source: CredentialSource::Header,
+            token,
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    fn identity(access_token: Option<usize>) -> VerifiedIdentity {
+        VerifiedIdentity {
+            email: Some("user@example.com".to_string()),
+            groups: None,
+            claims: None,
+            subject: "user-1".to_string(),
+            issuer_url: IssuerUrl::new("https://test-team.cloudflareaccess.com".to_string())
+                .unwrap(),
+            expires_at: Utc::now() + chrono::Duration::minutes(5),
+            access_token,
+            service_token_id: None,
+            internal_claims: None,
+            jti: None,
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn oauth2_proxy_token_is_never_shared() {
+        let credentials = [credential("header.payload.signature")];
+        let identity = identity(None);
+
+        let mut headers = HeaderMap::new();
+        insert_oauth2_proxy_headers(
+            &mut headers,
+            "user-1",
+            Some("user@example.com"),
+            &[],
+            credentials[0].token.secret(),
+        );
+        assert!(SharedValidation::new(&headers, &identity, &credentials, TTL).is_none());
+
+        // Everything else the preset sets can be shared.
+        headers.remove(header::AUTHORIZATION);
+        assert!(SharedValidation::new(&headers, &identity, &credentials, TTL).is_some());
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn shared_validation_refers_to_presented_token() {
+        let credentials = [
+            credential("first.token.here"),
+            credential("second.token.here"),
+        ];
+        let headers = HeaderMap::new();
+
+        let shared =
+            SharedValidation::new(&headers, &identity(Some(1)), &credentials, TTL).unwrap();
+        assert_eq!(shared.access_token, Some(1));
+        let value = serde_json::to_vec(&shared).unwrap();
+        assert!(!String::from_utf8(value)
+            .unwrap()
+            .contains("second.token.here"));
+
+        // The token is taken from whichever request picks the validation up, which must have it.
+        let (cached, _) = shared.into_cached(&credentials).unwrap();
+        match cached {
+            CachedResult::Success { identity, .. } => assert_eq!(identity.access_token, Some(1)),
+            CachedResult::Failure(_) => panic!("validation should be cached as a success"),
+        }
+        let shared =
+            SharedValidation::new(&headers, &identity(Some(1)), &credentials, TTL).unwrap();
+        assert!(shared.into_cached(&credentials[..1]).is_none());
+    }
+}
@@ -0,0 +1,120 @@
+//! Contract tests for the validator, using tokens minted by the `test-util` kit.
+//!
+//! Run with `cargo test --features test-util`.
+
+use std::time::Duration;
+
+use cloudflare_access_forwardauth::{
+    config::Config,
+    test_util::{assert_header, assert_no_header, assert_status, TestIssuer, TestValidator},
+};
+use hyper::StatusCode;
+
+const TEAM_DOMAIN: &str = "https://test-team.cloudflareaccess.com";
+const AUDIENCE: &str = "4714c1358e65fe4b408ad6d432a5f878f08194bdb4752441fd56faefa9b2b6f2";
+const HOST: &str = "app.example.com";
+
+async fn validator(issuer: &TestIssuer) -> TestValidator {
+    let mut config = Config::default();
+    config.auth_domain = Some(issuer.issuer_url().clone());
+    config.hosts.insert(HOST.to_string(), AUDIENCE.to_string());
+
+    TestValidator::new(config, &[issuer])
+        .await
+        .expect("validator should start")
+}
+
+#[tokio::test]
+async fn validates_token_for_audience_in_path() {
+    let issuer = TestIssuer::new(TEAM_DOMAIN).unwrap();
+    let mut validator = validator(&issuer).await;
+    let token = issuer
+        .token(AUDIENCE)
+        .subject("user-1")
+        .email("user@example.com")
+        .mint();
+
+    let response = validator
+        .validate(
+            Some(AUDIENCE),
+            &[("cf-access-jwt-assertion", token.as_str())],
+        )
+        .await;
+    assert_status(&response, StatusCode::OK);
+    assert_header(&response, "x-auth-aud", AUDIENCE);
+    assert_header(&response, "x-auth-email", "user@example.com");
+    assert_header(&response, "x-auth-sub", "user-1");
+    assert_header(&response, "x-auth-token-type", "app");
+    assert_header(&response, "content-length", "0");
+    assert_no_header(&response, "transfer-encoding");
+}
+
+#[tokio::test]
+async fn validates_token_for_audience_of_forwarded_host() {
+    let issuer = TestIssuer::new(TEAM_DOMAIN).unwrap();
+    let mut validator = validator(&issuer).await;
+    let token = issuer
+        .token(AUDIENCE)
+        .subject("user-2")
+        .email("user@example.com")
+        .mint();
+
+    let response = validator
+        .validate(
+            None,
+            &[
+                ("cf-access-jwt-assertion", token.as_str()),
+                ("x-forwarded-host", HOST),
+            ],
+        )
+        .await;
+    assert_status(&response, StatusCode::OK);
+    assert_header(&response, "x-auth-aud", AUDIENCE);
+    assert_header(&response, "x-auth-email", "user@example.com");
+    assert_header(&response, "x-auth-sub", "user-2");
+
+    // Tokens for any other audience are rejected, wherever the audience comes from.
+    let other_audience = "0".repeat(64);
+    let token = issuer.token(&other_audience).mint();
+    let response = validator
+        .validate(
+            None,
+            &[
+                ("cf-access-jwt-assertion", token.as_str()),
+                ("x-forwarded-host", HOST),
+            ],
+        )
+        .await;
+    assert_status(&response, StatusCode::UNAUTHORIZED);
+    assert_header(&response, "x-auth-error", "invalid_token");
+}
+
+#[tokio::test]
+async fn rejects_expired_token() {
+    let issuer = TestIssuer::new(TEAM_DOMAIN).unwrap();
+    let mut validator = validator(&issuer).await;
+
+    let token = issuer.token(AUDIENCE).expired().mint();
+    let response = validator
+        .validate(
+            Some(AUDIENCE),
+            &[("cf-access-jwt-assertion", token.as_str())],
+        )
+        .await;
+    assert_status(&response, StatusCode::UNAUTHORIZED);
+    assert_header(&response, "x-auth-error", "expired_token");
+    assert_no_header(&response, "x-auth-email");
+
+    // Tokens that only just expired are still accepted, within the clock skew leeway.
+    let token = issuer
+        .token(AUDIENCE)
+        .expires_in(Duration::from_secs(0))
+        .mint();
+    let response = validator
+        .validate(
+            Some(AUDIENCE),
+            &[("cf-access-jwt-assertion", token.as_str())],
+        )
+        .await;
+    assert_status(&response, StatusCode::OK);
+}